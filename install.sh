@@ -3,6 +3,7 @@
 
 # DPIBreak install script
 # CHANGELOG:
+# v1.5 - Install the release key for `dpibreak self-update`
 # v1.4 - Support for new tarball/exdir naming (since DPIBreak v0.6.2)
 # v1.3 - Fix to prevent tarball fetch and install logs from appearing during uninstallation
 # v1.2 - Remove -v on tar, fix/add logs and add SVERSION variable
@@ -10,7 +11,7 @@
 
 set -eu
 
-SVERSION='v1.4'
+SVERSION='v1.5'
 PROJECT='DPIBreak'
 REPO='dilluti0n/dpibreak'
 LINUX='Linux'
@@ -77,6 +78,8 @@ PREFIX=/usr/local
 MANPREFIX="$PREFIX/share/man"
 PROG='dpibreak'
 MAN='dpibreak.1'
+KEY='release-key.asc'
+KEYDIR='/etc/dpibreak'
 
 echo "$PROJECT installer $SVERSION for $AMD64 $LINUX"
 echo Source: "https://github.com/$REPO/blob/master/install.sh"
@@ -111,12 +114,14 @@ do_install() {
 
     do_sudo install -Dm755 "$PROG" "$PREFIX/bin/$PROG"
     do_sudo install -Dm644 "$MAN"  "$MANPREFIX/man1/$MAN"
+    [ ! -f "$KEY" ] || do_sudo install -Dm644 "$KEY" "$KEYDIR/$KEY"
     echo "Installation complete." >&2
 }
 
 do_uninstall() {
     do_sudo rm -f "$PREFIX/bin/$PROG"
     do_sudo rm -f "$MANPREFIX/man1/$MAN"
+    do_sudo rm -f "$KEYDIR/$KEY"
     echo "Uninstallation complete." >&2
 }
 
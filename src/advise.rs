@@ -0,0 +1,398 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `dpibreak advise [OPTIONS]`: what DPIBreak would do here, without
+//! root
+//!
+//! Watches the TCP sockets of the system in `/proc/net/tcp{,6}` while
+//! the user browses as usual, and tells from their state which servers
+//! show the symptoms of DPI interference:
+//!
+//! - a handshake never answered (SYN_SENT with retransmissions): the
+//!   address itself is blocked, which no desync gets around;
+//! - data sent and never acknowledged (ESTABLISHED with a send queue
+//!   and retransmissions): the ClientHello is dropped;
+//! - connections gone within [`SHORT`] of being set up while the
+//!   system-wide count of reset connections grows: the ClientHello is
+//!   answered with a forged RST.
+//!
+//! Nothing is captured or sent, so no privilege is needed; anyone can
+//! read these files. A config for `--config` suited to what was seen is
+//! then written in the state [`datadir`].
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::platform::datadir;
+
+const INTERVAL: Duration = Duration::from_millis(500);
+const DEFAULT_DURATION: Duration = Duration::from_secs(60);
+
+/// Lifetime after which a connection closing is not suspicious
+const SHORT: Duration = Duration::from_secs(2);
+const SHORT_SAMPLES: u32 = (SHORT.as_millis() / INTERVAL.as_millis()) as u32;
+
+const TCP_ESTABLISHED: u8 = 0x01;
+const TCP_SYN_SENT: u8 = 0x02;
+
+/// Socket, as a line of `/proc/net/tcp{,6}` shows it
+#[derive(Debug, PartialEq)]
+struct Sock {
+    local: SocketAddr,
+    remote: SocketAddr,
+    state: u8,
+
+    /// Bytes sent and not yet acknowledged, when established
+    tx_queue: u32,
+    retransmits: u32,
+}
+
+/// Address of `/proc/net/tcp{,6}`: 32-bit words in hex, each in host
+/// byte order, then the port.
+fn parse_addr(s: &str) -> Option<SocketAddr> {
+    let (ip, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let mut bytes = Vec::with_capacity(16);
+    for i in (0..ip.len()).step_by(8) {
+        bytes.extend(u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?.to_ne_bytes());
+    }
+    let ip = match bytes.len() {
+        4 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(bytes).ok()?)),
+        16 => Ipv6Addr::from(<[u8; 16]>::try_from(bytes).ok()?).to_canonical(),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// Socket of a line of `/proc/net/tcp{,6}`; None for the header.
+fn parse_sock(line: &str) -> Option<Sock> {
+    // sl local remote st tx_queue:rx_queue tr:when retrnsmt ...
+    let f: Vec<&str> = line.split_whitespace().collect();
+    let (tx_queue, _) = f.get(4)?.split_once(':')?;
+
+    Some(Sock {
+        local: parse_addr(f.get(1)?)?,
+        remote: parse_addr(f.get(2)?)?,
+        state: u8::from_str_radix(f.get(3)?, 16).ok()?,
+        tx_queue: u32::from_str_radix(tx_queue, 16).ok()?,
+        retransmits: u32::from_str_radix(f.get(6)?, 16).ok()?,
+    })
+}
+
+/// Counter `name` of `group` in `/proc/net/snmp`, where a line of names
+/// is followed by a line of values.
+fn snmp_counter(text: &str, group: &str, name: &str) -> Option<u64> {
+    let prefix = format!("{group}:");
+    let mut lines = text.lines().filter(|l| l.starts_with(&prefix));
+    let names = lines.next()?.split_whitespace();
+    let values = lines.next()?.split_whitespace();
+    names.zip(values).find(|&(n, _)| n == name)?.1.parse().ok()
+}
+
+/// Whether `ip` is of this network, where DPI is not
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified(),
+        IpAddr::V6(ip) => ip.is_loopback() || ip.is_unspecified()
+            || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80,
+    }
+}
+
+/// Sockets of the system to `ports` of remote servers
+fn sample(ports: &[u16]) -> Result<Vec<Sock>> {
+    let mut socks = Vec::new();
+    for path in ["/proc/net/tcp", "/proc/net/tcp6"] {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue, // no IPv6
+            Err(e) => return Err(e).with_context(|| format!("cannot read {path}")),
+        };
+        socks.extend(text.lines()
+            .filter_map(parse_sock)
+            .filter(|s| ports.contains(&s.remote.port()) && !is_local(s.remote.ip())));
+    }
+    Ok(socks)
+}
+
+fn estab_resets() -> Option<u64> {
+    snmp_counter(&std::fs::read_to_string("/proc/net/snmp").ok()?, "Tcp", "EstabResets")
+}
+
+/// Symptoms seen for a server
+#[derive(Default, Debug)]
+struct Dest {
+    connections: u32,
+    established: u32,
+
+    /// Handshakes retransmitted
+    unanswered: u32,
+
+    /// Data retransmitted while established
+    stalled: u32,
+
+    /// Closed within [`SHORT`] of being established
+    short: u32,
+}
+
+/// Connection being watched
+struct Conn {
+    /// Sample it was established in
+    established: Option<u32>,
+    unanswered: bool,
+    stalled: bool,
+    seen: u32,
+}
+
+#[derive(Default)]
+struct Tracker {
+    samples: u32,
+    conns: HashMap<(SocketAddr, SocketAddr), Conn>,
+    dests: HashMap<IpAddr, Dest>,
+}
+
+impl Tracker {
+    fn observe(&mut self, socks: &[Sock]) {
+        let now = self.samples;
+        self.samples += 1;
+
+        for s in socks {
+            let dest = self.dests.entry(s.remote.ip()).or_default();
+            let conn = self.conns.entry((s.local, s.remote)).or_insert_with(|| {
+                dest.connections += 1;
+                Conn { established: None, unanswered: false, stalled: false, seen: now }
+            });
+            conn.seen = now;
+
+            if s.state == TCP_SYN_SENT && s.retransmits > 0 && !conn.unanswered {
+                conn.unanswered = true;
+                dest.unanswered += 1;
+            }
+            if s.state != TCP_ESTABLISHED {
+                continue;
+            }
+            if conn.established.is_none() {
+                conn.established = Some(now);
+                dest.established += 1;
+            }
+            if s.tx_queue > 0 && s.retransmits > 0 && !conn.stalled {
+                conn.stalled = true;
+                dest.stalled += 1;
+            }
+        }
+
+        // Gone since the last sample
+        let dests = &mut self.dests;
+        self.conns.retain(|(_, remote), conn| {
+            if conn.seen == now {
+                return true;
+            }
+            if let Some(at) = conn.established && !conn.stalled && now - at <= SHORT_SAMPLES
+                && let Some(dest) = dests.get_mut(&remote.ip()) {
+                dest.short += 1;
+            }
+            false
+        });
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Fine,
+    Blocked,
+    Dropped,
+    Reset,
+}
+
+impl Dest {
+    /// What the symptoms point to; short connections count as reset
+    /// only if some were reset in the system meanwhile.
+    fn verdict(&self, resets: bool) -> Verdict {
+        if self.stalled > 0 {
+            Verdict::Dropped
+        } else if resets && self.short > 0 && self.short * 2 >= self.established {
+            Verdict::Reset
+        } else if self.unanswered > 0 && self.established == 0 {
+            Verdict::Blocked
+        } else {
+            Verdict::Fine
+        }
+    }
+}
+
+/// Lines of the suggested config for the verdicts seen, or None if
+/// DPIBreak would not help.
+fn suggest(verdicts: &[Verdict]) -> Option<String> {
+    let dropped = verdicts.contains(&Verdict::Dropped);
+    let reset = verdicts.contains(&Verdict::Reset);
+    if !dropped && !reset {
+        return None;
+    }
+
+    let mut config = String::from("# Suggested by `dpibreak advise`\n\
+                                   # First byte split off and a fake before each segment\n\
+                                   preset = generic\n");
+    if reset {
+        config += "# Servers were reset right after the handshake\ndrop-forged-rst\n";
+    }
+    Some(config)
+}
+
+/// `dpibreak advise [OPTIONS]`
+pub fn command(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut duration = DEFAULT_DURATION;
+    let mut ports = Vec::new();
+    let mut out: Option<PathBuf> = None;
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| anyhow!("advise: {arg}: missing value"));
+        match arg.as_str() {
+            "--duration" => duration = Duration::from_secs(value()?.parse()
+                .context("advise: --duration: not a number of seconds")?),
+            "--port" => ports.push(value()?.parse().context("advise: --port: not a port")?),
+            "--out" => out = Some(PathBuf::from(value()?)),
+            _ => return Err(anyhow!("advise: unknown argument: {arg}")),
+        }
+    }
+    if ports.is_empty() {
+        ports.push(443);
+    }
+
+    println!("Watching connections to port {} for {}s; browse as usual meanwhile.",
+             ports.iter().map(u16::to_string).collect::<Vec<_>>().join(","), duration.as_secs());
+
+    let resets_before = estab_resets();
+    let mut tracker = Tracker::default();
+    for _ in 0..(duration.as_millis() / INTERVAL.as_millis()).max(1) {
+        tracker.observe(&sample(&ports).context("advise")?);
+        std::thread::sleep(INTERVAL);
+    }
+    let resets = match (resets_before, estab_resets()) {
+        (Some(before), Some(after)) => after > before,
+        _ => false,
+    };
+
+    let mut dests: Vec<(IpAddr, Dest)> = tracker.dests.into_iter().collect();
+    dests.sort_by_key(|&(ip, _)| ip);
+    println!("\n{:<40} {:>5} {:>7} {:>6} {:>5}  VERDICT", "SERVER", "CONNS", "NO-SYN", "STALL", "SHORT");
+    let mut verdicts = Vec::new();
+    for (ip, d) in &dests {
+        let verdict = d.verdict(resets);
+        let text = match verdict {
+            Verdict::Fine => "fine",
+            Verdict::Blocked => "address blocked; DPIBreak cannot help",
+            Verdict::Dropped => "ClientHello dropped",
+            Verdict::Reset => "reset after ClientHello",
+        };
+        println!("{:<40} {:>5} {:>7} {:>6} {:>5}  {text}",
+                 ip.to_string(), d.connections, d.unanswered, d.stalled, d.short);
+        verdicts.push(verdict);
+    }
+
+    let Some(config) = suggest(&verdicts) else {
+        println!("\nNo interference DPIBreak can get around was seen.");
+        return Ok(());
+    };
+
+    let out = match out {
+        Some(out) => out,
+        None => datadir::create(datadir::Kind::State).context("advise")?.join("advised.conf"),
+    };
+    std::fs::write(&out, &config).with_context(|| format!("advise: cannot write {}", out.display()))?;
+    println!("\nSuggested config written to {}; run it with\n  dpibreak --config {}",
+             out.display(), out.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(ip: [u8; 4]) -> String {
+        format!("{:08X}", u32::from_ne_bytes(ip))
+    }
+
+    fn sock(remote: &str, state: u8, tx_queue: u32, retransmits: u32) -> Sock {
+        Sock {
+            local: "192.168.0.2:40000".parse().unwrap(),
+            remote: remote.parse().unwrap(),
+            state,
+            tx_queue,
+            retransmits,
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let line = format!("   3: {}:9C40 {}:01BB 01 00000205:00000000 01:0000002A 00000002  1000 \
+                            0 54321 1 0000000000000000 20 4 30 10 -1",
+                           hex([192, 168, 0, 2]), hex([93, 184, 216, 34]));
+        assert_eq!(parse_sock(&line).unwrap(), Sock {
+            local: "192.168.0.2:40000".parse().unwrap(),
+            remote: "93.184.216.34:443".parse().unwrap(),
+            state: TCP_ESTABLISHED,
+            tx_queue: 0x205,
+            retransmits: 2,
+        });
+        assert!(parse_sock("  sl  local_address rem_address   st tx_queue rx_queue").is_none());
+
+        let v6 = format!("{}{}{}{}:01BB", hex([0x20, 0x01, 0x0d, 0xb8]), hex([0; 4]), hex([0; 4]),
+                         hex([0, 0, 0, 1]));
+        assert_eq!(parse_addr(&v6).unwrap(), "[2001:db8::1]:443".parse().unwrap());
+        let mapped = format!("{}{}{}{}:01BB", hex([0; 4]), hex([0; 4]), hex([0, 0, 0xff, 0xff]),
+                             hex([93, 184, 216, 34]));
+        assert_eq!(parse_addr(&mapped).unwrap(), "93.184.216.34:443".parse().unwrap());
+
+        let snmp = "Tcp: RtoAlgorithm RtoMin EstabResets\nTcp: 1 200 17\nUdp: InDatagrams\nUdp: 5\n";
+        assert_eq!(snmp_counter(snmp, "Tcp", "EstabResets"), Some(17));
+        assert_eq!(snmp_counter(snmp, "Udp", "EstabResets"), None);
+    }
+
+    #[test]
+    fn test_tracker() {
+        let mut t = Tracker::default();
+        t.observe(&[sock("198.51.100.1:443", TCP_SYN_SENT, 0, 0),
+                    sock("198.51.100.2:443", TCP_ESTABLISHED, 517, 0),
+                    sock("198.51.100.3:443", TCP_ESTABLISHED, 0, 0),
+                    sock("198.51.100.4:443", TCP_ESTABLISHED, 0, 0)]);
+        t.observe(&[sock("198.51.100.1:443", TCP_SYN_SENT, 0, 1),
+                    sock("198.51.100.2:443", TCP_ESTABLISHED, 517, 1),
+                    sock("198.51.100.4:443", TCP_ESTABLISHED, 0, 0)]);
+        for _ in 0..SHORT_SAMPLES {
+            t.observe(&[sock("198.51.100.4:443", TCP_ESTABLISHED, 0, 0)]);
+        }
+        t.observe(&[]);
+
+        let dest = |ip: &str| &t.dests[&ip.parse::<IpAddr>().unwrap()];
+        assert_eq!(dest("198.51.100.1").verdict(true), Verdict::Blocked);
+        assert_eq!(dest("198.51.100.2").verdict(true), Verdict::Dropped);
+        assert_eq!(dest("198.51.100.3").verdict(true), Verdict::Reset);
+        assert_eq!(dest("198.51.100.3").verdict(false), Verdict::Fine);
+        assert_eq!(dest("198.51.100.4").verdict(true), Verdict::Fine);
+        assert!(t.conns.is_empty());
+
+        assert!(is_local("10.1.2.3".parse().unwrap()));
+        assert!(is_local("fd00::1".parse().unwrap()));
+        assert!(!is_local("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_suggest() {
+        assert_eq!(suggest(&[Verdict::Fine, Verdict::Blocked]), None);
+
+        let config = suggest(&[Verdict::Dropped, Verdict::Reset]).unwrap();
+        assert!(config.contains("preset = generic\n") && config.contains("drop-forged-rst\n"));
+        assert!(!suggest(&[Verdict::Dropped]).unwrap().contains("drop-forged-rst"));
+
+        let path = std::env::temp_dir().join(format!("dpibreak-advised-{}.conf", std::process::id()));
+        std::fs::write(&path, &config).unwrap();
+        let args = ["--config".to_string(), path.display().to_string()];
+        let parsed = crate::opt::Config::parse(args.into_iter());
+        std::fs::remove_file(&path).unwrap();
+        assert!(parsed.is_ok(), "{:#}", parsed.err().unwrap());
+    }
+}
@@ -0,0 +1,132 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Control socket: a Unix socket at `--control-socket` answering
+//! commands of running DPIBreak
+//!
+//! A client connects, writes one command line and reads the answer
+//! until the socket is closed. Answers are `key: value` lines; a failed
+//! command is answered with a single line starting with `error: `.
+//! `dpibreak <command>` and `dpibreak top` are such clients.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::opt;
+
+/// How long a client may take to send its command
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct Command {
+    name: &'static str,
+
+    /// Answer to the command
+    run: fn() -> String,
+}
+
+/// Commands a client may send
+const COMMANDS: &[Command] = &[
+    Command { name: "status", run: crate::stats::status },
+    Command { name: "capabilities", run: crate::platform::capabilities },
+    Command { name: "flows", run: crate::pkt::outcome::recent },
+];
+
+fn answer(line: &str) -> String {
+    let cmd = line.trim();
+    match COMMANDS.iter().find(|c| c.name == cmd) {
+        Some(c) => (c.run)(),
+        None => format!("error: unknown command: {cmd}\n"),
+    }
+}
+
+fn serve(stream: UnixStream) -> Result<()> {
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+
+    let mut line = String::new();
+    BufReader::new(&stream).take(256).read_line(&mut line)?;
+    (&stream).write_all(answer(&line).as_bytes())?;
+
+    Ok(())
+}
+
+/// Listen on `--control-socket` on a background thread, unless
+/// `--no-control-socket`. The socket is accessible to root only.
+pub fn spawn() -> Result<()> {
+    let Some(path) = opt::control_socket() else {
+        return Ok(());
+    };
+
+    // Left by a run that was killed; the pid file tells us no other
+    // instance is using it.
+    _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)
+        .with_context(|| format!("control: cannot bind {path}"))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .with_context(|| format!("control: cannot chmod {path}"))?;
+
+    crate::info!("control: listening on {path}");
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let res = stream.map_err(anyhow::Error::from).and_then(serve);
+            if let Err(e) = res {
+                crate::warn!("control: {e}");
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Remove the socket file on exit.
+pub fn cleanup() {
+    if let Some(path) = opt::control_socket() {
+        _ = std::fs::remove_file(path);
+    }
+}
+
+/// Send `cmd` to the running instance at `--control-socket` and return
+/// its answer.
+pub fn query(cmd: &str) -> Result<String> {
+    let path = opt::control_socket()
+        .ok_or_else(|| anyhow!("{cmd}: control socket is disabled"))?;
+
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("{cmd}: cannot connect to {path}; is DPIBreak running?"))?;
+    writeln!(stream, "{cmd}")?;
+
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+
+    if let Some(e) = reply.strip_prefix("error: ") {
+        return Err(anyhow!("{cmd}: {}", e.trim_end()));
+    }
+
+    Ok(reply)
+}
+
+/// `dpibreak <command> [OPTIONS]`: send `cmd` to the running instance
+/// and print its answer.
+pub fn command(cmd: &str, args: impl Iterator<Item = String>) -> Result<()> {
+    opt::Config::parse(args)?.install();
+    print!("{}", query(cmd)?);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_answer() {
+        assert!(answer("status\n").starts_with("uptime: "));
+        assert!(answer("capabilities\n").contains("\nstrategy-split: yes\n"));
+        assert!(answer("flows\n").lines().all(|l| l.starts_with("flow: ")));
+        assert_eq!(answer("reboot\n"), "error: unknown command: reboot\n");
+    }
+}
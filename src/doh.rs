@@ -0,0 +1,146 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--doh-forward`: local DNS listener forwarding to DNS-over-HTTPS
+//!
+//! Plaintext queries received on `--doh-listen` (UDP) are POSTed as
+//! `application/dns-message` (RFC 8484) to the resolver URL and the
+//! answer is sent back as is. HTTPS is done by the `curl` executable,
+//! like other external tools we drive, instead of linking a TLS stack;
+//! its connection to the resolver is desynced by ourselves like any
+//! other port 443 traffic. Queries are handled by a few worker threads
+//! so that a slow one does not hold back the others; when all are busy
+//! and the queue is full, more are dropped, so that a flood of queries
+//! cannot start curl without bound.
+
+use std::io::Write;
+use std::net::{SocketAddr, UdpSocket};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::opt;
+use crate::stats;
+
+/// Largest DNS message over UDP we accept (EDNS0 typical buffer size)
+const MAX_MSG: usize = 4096;
+
+/// Queries forwarded at once, each by its own curl
+const WORKERS: usize = 8;
+
+/// Queries waiting for a worker; more are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// Wait after a failed receive before the next one
+const RECV_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Seconds curl may take for one query
+const QUERY_TIMEOUT_SECS: &str = "5";
+
+fn doh_query(url: &str, query: &[u8]) -> Result<Vec<u8>> {
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", QUERY_TIMEOUT_SECS])
+        .args(["-H", "content-type: application/dns-message"])
+        .args(["-H", "accept: application/dns-message"])
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to spawn curl")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(query).context("failed to write query to curl")?;
+    }
+
+    let output = child.wait_with_output().context("failed to wait for curl")?;
+
+    if !output.status.success() {
+        return Err(anyhow!("curl: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    Ok(output.stdout)
+}
+
+fn forward(sock: &UdpSocket, url: &str, query: &[u8], from: SocketAddr) {
+    stats::COUNTERS.doh_queries.inc();
+
+    match doh_query(url, query) {
+        Ok(answer) if answer.len() >= 12 => {
+            if let Err(e) = sock.send_to(&answer, from) {
+                crate::warn!("doh: send to {from}: {e}");
+            }
+        }
+        Ok(answer) => {
+            stats::COUNTERS.doh_failed.inc();
+            crate::warn!("doh: short answer ({} bytes) from {url}", answer.len());
+        }
+        Err(e) => {
+            stats::COUNTERS.doh_failed.inc();
+            crate::warn!("doh: {e}");
+        }
+    }
+}
+
+/// Forward queries from `rx` until the listener is gone.
+fn work(sock: &UdpSocket, url: &str, rx: &Mutex<Receiver<(Vec<u8>, SocketAddr)>>) {
+    loop {
+        let next = rx.lock().unwrap_or_else(PoisonError::into_inner).recv();
+        let Ok((query, from)) = next else { return };
+        forward(sock, url, &query, from);
+    }
+}
+
+/// Bind `--doh-listen` and start forwarding on a background thread, if
+/// `--doh-forward` is given. Once running, later calls (on a restart
+/// by `--watch`) leave it as is.
+pub fn spawn() -> Result<()> {
+    static RUNNING: AtomicBool = AtomicBool::new(false);
+
+    let Some(url) = opt::doh_forward() else {
+        return Ok(());
+    };
+    if RUNNING.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let listen = opt::doh_listen();
+    let sock = Arc::new(UdpSocket::bind(listen)
+                        .with_context(|| format!("doh: cannot bind {listen}"))?);
+
+    crate::info!("doh: forwarding DNS on {listen} to {url}");
+    RUNNING.store(true, Ordering::Relaxed);
+
+    let (tx, rx) = mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(QUEUE_LEN);
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..WORKERS {
+        let (sock, rx) = (sock.clone(), rx.clone());
+        std::thread::spawn(move || work(&sock, url, &rx));
+    }
+
+    std::thread::spawn(move || {
+        let mut buf = [0u8; MAX_MSG];
+
+        loop {
+            let (n, from) = match sock.recv_from(&mut buf) {
+                Ok(r) => r,
+                Err(e) => {
+                    crate::warn!("doh: recv: {e}");
+                    std::thread::sleep(RECV_BACKOFF);
+                    continue;
+                }
+            };
+
+            if tx.try_send((buf[..n].to_vec(), from)).is_err() {
+                crate::debug!("doh: busy; query from {from} dropped");
+                stats::COUNTERS.doh_failed.inc();
+            }
+        }
+    });
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Exit status, and `--status-file` for supervisors
+//!
+//! An error that has a status of its own is tagged with
+//! [`WithStatus::status`] where it arises, and keeps it through the
+//! `anyhow` contexts added on its way up to `main`, which exits with it
+//! (see [`Error::from`]). Anything else exits with [`Status::Runtime`].
+
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::opt;
+
+/// Exit status of DPIBreak. Scripts and supervisors rely on these
+/// numbers (see EXIT STATUS in the man page): new ones may be added,
+/// existing ones are never renumbered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+
+    /// Failed while running, or for a reason not below
+    Runtime = 1,
+
+    /// Invalid or conflicting options
+    Args = 2,
+
+    /// Not root, or without the capabilities needed
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    Permission = 3,
+
+    /// The firewall or packet diversion backend cannot be set up
+    Backend = 4,
+
+    /// Another DPIBreak is running
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    Running = 5,
+
+    /// Cannot start as a daemon or service
+    Daemon = 6,
+}
+
+impl Status {
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+/// Error with the status to exit with
+#[derive(Debug)]
+pub struct Error {
+    pub status: Status,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+impl From<anyhow::Error> for Error {
+    /// `error` with the status it was tagged with, if any
+    fn from(error: anyhow::Error) -> Self {
+        let status = error.chain()
+            .find_map(|e| e.downcast_ref::<Error>())
+            .map_or(Status::Runtime, |e| e.status);
+        Self { status, error }
+    }
+}
+
+impl Error {
+    /// Messages of the error and of its causes, outermost first
+    pub fn chain(&self) -> anyhow::Chain<'_> {
+        self.error.chain()
+    }
+}
+
+pub trait WithStatus<T> {
+    /// Tag the error, if any, with `status` to exit with.
+    fn status(self, status: Status) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> WithStatus<T> for std::result::Result<T, E> {
+    fn status(self, status: Status) -> Result<T> {
+        self.map_err(|e| Error { status, error: e.into() }.into())
+    }
+}
+
+/// `--status-file` contents: one `key=value` per line
+fn status_text(state: &str, pid: u32, at: u64, exit: Option<&Error>) -> String {
+    let mut text = format!("state={state}\npid={pid}\nversion={}\ntime={at}\n",
+                           env!("CARGO_PKG_VERSION"));
+    if state != "running" {
+        text += &format!("exit_code={}\n", exit.map_or(Status::Ok, |e| e.status).code());
+    }
+    if let Some(e) = exit {
+        let message = format!("{:#}", e.error).replace(['\n', '\r'], " ");
+        text += &format!("error={message}\n");
+    }
+    text
+}
+
+/// Replace `--status-file`, if given, with `state` and, on exit, how.
+fn write_status(state: &str, exit: Option<&Error>) {
+    let Some(path) = opt::status_file() else { return };
+
+    let at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let text = status_text(state, std::process::id(), at, exit);
+
+    // Through a temporary file, so that readers never see half of it
+    let tmp = format!("{path}.tmp");
+    if let Err(e) = std::fs::write(&tmp, text).and_then(|_| std::fs::rename(&tmp, path)) {
+        crate::warn!("--status-file: cannot write {path}: {e}");
+    }
+}
+
+/// Tell `--status-file` that DPIBreak is up.
+pub fn report_running() {
+    write_status("running", None);
+}
+
+/// Tell `--status-file` how DPIBreak is exiting.
+pub fn report_exit(result: &std::result::Result<(), Error>) {
+    match result {
+        Ok(()) => write_status("stopped", None),
+        Err(e) => write_status("failed", Some(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use anyhow::{Context, anyhow};
+
+    #[test]
+    fn test_status() {
+        let tagged: Result<()> = Err(anyhow!("queue busy")).status(Status::Backend);
+        let e = Error::from(tagged.context("nfqueue").unwrap_err());
+        assert_eq!(e.status, Status::Backend);
+        assert_eq!(e.status.code(), 4);
+        assert_eq!(e.to_string(), "nfqueue");
+        assert_eq!(e.chain().map(|c| c.to_string()).collect::<Vec<_>>(), ["nfqueue", "queue busy"]);
+
+        assert_eq!(Error::from(anyhow!("boom")).status, Status::Runtime);
+    }
+
+    #[test]
+    fn test_status_text() {
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(status_text("running", 42, 1000, None),
+                   format!("state=running\npid=42\nversion={version}\ntime=1000\n"));
+        assert_eq!(status_text("stopped", 42, 1000, None),
+                   format!("state=stopped\npid=42\nversion={version}\ntime=1000\nexit_code=0\n"));
+
+        let e = Error::from(Err::<(), _>(anyhow!("locked\nby 7")).status(Status::Running).unwrap_err());
+        assert!(status_text("failed", 42, 1000, Some(&e)).ends_with("exit_code=5\nerror=locked by 7\n"));
+    }
+}
@@ -0,0 +1,472 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--hostlist`: handle only ClientHellos to listed server names
+//!
+//! A list is a text file with one domain per line; `#` starts a
+//! comment. A domain matches itself and its subdomains. Sources may be
+//! local paths or https:// URLs, and gzip or zstd compressed. URLs are
+//! downloaded with `curl` into the cache [`datadir`] and revalidated
+//! with their ETag every `--hostlist-refresh` seconds, so that a
+//! long-running instance follows a community-maintained list and still
+//! starts when the server is unreachable.
+//!
+//! With `--hostlist-regex`, wildcard and regex lines are matched too,
+//! as a slower tier behind the domains, with a cache of its results.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::opt;
+use crate::platform::datadir;
+use crate::regex::Regex;
+use crate::{inflate, sha256};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Entries per block of a [`HostList`]; a lookup decodes at most this
+/// many.
+const BLOCK: usize = 16;
+
+/// Server names whose pattern matches are cached; the cache is emptied
+/// when full.
+const CACHE_SIZE: usize = 4096;
+
+/// Listed domains, each reversed (`moc.elpmaxe`) so that the parents of
+/// a name are its prefixes, sorted and front-coded in blocks of
+/// [`BLOCK`]: an entry is the length of the prefix it shares with the
+/// one before, the length of the rest, and the rest. Domains share
+/// most of their suffixes, so a list of hundreds of thousands of them
+/// takes a fraction of the memory of a set of strings.
+#[derive(Default)]
+pub struct HostList {
+    data: Vec<u8>,
+
+    /// Offset of each block in `data`
+    blocks: Vec<u32>,
+    len: usize,
+
+    /// Wildcard and regex lines, with `--hostlist-regex`
+    patterns: Vec<Regex>,
+
+    /// Whether a pattern matches, by server name
+    cache: Mutex<HashMap<Box<str>, bool>>,
+}
+
+/// Line of a list
+enum Entry {
+    /// Reversed, lowercase domain
+    Domain(Vec<u8>),
+
+    /// Wildcard or regex line, as a regex
+    Pattern(String),
+}
+
+/// Regex of wildcard `w`, matching like a domain: the name and its
+/// subdomains
+fn wildcard(w: &str) -> String {
+    let mut re = String::from(r"(^|\.)");
+    for c in w.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            c if c.is_ascii_punctuation() && c != '-' => {
+                re.push('\\');
+                re.push(c);
+            }
+            c => re.push(c),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Entries of list `text`; names too long to be domains are left out.
+fn entries(text: &str) -> impl Iterator<Item = Entry> + '_ {
+    text.lines()
+        .map(|l| l.split('#').next().unwrap_or_default().trim())
+        .filter_map(|l| {
+            let re = l.strip_prefix("regexp:")
+                .or_else(|| l.strip_prefix('/').and_then(|l| l.strip_suffix('/')));
+            if let Some(re) = re {
+                return (!re.is_empty()).then(|| Entry::Pattern(re.to_string()));
+            }
+
+            let l = l.trim_start_matches("*.").trim_start_matches('.');
+            if l.contains('*') {
+                return Some(Entry::Pattern(wildcard(l)));
+            }
+            (!l.is_empty() && l.len() <= u8::MAX as usize)
+                .then(|| Entry::Domain(l.bytes().rev().map(|b| b.to_ascii_lowercase()).collect()))
+        })
+}
+
+impl HostList {
+    /// List of `entries`, compiling their patterns if `regex`
+    fn new(entries: Vec<Entry>, regex: bool) -> Self {
+        let mut domains = Vec::new();
+        let mut patterns = Vec::new();
+        let mut skipped = 0;
+        for e in entries {
+            match e {
+                Entry::Domain(d) => domains.push(d),
+                Entry::Pattern(_) if !regex => skipped += 1,
+                Entry::Pattern(p) => match Regex::new(&p) {
+                    Ok(re) => patterns.push(re),
+                    Err(e) => crate::warn!("hostlist: /{p}/: {e}; skipped"),
+                },
+            }
+        }
+        if skipped > 0 {
+            crate::warn!("hostlist: {skipped} wildcard or regex lines skipped; \
+                          --hostlist-regex to match them");
+        }
+
+        domains.sort_unstable();
+        domains.dedup();
+
+        let mut list = Self { len: domains.len() + patterns.len(), patterns, ..Self::default() };
+        let mut prev: &[u8] = &[];
+        for (i, d) in domains.iter().enumerate() {
+            let shared = if i % BLOCK == 0 {
+                list.blocks.push(list.data.len() as u32);
+                0
+            } else {
+                prev.iter().zip(d).take_while(|(a, b)| a == b).count()
+            };
+            list.data.push(shared as u8);
+            list.data.push((d.len() - shared) as u8);
+            list.data.extend_from_slice(&d[shared..]);
+            prev = d;
+        }
+
+        list.data.shrink_to_fit();
+        list.blocks.shrink_to_fit();
+        list
+    }
+
+    #[cfg(test)]
+    fn parse(text: &str) -> Self {
+        Self::new(entries(text).collect(), true)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// First entry of the block at `offset`, stored whole
+    fn first(&self, offset: u32) -> &[u8] {
+        let offset = offset as usize;
+        &self.data[offset + 2..offset + 2 + self.data[offset + 1] as usize]
+    }
+
+    /// Whether reversed domain `key` is listed
+    fn contains(&self, key: &[u8]) -> bool {
+        let n = self.blocks.partition_point(|&b| self.first(b) <= key);
+        if n == 0 {
+            return false;
+        }
+
+        let mut off = self.blocks[n - 1] as usize;
+        let end = self.blocks.get(n).map_or(self.data.len(), |&b| b as usize);
+        let mut entry = [0u8; u8::MAX as usize];
+        while off < end {
+            let (shared, rest) = (self.data[off] as usize, self.data[off + 1] as usize);
+            entry[shared..shared + rest].copy_from_slice(&self.data[off + 2..off + 2 + rest]);
+            match entry[..shared + rest].cmp(key) {
+                std::cmp::Ordering::Equal => return true,
+                std::cmp::Ordering::Greater => return false,
+                std::cmp::Ordering::Less => off += 2 + rest,
+            }
+        }
+        false
+    }
+
+    /// Whether a pattern matches lowercase `host`, from the cache if
+    /// it has been seen
+    fn matches_pattern(&self, host: &str) -> bool {
+        if self.patterns.is_empty() {
+            return false;
+        }
+
+        let cache = || self.cache.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&m) = cache().get(host) {
+            return m;
+        }
+
+        let m = self.patterns.iter().any(|p| p.is_match(host.as_bytes()));
+        let mut cache = cache();
+        if cache.len() >= CACHE_SIZE {
+            cache.clear();
+        }
+        cache.insert(host.into(), m);
+        m
+    }
+
+    /// Whether `host` or one of its parent domains is listed, or else a
+    /// pattern matches it
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let rev: Vec<u8> = host.bytes().rev().collect();
+
+        (1..=rev.len())
+            .filter(|&i| i == rev.len() || rev[i] == b'.')
+            .any(|i| self.contains(&rev[..i]))
+            || self.matches_pattern(&host)
+    }
+}
+
+static HOSTLIST: RwLock<Option<Arc<HostList>>> = RwLock::new(None);
+
+/// Current list, or None if `--hostlist` is not given
+pub fn get() -> Option<Arc<HostList>> {
+    HOSTLIST.read().unwrap().clone()
+}
+
+fn is_url(source: &str) -> bool {
+    source.starts_with("https://") || source.starts_with("http://")
+}
+
+/// Cached copy of URL `source` and its ETag file
+fn cache_paths(source: &str) -> (PathBuf, PathBuf) {
+    let name = &sha256::hex_digest(source.as_bytes())[..16];
+    let dir = datadir::dir(datadir::Kind::Cache);
+    (dir.join(format!("hostlist-{name}.txt")), dir.join(format!("hostlist-{name}.etag")))
+}
+
+/// Output of `curl` with `args`, failing on HTTP errors
+pub fn curl(args: &[&str]) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--location", "--max-time", "60"])
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .context("failed to spawn curl")?;
+
+    if !output.status.success() {
+        return Err(anyhow!("curl: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Check `data` against `--hostlist-sha256`: either a hex digest, or
+/// URL of a digest file (`sha256sum` format, first word is used).
+fn verify(data: &[u8]) -> Result<()> {
+    let Some(expected) = opt::hostlist_sha256() else {
+        return Ok(());
+    };
+
+    let expected = if is_url(expected) {
+        let file = curl(&[expected])?;
+        String::from_utf8_lossy(&file).split_whitespace().next().unwrap_or_default().to_string()
+    } else {
+        expected.to_string()
+    };
+
+    let actual = sha256::hex_digest(data);
+    if !actual.eq_ignore_ascii_case(&expected) {
+        return Err(anyhow!("sha256 mismatch: expected {expected}, got {actual}"));
+    }
+
+    Ok(())
+}
+
+/// Download URL `source` into the cache if it has changed. Return
+/// whether the cache has been updated.
+fn fetch(source: &str) -> Result<bool> {
+    let (cache, etag) = cache_paths(source);
+    datadir::create(datadir::Kind::Cache)?;
+
+    let tmp = cache.with_extension("tmp");
+    let (etag_s, tmp_s) = (etag.to_string_lossy(), tmp.to_string_lossy());
+    let mut args = vec!["--etag-save", &*etag_s, "--output", &*tmp_s, "--write-out", "%{http_code}"];
+    if cache.exists() {
+        // Keep the old ETag unless the cache is there to fall back on
+        args.extend_from_slice(&["--etag-compare", &*etag_s]);
+    }
+    args.push(source);
+
+    let status = curl(&args)?;
+    if status == b"304" {
+        let _ = std::fs::remove_file(&tmp);
+        return Ok(false);
+    }
+
+    let body = std::fs::read(&tmp)?;
+    if let Err(e) = verify(&body) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e);
+    }
+    std::fs::rename(&tmp, &cache)?;
+
+    Ok(true)
+}
+
+/// `data` read from `path`, decompressed if it is gzip or zstd. zstd is
+/// left to the `zstd` executable, as downloads are to `curl`.
+fn decompress(path: &Path, data: Vec<u8>) -> Result<Vec<u8>> {
+    if data.starts_with(&inflate::GZIP_MAGIC) {
+        return inflate::gunzip(&data);
+    }
+    if !data.starts_with(&ZSTD_MAGIC) {
+        return Ok(data);
+    }
+
+    let output = Command::new("zstd")
+        .args(["--decompress", "--stdout", "--quiet", "--"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .context("failed to spawn zstd, needed for zstd compressed lists")?;
+    if !output.status.success() {
+        return Err(anyhow!("zstd: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    Ok(output.stdout)
+}
+
+/// Text of list `source`
+fn load_source(source: &str) -> Result<String> {
+    let path = if is_url(source) {
+        match fetch(source) {
+            Ok(true) => crate::info!("hostlist: {source}: downloaded"),
+            Ok(false) => crate::info!("hostlist: {source}: not modified"),
+            Err(e) => crate::warn!("hostlist: {source}: {e}; using cached copy"),
+        }
+        let cache = cache_paths(source).0;
+        if !cache.exists() {
+            // Left for the refresh or a reload to download
+            crate::warn!("hostlist: {source}: no cached copy; empty until downloaded");
+            return Ok(String::new());
+        }
+        cache
+    } else {
+        PathBuf::from(source)
+    };
+
+    let data = std::fs::read(&path)
+        .with_context(|| format!("hostlist: cannot read {}", path.display()))?;
+    if !is_url(source) {
+        verify(&data).with_context(|| format!("hostlist: {source}"))?;
+    }
+    let data = decompress(&path, data).with_context(|| format!("hostlist: {source}"))?;
+
+    Ok(String::from_utf8_lossy(&data).into_owned())
+}
+
+fn load() -> Result<HostList> {
+    let mut list = Vec::new();
+    for source in opt::hostlist().iter() {
+        list.extend(entries(&load_source(source)?));
+    }
+    Ok(HostList::new(list, opt::hostlist_regex()))
+}
+
+/// Load the lists again and replace the current one on success.
+fn reload_1() {
+    match load() {
+        Ok(list) => {
+            crate::info!("hostlist: reloaded, {} domains", list.len());
+            *HOSTLIST.write().unwrap() = Some(Arc::new(list));
+        }
+        Err(e) => crate::warn!("hostlist: reload: {e}; keeping the current list"),
+    }
+}
+
+/// Reload `--hostlist` in background, e.g. on SIGHUP.
+pub fn reload() {
+    if opt::hostlist().is_empty() {
+        return;
+    }
+
+    std::thread::spawn(reload_1);
+}
+
+/// Load `--hostlist` and start refreshing remote ones in background.
+pub fn init() -> Result<()> {
+    // Already loaded and kept fresh by the reloads (restart by --watch)
+    if opt::hostlist().is_empty() || get().is_some() {
+        return Ok(());
+    }
+
+    let list = load()?;
+    crate::info!("hostlist: {} domains", list.len());
+    *HOSTLIST.write().unwrap() = Some(Arc::new(list));
+
+    let refresh = opt::hostlist_refresh();
+    if refresh == 0 || !opt::hostlist().iter().any(|s| is_url(s)) {
+        return Ok(());
+    }
+
+    std::thread::spawn(move || loop {
+        std::thread::sleep(Duration::from_secs(refresh));
+        reload_1();
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matches() {
+        let list = HostList::parse("# comment\nexample.com\n*.Foo.org  # wildcard\n\n.bar.net\n");
+
+        assert_eq!(list.len(), 3);
+        assert!(list.matches("example.com"));
+        assert!(list.matches("www.example.com."));
+        assert!(list.matches("a.b.FOO.org"));
+        assert!(list.matches("bar.net"));
+        assert!(!list.matches("notexample.com"));
+        assert!(!list.matches("com"));
+        assert!(!list.matches(""));
+        assert!(!HostList::default().matches("example.com"));
+    }
+
+    #[test]
+    fn test_patterns() {
+        let text = "example.com\nads*.example.*\n/^r[0-9]+---sn-[a-z0-9-]+\\.gvt\\.com$/\n\
+                    regexp:^cdn[0-9]\\.\n/(/\n";
+        let list = HostList::parse(text);
+
+        assert_eq!(list.len(), 4);
+        assert!(list.matches("ads1.example.net"));
+        assert!(list.matches("x.ADS.example.org"));
+        assert!(!list.matches("xads.example.org"));
+        assert!(list.matches("r5---sn-ab-12.gvt.com"));
+        assert!(!list.matches("a.r5---sn-ab-12.gvt.com"));
+        assert!(list.matches("cdn7.anything.io"));
+        assert!(list.matches("cdn7.anything.io"));
+        assert!(!list.matches("cdn.anything.io"));
+        assert_eq!(list.cache.lock().unwrap().len(), 7);
+
+        let list = HostList::new(entries(text).collect(), false);
+        assert_eq!(list.len(), 1);
+        assert!(list.matches("www.example.com"));
+        assert!(!list.matches("ads1.example.net"));
+    }
+
+    #[test]
+    fn test_blocks() {
+        let text: String = (0..1000).map(|i| format!("host{i}.example.com\n")).collect();
+        let list = HostList::parse(&(text + "example.org\nhost5.example.com\n"));
+
+        assert_eq!(list.len(), 1001);
+        assert!(list.data.len() < 1001 * "host999.example.com".len() / 2);
+        for i in 0..1000 {
+            assert!(list.matches(&format!("www.host{i}.example.com")), "{i}");
+        }
+        assert!(list.matches("example.org"));
+        assert!(!list.matches("host1000.example.com"));
+        assert!(!list.matches("example.com"));
+        assert!(!list.matches("aaa"));
+        assert!(!list.matches("zzz"));
+    }
+}
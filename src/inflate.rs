@@ -0,0 +1,335 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! gzip (RFC 1952) decompression, for compressed hostlists without an
+//! extra dependency. Meant for files of a few megabytes read once, not
+//! for speed.
+
+use anyhow::{Result, anyhow};
+
+pub const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+const MAX_BITS: usize = 15;
+
+/// Largest decompressed size, to refuse a small file that inflates to
+/// gigabytes
+const MAX_OUTPUT: usize = 64 << 20;
+
+/// Base lengths and extra bits of length codes 257..285
+const LEN_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LEN_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances and extra bits of distance codes 0..29
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/// Order of the code length code lengths in a dynamic block header
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn truncated() -> anyhow::Error {
+    anyhow!("inflate: truncated input")
+}
+
+fn too_large() -> anyhow::Error {
+    anyhow!("inflate: output larger than {} MiB", MAX_OUTPUT >> 20)
+}
+
+/// Bits of the input, least significant first
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0, buf: 0, count: 0 }
+    }
+
+    fn take(&mut self, n: u32) -> Result<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or_else(truncated)?;
+            self.pos += 1;
+            self.buf |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let v = self.buf & ((1u32 << n) - 1);
+        self.buf >>= n;
+        self.count -= n;
+        Ok(v)
+    }
+
+    /// Skip to the next byte boundary.
+    fn align(&mut self) {
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+/// Canonical Huffman code: number of codes of each length, and the
+/// symbols ordered by code
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &l in lengths {
+            counts[l as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut left: i32 = 1;
+        for &c in &counts[1..] {
+            left = (left << 1) - c as i32;
+            if left < 0 {
+                return Err(anyhow!("inflate: over-subscribed code"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; offsets[MAX_BITS + 1] as usize];
+        for (sym, &l) in lengths.iter().enumerate() {
+            if l != 0 {
+                symbols[offsets[l as usize] as usize] = sym as u16;
+                offsets[l as usize] += 1;
+            }
+        }
+
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            code |= bits.take(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(anyhow!("inflate: invalid code"))
+    }
+}
+
+fn fixed_codes() -> Result<(Huffman, Huffman)> {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    Ok((Huffman::new(&lengths)?, Huffman::new(&[5; 30])?))
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman)> {
+    let nlen = bits.take(5)? as usize + 257;
+    let ndist = bits.take(5)? as usize + 1;
+    let nclen = bits.take(4)? as usize + 4;
+
+    let mut clen = [0u8; 19];
+    for &i in &CLEN_ORDER[..nclen] {
+        clen[i] = bits.take(3)? as u8;
+    }
+    let clen = Huffman::new(&clen)?;
+
+    let mut lengths = vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < lengths.len() {
+        let sym = clen.decode(bits)?;
+        let (value, repeat) = match sym {
+            0..=15 => (sym as u8, 1),
+            16 => {
+                let prev = *lengths[..i].last().ok_or_else(|| anyhow!("inflate: repeat with no length"))?;
+                (prev, 3 + bits.take(2)? as usize)
+            }
+            17 => (0, 3 + bits.take(3)? as usize),
+            _ => (0, 11 + bits.take(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err(anyhow!("inflate: too many lengths"));
+        }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+
+    Ok((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+fn inflate_block(bits: &mut Bits, out: &mut Vec<u8>, limit: usize,
+                 lit: &Huffman, dist: &Huffman) -> Result<()> {
+    while out.len() <= limit {
+        let sym = lit.decode(bits)? as usize;
+        match sym {
+            0..=255 => out.push(sym as u8),
+            256 => return Ok(()),
+            257..=285 => {
+                let i = sym - 257;
+                let len = LEN_BASE[i] as usize + bits.take(LEN_EXTRA[i] as u32)? as usize;
+                let d = dist.decode(bits)? as usize;
+                if d >= DIST_BASE.len() {
+                    return Err(anyhow!("inflate: invalid distance code"));
+                }
+                let back = DIST_BASE[d] as usize + bits.take(DIST_EXTRA[d] as u32)? as usize;
+                if back > out.len() {
+                    return Err(anyhow!("inflate: distance too far back"));
+                }
+                let start = out.len() - back;
+                for k in 0..len {
+                    out.push(out[start + k]);
+                }
+            }
+            _ => return Err(anyhow!("inflate: invalid literal/length code")),
+        }
+    }
+    Err(too_large())
+}
+
+/// Decompress raw DEFLATE `data` into at most `limit` bytes; also
+/// return the bytes it took.
+fn inflate(data: &[u8], limit: usize) -> Result<(Vec<u8>, usize)> {
+    let mut bits = Bits::new(data);
+    let mut out = Vec::new();
+
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let header = data.get(bits.pos..bits.pos + 4).ok_or_else(truncated)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(anyhow!("inflate: stored block length mismatch"));
+                }
+                let start = bits.pos + 4;
+                if out.len() + len as usize > limit {
+                    return Err(too_large());
+                }
+                out.extend_from_slice(data.get(start..start + len as usize).ok_or_else(truncated)?);
+                bits.pos = start + len as usize;
+            }
+            1 => {
+                let (lit, dist) = fixed_codes()?;
+                inflate_block(&mut bits, &mut out, limit, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, limit, &lit, &dist)?;
+            }
+            _ => return Err(anyhow!("inflate: invalid block type")),
+        }
+        if last {
+            return Ok((out, bits.pos));
+        }
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Decompress gzip `data`, checking its CRC and length. Concatenated
+/// members are decompressed one after another.
+pub fn gunzip(mut data: &[u8]) -> Result<Vec<u8>> {
+    const FHCRC: u8 = 0x02;
+    const FEXTRA: u8 = 0x04;
+    const FNAME: u8 = 0x08;
+    const FCOMMENT: u8 = 0x10;
+
+    let mut out = Vec::new();
+
+    while !data.is_empty() {
+        if data.len() < 18 || data[..2] != GZIP_MAGIC || data[2] != 8 {
+            return Err(anyhow!("gzip: not a gzip file"));
+        }
+        let flags = data[3];
+        let mut pos = 10;
+
+        if flags & FEXTRA != 0 {
+            let xlen = data.get(pos..pos + 2).ok_or_else(truncated)?;
+            pos += 2 + u16::from_le_bytes([xlen[0], xlen[1]]) as usize;
+        }
+        for flag in [FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                let rest = data.get(pos..).ok_or_else(truncated)?;
+                pos += rest.iter().position(|&b| b == 0).ok_or_else(truncated)? + 1;
+            }
+        }
+        if flags & FHCRC != 0 {
+            pos += 2;
+        }
+
+        let (member, used) = inflate(data.get(pos..).ok_or_else(truncated)?, MAX_OUTPUT - out.len())?;
+        let trailer = data.get(pos + used..pos + used + 8).ok_or_else(truncated)?;
+        let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+        let size = u32::from_le_bytes(trailer[4..].try_into().unwrap());
+        if crc != crc32(&member) || size != member.len() as u32 {
+            return Err(anyhow!("gzip: checksum mismatch"));
+        }
+
+        out.extend_from_slice(&member);
+        data = &data[pos + used + 8..];
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_gunzip() {
+        // gzip -9 and -0, mtime 0
+        let fixed = unhex("1f8b08000000000002034bad48cc2dc849d54bcecfe50200f5f7dc800c000000");
+        let dynamic = unhex("1f8b08000000000002036dd23b0a84500043d1dec58849fccc2c47447885a2a085\
+                             cb9f69e5bef6568790725c77d7aecfbc9fdbda2ec7de947f118a5182d2a30c2823\
+                             ca84f241f9525841532db245b70817e5225db48b78516fea5dd99c7a536fea4dbd\
+                             a937f5a6ded487fa509fca65a80ff5a13ed487fa509fb7fe07347c48baee020000");
+        let stored = unhex("1f8b0800000000000403010500faff612e696f0a341f2b6705000000");
+
+        assert_eq!(gunzip(&fixed).unwrap(), b"example.com\n");
+        let hosts: String = (0..40).map(|i| format!("host{i}.example.com\n")).collect();
+        assert_eq!(gunzip(&dynamic).unwrap(), hosts.as_bytes());
+        assert_eq!(inflate(&dynamic[10..], hosts.len()).unwrap().0, hosts.as_bytes());
+        assert!(inflate(&dynamic[10..], hosts.len() - 1).is_err());
+        assert_eq!(gunzip(&[fixed.clone(), stored].concat()).unwrap(), b"example.com\na.io\n");
+
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert!(gunzip(b"example.com\n").is_err());
+        assert!(gunzip(&fixed[..fixed.len() - 1]).is_err());
+        let mut corrupt = fixed;
+        corrupt[14] ^= 1;
+        assert!(gunzip(&corrupt).is_err());
+    }
+}
@@ -15,7 +15,16 @@
 // You should have received a copy of the GNU General Public License
 // along with DPIBreak. If not, see <https://www.gnu.org/licenses/>.
 
+use std::collections::VecDeque;
 use std::fmt;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::platform::datadir;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum LogLevel {
@@ -61,13 +70,247 @@ impl std::str::FromStr for LogLevel {
     }
 }
 
+impl LogLevel {
+    /// Syslog severity
+    fn severity(self) -> u8 {
+        match self {
+            LogLevel::Debug   => 7,
+            LogLevel::Info    => 6,
+            LogLevel::Warning => 4,
+            LogLevel::Error   => 3,
+        }
+    }
+}
+
+/// Lines per second shipped to `--log-target` in the long run
+const SHIP_RATE: f64 = 20.0;
+
+/// Lines that may be shipped at once after a quiet period
+const SHIP_BURST: f64 = 100.0;
+
+/// Token bucket: `rate` tokens per second, up to `burst` at once
+pub struct Bucket {
+    tokens: f64,
+    last: Instant,
+    rate: f64,
+    burst: f64,
+
+    /// Takes refused since the last one granted
+    dropped: u64,
+}
+
+impl Bucket {
+    /// Full bucket
+    pub fn new(rate: f64, burst: f64, now: Instant) -> Self {
+        Self { tokens: burst, last: now, rate, burst, dropped: 0 }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+    }
+
+    /// Take a token. `Some(n)` if there was one, `n` being the takes
+    /// refused before it.
+    pub fn take(&mut self, now: Instant) -> Option<u64> {
+        self.refill(now);
+
+        if self.tokens < 1.0 {
+            self.dropped += 1;
+            return None;
+        }
+        self.tokens -= 1.0;
+        Some(std::mem::take(&mut self.dropped))
+    }
+
+    /// Whether it has refilled to `burst` by `now`, the same as a new
+    /// one
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.burst
+    }
+}
+
+struct Shipper {
+    sock: UdpSocket,
+    host: String,
+
+    /// Limits what is shipped, so that an error storm on the packet
+    /// path cannot flood the network
+    bucket: Bucket,
+}
+
+impl Shipper {
+    fn send(&self, level: LogLevel, msg: fmt::Arguments) {
+        // RFC 5424, facility daemon; the collector stamps the time.
+        let line = format!("<{}>1 - {} dpibreak {} - - {msg}",
+                           3 * 8 + level.severity(), self.host, std::process::id());
+        // Nowhere to report it to: logging it would ship it again.
+        _ = self.sock.send(line.as_bytes());
+    }
+}
+
+static SHIPPER: OnceLock<Mutex<Shipper>> = OnceLock::new();
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname").ok()
+        .or_else(|| std::env::var("COMPUTERNAME").ok())
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty() && !h.contains(' '))
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Open `--log-target`, if given. Lines logged before are not shipped.
+pub fn open_target() -> Result<()> {
+    let Some(url) = crate::opt::log_target() else {
+        return Ok(());
+    };
+
+    let hostport = url.strip_prefix("udp://").unwrap_or(url);
+    let addr = hostport.to_socket_addrs()
+        .with_context(|| format!("--log-target: cannot resolve {hostport}"))?
+        .next()
+        .ok_or_else(|| anyhow!("--log-target: no address for {hostport}"))?;
+    let sock = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+        .context("--log-target: cannot open socket")?;
+    sock.connect(addr)
+        .with_context(|| format!("--log-target: cannot connect to {addr}"))?;
+
+    _ = SHIPPER.set(Mutex::new(Shipper { sock, host: hostname(), bucket: Bucket::new(SHIP_RATE, SHIP_BURST, Instant::now()) }));
+
+    Ok(())
+}
+
+fn ship(level: LogLevel, msg: fmt::Arguments) {
+    let Some(shipper) = SHIPPER.get() else {
+        return;
+    };
+    let Ok(mut shipper) = shipper.lock() else {
+        return;
+    };
+
+    match shipper.bucket.take(Instant::now()) {
+        Some(0) => {}
+        Some(n) => shipper.send(LogLevel::Warning, format_args!("{n} log lines dropped")),
+        None => return,
+    }
+    shipper.send(level, msg);
+}
+
+/// Last lines logged, for the crash log: a panic on the packet path
+/// is often one packet in millions, and what led to it is long gone
+/// from a terminal by the time it is reported.
+#[derive(Default)]
+struct Ring {
+    lines: VecDeque<String>,
+}
+
+impl Ring {
+    /// Keep `line`, and the `cap` - 1 before it.
+    fn push(&mut self, line: String, cap: usize) {
+        while self.lines.len() >= cap.max(1) {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+}
+
+static RING: OnceLock<Mutex<Ring>> = OnceLock::new();
+
+fn ring() -> &'static Mutex<Ring> {
+    RING.get_or_init(Mutex::default)
+}
+
+fn remember(line: String) {
+    let cap = crate::opt::crash_log_lines() as usize;
+    if cap == 0 {
+        return;
+    }
+    if let Ok(mut ring) = ring().lock() {
+        ring.push(line, cap);
+    }
+}
+
+/// In the state directory
+const CRASH_FILE: &str = "crash.log";
+
+/// Crash log contents: what happened, then the last lines logged
+fn crash_text(what: &str, lines: &VecDeque<String>) -> String {
+    let mut text = format!("{} v{} crashed: {what}\n\nLast {} lines logged:\n",
+                           crate::PROJECT_NAME, crate::PKG_VERSION, lines.len());
+    for line in lines {
+        text += line;
+        text.push('\n');
+    }
+    text
+}
+
+/// Whether [`CRASH_FILE`] was written by this process
+static CRASH_WRITTEN: AtomicBool = AtomicBool::new(false);
+
+/// Write `what` and the last lines logged to [`CRASH_FILE`], the first
+/// time only: a bad packet that keeps coming back panics on each, and
+/// the first one is what to look at. Called on a panic or a fatal
+/// exception, so it does not wait for the lines (whose lock the
+/// crashing thread may hold), and reports its own failure on stderr
+/// only.
+pub fn write_crash(what: &str) {
+    if crate::opt::crash_log_lines() == 0 || CRASH_WRITTEN.swap(true, Ordering::Relaxed) {
+        return;
+    }
+
+    let text = match ring().try_lock() {
+        Ok(ring) => crash_text(what, &ring.lines),
+        Err(_) => crash_text(what, &VecDeque::from(["(lines locked by the crashing thread)".to_string()])),
+    };
+
+    let result = datadir::create(datadir::Kind::State)
+        .and_then(|dir| {
+            let path = dir.join(CRASH_FILE);
+            std::fs::write(&path, text)?;
+            Ok(path)
+        });
+    match result {
+        Ok(path) => eprintln!("crash log written to {}", path.display()),
+        Err(e) => eprintln!("cannot write crash log: {e:#}"),
+    }
+}
+
+/// Write the crash log on any panic, after the default message. A panic
+/// on the packet path costs only its packet (see `pkt::isolate`) but is
+/// a bug all the same.
+pub fn install_panic_hook() {
+    let default = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default(info);
+        // Capturing takes a while; not for panics that write nothing
+        if crate::opt::crash_log_lines() == 0 || CRASH_WRITTEN.load(Ordering::Relaxed) {
+            return;
+        }
+        let backtrace = std::backtrace::Backtrace::force_capture();
+        write_crash(&format!("{info}\n\n{backtrace}"));
+    }));
+}
+
+fn stamp() -> String {
+    let (y, mo, d, h, mi, s) = crate::platform::local_time();
+    format!("{y:04}-{mo:02}-{d:02} {h:02}:{mi:02}:{s:02}")
+}
+
+/// Print a log line, and ship it to `--log-target` if given.
+pub fn emit(level: LogLevel, msg: fmt::Arguments) {
+    let line = format!("{} {level} {msg}", stamp());
+    println!("{line}");
+    remember(line);
+    ship(level, msg);
+}
+
 #[macro_export]
 macro_rules! log_println {
     ($level:expr, $($arg:tt)*) => {{
         if $level >= crate::opt::log_level() {
-            let (y, mo, d, h, mi, s) = crate::platform::local_time();
-            println!("{y:04}-{mo:02}-{d:02} {h:02}:{mi:02}:{s:02} {} {}",
-                $level, format_args!($($arg)*));
+            crate::log::emit($level, format_args!($($arg)*));
         }
     }};
 }
@@ -100,6 +343,23 @@ macro_rules! error {
     }
 }
 
+/// For `--trace`: printed whatever `--log-level` is, since only the
+/// flows asked for are traced.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => {{
+        crate::log::emit_trace(format_args!($($arg)*));
+    }};
+}
+
+/// Print a `--trace` line; shipped as debug.
+pub fn emit_trace(msg: fmt::Arguments) {
+    let line = format!("{} [TRACE] {msg}", stamp());
+    println!("{line}");
+    remember(line);
+    ship(LogLevel::Debug, msg);
+}
+
 #[macro_export]
 macro_rules! splash {
     ($($arg:tt)*) => {{
@@ -108,3 +368,47 @@ macro_rules! splash {
         }
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bucket() {
+        let t0 = Instant::now();
+        let mut b = Bucket::new(SHIP_RATE, SHIP_BURST, t0);
+
+        for _ in 0..SHIP_BURST as usize {
+            assert_eq!(b.take(t0), Some(0));
+        }
+        assert_eq!(b.take(t0), None);
+        assert_eq!(b.take(t0), None);
+
+        // Two tokens back after 2/SHIP_RATE seconds.
+        let t1 = t0 + Duration::from_millis(100);
+        assert_eq!(b.take(t1), Some(2));
+        assert_eq!(b.take(t1), Some(0));
+        assert_eq!(b.take(t1), None);
+
+        // Refill is capped at the burst.
+        let t2 = t1 + Duration::from_secs(3600);
+        for _ in 0..SHIP_BURST as usize {
+            assert!(b.take(t2).is_some());
+        }
+        assert_eq!(b.take(t2), None);
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut ring = Ring::default();
+        for line in ["a", "b", "c"] {
+            ring.push(line.to_string(), 2);
+        }
+        assert_eq!(ring.lines, ["b", "c"]);
+        assert!(crash_text("boom", &ring.lines).ends_with("crashed: boom\n\nLast 2 lines logged:\nb\nc\n"));
+
+        ring.push("d".to_string(), 1);
+        assert_eq!(ring.lines, ["d"]);
+    }
+}
@@ -17,11 +17,30 @@
 
 use anyhow::Result;
 
+use exit::{Status, WithStatus};
+
 mod platform;
 mod pkt;
 mod tls;
 mod log;
+mod exit;
 mod opt;
+mod stats;
+mod rand;
+mod doh;
+mod hostlist;
+mod inflate;
+mod regex;
+mod sha256;
+mod schedule;
+mod update;
+mod webhook;
+#[cfg(target_os = "linux")]
+mod control;
+#[cfg(target_os = "linux")]
+mod top;
+#[cfg(target_os = "linux")]
+mod advise;
 
 const PROJECT_NAME: &str = "DPIBreak";
 const PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -36,27 +55,62 @@ fn splash_banner() {
 }
 
 fn main_1() -> Result<()> {
-    let opt = opt::Opt::from_args()?;
-    let initialized = opt.set_opt()?;
+    match std::env::args().nth(1).as_deref() {
+        Some("stats") => return pkt::outcome::stats_command(std::env::args().skip(2)),
+        Some("state") => return pkt::state::command(std::env::args().skip(2)),
+        Some("decode") => return pkt::decode::command(std::env::args().skip(2)),
+        Some("self-update") => return update::command(std::env::args().skip(2)),
+        #[cfg(target_os = "linux")]
+        Some("status") => return control::command("status", std::env::args().skip(2)),
+        #[cfg(target_os = "linux")]
+        Some("capabilities") => return control::command("capabilities", std::env::args().skip(2)),
+        #[cfg(target_os = "linux")]
+        Some("top") => return top::command(std::env::args().skip(2)),
+        #[cfg(target_os = "linux")]
+        Some("advise") => return advise::command(std::env::args().skip(2)),
+        #[cfg(target_os = "linux")]
+        Some(platform::CLEANUP_HELPER) => return platform::cleanup_helper(),
+        _ => {}
+    }
+
+    let initialized = opt::Config::from_args().status(Status::Args)?.install();
+    log::open_target()?;
+    log::install_panic_hook();
+    if opt::replay().is_some() {
+        return pkt::replay::run();
+    }
     splash_banner();
     platform::bootstrap()?;
     crate::info!("{PROJECT_NAME} v{PKG_VERSION}");
     initialized.log();
-    platform::run()?;
+    #[cfg(target_os = "linux")]
+    control::spawn()?;
+    let ret = platform::supervise();
+    #[cfg(target_os = "linux")]
+    control::cleanup();
+    ret?;
+
+    splash!("Stopped after {}; {} ClientHello handled",
+            stats::format_uptime(stats::uptime()), stats::COUNTERS.handled.get());
 
     Ok(())
 }
 
 fn main() {
-    match main_1() {
-        Ok(()) => { std::process::exit(0); }
+    let result = main_1().map_err(exit::Error::from);
+    exit::report_exit(&result);
+
+    match result {
+        Ok(()) => { std::process::exit(Status::Ok.code()); }
         Err(e) => {
             crate::error!("{e}");
+            webhook::notify_now("failed", &[("exit_code", &e.status.code().to_string()),
+                                            ("error", &format!("{e:#}"))]);
 
             for (i, cause) in e.chain().skip(1).enumerate() {
                 crate::error!("caused by[{i}]: {cause}");
             }
-            platform::paexit(1);
+            platform::paexit(e.status.code());
         }
     };
 }
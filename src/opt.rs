@@ -3,13 +3,14 @@
 
 use anyhow::{Result, anyhow, Context};
 use std::sync::OnceLock;
+use std::sync::atomic::{AtomicPtr, Ordering};
 
 use crate::log;
 use crate::platform;
 
 use log::LogLevel;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Segment(pub u32, pub u32);
 
 impl std::fmt::Display for Segment {
@@ -71,9 +72,663 @@ impl SegmentOrder {
         })
     }
 
+    /// As given, e.g. `0,1`
+    pub fn raw(&self) -> &str {
+        &self.raw
+    }
+
     pub fn segments(&self) -> &[Segment] {
         &self.segments
     }
+
+    /// Sort the segments by offset, e.g. `1,0` to `0,1`.
+    fn sort(&mut self) {
+        self.segments.sort_unstable_by_key(|s| s.0);
+        self.raw = self.segments.iter().map(|s| s.0.to_string()).collect::<Vec<_>>().join(",");
+    }
+
+    /// Smallest non-zero offset and the one next to it, if any.
+    fn first_boundaries(&self) -> (Option<u32>, Option<u32>) {
+        let mut points: Vec<u32> = self.segments.iter()
+            .map(|s| s.0)
+            .filter(|&p| p != 0)
+            .collect();
+        points.sort_unstable();
+
+        (points.first().copied(), points.get(1).copied())
+    }
+
+    /// Whether the first boundary can be moved to `at`: there is one and
+    /// `at` is between 0 and the next boundary.
+    pub fn can_move_first_boundary(&self, at: u32) -> bool {
+        match self.first_boundaries() {
+            (None, _) => false,
+            (_, next) => at > 0 && next.is_none_or(|n| at < n),
+        }
+    }
+
+    /// Segments with the first boundary (smallest non-zero offset) moved
+    /// to `at`. `at` must be less than the next boundary.
+    pub fn with_first_boundary(&self, at: u32) -> Vec<Segment> {
+        let Some(first) = self.first_boundaries().0 else {
+            return self.segments.clone();
+        };
+
+        self.segments.iter()
+            .map(|&Segment(start, end)| match (start, end) {
+                (0, e) if e == first => Segment(0, at),
+                (s, e) if s == first => Segment(at, e),
+                _ => Segment(start, end),
+            })
+            .collect()
+    }
+}
+
+/// Values of an option that may be given multiple times
+#[derive(Clone, Default)]
+pub struct StringList(Vec<String>);
+
+impl std::ops::Deref for StringList {
+    type Target = [String];
+
+    fn deref(&self) -> &[String] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for StringList {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        write!(f, "{}", self.0.join(", "))
+    }
+}
+
+/// `--trace` filter; a flow is traced if it matches all of the terms
+pub struct TraceFilter {
+    pub addr: Option<std::net::IpAddr>,
+    pub port: Option<u16>,
+
+    /// Server name, where `*` matches any run of characters
+    pub sni: Option<String>,
+}
+
+impl TraceFilter {
+    /// Parse comma separated `ip=ADDR`, `port=N` and `sni=GLOB`.
+    pub fn new(s: &str) -> Result<Self> {
+        let invalid = || format!("--trace: invalid term in '{s}' (use: ip=ADDR,port=N,sni=GLOB)");
+        let mut filter = Self { addr: None, port: None, sni: None };
+
+        for term in s.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (key, value) = term.split_once('=').with_context(invalid)?;
+            match key {
+                "ip" => filter.addr = Some(value.parse().with_context(invalid)?),
+                "port" => filter.port = Some(value.parse().with_context(invalid)?),
+                "sni" => filter.sni = Some(value.to_ascii_lowercase()),
+                _ => return Err(anyhow!(invalid())),
+            }
+        }
+
+        if filter.addr.is_none() && filter.port.is_none() && filter.sni.is_none() {
+            return Err(anyhow!("--trace: empty filter"));
+        }
+
+        Ok(filter)
+    }
+}
+
+impl std::fmt::Display for TraceFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mut terms = Vec::new();
+        if let Some(addr) = self.addr { terms.push(format!("ip={addr}")); }
+        if let Some(port) = self.port { terms.push(format!("port={port}")); }
+        if let Some(sni) = &self.sni { terms.push(format!("sni={sni}")); }
+        write!(f, "{}", terms.join(","))
+    }
+}
+
+/// `--trace` filters; a flow is traced if any of them matches
+#[derive(Default)]
+pub struct TraceList(Vec<TraceFilter>);
+
+impl std::ops::Deref for TraceList {
+    type Target = [TraceFilter];
+
+    fn deref(&self) -> &[TraceFilter] {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for TraceList {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let filters: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        write!(f, "{}", filters.join(" | "))
+    }
+}
+
+/// Where a `--send-raw` packet is sent among those of a ClientHello
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RawWhen {
+    /// Before anything else, fakes included
+    Before,
+
+    /// Between each two segments
+    Between,
+
+    /// After the last segment
+    After,
+}
+
+/// Field of the flow a `--send-raw` placeholder stands for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RawField {
+    Saddr,
+    Daddr,
+    Sport,
+    Dport,
+    Seq,
+    Ack,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum RawPiece {
+    Bytes(Vec<u8>),
+
+    /// Field in network byte order; a sequence number plus the offset
+    Field(RawField, i32),
+}
+
+/// `--send-raw` packet: an IP packet in hex with placeholders of the
+/// flow in braces, and where it is sent
+pub struct RawPacket {
+    pub pieces: Vec<RawPiece>,
+    pub ipv6: bool,
+    pub when: RawWhen,
+    raw: String,
+}
+
+impl RawPacket {
+    /// Parse `<hex>@<before|between|after>`; whitespace in hex is
+    /// skipped, placeholders are `{saddr}`, `{daddr}`, `{sport}`,
+    /// `{dport}`, `{seq}` and `{ack}`, the last two with an optional
+    /// `+N` or `-N`.
+    pub fn new(s: &str) -> Result<Self> {
+        let (hex, when) = s.rsplit_once('@')
+            .with_context(|| format!("--send-raw: '{s}': missing @before, @between or @after"))?;
+        let when = match when.trim() {
+            "before" => RawWhen::Before,
+            "between" => RawWhen::Between,
+            "after" => RawWhen::After,
+            w => return Err(anyhow!("--send-raw: invalid position '{w}' (use: before, between, after)")),
+        };
+
+        let mut pieces = Vec::new();
+        let mut rest = hex;
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('{') {
+                let (name, tail) = after.split_once('}')
+                    .with_context(|| format!("--send-raw: unclosed '{{' in '{hex}'"))?;
+                pieces.push(Self::field(name)?);
+                rest = tail;
+                continue;
+            }
+
+            let end = rest.find('{').unwrap_or(rest.len());
+            let digits: String = rest[..end].chars().filter(|c| !c.is_whitespace()).collect();
+            if !digits.len().is_multiple_of(2) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return Err(anyhow!("--send-raw: invalid hex '{}'", rest[..end].trim()));
+            }
+            let bytes = (0..digits.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).expect("hex digits"))
+                .collect::<Vec<_>>();
+            if !bytes.is_empty() {
+                pieces.push(RawPiece::Bytes(bytes));
+            }
+            rest = &rest[end..];
+        }
+
+        let ipv6 = match pieces.first() {
+            Some(RawPiece::Bytes(b)) if b[0] >> 4 == 4 => false,
+            Some(RawPiece::Bytes(b)) if b[0] >> 4 == 6 => true,
+            _ => return Err(anyhow!("--send-raw: '{hex}' does not start with an IPv4 or IPv6 header")),
+        };
+
+        let packet = Self { pieces, ipv6, when, raw: s.trim().to_string() };
+        let header = if ipv6 { 40 } else { 20 };
+        if packet.expanded_len() < header {
+            return Err(anyhow!("--send-raw: '{hex}' is shorter than an IP header"));
+        }
+        Ok(packet)
+    }
+
+    fn field(name: &str) -> Result<RawPiece> {
+        let invalid = || anyhow!("--send-raw: invalid placeholder '{{{name}}}' \
+                                  (use: saddr, daddr, sport, dport, seq[+-N], ack[+-N])");
+        let (key, offset) = match name.find(['+', '-']) {
+            Some(at) => (&name[..at], name[at..].trim_start_matches('+').parse().map_err(|_| invalid())?),
+            None => (name, 0),
+        };
+        let field = match key.trim() {
+            "saddr" => RawField::Saddr,
+            "daddr" => RawField::Daddr,
+            "sport" => RawField::Sport,
+            "dport" => RawField::Dport,
+            "seq" => RawField::Seq,
+            "ack" => RawField::Ack,
+            _ => return Err(invalid()),
+        };
+        if offset != 0 && !matches!(field, RawField::Seq | RawField::Ack) {
+            return Err(invalid());
+        }
+        Ok(RawPiece::Field(field, offset))
+    }
+
+    /// Length once the placeholders are expanded
+    pub fn expanded_len(&self) -> usize {
+        self.pieces.iter().map(|p| match p {
+            RawPiece::Bytes(b) => b.len(),
+            RawPiece::Field(RawField::Saddr | RawField::Daddr, _) => if self.ipv6 { 16 } else { 4 },
+            RawPiece::Field(RawField::Sport | RawField::Dport, _) => 2,
+            RawPiece::Field(RawField::Seq | RawField::Ack, _) => 4,
+        }).sum()
+    }
+}
+
+impl std::fmt::Display for RawPacket {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+/// `--schedule` window: days of the week and a time of day range in
+/// local time. A range ending before it starts runs past midnight, into
+/// the following day.
+pub struct Window {
+    /// Bit n set for the nth day from Monday
+    days: u8,
+
+    /// Minutes since midnight
+    start: u16,
+    end: u16,
+
+    raw: String,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+impl Window {
+    /// Parse `[DAYS] HH:MM-HH:MM`, where DAYS is comma separated days
+    /// or day ranges such as `Mon-Fri,Sun`; every day if omitted.
+    pub fn new(s: &str) -> Result<Self> {
+        let invalid = || format!("--schedule: invalid window '{s}' (e.g. Mon-Fri 09:00-18:00)");
+
+        let (days, time) = match s.trim().rsplit_once(char::is_whitespace) {
+            Some((days, time)) => (Self::parse_days(days.trim()).with_context(invalid)?, time),
+            None => (0x7f, s.trim()),
+        };
+
+        let (start, end) = time.split_once('-').with_context(invalid)?;
+        let (start, end) = (Self::parse_time(start).with_context(invalid)?,
+                            Self::parse_time(end).with_context(invalid)?);
+        if start == end {
+            return Err(anyhow!("--schedule: empty window '{s}'"));
+        }
+
+        Ok(Self { days, start, end, raw: s.trim().to_string() })
+    }
+
+    fn parse_days(s: &str) -> Option<u8> {
+        let day = |d: &str| WEEKDAYS.iter().position(|w| w.eq_ignore_ascii_case(d.trim()));
+
+        let mut days = 0;
+        for part in s.split(',') {
+            let (first, last) = match part.split_once('-') {
+                Some((first, last)) => (day(first)?, day(last)?),
+                None => (day(part)?, day(part)?),
+            };
+            let mut d = first;
+            loop {
+                days |= 1 << d;
+                if d == last { break; }
+                d = (d + 1) % 7;
+            }
+        }
+        Some(days)
+    }
+
+    /// `HH:MM` as minutes since midnight; up to 24:00
+    fn parse_time(s: &str) -> Option<u16> {
+        let (h, m) = s.trim().split_once(':')?;
+        let (h, m): (u16, u16) = (h.parse().ok()?, m.parse().ok()?);
+        (h <= 24 && m < 60 && h * 60 + m <= 24 * 60).then(|| h * 60 + m)
+    }
+
+    /// Whether it is open on `weekday` (0 for Monday) at `minute` since
+    /// midnight
+    pub fn contains(&self, weekday: u8, minute: u16) -> bool {
+        let on = |d: u8| self.days & (1 << d) != 0;
+        if self.start < self.end {
+            on(weekday) && (self.start..self.end).contains(&minute)
+        } else {
+            (on(weekday) && minute >= self.start) || (on((weekday + 6) % 7) && minute < self.end)
+        }
+    }
+}
+
+/// `--schedule` windows; DPIBreak is active while any of them is open,
+/// or always if there is none.
+#[derive(Default)]
+pub struct Schedule(Vec<Window>);
+
+impl Schedule {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, weekday: u8, minute: u16) -> bool {
+        self.0.iter().any(|w| w.contains(weekday, minute))
+    }
+}
+
+impl std::fmt::Display for Schedule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "always");
+        }
+        let windows: Vec<&str> = self.0.iter().map(|w| w.raw.as_str()).collect();
+        write!(f, "{}", windows.join(" | "))
+    }
+}
+
+/// Strategy for the ClientHellos of some clients, by `--client-profile`
+pub struct ClientProfile {
+    pub name: &'static str,
+    description: &'static str,
+
+    /// Not desynced at all
+    pub off: bool,
+
+    /// Instead of `--segment-order` and `--split-random`
+    order: Option<&'static str>,
+
+    /// Instead of `--fake`
+    pub fake: Option<bool>,
+}
+
+const CLIENT_PROFILES: &[ClientProfile] = &[
+    ClientProfile {
+        name: "off",
+        description: "not desynced; passed by the kernel rules",
+        off: true, order: None, fake: None,
+    },
+    ClientProfile {
+        name: "default",
+        description: "as the other options say",
+        off: false, order: None, fake: None,
+    },
+    ClientProfile {
+        name: "light",
+        description: "first byte split off, no fakes",
+        off: false, order: Some("0,1"), fake: Some(false),
+    },
+    ClientProfile {
+        name: "aggressive",
+        description: "fakes before each of three segments, sent out of order",
+        off: false, order: Some("2,0,1"), fake: Some(true),
+    },
+];
+
+/// Profile named `name`, for option `opt`
+fn find_client_profile(opt: &str, name: &str) -> Result<&'static ClientProfile> {
+    let name = name.trim().to_ascii_lowercase();
+    CLIENT_PROFILES.iter().find(|p| p.name == name).ok_or_else(|| {
+        anyhow!("{opt}: unknown profile '{name}' (use: {})",
+                CLIENT_PROFILES.iter().map(|p| p.name).collect::<Vec<_>>().join("|"))
+    })
+}
+
+/// `--client-profile` entry: clients within `net`/`prefix` get `profile`
+pub struct ClientRule {
+    pub net: std::net::IpAddr,
+    pub prefix: u8,
+    pub profile: &'static ClientProfile,
+
+    /// Parsed [`ClientProfile::order`]
+    pub order: Option<SegmentOrder>,
+}
+
+impl ClientRule {
+    /// Parse `192.168.1.0/24=aggressive`; a bare address is a /32 or /128.
+    fn new(s: &str) -> Result<Self> {
+        use std::net::IpAddr;
+
+        let invalid = || format!("--client-profile: invalid entry '{s}' (e.g. 192.168.1.0/24=off)");
+
+        let (net, name) = s.split_once('=').with_context(invalid)?;
+        let (addr, prefix) = net.trim().split_once('/').unwrap_or((net.trim(), ""));
+        let addr: IpAddr = addr.parse().with_context(invalid)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix: u8 = if prefix.is_empty() { max } else { prefix.parse().with_context(invalid)? };
+        if prefix > max {
+            return Err(anyhow!("--client-profile: prefix /{prefix} too long in '{s}'"));
+        }
+
+        let profile = find_client_profile("--client-profile", name)?;
+
+        let net = match (addr, platform::prefix_mask(addr, prefix)) {
+            (IpAddr::V4(a), IpAddr::V4(m)) => IpAddr::V4((a.to_bits() & m.to_bits()).into()),
+            (IpAddr::V6(a), IpAddr::V6(m)) => IpAddr::V6((a.to_bits() & m.to_bits()).into()),
+            _ => unreachable!("mask is of the family of addr"),
+        };
+
+        Ok(Self { net, prefix, profile, order: profile.order.map(SegmentOrder::new).transpose()? })
+    }
+
+    pub fn contains(&self, addr: std::net::IpAddr) -> bool {
+        use std::net::IpAddr;
+
+        match (self.net, addr, platform::prefix_mask(self.net, self.prefix)) {
+            (IpAddr::V4(n), IpAddr::V4(a), IpAddr::V4(m)) => a.to_bits() & m.to_bits() == n.to_bits(),
+            (IpAddr::V6(n), IpAddr::V6(a), IpAddr::V6(m)) => a.to_bits() & m.to_bits() == n.to_bits(),
+            _ => false,
+        }
+    }
+}
+
+impl std::fmt::Display for ClientRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}={}", self.net, self.prefix, self.profile.name)
+    }
+}
+
+/// `--client-profile` entries
+#[derive(Default)]
+pub struct ClientProfiles(Vec<ClientRule>);
+
+impl ClientProfiles {
+    /// Add the comma separated entries of `s`.
+    fn extend(&mut self, s: &str) -> Result<()> {
+        for entry in s.split(',').filter(|e| !e.trim().is_empty()) {
+            self.0.push(ClientRule::new(entry)?);
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn rules(&self) -> &[ClientRule] {
+        &self.0
+    }
+
+    /// Entry of the longest prefix containing `client`, if any
+    pub fn resolve(&self, client: std::net::IpAddr) -> Option<&ClientRule> {
+        self.0.iter().filter(|r| r.contains(client)).max_by_key(|r| r.prefix)
+    }
+}
+
+impl std::fmt::Display for ClientProfiles {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let rules: Vec<String> = self.0.iter().map(ToString::to_string).collect();
+        write!(f, "{}", rules.join(","))
+    }
+}
+
+/// Profile of `--strategy-rotate`
+pub struct RotatedProfile {
+    pub profile: &'static ClientProfile,
+
+    /// Parsed [`ClientProfile::order`]
+    pub order: Option<SegmentOrder>,
+}
+
+/// Profile named `name` with its order parsed, e.g. as named by
+/// `--verdict-hook`
+pub fn named_profile(name: &str) -> Option<&'static RotatedProfile> {
+    static PROFILES: OnceLock<Vec<RotatedProfile>> = OnceLock::new();
+
+    PROFILES.get_or_init(|| CLIENT_PROFILES.iter().map(|profile| RotatedProfile {
+        profile,
+        order: profile.order.map(|o| SegmentOrder::new(o).expect("valid profile order")),
+    }).collect()).iter().find(|p| p.profile.name.eq_ignore_ascii_case(name))
+}
+
+/// `--strategy-rotate`: profiles one of which is drawn for each
+/// connection
+#[derive(Default)]
+pub struct StrategyRotation(Vec<RotatedProfile>);
+
+impl StrategyRotation {
+    /// Parse `light,aggressive,default`.
+    fn new(s: &str) -> Result<Self> {
+        let mut profiles: Vec<RotatedProfile> = Vec::new();
+        for name in s.split(',') {
+            let profile = find_client_profile("--strategy-rotate", name)?;
+            if profile.off {
+                return Err(anyhow!("--strategy-rotate: '{}' does not desync", profile.name));
+            }
+            if profiles.iter().any(|p| p.profile.name == profile.name) {
+                return Err(anyhow!("--strategy-rotate: '{}' given twice", profile.name));
+            }
+            profiles.push(RotatedProfile { profile, order: profile.order.map(SegmentOrder::new).transpose()? });
+        }
+
+        if profiles.len() < 2 {
+            return Err(anyhow!("--strategy-rotate: at least two profiles are needed"));
+        }
+        Ok(Self(profiles))
+    }
+
+    /// Profile for a new connection, if rotating
+    pub fn pick(&self) -> Option<&RotatedProfile> {
+        if self.0.is_empty() {
+            return None;
+        }
+        self.0.get(crate::rand::range(0, self.0.len() as u64 - 1) as usize)
+    }
+}
+
+impl std::fmt::Display for StrategyRotation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+        let names: Vec<&str> = self.0.iter().map(|p| p.profile.name).collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+fn list_client_profiles() {
+    for p in CLIENT_PROFILES {
+        println!("{:<12} {}", p.name, p.description);
+    }
+}
+
+/// Range of `--split-random`, inclusive
+pub struct SplitRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl SplitRange {
+    /// Parse 1:5 to (1, 5).
+    pub fn new(s: &str) -> Result<Self> {
+        let invalid = || format!("--split-random: invalid value '{s}'");
+
+        let (min, max) = s.split_once(':').with_context(invalid)?;
+        let min: u32 = min.trim().parse().with_context(invalid)?;
+        let max: u32 = max.trim().parse().with_context(invalid)?;
+
+        if min == 0 || min > max {
+            return Err(anyhow!("--split-random: expected 0 < min <= max"));
+        }
+
+        Ok(Self { min, max })
+    }
+
+    /// Random first fragment length in this range
+    pub fn pick(&self) -> u32 {
+        crate::rand::range(self.min.into(), self.max.into()) as u32
+    }
+}
+
+impl std::fmt::Display for SplitRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}", self.min, self.max)
+    }
+}
+
+/// TLS extension of `--split-ext`
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct TlsExt(pub u16);
+
+/// Names of the extensions worth splitting at, as in the IANA registry
+const TLS_EXT_NAMES: &[(&str, u16)] = &[
+    ("server_name", 0),
+    ("supported_groups", 10),
+    ("ec_point_formats", 11),
+    ("signature_algorithms", 13),
+    ("alpn", 16),
+    ("padding", 21),
+    ("extended_master_secret", 23),
+    ("session_ticket", 35),
+    ("pre_shared_key", 41),
+    ("supported_versions", 43),
+    ("psk_key_exchange_modes", 45),
+    ("key_share", 51),
+    ("encrypted_client_hello", 0xfe0d),
+];
+
+impl TlsExt {
+    /// Parse a name of [`TLS_EXT_NAMES`] or a number, e.g. `key_share`
+    /// or `51`.
+    pub fn new(s: &str) -> Result<Self> {
+        let s = s.trim();
+        if let Some(&(_, id)) = TLS_EXT_NAMES.iter().find(|(name, _)| name.eq_ignore_ascii_case(s)) {
+            return Ok(Self(id));
+        }
+        s.parse().map(Self).map_err(|_| {
+            anyhow!("--split-ext: unknown extension '{s}' (use a number or: {})",
+                    TLS_EXT_NAMES.iter().map(|(name, _)| *name).collect::<Vec<_>>().join("|"))
+        })
+    }
+}
+
+impl std::fmt::Display for TlsExt {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match TLS_EXT_NAMES.iter().find(|&&(_, id)| id == self.0) {
+            Some((name, _)) => write!(f, "{name}"),
+            None => write!(f, "{}", self.0),
+        }
+    }
 }
 
 impl std::fmt::Display for SegmentOrder {
@@ -87,149 +742,1567 @@ impl std::fmt::Display for SegmentOrder {
     }
 }
 
-static OPT_DAEMON: OnceLock<bool> = OnceLock::new();
-static OPT_LOG_LEVEL: OnceLock<LogLevel> = OnceLock::new();
-static OPT_NO_SPLASH: OnceLock<bool> = OnceLock::new();
-static OPT_FAKE: OnceLock<bool> = OnceLock::new();
-static OPT_FAKE_TTL: OnceLock<u8> = OnceLock::new();
-static OPT_FAKE_AUTOTTL: OnceLock<bool> = OnceLock::new();
-static OPT_FAKE_BADSUM: OnceLock<bool> = OnceLock::new();
-static OPT_DELAY_MS: OnceLock<u64> = OnceLock::new();
-#[cfg(target_os = "linux")] static OPT_QUEUE_NUM: OnceLock<u16> = OnceLock::new();
-#[cfg(target_os = "linux")] static OPT_NFT_COMMAND: OnceLock<String> = OnceLock::new();
-static OPT_SEGMENT_ORDER: OnceLock<SegmentOrder> = OnceLock::new();
+/// Comma separated list of TCP ports, e.g. `3128,8080`
+#[derive(Clone, Default)]
+pub struct PortList(Vec<u16>);
+
+impl PortList {
+    pub fn ports(&self) -> &[u16] {
+        &self.0
+    }
+
+    pub fn contains(&self, port: u16) -> bool {
+        self.0.contains(&port)
+    }
+}
+
+impl std::str::FromStr for PortList {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let mut ports: Vec<u16> = s
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(str::parse)
+            .collect::<std::result::Result<_, _>>()?;
+
+        ports.sort_unstable();
+        ports.dedup();
+
+        Ok(Self(ports))
+    }
+}
+
+impl std::fmt::Display for PortList {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+
+        for (i, port) in self.0.iter().enumerate() {
+            if i > 0 { write!(f, ",")?; }
+            write!(f, "{port}")?;
+        }
+        Ok(())
+    }
+}
+
+/// TCP flags, e.g. `psh,fin`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TcpFlags(pub u8);
+
+impl TcpFlags {
+    pub const FIN: u8 = 0x01;
+    pub const SYN: u8 = 0x02;
+    pub const RST: u8 = 0x04;
+    pub const PSH: u8 = 0x08;
+    pub const ACK: u8 = 0x10;
+    pub const URG: u8 = 0x20;
+    pub const ECE: u8 = 0x40;
+    pub const CWR: u8 = 0x80;
+
+    const NAMES: [(&str, u8); 8] = [
+        ("fin", Self::FIN), ("syn", Self::SYN), ("rst", Self::RST), ("psh", Self::PSH),
+        ("ack", Self::ACK), ("urg", Self::URG), ("ece", Self::ECE), ("cwr", Self::CWR),
+    ];
+
+    /// Parse comma separated flag names. SYN is rejected: a fake must
+    /// not look like a new connection.
+    pub fn new(s: &str) -> Result<Self> {
+        let mut flags = 0;
+
+        for name in s.split(',').map(|x| x.trim().to_ascii_lowercase()) {
+            let (_, bit) = Self::NAMES.iter()
+                .find(|(n, _)| *n == name)
+                .ok_or_else(|| anyhow!("--fake-tcp-flags: invalid flag '{name}' \
+                                        (use: fin|rst|psh|ack|urg|ece|cwr)"))?;
+            flags |= bit;
+        }
+
+        if flags & Self::SYN != 0 {
+            return Err(anyhow!("--fake-tcp-flags: syn is not allowed"));
+        }
+
+        Ok(Self(flags))
+    }
+
+    pub fn has(&self, bit: u8) -> bool {
+        self.0 & bit != 0
+    }
+}
+
+impl std::fmt::Display for TcpFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let names: Vec<&str> = Self::NAMES.iter()
+            .filter(|(_, bit)| self.has(*bit))
+            .map(|(n, _)| *n)
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// Set of TCP option kinds, e.g. `sack-perm,ts`
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct TcpOptKinds(Vec<u8>);
+
+impl TcpOptKinds {
+    const NAMES: [(&str, u8); 7] = [
+        ("mss", 2), ("wscale", 3), ("sack-perm", 4), ("sack", 5),
+        ("ts", 8), ("md5", 19), ("tfo", 34),
+    ];
+
+    /// Parse comma separated option names or kind numbers. EOL and NOP
+    /// are rejected: they are padding, not options.
+    pub fn new(s: &str) -> Result<Self> {
+        let mut kinds = Vec::new();
+
+        for name in s.split(',').map(|x| x.trim().to_ascii_lowercase()) {
+            let kind = match Self::NAMES.iter().find(|(n, _)| *n == name) {
+                Some(&(_, kind)) => kind,
+                None => name.parse().ok().filter(|&k| k > 1).ok_or_else(|| {
+                    anyhow!("--strip-tcp-opts: invalid option '{name}' \
+                             (use: mss|wscale|sack-perm|sack|ts|md5|tfo|<2-255>)")
+                })?,
+            };
+            if !kinds.contains(&kind) {
+                kinds.push(kind);
+            }
+        }
+
+        Ok(Self(kinds))
+    }
+
+    pub fn contains(&self, kind: u8) -> bool {
+        self.0.contains(&kind)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl std::fmt::Display for TcpOptKinds {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "none");
+        }
+
+        let names: Vec<String> = self.0.iter()
+            .map(|&k| Self::NAMES.iter().find(|(_, n)| *n == k)
+                 .map_or(k.to_string(), |(name, _)| name.to_string()))
+            .collect();
+        write!(f, "{}", names.join(","))
+    }
+}
+
+/// TCP/IP fingerprint to mimic on generated packets
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum FingerprintPreset {
+    None,
+    Auto,
+    Linux,
+    Windows,
+    MacOs,
+}
+
+impl FingerprintPreset {
+    pub fn new(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "none" => Self::None,
+            "auto" => Self::Auto,
+            "linux" => Self::Linux,
+            "windows" => Self::Windows,
+            "macos" => Self::MacOs,
+            _ => return Err(anyhow!("--fingerprint: invalid value '{s}' \
+                                     (use: none|auto|linux|windows|macos)")),
+        })
+    }
+}
+
+impl std::fmt::Display for FingerprintPreset {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            Self::None => "none",
+            Self::Auto => "auto",
+            Self::Linux => "linux",
+            Self::Windows => "windows",
+            Self::MacOs => "macos",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// `--df`: Don't Fragment bit of generated IPv4 packets
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum DfPolicy {
+    Set,
+    Clear,
+
+    /// As in the original packet, or as `--fingerprint` says
+    Copy,
+}
+
+impl DfPolicy {
+    pub fn new(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "set" => Self::Set,
+            "clear" => Self::Clear,
+            "copy" => Self::Copy,
+            _ => return Err(anyhow!("--df: invalid value '{s}' (use: set|clear|copy)")),
+        })
+    }
+}
+
+impl std::fmt::Display for DfPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            Self::Set => "set",
+            Self::Clear => "clear",
+            Self::Copy => "copy",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// `--v6-flowlabel`: flow label of generated IPv6 packets
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum FlowLabel {
+    /// As in the original packet
+    Keep,
+
+    /// Random, the same for all packets sent for one original
+    Random,
+
+    /// Zero (unlabeled)
+    Zero,
+}
+
+impl FlowLabel {
+    pub fn new(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "keep" => Self::Keep,
+            "random" => Self::Random,
+            "zero" => Self::Zero,
+            _ => return Err(anyhow!("--v6-flowlabel: invalid value '{s}' (use: keep|random|zero)")),
+        })
+    }
+}
+
+impl std::fmt::Display for FlowLabel {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            Self::Keep => "keep",
+            Self::Random => "random",
+            Self::Zero => "zero",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// How the Linux packet loop waits for packets
+#[cfg(target_os = "linux")]
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum PollMode {
+    /// Never sleep; poll without timeout in a loop
+    Busy,
+
+    /// Spin for a while after each packet, then sleep in poll(2)
+    Hybrid,
+
+    /// Always sleep in poll(2)
+    Block,
+}
+
+#[cfg(target_os = "linux")]
+impl PollMode {
+    pub fn new(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "busy" => Self::Busy,
+            "hybrid" => Self::Hybrid,
+            "block" => Self::Block,
+            _ => return Err(anyhow!("--poll-mode: invalid value '{s}' (use: busy|hybrid|block)")),
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl std::fmt::Display for PollMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let s = match self {
+            Self::Busy => "busy",
+            Self::Hybrid => "hybrid",
+            Self::Block => "block",
+        };
+        write!(f, "{s}")
+    }
+}
+
+/// `--mark`: packet mark of our injected packets, and the bits of the
+/// mark that are ours. Also `--connmark`, the conntrack mark of handled
+/// connections.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Mark {
+    pub value: u32,
+    pub mask: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl Mark {
+    /// Parse `value[/mask]` of `--mark`, each in decimal or 0x hex; the
+    /// mask defaults to all bits.
+    pub fn new(s: &str) -> Result<Self> {
+        Self::parse("--mark", s, false)
+    }
+
+    /// Parse `value[/mask]` of `--connmark`; the mask defaults to the
+    /// value, leaving the other bits of the conntrack mark alone.
+    pub fn connmark(s: &str) -> Result<Self> {
+        Self::parse("--connmark", s, true)
+    }
+
+    fn parse(name: &str, s: &str, mask_is_value: bool) -> Result<Self> {
+        let invalid = || format!("{name}: invalid value '{s}'");
+        let num = |n: &str| {
+            let n = n.trim();
+            match n.strip_prefix("0x").or_else(|| n.strip_prefix("0X")) {
+                Some(hex) => u32::from_str_radix(hex, 16),
+                None => n.parse(),
+            }
+        };
+
+        let (value, mask) = match s.split_once('/') {
+            Some((value, mask)) => (num(value).with_context(invalid)?, Some(mask)),
+            None => (num(s).with_context(invalid)?, None),
+        };
+        let mask = match mask {
+            Some(mask) => num(mask).with_context(invalid)?,
+            None if mask_is_value => value,
+            None => u32::MAX,
+        };
+
+        if value == 0 || value & !mask != 0 {
+            return Err(anyhow!("{name}: value must be nonzero and within the mask"));
+        }
+
+        Ok(Self { value, mask })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl std::fmt::Display for Mark {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:#x}/{:#x}", self.value, self.mask)
+    }
+}
 
 const DEFAULT_DAEMON: bool = false;
 #[cfg(debug_assertions)]      const DEFAULT_LOG_LEVEL: LogLevel = LogLevel::Debug;
 #[cfg(not(debug_assertions))] const DEFAULT_LOG_LEVEL: LogLevel = LogLevel::Warning;
 const DEFAULT_NO_SPLASH: bool = false;
+const DEFAULT_CRASH_LOG_LINES: u32 = 256;
 const DEFAULT_FAKE: bool = false;
 const DEFAULT_FAKE_TTL: u8 = 8;
 const DEFAULT_FAKE_AUTOTTL: bool = false;
+const DEFAULT_AUTOTTL_ANY: bool = false;
 const DEFAULT_FAKE_BADSUM: bool = false;
+const DEFAULT_FAKE_RATE: u32 = 0;
 const DEFAULT_DELAY_MS: u64 = 0;
+const DEFAULT_DELAY_JITTER: u64 = 0;
 #[cfg(target_os = "linux")] const DEFAULT_QUEUE_NUM: u16 = 1;
 #[cfg(target_os = "linux")] const DEFAULT_NFT_COMMAND: &str = "nft";
+#[cfg(target_os = "linux")] const DEFAULT_NFT_PRIORITY: i32 = 0;
+#[cfg(target_os = "linux")] const DEFAULT_FAIL_CLOSED: bool = false;
+#[cfg(target_os = "linux")] const DEFAULT_NOTRACK: bool = false;
+#[cfg(target_os = "linux")] const DEFAULT_BULK_BYTES: u64 = 65536;
+#[cfg(target_os = "linux")] const DEFAULT_POLL_MODE: PollMode = PollMode::Block;
+#[cfg(target_os = "linux")] const DEFAULT_RULE_CHECK_INTERVAL: u64 = 10;
+#[cfg(target_os = "linux")] const DEFAULT_MARK: &str = "0xd001";
+#[cfg(target_os = "linux")] const DEFAULT_CONNMARK: &str = "0x40000000";
+#[cfg(target_os = "linux")] const DEFAULT_MANGLE_ONLY: bool = false;
+#[cfg(target_os = "linux")] const DEFAULT_CONTAINER: bool = false;
+#[cfg(target_os = "linux")] const DEFAULT_CONTROL_SOCKET: &str = "/run/dpibreak.sock";
+#[cfg(windows)] const DEFAULT_SOCKET_LAYER: bool = false;
+#[cfg(windows)] const DEFAULT_WINDIVERT_PRIORITY: i16 = 0;
 const DEFAULT_SEGMENT_ORDER: &str = "0,1";
+const DEFAULT_ONCE_PER_HOST: u64 = 0;
+const DEFAULT_COALESCE: bool = false;
+const DEFAULT_BURST_LIMIT: u32 = 0;
+const DEFAULT_FINGERPRINT: FingerprintPreset = FingerprintPreset::None;
+const DEFAULT_DROP_FORGED_RST: bool = false;
+const DEFAULT_FORGED_RST_DELTA: u8 = 2;
+const DEFAULT_WATCH_PMTU: bool = false;
+const DEFAULT_DNS_GUARD: bool = false;
+const DEFAULT_DOH_LISTEN: &str = "127.0.0.1:53";
+const DEFAULT_HOSTLIST_REFRESH: u64 = 3600;
+const DEFAULT_HOSTLIST_REGEX: bool = false;
+const DEFAULT_SNI_BINDING_TTL: u64 = 600;
+const DEFAULT_MAX_BUFFER_MB: u64 = 16;
+const DEFAULT_PRIVATE_EXEMPT: bool = true;
+const DEFAULT_ADAPTIVE_EXEMPT: bool = false;
+const DEFAULT_PASS_SSLV2: bool = false;
+const DEFAULT_MIN_PAYLOAD: u32 = 16;
+const DEFAULT_CLEAR_ECN: bool = false;
+const DEFAULT_DF: DfPolicy = DfPolicy::Copy;
+const DEFAULT_V6_FLOWLABEL: FlowLabel = FlowLabel::Keep;
+const DEFAULT_V6_DEGRADE: bool = false;
+const DEFAULT_WATCH: bool = false;
+const DEFAULT_MAX_RESTARTS: u32 = 5;
+const DEFAULT_HEARTBEAT: u64 = 0;
+const DEFAULT_REPLAY_SCALE: f64 = 0.0;
+
+/// Combination of options that does not work as given. Fixes are
+/// applied in order, so a rule sees the options fixed by those before.
+struct Compat {
+    /// Why, if the options conflict
+    conflict: fn(&Config) -> Option<String>,
 
-pub struct Opt {
+    /// Make the options work, or None to reject them
+    fix: Option<fn(&mut Config)>,
+}
+
+const COMPAT: &[Compat] = &[
+    #[cfg(target_os = "linux")]
+    Compat {
+        conflict: |o| (o.container && o.daemon).then(|| {
+            "--container: cannot be used with --daemon; \
+             the container would exit with the parent process".into()
+        }),
+        fix: None,
+    },
+    Compat {
+        conflict: |o| {
+            let range = o.split_random.as_ref()?;
+            match o.segment_order.first_boundaries() {
+                (None, _) => Some("--split-random: --segment-order has nothing to split".into()),
+                (_, Some(next)) if next <= range.max => Some(format!(
+                    "--split-random: max must be less than {next}, \
+                     the second boundary of --segment-order")),
+                _ => None,
+            }
+        },
+        fix: None,
+    },
+    Compat {
+        conflict: |o| {
+            o.split_ext?;
+            if o.split_random.is_some() {
+                return Some("--split-ext: cannot be used with --split-random".into());
+            }
+            o.segment_order.first_boundaries().0.is_none()
+                .then(|| "--split-ext: --segment-order has nothing to split".into())
+        },
+        fix: None,
+    },
+    #[cfg(target_os = "linux")]
+    Compat {
+        conflict: |o| (o.mangle_only && o.fake).then(|| {
+            "--mangle-only: fakes need a raw socket; --fake ignored".into()
+        }),
+        fix: Some(|o| o.fake = false),
+    },
+    #[cfg(target_os = "linux")]
+    Compat {
+        conflict: |o| (o.mangle_only && o.segment_order.segments()[0].0 != 0).then(|| {
+            "--mangle-only: out-of-order segments need a raw socket; sent in order".into()
+        }),
+        fix: Some(|o| o.segment_order.sort()),
+    },
+    #[cfg(target_os = "linux")]
+    Compat {
+        conflict: |o| (o.mangle_only && o.zero_window.is_some()).then(|| {
+            "--mangle-only: --zero-window needs a raw socket; ignored".into()
+        }),
+        fix: Some(|o| o.zero_window = None),
+    },
+    #[cfg(target_os = "linux")]
+    Compat {
+        conflict: |o| (o.notrack && !o.client_profile.is_empty()).then(|| {
+            "--notrack: segments of --client-profile clients must be NATed as \
+             their connections are; --notrack ignored".into()
+        }),
+        fix: Some(|o| o.notrack = false),
+    },
+    Compat {
+        conflict: |o| (o.segment_order.segments().len() == 1 && !o.fake).then(|| {
+            "--segment-order: nothing to split and no --fake; \
+             ClientHellos would be sent unchanged".into()
+        }),
+        fix: None,
+    },
+    Compat {
+        conflict: |o| (o.segment_order.segments().len() == 1 && o.delay_jitter > 0).then(|| {
+            "--delay-jitter: --segment-order has a single segment; ignored".into()
+        }),
+        fix: Some(|o| o.delay_jitter = 0),
+    },
+    Compat {
+        conflict: |o| (o.hostlist_sha256.is_some() && o.hostlist.is_empty()).then(|| {
+            "--hostlist-sha256: no --hostlist to verify".into()
+        }),
+        fix: None,
+    },
+    Compat {
+        conflict: |o| (o.hostlist_sha256.is_some() && o.hostlist.0.len() > 1).then(|| {
+            "--hostlist-sha256: one digest cannot match several --hostlist".into()
+        }),
+        fix: None,
+    },
+    Compat {
+        conflict: |o| (o.v6_degrade && o.hostlist.is_empty()).then(|| {
+            "--v6-degrade: needs --hostlist, the servers to degrade".into()
+        }),
+        fix: None,
+    },
+    Compat {
+        conflict: |o| (o.hostlist_regex && o.hostlist.is_empty()).then(|| {
+            "--hostlist-regex: no --hostlist; ignored".into()
+        }),
+        fix: Some(|o| o.hostlist_regex = false),
+    },
+    Compat {
+        conflict: |o| {
+            let tuned = o.replay_out.is_some() || o.replay_scale != DEFAULT_REPLAY_SCALE;
+            (tuned && o.replay.is_none()).then(|| "--replay-out, --replay-scale: no --replay".into())
+        },
+        fix: None,
+    },
+];
+
+/// `--preset`: options known by users to work against the DPI of a
+/// country. They are put before the given options, so that those given
+/// explicitly take precedence.
+struct Preset {
+    name: &'static str,
+    description: &'static str,
+    args: &'static [&'static str],
+}
+
+const PRESETS: &[Preset] = &[
+    Preset {
+        name: "generic",
+        description: "first byte split off and a fake before each segment",
+        args: &["--fake-autottl"],
+    },
+    Preset {
+        name: "ru",
+        description: "TSPU: fakes with bad checksum, first byte split off",
+        args: &["--fake-autottl", "--fake-badsum", "--segment-order", "0,1"],
+    },
+    Preset {
+        name: "ir",
+        description: "fakes, record header split and sent out of order",
+        args: &["--fake-autottl", "--segment-order", "1,0"],
+    },
+    Preset {
+        name: "cn",
+        description: "GFW: fakes, out-of-order split, forged RST and DNS dropped",
+        args: &["--fake-autottl", "--fake-badsum", "--segment-order", "2,0",
+                "--drop-forged-rst", "--dns-guard"],
+    },
+    Preset {
+        name: "tm",
+        description: "short-lived fakes, three segments paced apart",
+        args: &["--fake", "--fake-ttl", "3", "--segment-order", "0,1,5", "--delay-ms", "5"],
+    },
+];
+
+/// `args` with the arguments of its `--preset` put first, and the
+/// name of the preset if any.
+fn expand_preset(args: Vec<String>) -> Result<(Vec<String>, Option<&'static Preset>)> {
+    let mut rest = Vec::with_capacity(args.len());
+    let mut preset: Option<&Preset> = None;
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg != "--preset" {
+            rest.push(arg);
+            continue;
+        }
+
+        let name: String = take_value(&mut iter, "--preset")?;
+        if preset.is_some() {
+            return Err(anyhow!("--preset: given more than once"));
+        }
+        preset = Some(PRESETS.iter().find(|p| p.name == name).ok_or_else(|| {
+            anyhow!("--preset: unknown preset '{name}' (use: {})",
+                    PRESETS.iter().map(|p| p.name).collect::<Vec<_>>().join("|"))
+        })?);
+    }
+
+    let Some(preset) = preset else { return Ok((rest, None)) };
+    let mut args: Vec<String> = preset.args.iter().map(|s| s.to_string()).collect();
+    args.extend(rest);
+
+    Ok((args, Some(preset)))
+}
+
+fn list_presets() {
+    for p in PRESETS {
+        println!("{:<8} {}", p.name, p.description);
+        println!("{:<8} {}", "", p.args.join(" "));
+    }
+}
+
+/// Minimum retransmission timeout of common TCP stacks (Linux
+/// TCP_RTO_MIN). Delaying the ClientHello longer than this may let the
+/// client retransmit it before we have sent all the segments.
+const RTO_MIN_MS: u64 = 200;
+
+/// All the options, parsed; see [`config`] for the ones in effect.
+pub struct Config {
     daemon: bool,
     log_level: LogLevel,
+    log_target: Option<String>,
+    crash_log_lines: u32,
     no_splash: bool,
     fake: bool,
     fake_ttl: u8,
     fake_autottl: bool,
+    autottl_any: bool,
     fake_badsum: bool,
+    fake_rate: u32,
+    fake_tcp_flags: Option<TcpFlags>,
+    fake_split: Option<u32>,
     delay_ms: u64,
-    #[cfg(target_os = "linux")] queue_num: u16,
+    delay_jitter: u64,
+    delay_schedule: Vec<u64>,
+    zero_window: Option<u64>,
+    send_raw: Vec<RawPacket>,
+    #[cfg(target_os = "linux")] queue_num: Option<u16>,
+    #[cfg(target_os = "linux")] queue_maxlen: Option<u32>,
     #[cfg(target_os = "linux")] nft_command: String,
+    #[cfg(target_os = "linux")] nft_priority: i32,
+    #[cfg(target_os = "linux")] fail_closed: bool,
+    #[cfg(target_os = "linux")] notrack: bool,
+    #[cfg(target_os = "linux")] bulk_bytes: u64,
+    #[cfg(target_os = "linux")] poll_mode: PollMode,
+    #[cfg(target_os = "linux")] rule_check_interval: u64,
+    #[cfg(target_os = "linux")] mark: Mark,
+    #[cfg(target_os = "linux")] connmark: Mark,
+    #[cfg(target_os = "linux")] mangle_only: bool,
+    #[cfg(target_os = "linux")] container: bool,
+    #[cfg(target_os = "linux")] control_socket: String,
+    #[cfg(windows)] socket_layer: bool,
+    #[cfg(windows)] iface: StringList,
+    #[cfg(windows)] windivert_priority: i16,
     segment_order: SegmentOrder,
+    proxy_ports: PortList,
+    once_per_host: u64,
+    coalesce: bool,
+    burst_limit: u32,
+    split_random: Option<SplitRange>,
+    split_ext: Option<TlsExt>,
+    fingerprint: FingerprintPreset,
+    strip_tcp_opts: TcpOptKinds,
+    dscp: Option<u8>,
+    clear_ecn: bool,
+    server_window: Option<u16>,
+    df: DfPolicy,
+    v6_flowlabel: FlowLabel,
+    v6_degrade: bool,
+    preset: String,
+    drop_forged_rst: bool,
+    forged_rst_delta: u8,
+    watch_pmtu: bool,
+    dns_guard: bool,
+    doh_forward: Option<String>,
+    doh_listen: std::net::SocketAddr,
+    hostlist: StringList,
+    hostlist_refresh: u64,
+    hostlist_sha256: Option<String>,
+    hostlist_regex: bool,
+    sni_binding_ttl: u64,
+    max_buffer_mb: u64,
+    private_exempt: bool,
+    adaptive_exempt: bool,
+    pass_sslv2: bool,
+    min_payload: u32,
+    watch: bool,
+    max_restarts: u32,
+    heartbeat: u64,
+    stats_shm: Option<String>,
+    status_file: Option<String>,
+    webhook: Option<String>,
+    replay: Option<String>,
+    replay_out: Option<String>,
+    replay_scale: f64,
+    trace: TraceList,
+    schedule: Schedule,
+    client_profile: ClientProfiles,
+    strategy_rotate: StrategyRotation,
+    verdict_hook: Option<String>,
 }
 
-impl Opt {
-    pub fn from_args() -> Result<Self> {
-        let mut daemon = DEFAULT_DAEMON;
-        let mut log_level     = DEFAULT_LOG_LEVEL;
-        let mut delay_ms      = DEFAULT_DELAY_MS;
-        let mut no_splash     = DEFAULT_NO_SPLASH;
-        let mut fake          = DEFAULT_FAKE;
-        let mut fake_ttl      = DEFAULT_FAKE_TTL;
-        let mut fake_autottl  = DEFAULT_FAKE_AUTOTTL;
-        let mut fake_badsum   = DEFAULT_FAKE_BADSUM;
-        let mut segment_order = SegmentOrder::new(DEFAULT_SEGMENT_ORDER)?;
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            daemon: DEFAULT_DAEMON,
+            log_level: DEFAULT_LOG_LEVEL,
+            log_target: None,
+            crash_log_lines: DEFAULT_CRASH_LOG_LINES,
+            no_splash: DEFAULT_NO_SPLASH,
+            fake: DEFAULT_FAKE,
+            fake_ttl: DEFAULT_FAKE_TTL,
+            fake_autottl: DEFAULT_FAKE_AUTOTTL,
+            autottl_any: DEFAULT_AUTOTTL_ANY,
+            fake_badsum: DEFAULT_FAKE_BADSUM,
+            fake_rate: DEFAULT_FAKE_RATE,
+            fake_tcp_flags: None,
+            fake_split: None,
+            delay_ms: DEFAULT_DELAY_MS,
+            delay_jitter: DEFAULT_DELAY_JITTER,
+            delay_schedule: Vec::new(),
+            zero_window: None,
+            send_raw: Vec::new(),
+            #[cfg(target_os = "linux")] queue_num: None,
+            #[cfg(target_os = "linux")] queue_maxlen: None,
+            #[cfg(target_os = "linux")] nft_command: DEFAULT_NFT_COMMAND.to_string(),
+            #[cfg(target_os = "linux")] nft_priority: DEFAULT_NFT_PRIORITY,
+            #[cfg(target_os = "linux")] fail_closed: DEFAULT_FAIL_CLOSED,
+            #[cfg(target_os = "linux")] notrack: DEFAULT_NOTRACK,
+            #[cfg(target_os = "linux")] bulk_bytes: DEFAULT_BULK_BYTES,
+            #[cfg(target_os = "linux")] poll_mode: DEFAULT_POLL_MODE,
+            #[cfg(target_os = "linux")] rule_check_interval: DEFAULT_RULE_CHECK_INTERVAL,
+            #[cfg(target_os = "linux")] mark: Mark::new(DEFAULT_MARK).expect("valid default"),
+            #[cfg(target_os = "linux")] connmark: Mark::connmark(DEFAULT_CONNMARK).expect("valid default"),
+            #[cfg(target_os = "linux")] mangle_only: DEFAULT_MANGLE_ONLY,
+            #[cfg(target_os = "linux")] container: DEFAULT_CONTAINER,
+            #[cfg(target_os = "linux")] control_socket: DEFAULT_CONTROL_SOCKET.to_string(),
+            #[cfg(windows)] socket_layer: DEFAULT_SOCKET_LAYER,
+            #[cfg(windows)] iface: StringList::default(),
+            #[cfg(windows)] windivert_priority: DEFAULT_WINDIVERT_PRIORITY,
+            segment_order: SegmentOrder::new(DEFAULT_SEGMENT_ORDER).expect("valid default"),
+            proxy_ports: PortList::default(),
+            once_per_host: DEFAULT_ONCE_PER_HOST,
+            coalesce: DEFAULT_COALESCE,
+            burst_limit: DEFAULT_BURST_LIMIT,
+            split_random: None,
+            split_ext: None,
+            fingerprint: DEFAULT_FINGERPRINT,
+            strip_tcp_opts: TcpOptKinds::default(),
+            dscp: None,
+            clear_ecn: DEFAULT_CLEAR_ECN,
+            server_window: None,
+            df: DEFAULT_DF,
+            v6_flowlabel: DEFAULT_V6_FLOWLABEL,
+            v6_degrade: DEFAULT_V6_DEGRADE,
+            preset: "none".to_string(),
+            drop_forged_rst: DEFAULT_DROP_FORGED_RST,
+            forged_rst_delta: DEFAULT_FORGED_RST_DELTA,
+            watch_pmtu: DEFAULT_WATCH_PMTU,
+            dns_guard: DEFAULT_DNS_GUARD,
+            doh_forward: None,
+            doh_listen: DEFAULT_DOH_LISTEN.parse().expect("valid default"),
+            hostlist: StringList::default(),
+            hostlist_refresh: DEFAULT_HOSTLIST_REFRESH,
+            hostlist_sha256: None,
+            hostlist_regex: DEFAULT_HOSTLIST_REGEX,
+            sni_binding_ttl: DEFAULT_SNI_BINDING_TTL,
+            max_buffer_mb: DEFAULT_MAX_BUFFER_MB,
+            private_exempt: DEFAULT_PRIVATE_EXEMPT,
+            adaptive_exempt: DEFAULT_ADAPTIVE_EXEMPT,
+            pass_sslv2: DEFAULT_PASS_SSLV2,
+            min_payload: DEFAULT_MIN_PAYLOAD,
+            watch: DEFAULT_WATCH,
+            max_restarts: DEFAULT_MAX_RESTARTS,
+            heartbeat: DEFAULT_HEARTBEAT,
+            stats_shm: None,
+            status_file: None,
+            webhook: None,
+            replay: None,
+            replay_out: None,
+            replay_scale: DEFAULT_REPLAY_SCALE,
+            trace: TraceList::default(),
+            schedule: Schedule::default(),
+            client_profile: ClientProfiles::default(),
+            strategy_rotate: StrategyRotation::default(),
+            verdict_hook: None,
+        }
+    }
+}
 
-        #[cfg(target_os = "linux")]
-        let mut queue_num: u16 = DEFAULT_QUEUE_NUM;
-        #[cfg(target_os = "linux")]
-        let mut nft_command = String::from(DEFAULT_NFT_COMMAND);
+/// Value given to an option
+struct Value<'a> {
+    /// Option as given, e.g. `-t`
+    name: &'a str,
+    raw: &'a str,
+}
+
+impl Value<'_> {
+    fn parse<T>(&self) -> Result<T>
+    where
+        T: std::str::FromStr,
+        T::Err: std::error::Error + Send + Sync + 'static,
+    {
+        self.raw.parse::<T>()
+            .with_context(|| format!("argument: {}: invalid value '{}'", self.name, self.raw))
+    }
+}
 
-        let mut args = std::env::args().skip(1); // program name
+/// Option of [`OPTIONS`]. Its usage line, its `--config` key and its
+/// parsing all come from here.
+struct OptSpec {
+    /// Long name, e.g. `--fake-ttl`; without the dashes, the key in a
+    /// `--config` file
+    name: &'static str,
 
-        let mut warned_loglevel_deprecated = false;
-        let mut warned_daemon_deprecated = false;
+    /// e.g. `-t`, or ""
+    short: &'static str,
+
+    /// Placeholder of the value in usage, or "" if it takes none
+    value: &'static str,
+
+    /// Usage text; `{default}` is replaced with [`Self::default`].
+    help: &'static str,
+    default: Option<fn() -> String>,
+
+    /// Check the value and put it in the config
+    set: fn(&mut Config, &Value) -> Result<()>,
+}
+
+/// Options not about how ClientHellos are desynced
+const GENERAL: &[OptSpec] = &[
+    OptSpec {
+        name: "--help", short: "-h", value: "",
+        help: "Show this help", default: None,
+        set: |_, _| { usage(); platform::paexit(0) },
+    },
+    OptSpec {
+        name: "--daemon", short: "-d", value: "",
+        help: "Run as daemon. kill `pidof dpibreak` to stop", default: None,
+        set: |c, _| {
+            c.no_splash = true;
+            // if it is unchanged explicitly by argument, set it to info
+            if c.log_level == DEFAULT_LOG_LEVEL {
+                c.log_level = LogLevel::Info;
+            }
+            c.daemon = true;
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--config", short: "", value: "<path>",
+        help: "Read options from this file, `name = value` per line; given options override (repeatable)",
+        default: None,
+        set: |_, v| Err(anyhow!("{}: not allowed in a config file", v.name)),
+    },
+    OptSpec {
+        name: "--delay-ms", short: "", value: "<u64>",
+        help: "Delay milliseconds between each segment packets (default: {default})",
+        default: Some(|| DEFAULT_DELAY_MS.to_string()),
+        set: |c, v| { c.delay_ms = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--delay-jitter", short: "", value: "<u64>",
+        help: "Add random 0..=N milliseconds to each delay (default: {default})",
+        default: Some(|| DEFAULT_DELAY_JITTER.to_string()),
+        set: |c, v| { c.delay_jitter = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--delay-schedule", short: "", value: "<u64,u64,...>",
+        help: "Delay milliseconds before each segment, in the order they are sent; overrides --delay-ms",
+        default: None,
+        set: |c, v| {
+            c.delay_schedule = v.raw.split(',')
+                .map(|ms| ms.trim().parse::<u64>())
+                .collect::<std::result::Result<_, _>>()
+                .map_err(|_| anyhow!("argument: {}: invalid value '{}'", v.name, v.raw))?;
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--zero-window", short: "", value: "<ms>",
+        help: "Stall the flow this long with a zero window between the fake and the first segment",
+        default: None,
+        set: |c, v| { c.zero_window = Some(v.parse()?); Ok(()) },
+    },
+    OptSpec {
+        name: "--send-raw", short: "", value: "<hex>@<before|between|after>",
+        help: "Also send this IP packet, with {seq}, {daddr}... of the flow, among the segments (repeatable)",
+        default: None,
+        set: |c, v| { c.send_raw.push(RawPacket::new(v.raw)?); Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--queue-num", short: "", value: "<u16>",
+        help: "Netfilter queue number to bind (default: first free from {default})",
+        default: Some(|| DEFAULT_QUEUE_NUM.to_string()),
+        set: |c, v| { c.queue_num = Some(v.parse()?); Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--queue-maxlen", short: "", value: "<u32>",
+        help: "Packets the kernel may hold in the queue for us; more are dropped \
+               (default: by the number of workers)",
+        default: None,
+        set: |c, v| {
+            let n: u32 = v.parse()?;
+            if n == 0 {
+                return Err(anyhow!("--queue-maxlen: must be at least 1"));
+            }
+            c.queue_maxlen = Some(n);
+            Ok(())
+        },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--nft-command", short: "", value: "<string>",
+        help: "(default: {default})",
+        default: Some(|| DEFAULT_NFT_COMMAND.to_string()),
+        set: |c, v| { c.nft_command = v.raw.to_string(); Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--nft-priority", short: "", value: "<i32>",
+        help: "Priority of nftables OUTPUT chain (default: {default})",
+        default: Some(|| DEFAULT_NFT_PRIORITY.to_string()),
+        set: |c, v| { c.nft_priority = v.parse()?; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--fail-closed", short: "", value: "",
+        help: "Block HTTPS instead of bypassing when DPIBreak is not running", default: None,
+        set: |c, _| { c.fail_closed = true; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--notrack", short: "", value: "",
+        help: "Keep packets sent by DPIBreak out of conntrack", default: None,
+        set: |c, _| { c.notrack = true; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--bulk-bytes", short: "", value: "<u64>",
+        help: "Stop queuing a connection after this many bytes when ClientHellos \
+               cannot be matched in kernel; 0 to never (default: {default})",
+        default: Some(|| DEFAULT_BULK_BYTES.to_string()),
+        set: |c, v| { c.bulk_bytes = v.parse()?; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--poll-mode", short: "", value: "<busy|hybrid|block>",
+        help: "Spin instead of sleeping while waiting for packets (default: {default})",
+        default: Some(|| DEFAULT_POLL_MODE.to_string()),
+        set: |c, v| { c.poll_mode = PollMode::new(v.raw)?; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--rule-check-interval", short: "", value: "<seconds>",
+        help: "Reinstall rules removed by a firewall reload (default: {default}, 0 to disable)",
+        default: Some(|| DEFAULT_RULE_CHECK_INTERVAL.to_string()),
+        set: |c, v| { c.rule_check_interval = v.parse()?; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--mark", short: "", value: "<value[/mask]>",
+        help: "Packet mark of injected packets, and the bits of it that are ours (default: {default})",
+        default: Some(|| DEFAULT_MARK.to_string()),
+        set: |c, v| { c.mark = Mark::new(v.raw)?; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--connmark", short: "", value: "<value[/mask]>",
+        help: "Conntrack mark of handled connections, and the bits of it that are ours (default: {default})",
+        default: Some(|| DEFAULT_CONNMARK.to_string()),
+        set: |c, v| { c.connmark = Mark::connmark(v.raw)?; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--mangle-only", short: "", value: "",
+        help: "Cut ClientHello in place instead of sending segments (automatic without raw sockets)",
+        default: None,
+        set: |c, _| { c.mangle_only = true; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--container", short: "", value: "",
+        help: "Run in a container sharing the host network (check capabilities, no modprobe)",
+        default: None,
+        set: |c, _| { c.container = true; Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--control-socket", short: "", value: "<path>",
+        help: "Answer `dpibreak status` on this Unix socket (default: {default})",
+        default: Some(|| DEFAULT_CONTROL_SOCKET.to_string()),
+        set: |c, v| { c.control_socket = v.raw.to_string(); Ok(()) },
+    },
+    #[cfg(target_os = "linux")]
+    OptSpec {
+        name: "--no-control-socket", short: "", value: "",
+        help: "Do not listen on a control socket", default: None,
+        set: |c, _| { c.control_socket.clear(); Ok(()) },
+    },
+    #[cfg(windows)]
+    OptSpec {
+        name: "--socket-layer", short: "", value: "",
+        help: "Track connections and their processes with a WinDivert socket handle", default: None,
+        set: |c, _| { c.socket_layer = true; Ok(()) },
+    },
+    #[cfg(windows)]
+    OptSpec {
+        name: "--iface", short: "", value: "<name|guid|index>",
+        help: "Handle only packets on this adapter; may be repeated", default: None,
+        set: |c, v| { c.iface.0.push(v.raw.to_string()); Ok(()) },
+    },
+    #[cfg(windows)]
+    OptSpec {
+        name: "--windivert-priority", short: "", value: "<i16>",
+        help: "Priority of the WinDivert handles, -30000 to 30000; higher goes first (default: {default})",
+        default: Some(|| DEFAULT_WINDIVERT_PRIORITY.to_string()),
+        set: |c, v| {
+            let priority: i16 = v.parse()?;
+            if !(-30000..=30000).contains(&priority) {
+                return Err(anyhow!("--windivert-priority: expected -30000 to 30000, got {priority}"));
+            }
+            c.windivert_priority = priority;
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--log-level", short: "", value: "<debug|info|warning|error>",
+        help: "(default: {default})",
+        default: Some(|| DEFAULT_LOG_LEVEL.to_string()),
+        set: |c, v| { c.log_level = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--no-splash", short: "", value: "",
+        help: "Do not print splash messages on startup", default: None,
+        set: |c, _| { c.no_splash = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--log-target", short: "", value: "<udp://host:port>",
+        help: "Also send log lines to this syslog collector, rate limited", default: None,
+        set: |c, v| {
+            if !v.raw.starts_with("udp://") {
+                return Err(anyhow!("--log-target: expected udp://host:port, got '{}'", v.raw));
+            }
+            c.log_target = Some(v.raw.to_string());
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--crash-log-lines", short: "", value: "<u32>",
+        help: "Log lines kept in memory for the crash log (default: {default}, 0 to disable)",
+        default: Some(|| DEFAULT_CRASH_LOG_LINES.to_string()),
+        set: |c, v| { c.crash_log_lines = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--watch", short: "", value: "",
+        help: "Restart after a fatal error, with exponential backoff", default: None,
+        set: |c, _| { c.watch = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--max-restarts", short: "", value: "<u32>",
+        help: "Give up --watch after this many restarts in a row (default: {default}, 0 for no limit)",
+        default: Some(|| DEFAULT_MAX_RESTARTS.to_string()),
+        set: |c, v| { c.watch = true; c.max_restarts = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--heartbeat", short: "", value: "<minutes>",
+        help: "Log uptime and what was handled since the last beat (default: {default}, disabled)",
+        default: Some(|| DEFAULT_HEARTBEAT.to_string()),
+        set: |c, v| { c.heartbeat = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--stats-shm", short: "", value: "<path|name>",
+        help: "Publish counters in shared memory, refreshed every second",
+        default: None,
+        set: |c, v| { c.stats_shm = Some(v.raw.to_string()); Ok(()) },
+    },
+    OptSpec {
+        name: "--status-file", short: "", value: "<path>",
+        help: "Write the state, PID and exit status here on startup and on exit",
+        default: None,
+        set: |c, v| { c.status_file = Some(v.raw.to_string()); Ok(()) },
+    },
+    OptSpec {
+        name: "--webhook", short: "", value: "<url>",
+        help: "POST a JSON object to this URL on exemptions, revocations and failed exits",
+        default: None,
+        set: |c, v| {
+            if !v.raw.starts_with("https://") && !v.raw.starts_with("http://") {
+                return Err(anyhow!("--webhook: expected http:// or https:// URL, got '{}'", v.raw));
+            }
+            c.webhook = Some(v.raw.to_string());
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--replay", short: "", value: "<pcap>",
+        help: "Run the packets of this capture through the pipeline instead of the network",
+        default: None,
+        set: |c, v| { c.replay = Some(v.raw.to_string()); Ok(()) },
+    },
+    OptSpec {
+        name: "--replay-out", short: "", value: "<pcap>",
+        help: "Write what --replay would send to this capture (default: <pcap>.out.pcap)",
+        default: None,
+        set: |c, v| { c.replay_out = Some(v.raw.to_string()); Ok(()) },
+    },
+    OptSpec {
+        name: "--replay-scale", short: "", value: "<f64>",
+        help: "Wait this fraction of the capture's timing and delays in real time (default: {default}, at once)",
+        default: Some(|| DEFAULT_REPLAY_SCALE.to_string()),
+        set: |c, v| {
+            let scale: f64 = v.parse()?;
+            if !(scale.is_finite() && scale >= 0.0) {
+                return Err(anyhow!("--replay-scale: expected a non-negative number, got '{}'", v.raw));
+            }
+            c.replay_scale = scale;
+            Ok(())
+        },
+    },
+];
+
+/// Options about which ClientHellos are desynced, and how
+const DESYNC: &[OptSpec] = &[
+    OptSpec {
+        name: "--preset", short: "", value: "<generic|ru|ir|cn|tm>",
+        help: "Start from options known to work in a country; given options override",
+        default: None,
+        // Taken out by expand_preset before
+        set: |_, _| Ok(()),
+    },
+    OptSpec {
+        name: "--list-presets", short: "", value: "",
+        help: "Show presets and their options, then exit", default: None,
+        set: |_, _| { list_presets(); platform::paexit(0) },
+    },
+    OptSpec {
+        name: "--fake", short: "", value: "",
+        help: "Enable fake clienthello injection", default: None,
+        set: |c, _| { c.fake = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--fake-ttl", short: "-t", value: "<u8>",
+        help: "Override ttl of fake clienthello (default: {default})",
+        default: Some(|| DEFAULT_FAKE_TTL.to_string()),
+        set: |c, v| { c.fake = true; c.fake_ttl = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--fake-autottl", short: "-a", value: "",
+        help: "Infer ttl of fake clienthello automatically and override it", default: None,
+        set: |c, _| { c.fake = true; c.fake_autottl = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--autottl-any", short: "", value: "",
+        help: "Also learn hops from data and FIN packets of handled flows, not only SYN/ACK",
+        default: None,
+        set: |c, _| { c.autottl_any = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--fake-badsum", short: "", value: "",
+        help: "Modifies the TCP checksum of the fake packet to an invalid value", default: None,
+        set: |c, _| { c.fake = true; c.fake_badsum = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--fake-tcp-flags", short: "", value: "<fin,rst,psh,ack,...>",
+        help: "Override TCP flags of the fake packet", default: None,
+        set: |c, v| { c.fake = true; c.fake_tcp_flags = Some(TcpFlags::new(v.raw)?); Ok(()) },
+    },
+    OptSpec {
+        name: "--fake-split", short: "", value: "<u32>",
+        help: "Send each fake as two segments, split at this offset of the ClientHello", default: None,
+        set: |c, v| { c.fake = true; c.fake_split = Some(v.parse()?); Ok(()) },
+    },
+    OptSpec {
+        name: "--fake-rate", short: "", value: "<n/s>",
+        help: "ClientHellos per second with fakes to one destination; others only split (default: {default}, no limit)",
+        default: Some(|| DEFAULT_FAKE_RATE.to_string()),
+        set: |c, v| {
+            c.fake_rate = v.raw.strip_suffix("/s").unwrap_or(v.raw).parse()
+                .map_err(|_| anyhow!("--fake-rate: invalid value '{}' (e.g. 20/s)", v.raw))?;
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--segment-order", short: "-o", value: "<u32,u32,...>",
+        help: "Byte offsets defining segment boundaries and transmission order.\n\
+               Must include 0 (default: {default})",
+        default: Some(|| DEFAULT_SEGMENT_ORDER.to_string()),
+        set: |c, v| { c.segment_order = SegmentOrder::new(v.raw)?; Ok(()) },
+    },
+    OptSpec {
+        name: "--drop-forged-rst", short: "", value: "",
+        help: "Drop inbound RST/FIN whose TTL does not match the server's", default: None,
+        set: |c, _| { c.drop_forged_rst = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--forged-rst-delta", short: "", value: "<u8>",
+        help: "Hop difference to regard RST/FIN as forged (default: {default})",
+        default: Some(|| DEFAULT_FORGED_RST_DELTA.to_string()),
+        set: |c, v| { c.drop_forged_rst = true; c.forged_rst_delta = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--watch-pmtu", short: "", value: "",
+        help: "Fit segments to the path MTU reported by ICMP for handled flows", default: None,
+        set: |c, _| { c.watch_pmtu = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--dns-guard", short: "", value: "",
+        help: "Drop DNS responses that look forged by an injector", default: None,
+        set: |c, _| { c.dns_guard = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--doh-forward", short: "", value: "<url>",
+        help: "Forward DNS received on --doh-listen to this DoH resolver", default: None,
+        set: |c, v| {
+            if !v.raw.starts_with("https://") {
+                return Err(anyhow!("--doh-forward: expected https:// URL, got '{}'", v.raw));
+            }
+            c.doh_forward = Some(v.raw.to_string());
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--doh-listen", short: "", value: "<addr:port>",
+        help: "(default: {default})",
+        default: Some(|| DEFAULT_DOH_LISTEN.to_string()),
+        set: |c, v| { c.doh_listen = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--hostlist", short: "", value: "<path|url>",
+        help: "Handle only ClientHello to listed domains (repeatable)", default: None,
+        set: |c, v| { c.hostlist.0.push(v.raw.to_string()); Ok(()) },
+    },
+    OptSpec {
+        name: "--hostlist-refresh", short: "", value: "<seconds>",
+        help: "Revalidate remote hostlists (default: {default}, 0 to disable)",
+        default: Some(|| DEFAULT_HOSTLIST_REFRESH.to_string()),
+        set: |c, v| { c.hostlist_refresh = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--hostlist-sha256", short: "", value: "<hex|url>",
+        help: "Verify hostlists against this SHA-256 digest", default: None,
+        set: |c, v| { c.hostlist_sha256 = Some(v.raw.to_string()); Ok(()) },
+    },
+    OptSpec {
+        name: "--hostlist-regex", short: "", value: "",
+        help: "Match hostlist wildcard and /regex/ lines when the domains miss",
+        default: None,
+        set: |c, _| { c.hostlist_regex = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--sni-binding-ttl", short: "", value: "<seconds>",
+        help: "Classify ClientHellos without SNI by the last one to their address (default: {default}, 0 to disable)",
+        default: Some(|| DEFAULT_SNI_BINDING_TTL.to_string()),
+        set: |c, v| { c.sni_binding_ttl = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--max-buffer-mb", short: "", value: "<u64>",
+        help: "Memory budget for buffered packets and flow state (default: {default}, 0 for unlimited)",
+        default: Some(|| DEFAULT_MAX_BUFFER_MB.to_string()),
+        set: |c, v| { c.max_buffer_mb = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--adaptive-exempt", short: "", value: "",
+        help: "Stop desyncing servers that are found to work without it", default: None,
+        set: |c, _| { c.adaptive_exempt = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--pass-sslv2", short: "", value: "",
+        help: "Accept SSLv2-compatible ClientHellos unchanged instead of desyncing them",
+        default: None,
+        set: |c, _| { c.pass_sslv2 = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--min-payload", short: "", value: "<bytes>",
+        help: "Accept shorter handshake-looking payloads unchanged (default: {default})",
+        default: Some(|| DEFAULT_MIN_PAYLOAD.to_string()),
+        set: |c, v| { c.min_payload = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--trace", short: "", value: "<ip=ADDR,port=N,sni=GLOB>",
+        help: "Log every step of handling matching flows, with hex dumps (repeatable)",
+        default: None,
+        set: |c, v| { c.trace.0.push(TraceFilter::new(v.raw)?); Ok(()) },
+    },
+    OptSpec {
+        name: "--schedule", short: "", value: "<[DAYS] HH:MM-HH:MM>",
+        help: "Be active only within this local time window, e.g. \"Mon-Fri 18:00-09:00\" (repeatable)",
+        default: None,
+        set: |c, v| { c.schedule.0.push(Window::new(v.raw)?); Ok(()) },
+    },
+    OptSpec {
+        name: "--client-profile", short: "", value: "<CIDR=PROFILE,...>",
+        help: "Profile for ClientHellos of these clients, e.g. 192.168.2.0/24=off (repeatable)",
+        default: None,
+        set: |c, v| c.client_profile.extend(v.raw),
+    },
+    OptSpec {
+        name: "--list-client-profiles", short: "", value: "",
+        help: "Show profiles of --client-profile, then exit", default: None,
+        set: |_, _| { list_client_profiles(); platform::paexit(0) },
+    },
+    OptSpec {
+        name: "--strategy-rotate", short: "", value: "<PROFILE,...>",
+        help: "Desync each connection with one of these profiles, drawn at random",
+        default: None,
+        set: |c, v| { c.strategy_rotate = StrategyRotation::new(v.raw)?; Ok(()) },
+    },
+    OptSpec {
+        name: "--verdict-hook", short: "", value: "<command>",
+        help: "Ask this program how to handle each ClientHello: pass, default or a profile",
+        default: None,
+        set: |c, v| {
+            if v.raw.split_whitespace().next().is_none() {
+                return Err(anyhow!("--verdict-hook: empty command"));
+            }
+            c.verdict_hook = Some(v.raw.to_string());
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--no-private-exempt", short: "", value: "",
+        help: "Also handle connections to loopback, private and link-local addresses",
+        default: None,
+        set: |c, _| { c.private_exempt = false; Ok(()) },
+    },
+    OptSpec {
+        name: "--split-random", short: "", value: "<min:max>",
+        help: "Move the first segment boundary to a random offset in the range", default: None,
+        set: |c, v| { c.split_random = Some(SplitRange::new(v.raw)?); Ok(()) },
+    },
+    OptSpec {
+        name: "--split-ext", short: "", value: "<name|id>",
+        help: "Move the first segment boundary to the start of this TLS extension", default: None,
+        set: |c, v| { c.split_ext = Some(TlsExt::new(v.raw)?); Ok(()) },
+    },
+    OptSpec {
+        name: "--fingerprint", short: "", value: "<none|auto|linux|windows|macos>",
+        help: "Mimic TCP/IP fingerprint of OS on generated packets (default: {default})",
+        default: Some(|| DEFAULT_FINGERPRINT.to_string()),
+        set: |c, v| { c.fingerprint = FingerprintPreset::new(v.raw)?; Ok(()) },
+    },
+    OptSpec {
+        name: "--strip-tcp-opts", short: "", value: "<mss,sack-perm,ts,...>",
+        help: "Remove these TCP options from generated segments", default: None,
+        set: |c, v| { c.strip_tcp_opts = TcpOptKinds::new(v.raw)?; Ok(()) },
+    },
+    OptSpec {
+        name: "--dscp", short: "", value: "<0-63>",
+        help: "Set DSCP of generated packets instead of keeping the original one", default: None,
+        set: |c, v| {
+            let dscp: u8 = v.parse()?;
+            if dscp > 63 {
+                return Err(anyhow!("--dscp: {dscp} is not a 6-bit value (0-63)"));
+            }
+            c.dscp = Some(dscp);
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--clear-ecn", short: "", value: "",
+        help: "Send generated packets without ECN codepoint and ECE/CWR flags", default: None,
+        set: |c, _| { c.clear_ecn = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--df", short: "", value: "<set|clear|copy>",
+        help: "Don't Fragment bit of generated IPv4 packets (default: {default})",
+        default: Some(|| DEFAULT_DF.to_string()),
+        set: |c, v| { c.df = DfPolicy::new(v.raw)?; Ok(()) },
+    },
+    OptSpec {
+        name: "--v6-flowlabel", short: "", value: "<keep|random|zero>",
+        help: "Flow label of generated IPv6 packets (default: {default})",
+        default: Some(|| DEFAULT_V6_FLOWLABEL.to_string()),
+        set: |c, v| { c.v6_flowlabel = FlowLabel::new(v.raw)?; Ok(()) },
+    },
+    OptSpec {
+        name: "--v6-degrade", short: "", value: "",
+        help: "Drop IPv6 SYNs to --hostlist servers seen over IPv6, for clients to use IPv4",
+        default: None,
+        set: |c, _| { c.v6_degrade = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--server-window", short: "", value: "<u16>",
+        help: "TCP window of the ClientHello segments, so the server replies in small segments",
+        default: None,
+        set: |c, v| {
+            let window: u16 = v.parse()?;
+            if window == 0 {
+                return Err(anyhow!("--server-window: 0 would stall the connection"));
+            }
+            c.server_window = Some(window);
+            Ok(())
+        },
+    },
+    OptSpec {
+        name: "--proxy-ports", short: "", value: "<u16,u16,...>",
+        help: "Also handle ClientHello tunneled by HTTP CONNECT to these ports", default: None,
+        set: |c, v| { c.proxy_ports = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--once-per-host", short: "", value: "<seconds>",
+        help: "Handle only the first connection to a host within this window (default: {default}, disabled)",
+        default: Some(|| DEFAULT_ONCE_PER_HOST.to_string()),
+        set: |c, v| { c.once_per_host = v.parse()?; Ok(()) },
+    },
+    OptSpec {
+        name: "--coalesce", short: "", value: "",
+        help: "Split parallel connections to a host alike, as the first of them", default: None,
+        set: |c, _| { c.coalesce = true; Ok(()) },
+    },
+    OptSpec {
+        name: "--burst-limit", short: "", value: "<K>",
+        help: "Desync only the first K connections to a host per second (default: {default}, no limit)",
+        default: Some(|| DEFAULT_BURST_LIMIT.to_string()),
+        set: |c, v| { c.burst_limit = v.parse()?; Ok(()) },
+    },
+];
+
+/// Options, in groups separated by a blank line in usage
+const OPTIONS: &[&[OptSpec]] = &[GENERAL, DESYNC];
+
+/// Old names of options: old, new, and the version deprecating it
+// FIXME(on release): remove these on v1.0.0
+const DEPRECATED: &[(&str, &str, &str)] = &[
+    ("-D", "-d", "v0.6.0"),
+    ("--loglevel", "--log-level", "v0.1.1"),
+];
+
+fn find_option(name: &str) -> Option<&'static OptSpec> {
+    OPTIONS.iter()
+        .flat_map(|group| group.iter())
+        .find(|o| o.name == name || (!o.short.is_empty() && o.short == name))
+}
+
+/// `args` with the options of its `--config` files put first, so that
+/// those given on the command line take precedence.
+fn expand_config(args: Vec<String>) -> Result<Vec<String>> {
+    let mut expanded = Vec::new();
+    let mut rest = Vec::with_capacity(args.len());
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        if arg != "--config" {
+            rest.push(arg);
+            continue;
+        }
+
+        let path: String = take_value(&mut iter, "--config")?;
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("--config: cannot read {path}"))?;
+        expanded.extend(config_args(&text).with_context(|| format!("--config: {path}"))?);
+    }
+
+    expanded.extend(rest);
+    Ok(expanded)
+}
+
+/// Arguments for the lines of a config file: `name = value` for
+/// `--name value`, or `name` alone for a flag. Lines starting with `#`
+/// are comments.
+fn config_args(text: &str) -> Result<Vec<String>> {
+    let mut args = Vec::new();
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), Some(value.trim().trim_matches('"'))),
+            None => (line, None),
+        };
+        let name = format!("--{key}");
+        let spec = find_option(&name)
+            .ok_or_else(|| anyhow!("line {}: unknown option '{key}'", i + 1))?;
+
+        match (spec.value.is_empty(), value) {
+            (true, None) => args.push(name),
+            (false, Some(value)) => args.extend([name, value.to_string()]),
+            (true, Some(_)) => return Err(anyhow!("line {}: {key} takes no value", i + 1)),
+            (false, None) => return Err(anyhow!("line {}: {key} needs a value", i + 1)),
+        }
+    }
+
+    Ok(args)
+}
+
+impl Config {
+    pub fn from_args() -> Result<Self> {
+        Self::parse(std::env::args().skip(1)) // program name
+    }
+
+    /// Parse `args`, without the program name (or subcommand).
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self> {
+        let args = expand_config(args.collect())?;
+        let (args, preset) = expand_preset(args)?;
+        let mut args = args.into_iter();
+
+        let mut config = Config {
+            preset: preset.map_or("none", |p| p.name).to_string(),
+            ..Config::default()
+        };
+        let mut warned: Vec<&str> = Vec::new();
 
         while let Some(arg) = args.next() {
-            let argv = arg.as_str();
-
-            match argv {
-                "-h" | "--help" => { usage(); platform::paexit(0); }
-                "-d" | "-D" | "--daemon" => {
-                    if argv == "-D" && !warned_daemon_deprecated {
-                        // FIXME(on release): remove this on v1.0.0
-                        warned_daemon_deprecated = true;
-                        eprintln!("Note: `{arg}' has been deprecated since v0.6.0 and planned to be removed on v1.0.0. Use `-d' instead.");
+            let name = match DEPRECATED.iter().find(|(old, ..)| *old == arg) {
+                Some(&(old, new, since)) => {
+                    if !warned.contains(&old) {
+                        warned.push(old);
+                        eprintln!("Note: `{old}' has been deprecated since {since} and planned to be removed on v1.0.0. Use `{new}' instead.");
                     }
-                    no_splash = true;
-                    // if it is unchanged explicitly by argument, set it to info
-                    if log_level == DEFAULT_LOG_LEVEL {
-                        log_level = LogLevel::Info;
-                    }
-                    daemon = true;
-                }
-                "--delay-ms" => { delay_ms = take_value(&mut args, argv)?; }
-                "--log-level" | "--loglevel" => {
-                    if argv == "--loglevel" && !warned_loglevel_deprecated {
-                        // FIXME(on release): remove this on v1.0.0
-                        warned_loglevel_deprecated = true;
-                        eprintln!("Note: `{arg}' has been deprecated since v0.1.1 and planned to be removed on v1.0.0. Use `--log-level' instead.");
-                    }
-                    log_level = take_value(&mut args, argv)?;
+                    new
                 }
-                "--no-splash" => { no_splash = true; }
+                None => arg.as_str(),
+            };
 
-                "-o" | "--segment-order" => {
-                    let s: String = take_value(&mut args, argv)?;
-                    segment_order = SegmentOrder::new(&s)?;
-                }
-
-                "--fake" => { fake = true; }
-                "-t" | "--fake-ttl" => { fake = true; fake_ttl = take_value(&mut args, argv)?; }
-                "-a" | "--fake-autottl" => { fake = true; fake_autottl = true }
-                "--fake-badsum" => { fake = true; fake_badsum = true }
-
-                #[cfg(target_os = "linux")]
-                "--queue-num" => { queue_num = take_value(&mut args, argv)?; }
+            let spec = find_option(name).ok_or_else(|| anyhow!("unknown argument: {arg}"))?;
+            let raw = match spec.value {
+                "" => String::new(),
+                _ => args.next().ok_or_else(|| anyhow!("argument: missing value after {arg}"))?,
+            };
+            (spec.set)(&mut config, &Value { name: &arg, raw: &raw })?;
+        }
+        config.check_compat()?;
 
-                #[cfg(target_os = "linux")]
-                "--nft-command" => { nft_command = take_value(&mut args, argv)?; }
+        Ok(config)
+    }
 
-                _ => { return Err(anyhow!("unknown argument: {}", arg)); }
+    /// Reject the combinations of [`COMPAT`] that cannot work, and fix
+    /// up the others with a warning.
+    fn check_compat(&mut self) -> Result<()> {
+        for rule in COMPAT {
+            let Some(why) = (rule.conflict)(self) else { continue };
+            match rule.fix {
+                Some(fix) => {
+                    crate::warn!("{why}");
+                    fix(self);
+                }
+                None => return Err(anyhow!("{why}")),
             }
         }
 
-        Ok(Opt {
-            daemon,
-            log_level,
-            no_splash,
-            segment_order,
-            fake,
-            fake_ttl,
-            fake_autottl,
-            fake_badsum,
-            delay_ms,
-            #[cfg(target_os = "linux")] queue_num,
-            #[cfg(target_os = "linux")] nft_command,
-        })
+        Ok(())
     }
 
-    pub fn set_opt(self) -> Result<InitializedOpts> {
-        set_opt("OPT_DAEMON", &OPT_DAEMON, self.daemon)?;
-        set_opt("OPT_LOG_LEVEL", &OPT_LOG_LEVEL, self.log_level)?;
-        set_opt("OPT_NO_SPLASH", &OPT_NO_SPLASH, self.no_splash)?;
+    /// Put this config in effect, in place of the one before, if any.
+    pub fn install(self) -> InitializedOpts {
+        // Leaked rather than freed when replaced: the getters hand out
+        // 'static borrows of it. It is replaced only on startup.
+        let config: &'static Config = Box::leak(Box::new(self));
+        CONFIG.store(std::ptr::from_ref(config).cast_mut(), Ordering::Release);
 
-        set_opt("OPT_SEGMENT_ORDER", &OPT_SEGMENT_ORDER, self.segment_order)?;
+        InitializedOpts
+    }
+}
 
-        set_opt("OPT_DELAY_MS", &OPT_DELAY_MS, self.delay_ms)?;
-        set_opt("OPT_FAKE", &OPT_FAKE, self.fake)?;
-        set_opt("OPT_FAKE_TTL", &OPT_FAKE_TTL, self.fake_ttl)?;
-        set_opt("OPT_FAKE_AUTOTTL", &OPT_FAKE_AUTOTTL, self.fake_autottl)?;
-        set_opt("OPT_FAKE_BADSUM", &OPT_FAKE_BADSUM, self.fake_badsum)?;
+/// Installed by [`Config::install`]
+static CONFIG: AtomicPtr<Config> = AtomicPtr::new(std::ptr::null_mut());
 
-        #[cfg(target_os = "linux")] set_opt("OPT_QUEUE_NUM", &OPT_QUEUE_NUM, self.queue_num)?;
-        #[cfg(target_os = "linux")] set_opt("OPT_NFT_COMMAND", &OPT_NFT_COMMAND, self.nft_command)?;
+/// Config in effect; the defaults until one is installed
+pub fn config() -> &'static Config {
+    static DEFAULT: OnceLock<Config> = OnceLock::new();
 
-        Ok(InitializedOpts)
-    }
+    // SAFETY: only ever set to a leaked Box, never freed
+    let installed = unsafe { CONFIG.load(Ordering::Acquire).as_ref() };
+    installed.unwrap_or_else(|| DEFAULT.get_or_init(Config::default))
 }
 
 pub struct InitializedOpts;
@@ -239,63 +2312,537 @@ impl InitializedOpts {
         crate::info!("OPT_DAEMON: {}", daemon());
         crate::info!("OPT_NO_SPLASH: {}", no_splash());
         crate::info!("OPT_LOG_LEVEL: {}", log_level());
+        crate::info!("OPT_LOG_TARGET: {}", log_target().unwrap_or("none"));
+        crate::info!("OPT_CRASH_LOG_LINES: {}", crash_log_lines());
         crate::info!("OPT_DELAY_MS: {}", delay_ms());
+        crate::info!("OPT_DELAY_JITTER: {}", delay_jitter());
+        crate::info!("OPT_ZERO_WINDOW: {}", zero_window().map_or("none".into(), |ms| format!("{ms}ms")));
+        crate::info!("OPT_SEND_RAW: {}", if send_raw().is_empty() {
+            "none".to_string()
+        } else {
+            send_raw().iter().map(RawPacket::to_string).collect::<Vec<_>>().join(" | ")
+        });
+        crate::info!("OPT_DELAY_SCHEDULE: {}", if delay_schedule().is_empty() {
+            "none".to_string()
+        } else {
+            delay_schedule().iter().map(u64::to_string).collect::<Vec<_>>().join(",")
+        });
         crate::info!("OPT_FAKE: {}", fake());
         crate::info!("OPT_FAKE_TTL: {}", fake_ttl());
         crate::info!("OPT_FAKE_AUTOTTL: {}", fake_autottl());
+        crate::info!("OPT_AUTOTTL_ANY: {}", autottl_any());
         crate::info!("OPT_FAKE_BADSUM: {}", fake_badsum());
+        crate::info!("OPT_FAKE_RATE: {}", fake_rate());
+        crate::info!("OPT_FAKE_TCP_FLAGS: {}", fake_tcp_flags().map_or("original".into(), |f| f.to_string()));
+        crate::info!("OPT_FAKE_SPLIT: {}", fake_split().map_or("none".into(), |at| at.to_string()));
         #[cfg(target_os = "linux")]
-        crate::info!("OPT_QUEUE_NUM: {}", queue_num());
+        crate::info!("OPT_QUEUE_NUM: {}{}", queue_num(), if queue_num_pinned() { "" } else { " (auto)" });
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_QUEUE_MAXLEN: {}", queue_maxlen().map_or("auto".to_string(), |n| n.to_string()));
         #[cfg(target_os = "linux")]
         crate::info!("OPT_NFT_COMMAND: {}", nft_command());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_NFT_PRIORITY: {}", nft_priority());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_FAIL_CLOSED: {}", fail_closed());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_NOTRACK: {}", notrack());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_BULK_BYTES: {}", bulk_bytes());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_POLL_MODE: {}", poll_mode());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_RULE_CHECK_INTERVAL: {}", rule_check_interval());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_MARK: {}", mark());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_CONNMARK: {}", connmark());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_MANGLE_ONLY: {}", mangle_only());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_CONTAINER: {}", container());
+        #[cfg(target_os = "linux")]
+        crate::info!("OPT_CONTROL_SOCKET: {}", control_socket().unwrap_or("none"));
+        #[cfg(windows)]
+        crate::info!("OPT_SOCKET_LAYER: {}", socket_layer());
+        #[cfg(windows)]
+        crate::info!("OPT_IFACE: {}", iface());
+        #[cfg(windows)]
+        crate::info!("OPT_WINDIVERT_PRIORITY: {}", windivert_priority());
+        crate::info!("OPT_PRESET: {}", preset());
         crate::info!("OPT_SEGMENT_ORDER: {}", segment_order());
+        crate::info!("OPT_PROXY_PORTS: {}", proxy_ports());
+        crate::info!("OPT_ONCE_PER_HOST: {}", once_per_host());
+        crate::info!("OPT_COALESCE: {}", coalesce());
+        crate::info!("OPT_BURST_LIMIT: {}", burst_limit());
+        crate::info!("OPT_FINGERPRINT: {}", fingerprint());
+        crate::info!("OPT_STRIP_TCP_OPTS: {}", strip_tcp_opts());
+        crate::info!("OPT_DSCP: {}", dscp().map_or("original".into(), |d| d.to_string()));
+        crate::info!("OPT_CLEAR_ECN: {}", clear_ecn());
+        crate::info!("OPT_SERVER_WINDOW: {}", server_window().map_or("original".into(), |w| w.to_string()));
+        crate::info!("OPT_DF: {}", df());
+        crate::info!("OPT_V6_FLOWLABEL: {}", v6_flowlabel());
+        crate::info!("OPT_V6_DEGRADE: {}", v6_degrade());
+        crate::info!("OPT_DROP_FORGED_RST: {}", drop_forged_rst());
+        crate::info!("OPT_FORGED_RST_DELTA: {}", forged_rst_delta());
+        crate::info!("OPT_WATCH_PMTU: {}", watch_pmtu());
+        crate::info!("OPT_DNS_GUARD: {}", dns_guard());
+        crate::info!("OPT_DOH_FORWARD: {}", doh_forward().unwrap_or("none"));
+        crate::info!("OPT_DOH_LISTEN: {}", doh_listen());
+        crate::info!("OPT_HOSTLIST: {}", hostlist());
+        crate::info!("OPT_HOSTLIST_REFRESH: {}", hostlist_refresh());
+        crate::info!("OPT_HOSTLIST_SHA256: {}", hostlist_sha256().unwrap_or("none"));
+        crate::info!("OPT_HOSTLIST_REGEX: {}", hostlist_regex());
+        crate::info!("OPT_SNI_BINDING_TTL: {}", sni_binding_ttl());
+        crate::info!("OPT_MAX_BUFFER_MB: {}", max_buffer_mb());
+        crate::info!("OPT_PRIVATE_EXEMPT: {}", private_exempt());
+        crate::info!("OPT_ADAPTIVE_EXEMPT: {}", adaptive_exempt());
+        crate::info!("OPT_PASS_SSLV2: {}", pass_sslv2());
+        crate::info!("OPT_MIN_PAYLOAD: {}", min_payload());
+        crate::info!("OPT_WATCH: {}", watch());
+        crate::info!("OPT_MAX_RESTARTS: {}", max_restarts());
+        crate::info!("OPT_HEARTBEAT: {}", heartbeat());
+        crate::info!("OPT_STATS_SHM: {}", stats_shm().unwrap_or("none"));
+        crate::info!("OPT_STATUS_FILE: {}", status_file().unwrap_or("none"));
+        crate::info!("OPT_WEBHOOK: {}", webhook().unwrap_or("none"));
+        crate::info!("OPT_REPLAY: {}", replay().unwrap_or("none"));
+        crate::info!("OPT_TRACE: {}", trace());
+        crate::info!("OPT_SCHEDULE: {}", schedule());
+        crate::info!("OPT_CLIENT_PROFILE: {}", client_profile());
+        crate::info!("OPT_STRATEGY_ROTATE: {}", strategy_rotate());
+        crate::info!("OPT_VERDICT_HOOK: {}", verdict_hook().unwrap_or("none"));
+        crate::info!("OPT_SPLIT_RANDOM: {}", split_random().map_or("none".into(), |r| r.to_string()));
+        crate::info!("OPT_SPLIT_EXT: {}", split_ext().map_or("none".into(), |e| e.to_string()));
+
+        let segments = segment_order().segments().len();
+        let total_delay_ms = if delay_schedule().is_empty() {
+            delay_ms().saturating_add(delay_jitter()).saturating_mul(segments.saturating_sub(1) as u64)
+        } else {
+            delay_schedule().iter().take(segments)
+                .filter(|&&ms| ms > 0)
+                .map(|ms| ms.saturating_add(delay_jitter()))
+                .sum()
+        }.saturating_add(zero_window().unwrap_or(0));
+        if total_delay_ms >= RTO_MIN_MS {
+            crate::warn!("--delay-ms: ClientHello is delayed {total_delay_ms}ms in total, \
+                          which may exceed the client's retransmission timeout \
+                          ({RTO_MIN_MS}ms); consider a smaller value");
+        }
     }
 }
 
 pub fn daemon() -> bool {
-    *OPT_DAEMON.get().unwrap_or(&DEFAULT_DAEMON)
+    config().daemon
 }
 
 pub fn no_splash() -> bool {
-    *OPT_NO_SPLASH.get().unwrap_or(&DEFAULT_NO_SPLASH)
+    config().no_splash
 }
 
 pub fn segment_order() -> &'static SegmentOrder {
-    OPT_SEGMENT_ORDER.get().unwrap()
+    &config().segment_order
+}
+
+/// Ports of HTTP proxies; ClientHello after CONNECT on them is also
+/// handled.
+pub fn proxy_ports() -> &'static PortList {
+    &config().proxy_ports
+}
+
+/// Seconds to skip ClientHellos from the same source to the same server
+/// name after handling one; 0 to disable.
+pub fn once_per_host() -> u64 {
+    config().once_per_host
+}
+
+/// Share the split plan of a ClientHello with those of its burst
+pub fn coalesce() -> bool {
+    config().coalesce
+}
+
+/// ClientHellos desynced per host per second; 0 for no limit
+pub fn burst_limit() -> u32 {
+    config().burst_limit
+}
+
+pub fn split_random() -> Option<&'static SplitRange> {
+    config().split_random.as_ref()
+}
+
+/// Extension to move the first segment boundary to, if present
+pub fn split_ext() -> Option<TlsExt> {
+    config().split_ext
+}
+
+pub fn fingerprint() -> FingerprintPreset {
+    config().fingerprint
+}
+
+/// TCP options removed from generated segments
+pub fn strip_tcp_opts() -> &'static TcpOptKinds {
+    &config().strip_tcp_opts
+}
+
+/// DSCP of generated packets, or None to keep the original one
+pub fn dscp() -> Option<u8> {
+    config().dscp
+}
+
+/// Whether generated packets are sent Not-ECT, without ECE and CWR
+pub fn clear_ecn() -> bool {
+    config().clear_ecn
+}
+
+/// TCP window of the segments sent in place of the ClientHello, or None
+/// to keep the original one
+pub fn server_window() -> Option<u16> {
+    config().server_window
+}
+
+pub fn df() -> DfPolicy {
+    config().df
+}
+
+pub fn v6_flowlabel() -> FlowLabel {
+    config().v6_flowlabel
+}
+
+pub fn v6_degrade() -> bool {
+    config().v6_degrade
+}
+
+/// Name of `--preset`, or "none"
+pub fn preset() -> &'static str {
+    &config().preset
+}
+
+pub fn drop_forged_rst() -> bool {
+    config().drop_forged_rst
+}
+
+/// Hops an inbound RST/FIN may differ from the server before it is
+/// regarded as forged
+pub fn forged_rst_delta() -> u8 {
+    config().forged_rst_delta
+}
+
+/// Whether ICMP "fragmentation needed" quoting handled flows is sniffed
+/// to fit segments to the path MTU
+pub fn watch_pmtu() -> bool {
+    config().watch_pmtu
+}
+
+pub fn dns_guard() -> bool {
+    config().dns_guard
+}
+
+/// DoH resolver URL to forward local DNS queries to
+pub fn doh_forward() -> Option<&'static str> {
+    config().doh_forward.as_deref()
+}
+
+pub fn doh_listen() -> std::net::SocketAddr {
+    config().doh_listen
+}
+
+pub fn hostlist() -> &'static StringList {
+    &config().hostlist
+}
+
+/// Seconds between revalidating remote hostlists; 0 to disable
+pub fn hostlist_refresh() -> u64 {
+    config().hostlist_refresh
+}
+
+pub fn hostlist_sha256() -> Option<&'static str> {
+    config().hostlist_sha256.as_deref()
+}
+
+/// Whether wildcard and regex lines of hostlists are matched
+pub fn hostlist_regex() -> bool {
+    config().hostlist_regex
+}
+
+/// Seconds a server name stays bound to its address; 0 to disable
+pub fn sni_binding_ttl() -> u64 {
+    config().sni_binding_ttl
+}
+
+/// Whether hops to servers are learned from their SYN/ACK
+pub fn learn_hops() -> bool {
+    fake_autottl() || drop_forged_rst()
+}
+
+/// Whether hops are also learned from other packets of handled flows
+/// than SYN/ACK
+pub fn autottl_any() -> bool {
+    config().autottl_any
+}
+
+/// Whether inbound RST/FIN from servers are queued
+pub fn watch_teardown() -> bool {
+    drop_forged_rst() || adaptive_exempt()
 }
 
 pub fn log_level() -> LogLevel {
-    *OPT_LOG_LEVEL.get().unwrap_or(&DEFAULT_LOG_LEVEL)
+    config().log_level
+}
+
+/// `udp://host:port` to also send log lines to, as syslog
+pub fn log_target() -> Option<&'static str> {
+    config().log_target.as_deref()
+}
+
+/// Last log lines written to the crash log; 0 for no crash log
+pub fn crash_log_lines() -> u32 {
+    config().crash_log_lines
 }
 
 pub fn fake() -> bool {
-    *OPT_FAKE.get().unwrap_or(&DEFAULT_FAKE)
+    config().fake
 }
 
 pub fn fake_ttl() -> u8 {
-    *OPT_FAKE_TTL.get().unwrap_or(&DEFAULT_FAKE_TTL)
+    config().fake_ttl
 }
 
 pub fn fake_autottl() -> bool {
-    *OPT_FAKE_AUTOTTL.get().unwrap_or(&DEFAULT_FAKE_AUTOTTL)
+    config().fake_autottl
 }
 
 pub fn fake_badsum() -> bool {
-    *OPT_FAKE_BADSUM.get().unwrap_or(&DEFAULT_FAKE_BADSUM)
+    config().fake_badsum
+}
+
+/// Fakes per second to one destination; 0 for no limit
+pub fn fake_rate() -> u32 {
+    config().fake_rate
+}
+
+/// Flags of fake packets; None to keep the original ones
+pub fn fake_tcp_flags() -> Option<TcpFlags> {
+    config().fake_tcp_flags
+}
+
+/// Offset at which fakes are split in two; None to send them whole
+pub fn fake_split() -> Option<u32> {
+    config().fake_split
+}
+
+/// Memory budget for buffered packets and per-flow state, in bytes;
+/// 0 for unlimited
+pub fn max_buffer_bytes() -> usize {
+    (max_buffer_mb() as usize).saturating_mul(1024 * 1024)
+}
+
+pub fn max_buffer_mb() -> u64 {
+    config().max_buffer_mb
+}
+
+/// Whether TCP to loopback, private and link-local destinations is left
+/// alone; see [`crate::platform::PRIVATE_NETS`]
+pub fn private_exempt() -> bool {
+    config().private_exempt
+}
+
+pub fn adaptive_exempt() -> bool {
+    config().adaptive_exempt
+}
+
+pub fn pass_sslv2() -> bool {
+    config().pass_sslv2
+}
+
+/// TCP payloads shorter than this are accepted without parsing
+pub fn min_payload() -> u32 {
+    config().min_payload
+}
+
+pub fn watch() -> bool {
+    config().watch
+}
+
+/// Restarts in a row `--watch` allows before giving up; 0 for no limit
+pub fn max_restarts() -> u32 {
+    config().max_restarts
+}
+
+/// Minutes between heartbeat logs; 0 for none
+pub fn heartbeat() -> u64 {
+    config().heartbeat
+}
+
+/// File (Linux) or file mapping name (Windows) to publish counters in
+pub fn stats_shm() -> Option<&'static str> {
+    config().stats_shm.as_deref()
+}
+
+pub fn status_file() -> Option<&'static str> {
+    config().status_file.as_deref()
+}
+
+pub fn webhook() -> Option<&'static str> {
+    config().webhook.as_deref()
+}
+
+/// Capture to replay offline instead of handling the network
+pub fn replay() -> Option<&'static str> {
+    config().replay.as_deref()
+}
+
+/// Where `--replay` writes the packets it would send
+pub fn replay_out() -> String {
+    match &config().replay_out {
+        Some(path) => path.clone(),
+        None => format!("{}.out.pcap", replay().unwrap_or("replay")),
+    }
+}
+
+/// Real time waited per virtual time of `--replay`
+pub fn replay_scale() -> f64 {
+    config().replay_scale
+}
+
+pub fn trace() -> &'static TraceList {
+    &config().trace
+}
+
+pub fn schedule() -> &'static Schedule {
+    &config().schedule
+}
+
+pub fn client_profile() -> &'static ClientProfiles {
+    &config().client_profile
+}
+
+pub fn strategy_rotate() -> &'static StrategyRotation {
+    &config().strategy_rotate
+}
+
+/// Command line of `--verdict-hook`: program and arguments separated
+/// by whitespace
+pub fn verdict_hook() -> Option<&'static str> {
+    config().verdict_hook.as_deref()
 }
 
 pub fn delay_ms() -> u64 {
-    *OPT_DELAY_MS.get().unwrap_or(&DEFAULT_DELAY_MS)
+    config().delay_ms
+}
+
+pub fn delay_jitter() -> u64 {
+    config().delay_jitter
+}
+
+pub fn zero_window() -> Option<u64> {
+    config().zero_window
+}
+
+pub fn send_raw() -> &'static [RawPacket] {
+    &config().send_raw
+}
+
+/// `--delay-schedule`: milliseconds before each segment sent; empty if
+/// `--delay-ms` applies instead
+pub fn delay_schedule() -> &'static [u64] {
+    &config().delay_schedule
 }
 
 #[cfg(target_os = "linux")]
 pub fn queue_num() -> u16 {
-    *OPT_QUEUE_NUM.get().unwrap_or(&DEFAULT_QUEUE_NUM)
+    config().queue_num.unwrap_or(DEFAULT_QUEUE_NUM)
+}
+
+/// Whether `--queue-num` is given explicitly
+#[cfg(target_os = "linux")]
+pub fn queue_num_pinned() -> bool {
+    config().queue_num.is_some()
+}
+
+/// `--queue-maxlen`; None to size it by the workers
+#[cfg(target_os = "linux")]
+pub fn queue_maxlen() -> Option<u32> {
+    config().queue_maxlen
 }
 
 #[cfg(target_os = "linux")]
 pub fn nft_command() -> &'static str {
-    OPT_NFT_COMMAND.get().map(String::as_str).unwrap_or(DEFAULT_NFT_COMMAND)
+    &config().nft_command
+}
+
+#[cfg(target_os = "linux")]
+pub fn nft_priority() -> i32 {
+    config().nft_priority
+}
+
+#[cfg(target_os = "linux")]
+pub fn fail_closed() -> bool {
+    config().fail_closed
+}
+
+#[cfg(target_os = "linux")]
+pub fn notrack() -> bool {
+    config().notrack
+}
+
+/// Bytes (both ways) after which a connection is no longer queued when
+/// every packet to the port would be; 0 for never
+#[cfg(target_os = "linux")]
+pub fn bulk_bytes() -> u64 {
+    config().bulk_bytes
+}
+
+#[cfg(target_os = "linux")]
+pub fn poll_mode() -> PollMode {
+    config().poll_mode
+}
+
+#[cfg(target_os = "linux")]
+pub fn rule_check_interval() -> u64 {
+    config().rule_check_interval
+}
+
+#[cfg(target_os = "linux")]
+pub fn mark() -> Mark {
+    config().mark
+}
+
+#[cfg(target_os = "linux")]
+pub fn connmark() -> Mark {
+    config().connmark
+}
+
+#[cfg(target_os = "linux")]
+pub fn mangle_only() -> bool {
+    config().mangle_only
+}
+
+#[cfg(target_os = "linux")]
+pub fn container() -> bool {
+    config().container
+}
+
+/// Path of the control socket, or None if `--no-control-socket`
+#[cfg(target_os = "linux")]
+pub fn control_socket() -> Option<&'static str> {
+    let path = config().control_socket.as_str();
+    (!path.is_empty()).then_some(path)
+}
+
+#[cfg(windows)]
+pub fn socket_layer() -> bool {
+    config().socket_layer
+}
+
+#[cfg(windows)]
+pub fn iface() -> &'static StringList {
+    &config().iface
+}
+
+/// Priority of the WinDivert handles; of those matching a packet, the
+/// highest gets it first.
+#[cfg(windows)]
+pub fn windivert_priority() -> i16 {
+    config().windivert_priority
 }
 
 fn take_value<T, I>(args: &mut I, arg_name: &str) -> Result<T>
@@ -311,35 +2858,295 @@ where
         .with_context(|| format!("argument: {}: invalid value '{}'", arg_name, raw))
 }
 
+/// Column of the help text in usage
+const USAGE_COLUMN: usize = 42;
+
+/// Usage line of `spec`; the help goes on a line of its own if the
+/// option does not leave room for it.
+fn usage_line(spec: &OptSpec) -> String {
+    let mut left = String::from("  ");
+    if !spec.short.is_empty() {
+        left += spec.short;
+        left += ", ";
+    }
+    left += spec.name;
+    if !spec.value.is_empty() {
+        left += " ";
+        left += spec.value;
+    }
+
+    let indent = format!("\n{:USAGE_COLUMN$}", "");
+    let help = spec.help
+        .replace("{default}", &spec.default.map(|d| d()).unwrap_or_default())
+        .replace('\n', &indent);
+
+    if left.len() < USAGE_COLUMN {
+        format!("{left:<USAGE_COLUMN$}{help}")
+    } else {
+        format!("{left}{indent}{help}")
+    }
+}
+
 fn usage() {
     println!("Usage: dpibreak [OPTIONS]");
-    println!();
-    println!("Options:");
-    println!("  -h, --help                              Show this help");
-    println!("  -d, --daemon                            Run as daemon. kill `pidof dpibreak` to stop");
-    println!("  --delay-ms    <u64>                     Delay milliseconds between each segment packets (default: {DEFAULT_DELAY_MS})");
+    println!("       dpibreak stats --by-strategy");
+    println!("       dpibreak state export|import FILE");
+    println!("       dpibreak decode FILE [OPTIONS]");
+    println!("       dpibreak self-update [--check-update] [--keyring PATH]");
     #[cfg(target_os = "linux")]
-    println!("  --queue-num   <u16>                     Netfilter queue number to bind (default: {DEFAULT_QUEUE_NUM})");
+    println!("       dpibreak status [--control-socket PATH]");
     #[cfg(target_os = "linux")]
-    println!("  --nft-command <string>                    (default: {DEFAULT_NFT_COMMAND})");
-    println!("  --log-level <debug|info|warning|error>    (default: {DEFAULT_LOG_LEVEL})");
-    println!("  --no-splash                             Do not print splash messages on startup");
-    println!();
-    println!("  --fake                                  Enable fake clienthello injection");
-    println!("  -t, --fake-ttl    <u8>                  Override ttl of fake clienthello (default: {DEFAULT_FAKE_TTL})");
-    println!("  -a, --fake-autottl                      Infer ttl of fake clienthello automatically and override it");
-    println!("  --fake-badsum                           Modifies the TCP checksum of the fake packet to an invalid value");
-    println!("  -o, --segment-order <u32,u32,...>       Byte offsets defining segment boundaries and transmission order.");
-    println!("                                          Must include 0 (default: {DEFAULT_SEGMENT_ORDER})");
+    println!("       dpibreak capabilities [--control-socket PATH]");
+    #[cfg(target_os = "linux")]
+    println!("       dpibreak top [--control-socket PATH]");
     println!();
+    println!("Options:");
+    for group in OPTIONS {
+        for spec in *group {
+            println!("{}", usage_line(spec));
+        }
+        println!();
+    }
     println!("See dpibreak(1) for more information.");
 }
 
-fn set_opt<T: std::fmt::Display>(
-    name: &str,
-    cell: &OnceLock<T>,
-    value: T,
-) -> Result<()> {
-    cell.set(value).map_err(|_| anyhow!("{name} already initialized"))?;
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config> {
+        Config::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_compat() {
+        assert!(parse(&[]).is_ok());
+        assert!(parse(&["--segment-order", "0"]).is_err());
+        assert!(parse(&["--segment-order", "0", "--fake"]).is_ok());
+        assert!(parse(&["--split-random", "2:4", "--segment-order", "0,1,3"]).is_err());
+        assert!(parse(&["--split-ext", "sni"]).is_err());
+        assert!(parse(&["--split-ext", "key_share", "--split-random", "2:4"]).is_err());
+        assert!(parse(&["--split-ext", "key_share", "--segment-order", "0", "--fake"]).is_err());
+        assert_eq!(parse(&["--split-ext", "Key_Share"]).unwrap().split_ext, Some(TlsExt(51)));
+        assert_eq!(parse(&["--split-ext", "65037"]).unwrap().split_ext.unwrap().to_string(),
+                   "encrypted_client_hello");
+        assert_eq!(TlsExt(1234).to_string(), "1234");
+
+        let order = SegmentOrder::new("0,1,40").unwrap();
+        assert!(order.can_move_first_boundary(39));
+        assert!(!order.can_move_first_boundary(40) && !order.can_move_first_boundary(0));
+        assert!(!SegmentOrder::new("0").unwrap().can_move_first_boundary(5));
+        assert!(parse(&["--hostlist-sha256", "00"]).is_err());
+        assert!(parse(&["--hostlist-sha256", "00", "--hostlist", "a", "--hostlist", "b"]).is_err());
+        assert!(parse(&["--hostlist-sha256", "00", "--hostlist", "a"]).is_ok());
+
+        let o = parse(&["--segment-order", "0", "--fake", "--delay-jitter", "5"]).unwrap();
+        assert_eq!(o.delay_jitter, 0);
+
+        #[cfg(target_os = "linux")]
+        {
+            let o = parse(&["--mangle-only", "--fake", "--segment-order", "1,0"]).unwrap();
+            assert!(!o.fake);
+            assert_eq!(o.segment_order.raw(), "0,1");
+            assert_eq!(o.segment_order.segments(), [Segment(0, 1), Segment(1, u32::MAX)]);
+
+            assert!(parse(&["--mangle-only", "--fake", "--segment-order", "0"]).is_err());
+            assert_eq!(parse(&["--mangle-only", "--zero-window", "50"]).unwrap().zero_window, None);
+            assert!(parse(&["--container", "--daemon"]).is_err());
+
+            assert!(parse(&["--notrack"]).unwrap().notrack);
+
+            assert_eq!(parse(&["--queue-maxlen", "8192"]).unwrap().queue_maxlen, Some(8192));
+            assert!(parse(&["--queue-maxlen", "0"]).is_err());
+            assert!(!parse(&["--notrack", "--client-profile", "192.168.1.0/24=light"]).unwrap().notrack);
+
+            let o = parse(&[]).unwrap();
+            assert_eq!((o.connmark.value, o.connmark.mask), (0x4000_0000, 0x4000_0000));
+            let o = parse(&["--connmark", "0x100/0x300"]).unwrap();
+            assert_eq!((o.connmark.value, o.connmark.mask), (0x100, 0x300));
+            assert!(parse(&["--connmark", "0x100/0x200"]).is_err());
+            assert_eq!(parse(&["--mark", "0x100"]).unwrap().mark.mask, u32::MAX);
+        }
+    }
+
+    #[test]
+    fn test_options() {
+        let specs: Vec<&OptSpec> = OPTIONS.iter().flat_map(|g| g.iter()).collect();
+        for (i, spec) in specs.iter().enumerate() {
+            assert!(spec.name.starts_with("--"), "{}", spec.name);
+            assert!(specs[..i].iter().all(|o| o.name != spec.name), "{} twice", spec.name);
+            assert_eq!(spec.help.contains("{default}"), spec.default.is_some(), "{}", spec.name);
+        }
+
+        let o = parse(&["-t", "3", "--loglevel", "error", "-o", "2,0"]).unwrap();
+        assert!(o.fake && o.fake_ttl == 3 && o.log_level == LogLevel::Error);
+        assert_eq!(o.segment_order.raw(), "2,0");
+        assert!(parse(&["--fake-ttl"]).is_err());
+        assert!(parse(&["--fake-ttl", "x"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+
+        assert_eq!(parse(&[]).unwrap().crash_log_lines, 256);
+        assert_eq!(parse(&["--crash-log-lines", "0"]).unwrap().crash_log_lines, 0);
+
+        let o = parse(&["--fake-split", "3"]).unwrap();
+        assert!(o.fake && o.fake_split == Some(3));
+        assert!(parse(&["--fake-split", "-1"]).is_err());
+
+        assert_eq!(parse(&["--delay-schedule", "0,50,5"]).unwrap().delay_schedule, [0, 50, 5]);
+        assert!(parse(&["--delay-schedule", "0,x"]).is_err());
+
+        assert!(usage_line(find_option("-o").unwrap())
+                .ends_with("order.\n                                          Must include 0 (default: 0,1)"));
+    }
+
+    #[test]
+    fn test_config_file() {
+        let text = "# comment\n\
+                    fake-autottl\n\
+                    segment-order = 2,0\n\
+                    trace = \"sni=*.example.com\"\n";
+        assert_eq!(config_args(text).unwrap(),
+                   ["--fake-autottl", "--segment-order", "2,0", "--trace", "sni=*.example.com"]);
+
+        for bad in ["frobnicate = 1", "fake = yes", "segment-order"] {
+            assert!(config_args(bad).is_err(), "{bad}");
+        }
+
+        let path = std::env::temp_dir().join(format!("dpibreak-test-{}.conf", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // Given options override the file, wherever they are
+        std::fs::write(path, "fake-ttl = 5\nsegment-order = 2,0\n").unwrap();
+        let o = parse(&["--segment-order", "0,3", "--config", path]).unwrap();
+        assert!(o.fake && o.fake_ttl == 5);
+        assert_eq!(o.segment_order.raw(), "0,3");
+
+        std::fs::write(path, "config = other.conf\n").unwrap();
+        assert!(matches!(parse(&["--config", path]), Err(e) if e.to_string().contains("not allowed")));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_header_opts() {
+        assert_eq!(parse(&["--dscp", "46"]).unwrap().dscp, Some(46));
+        assert!(parse(&["--dscp", "64"]).is_err());
+
+        assert!(parse(&["--df", "CLEAR"]).unwrap().df == DfPolicy::Clear);
+        assert!(parse(&[]).unwrap().df == DfPolicy::Copy);
+        assert!(parse(&["--df", "maybe"]).is_err());
+
+        assert!(parse(&[]).unwrap().v6_flowlabel == FlowLabel::Keep);
+        assert!(parse(&["--v6-flowlabel", "Random"]).unwrap().v6_flowlabel == FlowLabel::Random);
+        assert!(parse(&["--v6-flowlabel", "ecmp"]).is_err());
+        assert!(parse(&["--v6-degrade"]).is_err());
+        assert!(parse(&["--v6-degrade", "--hostlist", "list.txt"]).unwrap().v6_degrade);
+
+        let o = parse(&["--send-raw", "4500 0028 0000 4000 4006 0000 {saddr}{daddr} {sport}{dport} \
+                                          {seq-1}{ack} 5010 0000 0000 0000@between"]).unwrap();
+        let p = &o.send_raw[0];
+        assert!(!p.ipv6);
+        assert_eq!(p.when, RawWhen::Between);
+        assert_eq!(p.expanded_len(), 40);
+        assert_eq!(p.pieces[0], RawPiece::Bytes(vec![0x45, 0, 0, 0x28, 0, 0, 0x40, 0, 0x40, 6, 0, 0]));
+        assert_eq!(p.pieces[5], RawPiece::Field(RawField::Seq, -1));
+        assert_eq!(parse(&["--send-raw", &format!("60{}{{seq+8}}@after", "00".repeat(39))]).unwrap()
+                   .send_raw[0].expanded_len(), 44);
+        for bad in ["4500@later", "4500", "450@before", "45{seq@before", "45{ttl}@before",
+                    "45{sport+1}@before", "{saddr}@before", "5500@before", &format!("45{}@before", "00".repeat(18))] {
+            assert!(parse(&["--send-raw", bad]).is_err(), "{bad}");
+        }
+
+        assert_eq!(parse(&["--server-window", "2"]).unwrap().server_window, Some(2));
+        assert!(parse(&["--server-window", "0"]).is_err());
+    }
+
+    #[test]
+    fn test_schedule() {
+        const MON: u8 = 0;
+        const FRI: u8 = 4;
+        const SAT: u8 = 5;
+        const SUN: u8 = 6;
+        let at = |h: u16, m: u16| h * 60 + m;
+
+        let w = Window::new("Mon-Fri 09:00-18:00").unwrap();
+        assert!(w.contains(MON, at(9, 0)) && w.contains(FRI, at(17, 59)));
+        assert!(!w.contains(MON, at(18, 0)) && !w.contains(SAT, at(12, 0)));
+
+        // Past midnight, into Saturday; days wrap around the week
+        let w = Window::new("fri,sun-mon 22:30-06:00").unwrap();
+        assert!(w.contains(FRI, at(23, 0)) && w.contains(SAT, at(5, 59)));
+        assert!(!w.contains(SAT, at(23, 0)) && w.contains(SUN, at(23, 0)));
+        assert!(w.contains(MON, at(1, 0)) && !w.contains(FRI, at(1, 0)));
+
+        let w = Window::new("00:00-24:00").unwrap();
+        assert!(w.contains(SUN, 0) && w.contains(MON, at(23, 59)));
+
+        for bad in ["Mon-Fri", "Xyz 09:00-10:00", "09:00-09:00", "09:60-10:00", "00:00-24:01",
+                    "00:00-1093:00", "65535:00-10:00"] {
+            assert!(Window::new(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_client_profile() {
+        let ip = |s: &str| s.parse::<std::net::IpAddr>().unwrap();
+
+        let mut p = ClientProfiles::default();
+        p.extend("192.168.0.0/16=light, 192.168.2.7/24=off").unwrap();
+        p.extend("192.168.2.9=aggressive,fd00::/8=off").unwrap();
+        assert_eq!(p.to_string(), "192.168.0.0/16=light,192.168.2.0/24=off,\
+                                   192.168.2.9/32=aggressive,fd00::/8=off");
+
+        // Longest prefix wins
+        assert_eq!(p.resolve(ip("192.168.1.1")).unwrap().profile.name, "light");
+        assert_eq!(p.resolve(ip("192.168.2.1")).unwrap().profile.name, "off");
+        assert_eq!(p.resolve(ip("192.168.2.9")).unwrap().profile.name, "aggressive");
+        assert_eq!(p.resolve(ip("fd12::1")).unwrap().profile.name, "off");
+        assert!(p.resolve(ip("10.0.0.1")).is_none());
+        assert!(p.resolve(ip("::ffff:192.168.1.1")).is_none());
+
+        let r = p.resolve(ip("192.168.2.9")).unwrap();
+        assert_eq!(r.order.as_ref().unwrap().raw(), "2,0,1");
+        assert_eq!(r.profile.fake, Some(true));
+
+        for bad in ["192.168.1.0/24", "192.168.1.0/33=off", "host=off", "10.0.0.0/8=fast"] {
+            assert!(ClientProfiles::default().extend(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_strategy_rotate() {
+        let o = parse(&["--strategy-rotate", "light,Aggressive,default"]).unwrap();
+        assert_eq!(o.strategy_rotate.to_string(), "light,aggressive,default");
+        let mut seen = [false; 3];
+        for _ in 0..200 {
+            let p = o.strategy_rotate.pick().unwrap();
+            seen[o.strategy_rotate.0.iter().position(|q| q.profile.name == p.profile.name).unwrap()] = true;
+        }
+        assert_eq!(seen, [true; 3]);
+        assert_eq!(o.strategy_rotate.0[1].order.as_ref().unwrap().raw(), "2,0,1");
+        assert!(parse(&[]).unwrap().strategy_rotate.pick().is_none());
+
+        for bad in ["light", "light,light", "light,off", "light,fast"] {
+            assert!(parse(&["--strategy-rotate", bad]).is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_preset() {
+        let o = parse(&["--preset", "cn"]).unwrap();
+        assert!(o.fake && o.fake_badsum && o.drop_forged_rst);
+        assert_eq!(o.segment_order.raw(), "2,0");
+        assert_eq!(o.preset, "cn");
+
+        // Given options override the preset, wherever they are
+        let o = parse(&["--segment-order", "0,3", "--preset", "ru"]).unwrap();
+        assert_eq!(o.segment_order.raw(), "0,3");
+
+        assert!(parse(&["--preset", "xx"]).is_err());
+        assert!(parse(&["--preset", "ru", "--preset", "ir"]).is_err());
+        for p in PRESETS {
+            assert!(parse(&["--preset", p.name]).is_ok(), "{}", p.name);
+        }
+    }
 }
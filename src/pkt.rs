@@ -21,10 +21,41 @@ use anyhow::anyhow;
 
 use crate::opt;
 use crate::platform;
+use crate::stats;
 use crate::tls;
 
+mod adaptive;
+pub mod budget;
+mod burst;
+pub mod decode;
+mod dnsguard;
 mod fake;
+mod fingerprint;
+mod flowtab;
 mod hoptab;
+pub mod hook;
+pub mod outcome;
+pub mod pmtu;
+mod rawsend;
+pub mod replay;
+mod snitab;
+pub mod state;
+mod trace;
+mod v6degrade;
+#[cfg(windows)]
+pub mod socktab;
+pub mod worker;
+
+use flowtab::{FlowKey, HandledHello};
+
+/// A retransmission of the ClientHello received within this time after
+/// we finished handling it has been queued while we were still sending
+/// the segments (e.g. client RTO fired during `--delay-ms`).
+const MID_HANDLING_SLACK: std::time::Duration = std::time::Duration::from_millis(50);
+
+/// Retransmissions of a handled ClientHello within this time are
+/// passed unchanged instead of being split again.
+const RETRANS_WINDOW: std::time::Duration = std::time::Duration::from_secs(5);
 
 struct PktView<'a> {
     ip: IpSlice<'a>,
@@ -50,6 +81,15 @@ impl<'a> PktView<'a> {
         }
     }
 
+    /// IPv4 identification; None on IPv6
+    #[inline]
+    fn ip_id(&self) -> Option<u16> {
+        match &self.ip {
+            IpSlice::Ipv4(v4) => Some(v4.header().identification()),
+            IpSlice::Ipv6(_) => None,
+        }
+    }
+
     #[inline]
     fn saddr(&self) -> std::net::IpAddr {
         self.ip.source_addr()
@@ -59,26 +99,127 @@ impl<'a> PktView<'a> {
     fn daddr(&self) -> std::net::IpAddr {
         self.ip.destination_addr()
     }
+
+    #[inline]
+    fn flow_key(&self) -> FlowKey {
+        FlowKey {
+            saddr: self.saddr(),
+            daddr: self.daddr(),
+            sport: self.tcp.source_port(),
+            dport: self.tcp.destination_port(),
+        }
+    }
+}
+
+/// Length of TCP payload of raw IP packet `pkt` read directly from the
+/// headers, without parsing/validating the whole packet. Return None
+/// if it cannot be determined cheaply (e.g. IPv6 extension headers).
+fn tcp_payload_len_fast(pkt: &[u8]) -> Option<usize> {
+    const IPPROTO_TCP: u8 = 6;
+
+    let (l3_len, total_len) = match pkt.first()? >> 4 {
+        4 => {
+            if *pkt.get(9)? != IPPROTO_TCP {
+                return None;
+            }
+            ((*pkt.first()? & 0x0f) as usize * 4,
+             u16::from_be_bytes([*pkt.get(2)?, *pkt.get(3)?]) as usize)
+        }
+        6 => {
+            if *pkt.get(6)? != IPPROTO_TCP {
+                return None;
+            }
+            (40, 40 + u16::from_be_bytes([*pkt.get(4)?, *pkt.get(5)?]) as usize)
+        }
+        _ => return None,
+    };
+
+    let l4_len = (*pkt.get(l3_len + 12)? >> 4) as usize * 4;
+
+    total_len.checked_sub(l3_len + l4_len)
+}
+
+/// Sequence number `n` bytes after `seq`. Sequence space wraps at 2^32
+/// (RFC 9293 3.4); a long-lived flow may well be near it.
+#[inline]
+fn seq_add(seq: u32, n: u32) -> u32 {
+    seq.wrapping_add(n)
+}
+
+/// Fields of the original packet to override on [`build_packet`]; None
+/// keeps the original one.
+#[derive(Default)]
+struct Override<'a> {
+    payload: Option<&'a [u8]>,
+    ttl: Option<u8>,
+    tcp_checksum: Option<u16>,
+
+    /// IPv4 identification; ignored on IPv6
+    ip_id: Option<u16>,
+
+    /// IPv4 don't fragment; ignored on IPv6
+    dont_fragment: Option<bool>,
+    window: Option<u16>,
+    tcp_options: Option<&'a [u8]>,
+    tcp_flags: Option<opt::TcpFlags>,
+}
+
+/// IPv6 traffic class `tc` with its DSCP replaced by `dscp`, keeping ECN
+fn with_dscp(tc: u8, dscp: u8) -> u8 {
+    (dscp << 2) | (tc & 0b11)
+}
+
+/// Flow label for `--v6-flowlabel random`: random, but the same for all
+/// packets sent in place of one original, so that they still take one
+/// path
+fn random_flow_label(view: &PktView) -> u32 {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    static KEY: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+
+    let mut h = DefaultHasher::new();
+    KEY.get_or_init(crate::rand::u64).hash(&mut h);
+    view.flow_key().hash(&mut h);
+    view.tcp.sequence_number().hash(&mut h);
+    h.finish() as u32 & etherparse::Ipv6FlowLabel::MAX_U32
 }
 
 /// Write TCP/IP packet (payload = view.tcp.payload[start..Some(end)])
 /// to out_buf, explicitly clearing before.
 ///
-/// If payload, ttl or tcp_checksum is given, override view's one.
+/// Extension headers of IPv6 packet `ip`, copied to the packets built
+/// from it; an error if they cannot be, e.g. with a hop-by-hop header
+/// that is not first.
+fn ipv6_extensions(ip: &etherparse::Ipv6Slice) -> Result<etherparse::Ipv6Extensions> {
+    let exts = ip.extensions();
+    let Some(first) = exts.first_header() else {
+        return Ok(Default::default());
+    };
+
+    let (exts, next, _) = etherparse::Ipv6Extensions::from_slice(first, exts.slice())
+        .map_err(|e| anyhow!("IPv6 extension headers: {e}"))?;
+    if next != etherparse::IpNumber::TCP {
+        return Err(anyhow!("IPv6 extension headers: cannot copy the chain"));
+    }
+    Ok(exts)
+}
+
+/// Fields given in `ovr` override view's one. DSCP and ECN (codepoint
+/// and ECE/CWR) are those of view unless `--dscp` or `--clear-ecn`; DF
+/// is that of ovr or view unless `--df set|clear`; the IPv6 flow label
+/// that of view unless `--v6-flowlabel random|zero`.
 fn build_packet(
     view: &PktView,
     start: u32,
     end: Option<u32>,
     out_buf: &mut Vec<u8>,
-    payload: Option<&[u8]>,
-    ttl: Option<u8>,
-    tcp_checksum: Option<u16>
+    ovr: &Override
 ) -> Result<()> {
     use etherparse::*;
 
     let ip = &view.ip;
     let tcp = &view.tcp;
-    let payload = payload.unwrap_or(tcp.payload());
+    let payload = ovr.payload.unwrap_or(tcp.payload());
 
     let end = end.unwrap_or(payload.len().try_into()?);
 
@@ -86,14 +227,46 @@ fn build_packet(
         return Err(anyhow!("invalid index"));
     }
 
-    let opts = tcp.options();
+    let mut opts = ovr.tcp_options.unwrap_or(tcp.options());
+    let stripped;
+    if !opt::strip_tcp_opts().is_empty() && !tcp.syn()
+        && let Some(s) = strip_tcp_options(opts, opt::strip_tcp_opts()) {
+        stripped = s;
+        opts = &stripped;
+    }
+
     let mut tcp_hdr = tcp.to_header();
-    tcp_hdr.sequence_number += start;
+    tcp_hdr.sequence_number = seq_add(tcp_hdr.sequence_number, start);
+    if let Some(w) = ovr.window { tcp_hdr.window_size = w; }
+    if let Some(flags) = ovr.tcp_flags {
+        use opt::TcpFlags as F;
+
+        tcp_hdr.fin = flags.has(F::FIN);
+        tcp_hdr.syn = flags.has(F::SYN);
+        tcp_hdr.rst = flags.has(F::RST);
+        tcp_hdr.psh = flags.has(F::PSH);
+        tcp_hdr.ack = flags.has(F::ACK);
+        tcp_hdr.urg = flags.has(F::URG);
+        tcp_hdr.ece = flags.has(F::ECE);
+        tcp_hdr.cwr = flags.has(F::CWR);
+    }
+    if opt::clear_ecn() {
+        tcp_hdr.ece = false;
+        tcp_hdr.cwr = false;
+    }
 
     let (builder, l3_len) = match ip {
         IpSlice::Ipv4(hdr) => {
             let mut ip_hdr = hdr.header().to_header();
-            if let Some(t) = ttl { ip_hdr.time_to_live = t; };
+            if let Some(t) = ovr.ttl { ip_hdr.time_to_live = t; };
+            if let Some(id) = ovr.ip_id { ip_hdr.identification = id; };
+            match opt::df() {
+                opt::DfPolicy::Set => ip_hdr.dont_fragment = true,
+                opt::DfPolicy::Clear => ip_hdr.dont_fragment = false,
+                opt::DfPolicy::Copy => if let Some(df) = ovr.dont_fragment { ip_hdr.dont_fragment = df; },
+            };
+            if let Some(d) = opt::dscp() { ip_hdr.dscp = Ipv4Dscp::try_new(d)?; };
+            if opt::clear_ecn() { ip_hdr.ecn = IpEcn::NOT_ECT; };
 
             let exts = hdr.extensions().to_header();
             let l3_len = ip_hdr.header_len() + exts.header_len();
@@ -106,13 +279,21 @@ fn build_packet(
 
         IpSlice::Ipv6(hdr) => {
             let mut ip6_hdr = hdr.header().to_header();
-            if let Some(t) = ttl { ip6_hdr.hop_limit = t; };
+            if let Some(t) = ovr.ttl { ip6_hdr.hop_limit = t; };
+            if let Some(d) = opt::dscp() { ip6_hdr.traffic_class = with_dscp(ip6_hdr.traffic_class, d); };
+            if opt::clear_ecn() { ip6_hdr.traffic_class &= !0b11; };
+            match opt::v6_flowlabel() {
+                opt::FlowLabel::Keep => {}
+                opt::FlowLabel::Random => ip6_hdr.flow_label = Ipv6FlowLabel::try_new(random_flow_label(view))?,
+                opt::FlowLabel::Zero => ip6_hdr.flow_label = Ipv6FlowLabel::ZERO,
+            };
 
-            let l3_len = Ipv6Header::LEN;
+            let exts = ipv6_extensions(hdr)?;
+            let l3_len = Ipv6Header::LEN + exts.header_len();
 
             (PacketBuilder::ip(IpHeaders::Ipv6(
                 ip6_hdr,
-                Default::default()
+                exts
             )), l3_len)
         }
     };
@@ -124,7 +305,7 @@ fn build_packet(
     out_buf.clear();
     builder.write(out_buf, payload)?;
 
-    if let Some(cs) = tcp_checksum {
+    if let Some(cs) = ovr.tcp_checksum {
         let tcp_csum_off = l3_len + 16;
 
         if out_buf.len() < tcp_csum_off + 2 {
@@ -136,37 +317,353 @@ fn build_packet(
     Ok(())
 }
 
+/// Header fields of the `nth` packet we send in place of the original
+/// one, following [`fingerprint`] if any.
+fn fingerprint_override<'a>(view: &PktView, nth: u16) -> Override<'a> {
+    let Some(fp) = fingerprint::get() else {
+        return Override::default();
+    };
+
+    Override {
+        ttl: Some(if view.ip_id().is_some() { fp.ttl } else { fp.hop_limit }),
+        ip_id: view.ip_id().map(|id| id.wrapping_add(nth)),
+        dont_fragment: Some(fp.df),
+        ..Default::default()
+    }
+}
+
+/// Flags of `tcp`
+fn tcp_flags(tcp: &TcpSlice) -> opt::TcpFlags {
+    use opt::TcpFlags as F;
+
+    let bits = [(tcp.fin(), F::FIN), (tcp.syn(), F::SYN), (tcp.rst(), F::RST), (tcp.psh(), F::PSH),
+                (tcp.ack(), F::ACK), (tcp.urg(), F::URG), (tcp.ece(), F::ECE), (tcp.cwr(), F::CWR)];
+    F(bits.iter().filter(|b| b.0).fold(0, |acc, b| acc | b.1))
+}
+
+/// Flags of the segment of a `len` byte payload sent with `flags`
+/// ending at `end`: PSH and FIN belong to the end of the data, so that
+/// the receiver neither delivers a partial ClientHello early nor takes
+/// the FIN before the bytes preceding it.
+fn segment_flags(flags: opt::TcpFlags, end: Option<u32>, len: u32) -> opt::TcpFlags {
+    use opt::TcpFlags as F;
+
+    if end.is_none_or(|e| e >= len) {
+        F(flags.0 | F::PSH)
+    } else {
+        F(flags.0 & !(F::PSH | F::FIN))
+    }
+}
+
+/// `order` with its boundaries beyond the ClientHello record of
+/// `payload` moved to its end, so that data following the record in the
+/// same segment (TLS 1.3 early data) is sent untouched in the last one.
+fn within_hello_record(order: &[opt::Segment], payload: &[u8]) -> Vec<opt::Segment> {
+    match tls::hello_record_end(payload) {
+        Some(record_end) => within_record(order, record_end as u32),
+        None => order.to_vec(),
+    }
+}
+
+/// `order` with its boundaries past `record_end` moved to it, and the
+/// segments left empty removed. A segment running to the end keeps
+/// doing so, to carry what follows the record.
+fn within_record(order: &[opt::Segment], record_end: u32) -> Vec<opt::Segment> {
+    order.iter()
+        .map(|&opt::Segment(start, end)| {
+            opt::Segment(start.min(record_end), if end == u32::MAX { end } else { end.min(record_end) })
+        })
+        .filter(|s| s.0 < s.1)
+        .collect()
+}
+
+/// `order` with its first boundary moved to where the `--split-ext`
+/// extension starts in ClientHello `payload`, if it is there and before
+/// the next boundary.
+fn split_at_extension(order: &opt::SegmentOrder, payload: &[u8]) -> Vec<opt::Segment> {
+    let at = opt::split_ext()
+        .and_then(|ext| tls::extension_offset(payload, ext.0))
+        .and_then(|at| u32::try_from(at).ok());
+
+    match at {
+        Some(at) if order.can_move_first_boundary(at) => order.with_first_boundary(at),
+        _ => order.segments().to_vec(),
+    }
+}
+
+/// `order` with its boundaries past `len` moved to it, and the segments
+/// left empty removed; and whether there were any.
+fn clamp_order(order: &[opt::Segment], len: u32) -> (Vec<opt::Segment>, bool) {
+    let beyond = order.iter().any(|s| s.0 >= len || (s.1 != u32::MAX && s.1 > len));
+    if !beyond {
+        return (order.to_vec(), false);
+    }
+
+    // Unlike a record, nothing follows the payload to carry.
+    let clamped = within_record(order, len).into_iter().filter(|s| s.0 < len).collect();
+    (clamped, true)
+}
+
+/// Profile of a ClientHello and the order it gives, if any: the one
+/// `hooked` by `--verdict-hook`, else that of `client`, else one drawn
+/// by `--strategy-rotate`, which is also returned.
+fn pick_profile(hooked: Option<&'static opt::RotatedProfile>, client: Option<&'static opt::ClientRule>)
+    -> (Option<&'static opt::ClientProfile>, Option<&'static opt::SegmentOrder>,
+        Option<&'static opt::RotatedProfile>) {
+    let rotated = if client.is_none() && hooked.is_none() { opt::strategy_rotate().pick() } else { None };
+    let (profile, order) = match (hooked, client, rotated) {
+        (Some(h), _, _) => (Some(h.profile), h.order.as_ref()),
+        (None, Some(c), _) => (Some(c.profile), c.order.as_ref()),
+        (None, None, Some(r)) => (Some(r.profile), r.order.as_ref()),
+        (None, None, None) => (None, None),
+    };
+    (profile, order, rotated)
+}
+
+/// Segments to split ClientHello `payload` into, before [`fit_order`]:
+/// `profile_order` if given, else `--segment-order` as moved by
+/// `--split-random`; either moved by `--split-ext`
+fn hello_order(profile_order: Option<&opt::SegmentOrder>, payload: &[u8]) -> Vec<opt::Segment> {
+    match (profile_order, opt::split_random()) {
+        (Some(order), _) => split_at_extension(order, payload),
+        (None, Some(range)) => opt::segment_order().with_first_boundary(range.pick()),
+        (None, None) => split_at_extension(opt::segment_order(), payload),
+    }
+}
+
+/// `order` kept within the ClientHello record of `payload` and clamped
+/// to its length; and whether it had to be clamped.
+fn fit_order(order: &[opt::Segment], payload: &[u8]) -> (Vec<opt::Segment>, bool) {
+    clamp_order(&within_hello_record(order, payload), payload.len() as u32)
+}
+
 fn build_segment(
     view: &PktView,
     start: u32,
     end: Option<u32>,
+    nth: u16,
     out_buf: &mut Vec<u8>
 ) -> Result<()> {
-    build_packet(view, start, end, out_buf, None, None, None)
+    let flags = segment_flags(tcp_flags(&view.tcp), end, view.tcp.payload().len() as u32);
+
+    build_packet(view, start, end, out_buf, &Override {
+        tcp_flags: Some(flags),
+        window: opt::server_window(),
+        ..fingerprint_override(view, nth)
+    })
+}
+
+/// `opts` without the options of `kinds`, padded with EOL to a multiple
+/// of 4 bytes; None if `opts` is malformed.
+fn strip_tcp_options(opts: &[u8], kinds: &opt::TcpOptKinds) -> Option<Vec<u8>> {
+    const EOL: u8 = 0;
+    const NOP: u8 = 1;
+
+    let mut out = Vec::with_capacity(opts.len());
+    let mut i = 0;
+
+    while i < opts.len() {
+        match opts[i] {
+            EOL => break,
+            NOP => { out.push(NOP); i += 1; }
+            kind => {
+                let len = *opts.get(i + 1)? as usize;
+                if len < 2 {
+                    return None;
+                }
+                let unit = opts.get(i..i + len)?;
+                if !kinds.contains(kind) {
+                    out.extend_from_slice(unit);
+                }
+                i += len;
+            }
+        }
+    }
+
+    out.resize(out.len().next_multiple_of(4), EOL);
+    Some(out)
+}
+
+/// `opts` with TCP Fast Open cookie options (the standard one and the
+/// experimental one of RFC 7413) overwritten by NOPs
+fn strip_tfo_cookie(opts: &[u8]) -> Vec<u8> {
+    const NOP: u8 = 1;
+    const TFO: u8 = 34;
+    const EXPERIMENTAL: u8 = 254;
+    const TFO_MAGIC: [u8; 2] = [0xf9, 0x89];
+
+    let mut out = opts.to_vec();
+    let mut i = 0;
+    while i < out.len() {
+        let kind = out[i];
+        if kind == 0 {
+            break;
+        }
+        if kind == NOP {
+            i += 1;
+            continue;
+        }
+        let Some(&len) = out.get(i + 1) else { break };
+        let len = len as usize;
+        if len < 2 || i + len > out.len() {
+            break;
+        }
+
+        if kind == TFO || (kind == EXPERIMENTAL && out.get(i + 2..i + 4) == Some(&TFO_MAGIC[..])) {
+            out[i..i + len].fill(NOP);
+        }
+        i += len;
+    }
+
+    out
+}
+
+/// The SYN of `view` without its data and TFO cookie. The server then
+/// acknowledges the SYN alone, and the client sends the data again
+/// after the handshake, where it is desynced as usual.
+fn build_plain_syn(view: &PktView, buf: &mut Vec<u8>) -> Result<()> {
+    let opts = strip_tfo_cookie(view.tcp.options());
+    build_packet(view, 0, Some(0), buf, &Override {
+        tcp_options: Some(&opts),
+        ..fingerprint_override(view, 0)
+    })
+}
+
+/// Empty ACK at the start of the ClientHello advertising `window`
+/// (`--zero-window`)
+fn build_window_ack(view: &PktView, window: Option<u16>, nth: u16, buf: &mut Vec<u8>) -> Result<()> {
+    build_packet(view, 0, Some(0), buf, &Override {
+        tcp_flags: Some(opt::TcpFlags(opt::TcpFlags::ACK)),
+        window,
+        ..fingerprint_override(view, nth)
+    })
+}
+
+/// `--zero-window`: close the window, wait `ms` and open it again, so
+/// that the flow stalls before the real data. DPIs giving up on stalled
+/// flows stop looking at it.
+fn send_stall(view: &PktView, ms: u64, nth: u16, buf: &mut Vec<u8>, traced: bool) -> Result<()> {
+    build_window_ack(view, Some(0), nth, buf)?;
+    if traced {
+        trace::hexdump("zero window", buf);
+    }
+    send(buf, view.daddr())?;
+
+    if !worker::stopping() {
+        pause(std::time::Duration::from_millis(ms));
+    }
+
+    build_window_ack(view, opt::server_window(), nth.wrapping_add(1), buf)?;
+    if traced {
+        trace::hexdump("window update", buf);
+    }
+    send(buf, view.daddr())
 }
 
+/// Packets sent by [`send_stall`]
+const STALL_PACKETS: u16 = 2;
+
+/// Send the segment (and its fakes); `nth` is the number of packets sent
+/// before for this ClientHello, and the number after is returned. The
+/// first one sent is followed by the stall of `--zero-window`.
 fn send_segment(
     view: &PktView,
     start: u32,
     end: Option<u32>,
-    buf: &mut Vec<u8>
-) -> Result<()> {
-    use platform::send_to_raw;
+    mut nth: u16,
+    buf: &mut Vec<u8>,
+    job: &Job,
+) -> Result<u16> {
+    let traced = job.traced;
+    let range = |start: u32, end: Option<u32>| {
+        format!("[{start}, {})", end.map_or("end".into(), |e| e.to_string()))
+    };
+    let first = nth == 0;
+
+    if job.fake {
+        for (fake_start, fake_end) in fake::fake_ranges(start, end) {
+            fake::fake_clienthello(view, fake_start, fake_end, nth, buf)?;
+            if traced {
+                trace::hexdump(&format!("fake {} for {}", range(fake_start, fake_end),
+                                        range(start, end)), buf);
+            }
+            send(buf, view.daddr())?;
+            nth = nth.wrapping_add(1);
+        }
+    }
+    if first && let Some(ms) = opt::zero_window() {
+        send_stall(view, ms, nth, buf, traced)?;
+        nth = nth.wrapping_add(STALL_PACKETS);
+    }
+    build_segment(view, start, end, nth, buf)?;
+    if traced {
+        trace::hexdump(&format!("segment {}", range(start, end)), buf);
+    }
+    send(buf, view.daddr())?;
+
+    Ok(nth.wrapping_add(1))
+}
+
+/// Send `pkt` to `dst` on a raw socket, or write it out if `--replay`.
+fn send(pkt: &[u8], dst: std::net::IpAddr) -> Result<()> {
+    if opt::replay().is_some() {
+        return replay::emit(pkt);
+    }
+    platform::send_to_raw(pkt, dst)
+}
 
-    if opt::fake() {
-        fake::fake_clienthello(view, start, end, buf)?;
-        send_to_raw(buf, view.daddr())?;
+/// Wait `gap` between segments; on the virtual clock if `--replay`.
+fn pause(gap: std::time::Duration) {
+    if opt::replay().is_some() {
+        replay::advance(gap);
+    } else {
+        std::thread::sleep(gap);
+    }
+}
+
+/// Gap of `ms` (`--delay-ms`, or of `--delay-schedule`) before sending
+/// the next segment, plus random `--delay-jitter`, so that the split
+/// timing is not a fixed pattern.
+fn segment_delay(ms: u64) -> std::time::Duration {
+    if worker::stopping() {
+        return std::time::Duration::ZERO;
     }
-    build_segment(view, start, end, buf)?;
-    send_to_raw(buf, view.daddr())?;
 
+    let jitter = crate::rand::range(0, opt::delay_jitter());
+    std::time::Duration::from_millis(ms.saturating_add(jitter))
+}
+
+/// Send the `--send-raw` packets to be sent `when`.
+fn send_raw(view: &PktView, when: opt::RawWhen, traced: bool) -> Result<()> {
+    let flow = rawsend::Flow {
+        saddr: view.saddr(),
+        daddr: view.daddr(),
+        sport: view.tcp.source_port(),
+        dport: view.tcp.destination_port(),
+        seq: view.tcp.sequence_number(),
+        ack: view.tcp.acknowledgment_number(),
+    };
+
+    for p in opt::send_raw().iter().filter(|p| p.when == when) {
+        let Some(pkt) = rawsend::build(p, &flow) else { continue };
+        if traced {
+            trace::hexdump(&format!("raw packet {when:?}"), &pkt);
+        }
+        send(&pkt, view.daddr())?;
+    }
     Ok(())
 }
 
-fn send_split(view: &PktView, order: &[opt::Segment], buf: &mut Vec<u8>) -> Result<()> {
+fn send_split(view: &PktView, job: &Job, buf: &mut Vec<u8>) -> Result<()> {
     let payload_len = view.tcp.payload().len() as u32;
+    let order = &job.order;
+
+    let schedule = opt::delay_schedule();
+    let mut nth = 0;
 
-    for &opt::Segment(start, end) in order {
+    send_raw(view, opt::RawWhen::Before, job.traced)?;
+
+    for (i, &opt::Segment(start, end)) in order.iter().enumerate() {
         if start >= payload_len {
             crate::warn!(
                 "send_split: segment {} exceeds payload len {payload_len}, skipping",
@@ -174,13 +671,21 @@ fn send_split(view: &PktView, order: &[opt::Segment], buf: &mut Vec<u8>) -> Resu
             );
             continue;
         }
+        if let Some(&ms) = schedule.get(i) && ms > 0 {
+            pause(segment_delay(ms));
+        }
         let end = if end == u32::MAX || end > payload_len { None } else { Some(end) };
-        send_segment(view, start, end, buf)?;
-        if end.is_some() {
-            std::thread::sleep(std::time::Duration::from_millis(opt::delay_ms()));
+        if nth > 0 {
+            send_raw(view, opt::RawWhen::Between, job.traced)?;
+        }
+        nth = send_segment(view, start, end, nth, buf, job)?;
+        if schedule.is_empty() && end.is_some() {
+            pause(segment_delay(opt::delay_ms()));
         }
     }
 
+    send_raw(view, opt::RawWhen::After, job.traced)?;
+
     crate::debug!(
         "send_split: dst={} order={:?} tcp_payload_len={}",
         view.daddr(),
@@ -214,6 +719,21 @@ fn put_hop_1(pkt: &[u8]) -> Result<()> {
     let ttl = view.ttl();
     let hop = infer_hops(view.ttl());
 
+    // Other packets (--autottl-any) only of our flows, and only if they
+    // tell something new; every put ages the table, and a download
+    // brings many.
+    if !(view.tcp.syn() && view.tcp.ack()) {
+        let key = FlowKey {
+            saddr: view.daddr(),
+            daddr: addr,
+            sport: view.tcp.destination_port(),
+            dport: view.tcp.source_port(),
+        };
+        if hoptab::peek(addr) == Some(hop) || flowtab::ftab().get(&key).is_none() {
+            return Ok(());
+        }
+    }
+
     crate::debug!(
         "put_hop_1: {}: observed ttl={}, put hop={}",
         addr, ttl, hop
@@ -231,30 +751,663 @@ pub fn put_hop(pkt: &[u8]) {
     }
 }
 
-/// Return Ok(true) if packet is handled
-pub fn handle_packet(pkt: &[u8], buf: &mut Vec::<u8>) -> Result<bool> {
+enum Retransmission {
+    /// Queued while we were still handling the original one
+    MidHandling,
+
+    /// Sent by the client after we had handled the original one
+    Handled,
+}
+
+/// Classify a ClientHello with `seq` on flow `key` as retransmission of
+/// one we have handled recently.
+fn retransmission(key: &FlowKey, seq: u32) -> Option<Retransmission> {
+    let hello = flowtab::ftab().get(key).and_then(|f| f.hello)?;
+    if hello.seq != seq {
+        return None;
+    }
+
+    let Some(finished) = hello.finished else {
+        return Some(Retransmission::MidHandling); // still on the worker
+    };
+
+    match finished.elapsed() {
+        d if d < MID_HANDLING_SLACK => Some(Retransmission::MidHandling),
+        d if d < RETRANS_WINDOW => Some(Retransmission::Handled),
+        _ => None,
+    }
+}
+
+/// If `pkt` is an inbound RST/FIN from a server (port 443), return
+/// whether it is forged by a middlebox: its TTL implies a path length
+/// that differs from the one learned from the server's SYN/ACK by more
+/// than `--forged-rst-delta` hops. Unknown servers are trusted, and so
+/// is everything without `--drop-forged-rst`.
+///
+/// A RST is also reported to [`adaptive`] and [`outcome`], forged or not.
+fn forged_teardown(pkt: &[u8]) -> Result<Option<bool>> {
+    let view = PktView::from_raw(pkt)?;
+
+    if view.tcp.source_port() != 443 || !(view.tcp.rst() || view.tcp.fin()) {
+        return Ok(None);
+    }
+
+    if view.tcp.rst() {
+        let key = view.flow_key();
+        let key = FlowKey { saddr: key.daddr, daddr: key.saddr, sport: key.dport, dport: key.sport };
+        if opt::adaptive_exempt() {
+            adaptive::reset(&key);
+        }
+        outcome::failed(&key);
+    }
+
+    if !opt::drop_forged_rst() {
+        return Ok(Some(false));
+    }
+
+    let Ok(hop) = hoptab::find(view.saddr()) else {
+        return Ok(Some(false));
+    };
+
+    let observed = infer_hops(view.ttl());
+    let forged = observed.abs_diff(hop) > opt::forged_rst_delta();
+
+    if forged {
+        crate::debug!("forged {} from {}: hop {observed}, expected {hop}; drop",
+                     if view.tcp.rst() { "RST" } else { "FIN" }, view.saddr());
+        stats::COUNTERS.forged_dropped.inc();
+    }
+
+    Ok(Some(forged))
+}
+
+/// Whether the packet on proxy port `view` carries a ClientHello
+/// tunneled by HTTP CONNECT. The CONNECT request itself is only
+/// recorded on the flow.
+///
+/// ClientHello without preceding CONNECT (e.g. TLS to the proxy itself)
+/// is not tunneled and left as is.
+fn is_tunneled_client_hello(view: &PktView) -> bool {
+    let payload = view.tcp.payload();
+    let key = view.flow_key();
+
+    if payload.starts_with(b"CONNECT ") {
+        crate::debug!("proxy: {}:{} CONNECT", view.daddr(), key.dport);
+        flowtab::ftab().entry(key).connect = true;
+        return false;
+    }
+
+    tls::is_client_hello(payload)
+        && flowtab::ftab().get(&key).is_some_and(|f| f.connect)
+}
+
+/// ClientHello to desync, decided by [`classify`] and sent by [`run_job`]
+pub struct Job {
+    pkt: Vec<u8>,
+    received: std::time::Instant,
+    order: Vec<opt::Segment>,
+
+    /// Matched by `--trace`
+    traced: bool,
+
+    /// Send fakes, by `--fake` or `--client-profile`
+    fake: bool,
+
+    /// ClientHello on a SYN (TCP Fast Open), to be sent as a plain SYN
+    /// instead of split
+    tfo: bool,
+
+    /// Profile by `--client-profile` or `--strategy-rotate`
+    profile: Option<&'static str>,
+
+    /// Charged to [`budget`] until dropped
+    cost: usize,
+}
+
+impl Job {
+    /// None if it does not fit in [`budget`]
+    fn new(
+        pkt: &[u8],
+        received: std::time::Instant,
+        order: Vec<opt::Segment>,
+        traced: bool,
+        fake: bool,
+        tfo: bool,
+        profile: Option<&'static str>,
+    ) -> Option<Self> {
+        let cost = std::mem::size_of::<Job>() + pkt.len()
+            + order.len() * std::mem::size_of::<opt::Segment>();
+        if !budget::try_charge(cost) {
+            return None;
+        }
+
+        Some(Self { pkt: pkt.to_vec(), received, order, traced, fake, tfo, profile, cost })
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        budget::release(self.cost);
+    }
+}
+
+pub enum Verdict {
+    Accept,
+    Drop,
+
+    /// Drop the original and send [`Job`] instead
+    Desync(Job),
+}
+
+/// Decide what to do with `pkt` without sending anything, so that the
+/// caller can give the verdict right away and leave the (possibly slow)
+/// sending to [`worker`].
+pub fn classify(pkt: &[u8]) -> Result<Verdict> {
+    use Verdict::*;
+
+    let bool_verdict = |drop: bool| if drop { Drop } else { Accept };
+
     #[cfg(target_os = "linux")]
     let is_filtered = platform::is_kernel_filtered_clienthello();
 
     #[cfg(windows)]
     let is_filtered = true;
 
+    let received = std::time::Instant::now();
+
+    if crate::schedule::paused() {
+        return Ok(Accept);
+    }
+
+    if dnsguard::is_udp(pkt) {
+        return Ok(bool_verdict(opt::dns_guard() && dnsguard::handle(pkt)?));
+    }
+
+    if opt::watch_teardown() && let Some(forged) = forged_teardown(pkt)? {
+        return Ok(bool_verdict(forged));
+    }
+
+    if opt::v6_degrade() && v6degrade::drops(pkt) {
+        return Ok(Drop);
+    }
+
+    // Pure ACKs and keep-alives may reach here if the ClientHello is not
+    // filtered by kernel; don't bother parsing them.
+    match tcp_payload_len_fast(pkt) {
+        Some(0) => {
+            stats::COUNTERS.empty_payload.inc();
+            return Ok(Accept);
+        }
+        // A probe of a few bytes has no server name to hide, nor room
+        // for the split positions.
+        Some(len) if len < opt::min_payload() as usize => {
+            stats::COUNTERS.short_payload.inc();
+            return Ok(Accept);
+        }
+        _ => {}
+    }
+
     let view = PktView::from_raw(pkt)?;
 
-    if !is_filtered && !tls::is_client_hello(view.tcp.payload()) {
-        return Ok(false);
+    let dport = view.tcp.destination_port();
+    let tunneled = dport != 443 && opt::proxy_ports().contains(dport);
+
+    if tunneled {
+        // CONNECT requests are queued along with ClientHellos here
+        if !is_tunneled_client_hello(&view) {
+            return Ok(Accept);
+        }
+    } else if !tls::is_client_hello(view.tcp.payload()) {
+        // Not everything on port 443 is TLS, and the prefilter looks at
+        // two bytes only. Whatever queued it, a payload that is not a
+        // ClientHello is passed unchanged. Without the prefilter, most
+        // are the rest of TLS records, which look like anything.
+        let kind = tls::non_tls(view.tcp.payload());
+        match kind {
+            Some(tls::NonTls::Http2) => stats::COUNTERS.non_tls_http2.inc(),
+            Some(tls::NonTls::Http) => stats::COUNTERS.non_tls_http.inc(),
+            Some(tls::NonTls::Other) if is_filtered => stats::COUNTERS.non_tls_other.inc(),
+            None if is_filtered => stats::COUNTERS.tls_not_hello.inc(),
+            _ => {}
+        }
+        if is_filtered {
+            crate::debug!("handle_packet: {} to {} is not a ClientHello; accept unchanged",
+                          kind.map_or("TLS record".into(), |k| k.to_string()),
+                          view.daddr());
+        } else {
+            stats::COUNTERS.unfiltered_passed.inc();
+            stats::COUNTERS.unfiltered_bytes.add(pkt.len() as u64);
+        }
+        return Ok(Accept);
+    }
+
+    if opt::pass_sslv2() && tls::hello_kind(view.tcp.payload()) == Some(tls::Hello::SslV2) {
+        stats::COUNTERS.sslv2_passed.inc();
+        return Ok(Accept);
     }
 
     // TODO: if clienthello packet has been (unlikely) fragmented,
     // we should find the second part and drop, reassemble it here.
 
-    send_split(&view, opt::segment_order().segments(), buf)?;
+    let key = view.flow_key();
+    let seq = view.tcp.sequence_number();
 
-    Ok(true)
+    let traced = !opt::trace().is_empty()
+        && trace::matches(&view, tls::server_name(view.tcp.payload()));
+    // A macro, so that its arguments are not even evaluated untraced
+    macro_rules! step {
+        ($($arg:tt)*) => {
+            if traced {
+                crate::trace!("{}:{} > {}:{}: {}", key.saddr, key.sport, key.daddr, key.dport,
+                              format_args!($($arg)*));
+            }
+        };
+    }
+    step!("seq={seq} len={} sni={} queued by {}", view.tcp.payload().len(),
+          tls::server_name(view.tcp.payload()).unwrap_or("none"),
+          if tunneled { "proxy port rule" }
+          else if is_filtered { "ClientHello prefilter" }
+          else { "port 443 rule" });
+
+    if let IpSlice::Ipv6(ip) = &view.ip && let Err(e) = ipv6_extensions(ip) {
+        crate::debug!("handle_packet: {e}; accept unchanged");
+        step!("{e}; accept unchanged");
+        return Ok(Accept);
+    }
+
+    let client = opt::client_profile().resolve(key.saddr);
+    if let Some(c) = client && c.profile.off {
+        step!("client profile {c}; accept unchanged");
+        stats::COUNTERS.client_off.inc();
+        return Ok(Accept);
+    }
+
+    // The SYN cannot be split, and its data is not acknowledged before
+    // the handshake anyway.
+    if view.tcp.syn() {
+        let Some(job) = Job::new(pkt, received, Vec::new(), traced, false, true, None) else {
+            step!("buffer budget exhausted; accept unchanged");
+            stats::COUNTERS.budget_passed.inc();
+            return Ok(Accept);
+        };
+        step!("on SYN (TCP Fast Open); send the SYN without it");
+        return Ok(Desync(job));
+    }
+
+    match retransmission(&key, seq) {
+        Some(Retransmission::MidHandling) => {
+            // Our segments carrying the same data are just sent; splitting
+            // it again only duplicates them (and the fakes).
+            crate::debug!("handle_packet: {} retransmitted mid-handling; drop", view.daddr());
+            step!("retransmitted while sending; drop");
+            stats::COUNTERS.retrans_suppressed.inc();
+            return Ok(Drop);
+        }
+        Some(Retransmission::Handled) => {
+            // Splitting it again injects duplicate fakes, which confuses
+            // some servers. Let the stack retransmit as it wants.
+            crate::debug!("handle_packet: {} retransmitted; accept unchanged", view.daddr());
+            step!("retransmitted after handling; accept unchanged");
+            stats::COUNTERS.retrans_passed.inc();
+            outcome::failed(&key);
+            return Ok(Accept);
+        }
+        None => {}
+    }
+
+    let hostlist = crate::hostlist::get();
+    let named = hostlist.is_some() || opt::once_per_host() > 0 || burst::enabled();
+    let sni = if named { tls::server_name(view.tcp.payload()) } else { None };
+
+    // A resumed session may not name its server; take the name last
+    // seen to that address. Not for a hello whose name may just be in
+    // a later segment: on a shared (CDN) address, the name last seen
+    // is often another one.
+    let bound = if !named {
+        None
+    } else if let Some(sni) = sni {
+        snitab::bind(key.daddr, sni);
+        None
+    } else if tls::lacks_server_name(view.tcp.payload()) {
+        snitab::lookup(key.daddr)
+    } else {
+        None
+    };
+    if let Some(bound) = &bound {
+        let why = if tls::is_resumption(view.tcp.payload()) { "resumed session" } else { "no name" };
+        crate::debug!("handle_packet: {} without server name ({why}); bound to {bound}", key.daddr);
+        step!("no server name ({why}); classified as {bound}, last seen to this address");
+        stats::COUNTERS.sni_bound.inc();
+    }
+    let sni = sni.or(bound.as_deref());
+
+    // ClientHello whose server name is not in this segment is handled
+    // anyway; better split too much than leave a blocked one.
+    if let Some(list) = &hostlist && let Some(sni) = sni && !list.matches(sni) {
+        crate::debug!("handle_packet: {sni} not in hostlist; accept unchanged");
+        step!("not in hostlist; accept unchanged");
+        stats::COUNTERS.host_unlisted.inc();
+        return Ok(Accept);
+    }
+
+    if opt::v6_degrade() && hostlist.is_some() && let Some(sni) = sni {
+        v6degrade::learn(key.daddr, sni);
+    }
+
+    let window = std::time::Duration::from_secs(opt::once_per_host());
+
+    if let Some(sni) = sni && window > std::time::Duration::ZERO
+        && flowtab::ftab().host_within(key.saddr, sni, window) {
+        crate::debug!("handle_packet: {sni} handled within {}s; accept unchanged", window.as_secs());
+        step!("host handled recently (--once-per-host); accept unchanged");
+        stats::COUNTERS.host_skipped.inc();
+        return Ok(Accept);
+    }
+
+    let hooked = if opt::verdict_hook().is_some() {
+        let query = hook::Query {
+            saddr: key.saddr,
+            daddr: key.daddr,
+            dport,
+            sni,
+            alpn: tls::alpn(view.tcp.payload()),
+        };
+        match hook::ask(&query) {
+            hook::Answer::Pass => {
+                crate::debug!("handle_packet: {} passed by --verdict-hook; accept unchanged", key.daddr);
+                step!("passed by --verdict-hook; accept unchanged");
+                stats::COUNTERS.hook_passed.inc();
+                return Ok(Accept);
+            }
+            hook::Answer::Default => None,
+            hook::Answer::Profile(p) => {
+                step!("profile {} given by --verdict-hook", p.profile.name);
+                Some(p)
+            }
+        }
+    } else {
+        None
+    };
+
+    // Server name, or address if none
+    let host = burst::enabled().then(|| sni.map_or_else(|| key.daddr.to_string(), str::to_string));
+    let shared = if let Some(host) = &host {
+        match burst::join(key.saddr, host) {
+            burst::Join::Member(plan) => plan,
+            burst::Join::Over => {
+                crate::debug!("handle_packet: {host} over --burst-limit; accept unchanged");
+                step!("over --burst-limit; accept unchanged");
+                stats::COUNTERS.burst_passed.inc();
+                return Ok(Accept);
+            }
+        }
+    } else {
+        None
+    };
+
+    // A proxy carries many servers; its outcome tells nothing
+    if opt::adaptive_exempt() && !tunneled {
+        match adaptive::decide(&key, seq) {
+            adaptive::Decision::Desync => {}
+            adaptive::Decision::Probe => {
+                crate::debug!("handle_packet: {} probed without desync; accept unchanged", view.daddr());
+                step!("adaptive probe; accept unchanged");
+                stats::COUNTERS.adaptive_probes.inc();
+                #[cfg(windows)]
+                if opt::socket_layer() {
+                    socktab::handled(&key, sni);
+                }
+                return Ok(Accept);
+            }
+            adaptive::Decision::Exempt => {
+                step!("adaptive exempt; accept unchanged");
+                stats::COUNTERS.adaptive_exempted.inc();
+                #[cfg(windows)]
+                if opt::socket_layer() {
+                    socktab::handled(&key, sni);
+                }
+                return Ok(Accept);
+            }
+        }
+    }
+
+    let (order, mut fake, profile) = if let Some(plan) = shared {
+        step!("split as the first of the burst (--coalesce)");
+        (plan.order, plan.fake, plan.profile)
+    } else {
+        let (profile, profile_order, rotated) = pick_profile(hooked, client);
+        if let Some(r) = rotated {
+            step!("profile {} drawn (--strategy-rotate)", r.profile.name);
+        }
+
+        let order = hello_order(profile_order, view.tcp.payload());
+        let fake = profile.and_then(|p| p.fake).unwrap_or(opt::fake());
+        let profile = profile.map(|p| p.name);
+        if let Some(host) = &host {
+            burst::share(key.saddr, host, burst::Plan { order: order.clone(), fake, profile });
+        }
+        (order, fake, profile)
+    };
+    let (order, clamped) = fit_order(&order, view.tcp.payload());
+    if clamped {
+        crate::debug!("handle_packet: split positions past the {} byte payload clamped",
+                      view.tcp.payload().len());
+        step!("split positions past the payload clamped");
+        stats::COUNTERS.split_clamped.inc();
+    }
+    let headers = (pkt.len() - view.tcp.payload().len()) as u32;
+    let order = match pmtu::fit(&order, key.daddr, view.tcp.payload().len() as u32, headers) {
+        Some(fitted) => {
+            crate::debug!("handle_packet: segments to {} cut to the path MTU", key.daddr);
+            step!("segments cut to the path MTU learned (--watch-pmtu)");
+            stats::COUNTERS.pmtu_cut.inc();
+            fitted
+        }
+        None => order,
+    };
+    if fake && !hoptab::take_fakes(key.daddr) {
+        crate::debug!("handle_packet: --fake-rate to {} exceeded; split without fakes", key.daddr);
+        step!("--fake-rate exceeded; split without fakes");
+        stats::COUNTERS.fake_limited.inc();
+        fake = false;
+    }
+    if let Some(c) = client {
+        step!("client profile {c}");
+    }
+
+    let Some(job) = Job::new(pkt, received, order, traced, fake, false, profile) else {
+        crate::debug!("handle_packet: buffer budget exhausted; accept unchanged");
+        step!("buffer budget exhausted; accept unchanged");
+        stats::COUNTERS.budget_passed.inc();
+        return Ok(Accept);
+    };
+
+    if let Some(sni) = sni && window > std::time::Duration::ZERO {
+        flowtab::ftab().put_host(key.saddr, sni);
+    }
+
+    // In progress until run_job() finishes
+    flowtab::ftab().entry(key).hello = Some(HandledHello { seq, finished: None });
+
+    #[cfg(windows)]
+    if opt::socket_layer() {
+        socktab::handled(&key, sni.or_else(|| tls::server_name(view.tcp.payload())));
+    }
+
+    step!("desync with {}, segments {}", outcome::strategy(),
+          job.order.iter().map(ToString::to_string).collect::<Vec<_>>().join(" "));
+    if traced {
+        trace::hexdump("original", pkt);
+    }
+
+    Ok(Desync(job))
+}
+
+/// Run `f` turning a panic into an error, so that one malformed packet
+/// or strategy bug costs only that packet instead of the process (or
+/// the worker thread).
+fn isolate<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).unwrap_or_else(|e| {
+        stats::COUNTERS.panics.inc();
+        let msg = e.downcast_ref::<&str>().copied()
+            .or_else(|| e.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown");
+        Err(anyhow!("panicked: {msg}"))
+    })
+}
+
+/// Send the segments of `job`.
+///
+/// The original packet has been dropped already; on error, the flow is
+/// forgotten so that the client's retransmission is handled afresh.
+pub fn run_job(job: &Job, buf: &mut Vec<u8>) -> Result<()> {
+    let view = PktView::from_raw(&job.pkt)?;
+
+    if job.tfo {
+        isolate(|| build_plain_syn(&view, buf))?;
+        if job.traced {
+            trace::hexdump("SYN without data", buf);
+        }
+        send(buf, view.daddr())?;
+        stats::COUNTERS.tfo_stripped.inc();
+        return Ok(());
+    }
+
+    let result = isolate(|| send_split(&view, job, buf));
+    finish_job(job, &view, result)
+}
+
+/// Record the result of handling `job`.
+fn finish_job(job: &Job, view: &PktView, result: Result<()>) -> Result<()> {
+    let key = view.flow_key();
+    let seq = view.tcp.sequence_number();
+
+    let mut ftab = flowtab::ftab();
+    let flow = ftab.entry(key);
+    if flow.hello.is_some_and(|h| h.seq == seq) {
+        flow.hello = match result {
+            Ok(()) => Some(HandledHello { seq, finished: Some(std::time::Instant::now()) }),
+            Err(_) => None,
+        };
+    }
+    drop(ftab);
+
+    result?;
+
+    outcome::handled(key, tls::server_name(view.tcp.payload()), strategy(job), job.profile);
+    stats::COUNTERS.handled.inc();
+    stats::record_latency(job.received.elapsed());
+
+    Ok(())
+}
+
+/// Short description of how `job` was desynced, e.g. `split 1,5 +fake`
+fn strategy(job: &Job) -> String {
+    let mut at: Vec<u32> = job.order.iter().map(|s| s.0).filter(|&s| s > 0).collect();
+    at.sort_unstable();
+
+    let at: Vec<String> = at.iter().map(u32::to_string).collect();
+    let mut s = format!("split {}", at.join(","));
+    if job.fake {
+        s.push_str(" +fake");
+    }
+    if let Some(p) = job.profile {
+        s = format!("{s} ({p})");
+    }
+    s
+}
+
+/// Return Ok(true) if packet is handled (dropped); the job, if any, is
+/// run on the calling thread. For the auxiliary WinDivert handles, which
+/// see no ClientHello.
+#[cfg(windows)]
+pub fn handle_packet(pkt: &[u8], buf: &mut Vec::<u8>) -> Result<bool> {
+    match isolate(|| classify(pkt))? {
+        Verdict::Accept => Ok(false),
+        Verdict::Drop => Ok(true),
+        Verdict::Desync(job) => run_job(&job, buf).map(|_| true),
+    }
+}
+
+/// Verdict of [`mangle_packet`]
+#[cfg(target_os = "linux")]
+pub enum Mangled {
+    Accept,
+    Drop,
+
+    /// Accept the packet left in the buffer instead
+    Replace,
+}
+
+/// Same as [`dispatch_packet`], for when nothing can be sent
+/// ([`platform::linux::mangle_only`]). A ClientHello to desync is cut
+/// down to its first segment in `buf`, to be accepted in place of the
+/// original; the stack sends the rest on its own, as a retransmission
+/// once the server has acknowledged the first part. Fakes and segments
+/// sent out of order are not possible this way.
+#[cfg(target_os = "linux")]
+pub fn mangle_packet(pkt: &[u8], buf: &mut Vec<u8>) -> Result<Mangled> {
+    let job = match isolate(|| classify(pkt))? {
+        Verdict::Accept => return Ok(Mangled::Accept),
+        Verdict::Drop => return Ok(Mangled::Drop),
+        Verdict::Desync(job) => job,
+    };
+
+    let view = PktView::from_raw(&job.pkt)?;
+
+    if job.tfo {
+        isolate(|| build_plain_syn(&view, buf))?;
+        if job.traced {
+            trace::hexdump("SYN without data, in place", buf);
+        }
+        stats::COUNTERS.tfo_stripped.inc();
+        return Ok(Mangled::Replace);
+    }
+
+    let payload_len = view.tcp.payload().len() as u32;
+    let cut = job.order.iter()
+        .find(|s| s.0 == 0)
+        .map_or(u32::MAX, |s| s.1);
+
+    if cut >= payload_len {
+        finish_job(&job, &view, Ok(()))?;
+        return Ok(Mangled::Accept);
+    }
+
+    let result = isolate(|| build_segment(&view, 0, Some(cut), 0, buf));
+    finish_job(&job, &view, result)?;
+    if job.traced {
+        trace::hexdump(&format!("cut in place at {cut}"), buf);
+    }
+
+    crate::debug!("mangle_packet: dst={} cut at {cut} of {payload_len}", view.daddr());
+    Ok(Mangled::Replace)
+}
+
+/// Same as [`handle_packet`], but the job is handed to `worker`.
+pub fn dispatch_packet(pkt: &[u8], worker: &mut worker::Worker) -> Result<bool> {
+    match isolate(|| classify(pkt))? {
+        Verdict::Accept => Ok(false),
+        Verdict::Drop => Ok(true),
+        Verdict::Desync(job) => {
+            worker.submit(job);
+            Ok(true)
+        }
+    }
 }
 
 #[macro_export]
 macro_rules! handle_packet {
+    ($bytes:expr, worker: $worker:expr, handled => $on_handled:expr, rejected => $on_rejected:expr $(,)?) => {{
+        match crate::pkt::dispatch_packet($bytes, $worker) {
+            Ok(true) => { $on_handled }
+            Ok(false) => { $on_rejected }
+            Err(e) => {
+                crate::warn!("handle_packet: {e}");
+                $on_rejected
+            }
+        }
+    }};
     ($bytes:expr, $buf:expr, handled => $on_handled:expr, rejected => $on_rejected:expr $(,)?) => {{
         match crate::pkt::handle_packet($bytes, $buf) {
             Ok(true) => { $on_handled }
@@ -266,3 +1419,439 @@ macro_rules! handle_packet {
         }
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// IPv4 + TCP (no options) header with `payload_len` bytes of payload
+    fn ipv4_tcp(payload_len: usize) -> Vec<u8> {
+        let total = 20 + 20 + payload_len;
+        let mut pkt = vec![0u8; total];
+        pkt[0] = 0x45;
+        pkt[2..4].copy_from_slice(&(total as u16).to_be_bytes());
+        pkt[9] = 6;
+        pkt[20 + 12] = 5 << 4;
+        pkt
+    }
+
+    const V6_SRC: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1];
+    const V6_DST: [u8; 16] = [0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2];
+
+    /// NOP, NOP, timestamps
+    const V6_OPTS: [u8; 12] = [1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2];
+
+    /// Hop-by-hop options, then destination options, each with a PadN
+    const V6_EXTS: [u8; 16] = [60, 0, 1, 4, 0, 0, 0, 0, 6, 0, 1, 4, 0, 0, 0, 0];
+
+    fn sum16(data: &[u8]) -> u32 {
+        data.chunks(2).map(|c| u32::from(u16::from_be_bytes([c[0], *c.get(1).unwrap_or(&0)]))).sum()
+    }
+
+    fn fold16(mut sum: u32) -> u16 {
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    /// TCP checksum of IPv6 packet `pkt` whose TCP header is at `l4`
+    fn tcp6_sum(pkt: &[u8], l4: usize) -> u16 {
+        let tcp = &pkt[l4..];
+        fold16(sum16(&pkt[8..40]) + sum16(&(tcp.len() as u32).to_be_bytes()) + 6 + sum16(tcp))
+    }
+
+    /// IPv6 packet from [`V6_SRC`]:40000 to [`V6_DST`]:443 with extension
+    /// headers `exts` (a chain ending in TCP) and a valid checksum
+    fn ipv6_tcp(exts: &[u8], hop_limit: u8, seq: u32, flags: u8, opts: &[u8], payload: &[u8]) -> Vec<u8> {
+        let tcp_len = 20 + opts.len() + payload.len();
+
+        let mut pkt = vec![0x60, 0, 0, 0];
+        pkt.extend_from_slice(&((exts.len() + tcp_len) as u16).to_be_bytes());
+        pkt.push(if exts.is_empty() { 6 } else { 0 });
+        pkt.push(hop_limit);
+        pkt.extend_from_slice(&V6_SRC);
+        pkt.extend_from_slice(&V6_DST);
+        pkt.extend_from_slice(exts);
+
+        let l4 = pkt.len();
+        pkt.extend_from_slice(&40000u16.to_be_bytes());
+        pkt.extend_from_slice(&443u16.to_be_bytes());
+        pkt.extend_from_slice(&seq.to_be_bytes());
+        pkt.extend_from_slice(&1u32.to_be_bytes());             // ack
+        pkt.push((((20 + opts.len()) / 4) << 4) as u8);
+        pkt.push(flags);
+        pkt.extend_from_slice(&502u16.to_be_bytes());           // window
+        pkt.extend_from_slice(&[0, 0, 0, 0]);                   // checksum, urgent
+        pkt.extend_from_slice(opts);
+        pkt.extend_from_slice(payload);
+
+        let sum = !tcp6_sum(&pkt, l4);
+        pkt[l4 + 16..l4 + 18].copy_from_slice(&sum.to_be_bytes());
+        pkt
+    }
+
+    /// Something like a ClientHello; only its bytes matter here
+    fn hello_payload() -> Vec<u8> {
+        let mut p = vec![22, 3, 1, 0, 250, 1];
+        p.extend((0..250u32).map(|i| i as u8));
+        p
+    }
+
+    const PSH_ACK: u8 = 0x18;
+    const ACK: u8 = 0x10;
+
+    #[test]
+    fn test_ipv6_split() {
+        let payload = hello_payload();
+        let len = payload.len() as u32;
+        let orders: [&[(u32, u32)]; 4] = [
+            &[(0, 1), (1, u32::MAX)],
+            &[(0, 2), (2, 5), (5, u32::MAX)],
+            &[(1, u32::MAX), (0, 1)],
+            &[(0, 100), (100, len)],
+        ];
+
+        for exts in [&[][..], &V6_EXTS[..]] {
+            for seq in [1000, u32::MAX - 1] {
+                let orig = ipv6_tcp(exts, 64, seq, PSH_ACK, &V6_OPTS, &payload);
+                let view = PktView::from_raw(&orig).unwrap();
+                assert_eq!(view.tcp.payload(), &payload[..]);
+
+                for order in orders {
+                    let mut got = Vec::new();
+
+                    for (nth, &(start, end)) in order.iter().enumerate() {
+                        let end = if end >= len { None } else { Some(end) };
+                        let mut buf = Vec::new();
+                        build_segment(&view, start, end, nth as u16, &mut buf).unwrap();
+
+                        // Extension headers are copied
+                        let stop = end.unwrap_or(len);
+                        let flags = if end.is_some() { ACK } else { PSH_ACK };
+                        let want = ipv6_tcp(exts, 64, seq_add(seq, start), flags, &V6_OPTS,
+                                            &payload[start as usize..stop as usize]);
+                        assert_eq!(buf, want, "exts={} seq={seq} order={order:?}", exts.len());
+
+                        got.push((start, buf[40 + exts.len() + 32..].to_vec()));
+                    }
+
+                    got.sort();
+                    let joined: Vec<u8> = got.into_iter().flat_map(|(_, p)| p).collect();
+                    assert_eq!(joined, payload);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_ipv6_ecn() {
+        const ECE_CWR: u8 = 0xc0;
+        let payload = hello_payload();
+
+        // ECT(0) and CE, with ECE and CWR
+        for tc in [0x02, 0x03] {
+            let mut orig = ipv6_tcp(&[], 64, 1000, ECE_CWR | PSH_ACK, &V6_OPTS, &payload);
+            orig[1] |= tc << 4;
+            let view = PktView::from_raw(&orig).unwrap();
+
+            for (start, end, flags) in [(0, Some(1), ECE_CWR | ACK), (1, None, ECE_CWR | PSH_ACK)] {
+                let mut buf = Vec::new();
+                build_segment(&view, start, end, 0, &mut buf).unwrap();
+
+                let stop = end.map_or(payload.len(), |e| e as usize);
+                let mut want = ipv6_tcp(&[], 64, 1000 + start, flags, &V6_OPTS,
+                                        &payload[start as usize..stop]);
+                want[1] |= tc << 4;
+                assert_eq!(buf, want, "tc={tc} start={start}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_ipv6_fake() {
+        let payload = hello_payload();
+
+        for exts in [&[][..], &V6_EXTS[..]] {
+            let orig = ipv6_tcp(exts, 64, 1000, PSH_ACK, &V6_OPTS, &payload);
+            let view = PktView::from_raw(&orig).unwrap();
+
+            for (start, end) in [(0, Some(1)), (1, None)] {
+                let mut buf = Vec::new();
+                fake::fake_clienthello(&view, start, end, 0, &mut buf).unwrap();
+
+                let fake_payload = &fake::DEFAULT_FAKE_TLS_CLIENTHELLO
+                    [start as usize..end.map_or(fake::DEFAULT_FAKE_TLS_CLIENTHELLO.len(), |e| e as usize)];
+                let tcp_len = 32 + fake_payload.len();
+                let l4 = 40 + exts.len();
+
+                assert_eq!(buf.len(), l4 + tcp_len);
+                assert_eq!(buf[..4], [0x60, 0, 0, 0]);
+                assert_eq!(buf[4..6], ((exts.len() + tcp_len) as u16).to_be_bytes());
+                assert_eq!(buf[6], if exts.is_empty() { 6 } else { 0 }, "next header");
+                assert_eq!(buf[7], opt::fake_ttl(), "hop limit");
+                assert_eq!(buf[8..40], [V6_SRC, V6_DST].concat());
+                assert_eq!(buf[40..l4], *exts);
+                assert_eq!(buf[l4 + 4..l4 + 8], seq_add(1000, start).to_be_bytes());
+                assert_eq!(buf[l4 + 13], PSH_ACK);
+                assert_eq!(tcp6_sum(&buf, l4), 0xffff, "checksum");
+                assert_eq!(&buf[l4 + 32..], fake_payload);
+
+                // Options are shuffled, not changed
+                let mut opts = buf[l4 + 20..l4 + 32].to_vec();
+                let mut want = V6_OPTS.to_vec();
+                opts.sort_unstable();
+                want.sort_unstable();
+                assert_eq!(opts, want);
+            }
+        }
+    }
+
+    #[test]
+    fn test_ipv6_window_ack() {
+        for exts in [&[][..], &V6_EXTS[..]] {
+            let orig = ipv6_tcp(exts, 64, 1000, PSH_ACK, &V6_OPTS, &hello_payload());
+            let view = PktView::from_raw(&orig).unwrap();
+            let l4 = 40 + exts.len();
+
+            for (window, want) in [(Some(0), 0), (None, 502)] {
+                let mut buf = Vec::new();
+                build_window_ack(&view, window, 0, &mut buf).unwrap();
+
+                assert_eq!(buf.len(), l4 + 32, "no payload");
+                assert_eq!(buf[40..l4], *exts);
+                assert_eq!(buf[l4 + 4..l4 + 8], 1000u32.to_be_bytes());
+                assert_eq!(buf[l4 + 13], ACK);
+                assert_eq!(buf[l4 + 14..l4 + 16], u16::to_be_bytes(want));
+                assert_eq!(tcp6_sum(&buf, l4), 0xffff, "checksum");
+            }
+        }
+    }
+
+    /// What `--fake-badsum`, `--fake-tcp-flags` and `--server-window`
+    /// set, past the extension headers
+    #[test]
+    fn test_ipv6_override() {
+        use opt::TcpFlags as F;
+
+        let payload = hello_payload();
+        let orig = ipv6_tcp(&V6_EXTS, 64, 1000, PSH_ACK, &V6_OPTS, &payload);
+        let view = PktView::from_raw(&orig).unwrap();
+        let l4 = 40 + V6_EXTS.len();
+
+        let mut buf = Vec::new();
+        build_packet(&view, 0, Some(1), &mut buf, &Override {
+            ttl: Some(3),
+            tcp_checksum: Some(0),
+            tcp_flags: Some(F(F::FIN | F::ACK)),
+            window: Some(7),
+            ..Default::default()
+        }).unwrap();
+
+        assert_eq!(buf.len(), l4 + 32 + 1);
+        assert_eq!(buf[7], 3, "hop limit");
+        assert_eq!(buf[40..l4], V6_EXTS);
+        assert_eq!(buf[l4 + 13], 0x11, "FIN, ACK");
+        assert_eq!(buf[l4 + 14..l4 + 16], 7u16.to_be_bytes());
+        assert_eq!(buf[l4 + 16..l4 + 18], [0, 0], "bad checksum");
+        assert_eq!(buf[l4 + 32], payload[0]);
+    }
+
+    #[test]
+    fn test_ipv6_extensions() {
+        let mut pkt = ipv6_tcp(&V6_EXTS, 64, 1000, PSH_ACK, &V6_OPTS, &hello_payload());
+        let exts = |pkt: &[u8]| match PktView::from_raw(pkt).unwrap().ip {
+            IpSlice::Ipv6(ip) => ipv6_extensions(&ip).map(|e| e.header_len()),
+            IpSlice::Ipv4(_) => unreachable!(),
+        };
+        assert_eq!(exts(&pkt).unwrap(), V6_EXTS.len());
+
+        // Destination options twice in a row cannot be copied.
+        pkt[6] = 60;
+        assert!(exts(&pkt).is_err());
+    }
+
+    #[test]
+    fn test_ipv6_tfo_syn() {
+        const SYN: u8 = 0x02;
+        let opts = [2, 4, 5, 0xa0, 34, 10, 1, 2, 3, 4, 5, 6, 7, 8, 1, 1];
+        let syn = ipv6_tcp(&V6_EXTS, 64, u32::MAX, SYN, &opts, &hello_payload());
+        let view = PktView::from_raw(&syn).unwrap();
+
+        let mut buf = Vec::new();
+        build_plain_syn(&view, &mut buf).unwrap();
+        let want = ipv6_tcp(&V6_EXTS, 64, u32::MAX, SYN, &strip_tfo_cookie(&opts), &[]);
+        assert_eq!(buf, want);
+    }
+
+    #[test]
+    fn test_tcp_payload_len_fast() {
+        assert_eq!(tcp_payload_len_fast(&ipv4_tcp(0)), Some(0));
+        assert_eq!(tcp_payload_len_fast(&ipv4_tcp(517)), Some(517));
+
+        let mut v6 = vec![0u8; 40 + 32];
+        v6[0] = 0x60;
+        v6[4..6].copy_from_slice(&32u16.to_be_bytes());
+        v6[6] = 6;
+        v6[40 + 12] = 8 << 4;   // 12 bytes of options
+        assert_eq!(tcp_payload_len_fast(&v6), Some(0));
+
+        v6[6] = 0;              // hop-by-hop extension header
+        assert_eq!(tcp_payload_len_fast(&v6), None);
+
+        assert_eq!(tcp_payload_len_fast(&[]), None);
+        assert_eq!(tcp_payload_len_fast(&ipv4_tcp(0)[..30]), None);
+    }
+
+    #[test]
+    fn test_strip_tfo_cookie() {
+        // MSS, NOP, NOP, TFO cookie of 8 bytes
+        let opts = [2, 4, 5, 0xb4, 1, 1, 34, 10, 1, 2, 3, 4, 5, 6, 7, 8];
+        let mut want = opts;
+        want[6..].fill(1);
+        assert_eq!(strip_tfo_cookie(&opts), want);
+
+        // Experimental TFO option, then EOL and garbage after it
+        let opts = [254, 6, 0xf9, 0x89, 1, 2, 0, 34, 2, 0];
+        assert_eq!(strip_tfo_cookie(&opts), [1, 1, 1, 1, 1, 1, 0, 34, 2, 0]);
+
+        // Another experimental option and a truncated one are kept
+        let opts = [254, 4, 0xab, 0xcd, 34, 10, 1];
+        assert_eq!(strip_tfo_cookie(&opts), opts);
+    }
+
+    #[test]
+    fn test_dscp() {
+        assert_eq!(with_dscp(0, 46), 0xb8);
+        assert_eq!(with_dscp(0xb9, 0), 0x01);   // ECN kept
+        assert_eq!(with_dscp(0x03, 63), 0xff);
+
+        // Without --dscp, traffic class (EF, ECT(1)) is kept as is
+        let mut pkt = ipv6_tcp(&[], 64, 1000, 0x18, &V6_OPTS, &hello_payload());
+        pkt[0] = 0x6b;
+        pkt[1] = 0x90;
+        let view = PktView::from_raw(&pkt).unwrap();
+
+        let mut buf = Vec::new();
+        build_segment(&view, 0, Some(1), 0, &mut buf).unwrap();
+        assert_eq!(buf[..2], [0x6b, 0x90]);
+    }
+
+    #[test]
+    fn test_ipv6_flowlabel() {
+        // Kept by default
+        let mut pkt = ipv6_tcp(&[], 64, 1000, 0x18, &V6_OPTS, &hello_payload());
+        pkt[1..4].copy_from_slice(&[0x0a, 0xbc, 0xde]);
+        let view = PktView::from_raw(&pkt).unwrap();
+
+        let mut buf = Vec::new();
+        build_segment(&view, 0, Some(1), 0, &mut buf).unwrap();
+        assert_eq!(buf[1..4], [0x0a, 0xbc, 0xde]);
+
+        // One for all packets of an original, within 20 bits
+        let label = random_flow_label(&view);
+        assert_eq!(label, random_flow_label(&view));
+        assert!(label <= 0xf_ffff);
+    }
+
+    #[test]
+    fn test_seq_add() {
+        assert_eq!(seq_add(1000, 517), 1517);
+        assert_eq!(seq_add(u32::MAX, 0), u32::MAX);
+        assert_eq!(seq_add(u32::MAX, 1), 0);
+        assert_eq!(seq_add(u32::MAX - 99, 517), 417);
+
+        // Segments of a ClientHello straddling the wrap stay contiguous
+        let seq = u32::MAX - 2;
+        let bounds = [0, 1, 3, 5, 517].map(|start| seq_add(seq, start));
+        assert_eq!(bounds, [u32::MAX - 2, u32::MAX - 1, 0, 2, 514]);
+        for w in bounds.windows(2) {
+            assert!(w[1].wrapping_sub(w[0]) < 517);
+        }
+    }
+
+    #[test]
+    fn test_within_hello_record() {
+        use opt::Segment as S;
+
+        let mut payload = vec![22, 3, 1, 0, 250, 1];
+        payload.extend((0..249u32).map(|i| i as u8));
+        let record_end = payload.len() as u32;
+        let order = opt::SegmentOrder::new(&format!("0,1,{}", record_end + 10)).unwrap();
+        assert!(within_hello_record(order.segments(), &payload) == order.segments());
+
+        payload.extend_from_slice(&[23, 0x03, 0x03, 0, 20]);    // early data
+        payload.extend_from_slice(&[0; 20]);
+        assert!(within_hello_record(order.segments(), &payload)
+                == [S(0, 1), S(1, record_end), S(record_end, u32::MAX)]);
+
+        let order = opt::SegmentOrder::new(&format!("{},0,1,{}", record_end + 3, record_end)).unwrap();
+        assert!(within_hello_record(order.segments(), &payload)
+                == [S(record_end, u32::MAX), S(0, 1), S(1, record_end)]);
+    }
+
+    #[test]
+    fn test_clamp_order() {
+        use opt::Segment as S;
+
+        let order = opt::SegmentOrder::new("0,1,40").unwrap();
+        let (clamped, beyond) = clamp_order(order.segments(), 100);
+        assert!(clamped == order.segments() && !beyond);
+
+        let (clamped, beyond) = clamp_order(order.segments(), 20);
+        assert!(clamped == [S(0, 1), S(1, 20)] && beyond);
+
+        let order = opt::SegmentOrder::new("2,0").unwrap();
+        let (clamped, beyond) = clamp_order(order.segments(), 2);
+        assert!(clamped == [S(0, 2)] && beyond);
+    }
+
+    #[test]
+    fn test_segment_flags() {
+        use opt::TcpFlags as F;
+
+        let psh_ack = F(F::PSH | F::ACK);
+        let fin = F(F::PSH | F::ACK | F::FIN);
+
+        // First and middle segments carry neither PSH nor FIN
+        assert_eq!(segment_flags(psh_ack, Some(1), 517), F(F::ACK));
+        assert_eq!(segment_flags(fin, Some(516), 517), F(F::ACK));
+
+        // The last one, however it is bounded, has them
+        assert_eq!(segment_flags(psh_ack, None, 517), psh_ack);
+        assert_eq!(segment_flags(fin, Some(517), 517), fin);
+        assert_eq!(segment_flags(fin, Some(u32::MAX), 517), fin);
+        assert_eq!(segment_flags(F(F::ACK), None, 517), psh_ack);
+
+        // ECN flags are on every segment
+        let ecn = F(F::ACK | F::ECE | F::CWR);
+        assert_eq!(segment_flags(F(ecn.0 | F::PSH), Some(1), 517), ecn);
+        assert_eq!(segment_flags(ecn, None, 517), F(ecn.0 | F::PSH));
+    }
+
+    #[test]
+    fn test_strip_tcp_options() {
+        let kinds = opt::TcpOptKinds::new("sack-perm,ts").unwrap();
+
+        // NOP, NOP, TS, then SACK-permitted, EOL padding
+        let opts = [1, 1, 8, 10, 0, 0, 0, 1, 0, 0, 0, 2, 4, 2, 0, 0];
+        assert_eq!(strip_tcp_options(&opts, &kinds), Some(vec![1, 1, 0, 0]));
+
+        // MSS, wscale kept
+        let opts = [2, 4, 5, 0xb4, 1, 3, 3, 7];
+        assert_eq!(strip_tcp_options(&opts, &kinds), Some(opts.to_vec()));
+
+        assert_eq!(strip_tcp_options(&[8, 10, 1], &kinds), None);
+        assert_eq!(strip_tcp_options(&[8, 0], &kinds), None);
+    }
+
+    #[test]
+    fn test_isolate() {
+        assert_eq!(isolate(|| Ok(1)).unwrap(), 1);
+
+        let before = stats::COUNTERS.panics.get();
+        let err = isolate::<()>(|| panic!("boom")).unwrap_err();
+        assert!(err.to_string().contains("boom"));
+        assert_eq!(stats::COUNTERS.panics.get(), before + 1);
+    }
+}
@@ -0,0 +1,276 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--adaptive-exempt`: stop desyncing servers that do not need it
+//!
+//! Every [`PROBE_EVERY`]th ClientHello to a server is passed unchanged
+//! as a probe. The probe fails if the client retransmits the same
+//! ClientHello (DPI dropped it) or the server side resets the
+//! connection within [`PROBE_WINDOW`] (DPI injected a RST); otherwise it
+//! succeeds. After [`EXEMPT_AFTER`] successes in a row the server is
+//! exempted for [`EXEMPT_TTL`]: its ClientHellos are passed unchanged.
+//!
+//! Exempted ClientHellos are watched the same way. On any failure the
+//! retransmission is desynced right away, so a wrong guess costs one
+//! retransmission timeout, and the server is not probed again for
+//! [`HOLD_TTL`].
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::budget;
+use super::flowtab::FlowKey;
+use crate::{stats, webhook};
+
+/// Probe one ClientHello out of this many to a server
+const PROBE_EVERY: u32 = 8;
+
+/// A passed ClientHello not retransmitted or reset within this time
+/// has got through.
+const PROBE_WINDOW: Duration = Duration::from_secs(2);
+
+/// Successful probes in a row to exempt a server
+const EXEMPT_AFTER: u8 = 2;
+
+const EXEMPT_TTL: Duration = Duration::from_secs(3600);
+
+/// No probe to a server for this long after a failure
+const HOLD_TTL: Duration = Duration::from_secs(3600);
+
+/// Servers not seen for this long are forgotten.
+const DEST_TTL: Duration = Duration::from_secs(2 * 3600);
+
+/// Sweep forgotten servers at most once per this interval.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Estimated memory of an entry, including hash table overhead
+const DEST_COST: usize = std::mem::size_of::<(IpAddr, Dest)>() + 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Decision {
+    Desync,
+
+    /// Pass unchanged to learn whether desync is needed
+    Probe,
+
+    /// Pass unchanged; the server is known to work without desync
+    Exempt,
+}
+
+/// ClientHello passed unchanged and being watched
+#[derive(Clone, Copy)]
+struct Passed {
+    key: FlowKey,
+    seq: u32,
+    at: Instant,
+    probe: bool,
+}
+
+struct Dest {
+    last_seen: Instant,
+    hellos: u32,
+    successes: u8,
+    passed: Option<Passed>,
+    exempt_until: Option<Instant>,
+    hold_until: Option<Instant>,
+}
+
+impl Dest {
+    fn new(now: Instant) -> Self {
+        Self { last_seen: now, hellos: 0, successes: 0, passed: None, exempt_until: None, hold_until: None }
+    }
+
+    fn fail(&mut self, now: Instant, why: &str) {
+        let Some(p) = self.passed.take() else { return };
+
+        crate::info!("adaptive: {} failed without desync ({why}); {}",
+                     p.key.daddr, if p.probe { "keep desyncing" } else { "exemption revoked" });
+        stats::COUNTERS.adaptive_failed.inc();
+        if !p.probe {
+            webhook::notify("revoked", &[("server", &p.key.daddr.to_string()), ("why", why)]);
+        }
+
+        self.successes = 0;
+        self.exempt_until = None;
+        self.hold_until = Some(now + HOLD_TTL);
+    }
+
+    /// Settle the watched ClientHello if its window is over.
+    fn settle(&mut self, now: Instant) {
+        let Some(p) = self.passed else { return };
+        if now.duration_since(p.at) < PROBE_WINDOW {
+            return;
+        }
+
+        self.passed = None;
+        if !p.probe {
+            return;
+        }
+
+        self.successes += 1;
+        if self.successes >= EXEMPT_AFTER {
+            crate::info!("adaptive: {} works without desync; exempted for {}s",
+                         p.key.daddr, EXEMPT_TTL.as_secs());
+            self.exempt_until = Some(now + EXEMPT_TTL);
+            webhook::notify("exempted", &[("server", &p.key.daddr.to_string())]);
+        }
+    }
+
+    fn decide(&mut self, key: &FlowKey, seq: u32, now: Instant) -> Decision {
+        self.last_seen = now;
+
+        if self.passed.is_some_and(|p| p.key == *key && p.seq == seq) {
+            self.fail(now, "ClientHello retransmitted");
+            return Decision::Desync;
+        }
+        self.settle(now);
+
+        let pass = |probe| Some(Passed { key: *key, seq, at: now, probe });
+
+        if self.exempt_until.is_some_and(|t| now < t) {
+            if self.passed.is_none() {
+                self.passed = pass(false);
+            }
+            return Decision::Exempt;
+        }
+
+        self.hellos += 1;
+        if self.hold_until.is_some_and(|t| now < t)
+            || self.passed.is_some()
+            || !self.hellos.is_multiple_of(PROBE_EVERY) {
+            return Decision::Desync;
+        }
+
+        self.passed = pass(true);
+        Decision::Probe
+    }
+
+    /// The server reset flow `key` (client to server).
+    fn reset(&mut self, key: &FlowKey, now: Instant) {
+        if self.passed.is_some_and(|p| p.key == *key && now.duration_since(p.at) < PROBE_WINDOW) {
+            self.fail(now, "connection reset");
+        }
+    }
+}
+
+struct DestTab {
+    dests: HashMap<IpAddr, Dest>,
+    last_sweep: Instant,
+}
+
+impl DestTab {
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+
+        let before = self.dests.len();
+        self.dests.retain(|_, d| now.duration_since(d.last_seen) < DEST_TTL);
+        budget::release((before - self.dests.len()) * DEST_COST);
+        self.last_sweep = now;
+    }
+
+    /// Return the entry of `daddr`, or None if it cannot be made within
+    /// the budget.
+    fn entry(&mut self, daddr: IpAddr, now: Instant) -> Option<&mut Dest> {
+        self.sweep(now);
+
+        if !self.dests.contains_key(&daddr) {
+            while !budget::try_charge(DEST_COST) {
+                let victims = budget::lru_victims(
+                    self.dests.iter().map(|(a, d)| (d.last_seen, *a)).collect());
+                if victims.is_empty() {
+                    stats::COUNTERS.budget_passed.inc();
+                    return None;
+                }
+                for a in &victims {
+                    self.dests.remove(a);
+                }
+                budget::release(victims.len() * DEST_COST);
+                stats::COUNTERS.budget_evicted.add(victims.len() as u64);
+            }
+        }
+
+        Some(self.dests.entry(daddr).or_insert_with(|| Dest::new(now)))
+    }
+}
+
+static D_TAB: OnceLock<Mutex<DestTab>> = OnceLock::new();
+
+fn dtab() -> MutexGuard<'static, DestTab> {
+    D_TAB.get_or_init(|| Mutex::new(DestTab { dests: HashMap::new(), last_sweep: Instant::now() }))
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What to do with ClientHello `seq` on `key`
+pub fn decide(key: &FlowKey, seq: u32) -> Decision {
+    let now = Instant::now();
+    dtab().entry(key.daddr, now).map_or(Decision::Desync, |d| d.decide(key, seq, now))
+}
+
+/// The server has reset flow `key` (client to server).
+pub fn reset(key: &FlowKey) {
+    let now = Instant::now();
+    if let Some(d) = dtab().dests.get_mut(&key.daddr) {
+        d.reset(key, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(sport: u16) -> FlowKey {
+        FlowKey {
+            saddr: "192.0.2.1".parse().unwrap(),
+            daddr: "198.51.100.1".parse().unwrap(),
+            sport,
+            dport: 443,
+        }
+    }
+
+    #[test]
+    fn test_decide() {
+        let mut d = Dest::new(Instant::now());
+        let mut now = Instant::now();
+        let mut sport = 40000;
+
+        let mut next = |d: &mut Dest, now: Instant| {
+            sport += 1;
+            (key(sport), d.decide(&key(sport), 1, now))
+        };
+
+        // Probe every PROBE_EVERY, exempt after EXEMPT_AFTER successes
+        for _ in 0..EXEMPT_AFTER {
+            for _ in 1..PROBE_EVERY {
+                assert_eq!(next(&mut d, now).1, Decision::Desync);
+            }
+            assert_eq!(next(&mut d, now).1, Decision::Probe);
+            now += PROBE_WINDOW;
+        }
+        let (k, decision) = next(&mut d, now);
+        assert_eq!(decision, Decision::Exempt);
+
+        // Retransmission of an exempted one revokes it and is desynced
+        assert_eq!(d.decide(&k, 1, now), Decision::Desync);
+        assert!(d.exempt_until.is_none());
+
+        // No probes while held
+        for _ in 0..PROBE_EVERY * 2 {
+            assert_eq!(next(&mut d, now).1, Decision::Desync);
+        }
+
+        // Reset of a probe fails it
+        let mut d = Dest::new(now);
+        for _ in 1..PROBE_EVERY {
+            next(&mut d, now);
+        }
+        let (k, decision) = next(&mut d, now);
+        assert_eq!(decision, Decision::Probe);
+        d.reset(&k, now);
+        assert!(d.passed.is_none() && d.hold_until.is_some());
+    }
+}
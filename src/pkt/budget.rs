@@ -0,0 +1,69 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--max-buffer-mb`: memory budget shared by buffered packets and
+//! per-flow tables
+//!
+//! Sizes are estimates (entry size plus heap data), not allocator
+//! truth; the point is that nothing grows without bound. Tables evict
+//! their least recently used entries to make room, and a ClientHello
+//! that cannot be buffered is passed unchanged.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::opt;
+
+static USED: AtomicUsize = AtomicUsize::new(0);
+
+/// Reserve `bytes`; false if that would exceed the budget.
+pub fn try_charge(bytes: usize) -> bool {
+    let limit = opt::max_buffer_bytes();
+
+    USED.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+        let new = used.saturating_add(bytes);
+        (limit == 0 || new <= limit).then_some(new)
+    }).is_ok()
+}
+
+/// Reserve `bytes` even over the budget.
+pub fn charge(bytes: usize) {
+    USED.fetch_add(bytes, Ordering::Relaxed);
+}
+
+pub fn release(bytes: usize) {
+    USED.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+pub fn used() -> usize {
+    USED.load(Ordering::Relaxed)
+}
+
+/// Keys of about the oldest 1/8 (at least one) of `entries` (last use,
+/// key), to evict in one go so that a full table is not rescanned on
+/// every insert.
+pub fn lru_victims<T: Ord, K>(mut entries: Vec<(T, K)>) -> Vec<K> {
+    if entries.is_empty() {
+        return Vec::new();
+    }
+
+    let n = (entries.len() / 8).max(1);
+    entries.select_nth_unstable_by(n - 1, |a, b| a.0.cmp(&b.0));
+    entries.truncate(n);
+    entries.into_iter().map(|(_, k)| k).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lru_victims() {
+        assert!(lru_victims::<u32, u32>(Vec::new()).is_empty());
+        assert_eq!(lru_victims(vec![(5, 'a')]), vec!['a']);
+
+        let stamps = [9, 3, 15, 1, 7, 12, 4, 8, 11, 2, 14, 6, 13, 0, 10, 5];
+        let mut v = lru_victims(stamps.iter().enumerate().map(|(i, &t)| (t, i)).collect());
+        v.sort();
+        assert_eq!(v, vec![3, 13]);         // stamps 1 and 0
+    }
+}
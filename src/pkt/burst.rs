@@ -0,0 +1,161 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Bursts of ClientHellos from a client to one host
+//!
+//! Browsers open several connections to a host at once. With
+//! `--coalesce`, they are split with the plan of the first one instead
+//! of each computing its own (and, with `--split-random`, each showing
+//! a different boundary to the DPI). With `--burst-limit`, only the
+//! first K of a burst are desynced.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::opt;
+
+/// A burst lasts this long from its first ClientHello
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Bursts kept at most; over it, the ended ones are dropped, or else
+/// the oldest.
+const CAP: usize = 1 << 10;
+
+/// How a ClientHello of a burst is desynced
+#[derive(Clone)]
+pub struct Plan {
+    pub order: Vec<opt::Segment>,
+    pub fake: bool,
+
+    /// Profile by `--client-profile` or `--strategy-rotate`
+    pub profile: Option<&'static str>,
+}
+
+struct Burst {
+    started: Instant,
+
+    /// ClientHellos seen in it
+    count: u32,
+
+    /// Of the first one, with `--coalesce`
+    plan: Option<Plan>,
+}
+
+pub enum Join {
+    /// Over `--burst-limit`; not to be desynced
+    Over,
+
+    /// Plan of the burst to follow, if any
+    Member(Option<Plan>),
+}
+
+struct BurstTab {
+    bursts: HashMap<(IpAddr, String), Burst>,
+}
+
+impl BurstTab {
+    fn new() -> Self {
+        Self { bursts: HashMap::new() }
+    }
+
+    fn join(&mut self, saddr: IpAddr, host: &str, limit: u32, now: Instant) -> Join {
+        let key = (saddr, host.to_string());
+
+        if self.bursts.len() >= CAP && !self.bursts.contains_key(&key) {
+            self.bursts.retain(|_, b| now.saturating_duration_since(b.started) < WINDOW);
+            if self.bursts.len() >= CAP
+                && let Some(oldest) = self.bursts.iter().min_by_key(|(_, b)| b.started).map(|(k, _)| k.clone()) {
+                self.bursts.remove(&oldest);
+            }
+        }
+
+        let burst = self.bursts.entry(key)
+            .or_insert(Burst { started: now, count: 0, plan: None });
+        if now.saturating_duration_since(burst.started) >= WINDOW {
+            *burst = Burst { started: now, count: 0, plan: None };
+        }
+
+        burst.count += 1;
+        if limit > 0 && burst.count > limit {
+            return Join::Over;
+        }
+        Join::Member(burst.plan.clone())
+    }
+
+    fn share(&mut self, saddr: IpAddr, host: &str, plan: Plan) {
+        if let Some(burst) = self.bursts.get_mut(&(saddr, host.to_string())) {
+            burst.plan.get_or_insert(plan);
+        }
+    }
+}
+
+static B_TAB: OnceLock<Mutex<BurstTab>> = OnceLock::new();
+
+fn btab() -> std::sync::MutexGuard<'static, BurstTab> {
+    B_TAB.get_or_init(|| Mutex::new(BurstTab::new()))
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Whether ClientHellos are tracked by burst at all
+pub fn enabled() -> bool {
+    opt::coalesce() || opt::burst_limit() > 0
+}
+
+/// Count a ClientHello from `saddr` to `host` (server name, or address
+/// if none) in its burst.
+pub fn join(saddr: IpAddr, host: &str) -> Join {
+    btab().join(saddr, host, opt::burst_limit(), Instant::now())
+}
+
+/// Make `plan` that of the burst, if it has none yet and `--coalesce`.
+pub fn share(saddr: IpAddr, host: &str, plan: Plan) {
+    if opt::coalesce() {
+        btab().share(saddr, host, plan);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_burst() {
+        let mut tab = BurstTab::new();
+        let t0 = Instant::now();
+        let a: IpAddr = "192.168.1.2".parse().unwrap();
+        let b: IpAddr = "192.168.1.3".parse().unwrap();
+        let plan = |o: u32| Plan { order: vec![opt::Segment(0, o), opt::Segment(o, u32::MAX)], fake: true, profile: None };
+        let first_boundary = |j: Join| match j {
+            Join::Member(p) => p.map(|p| p.order[0].1),
+            Join::Over => panic!("over limit"),
+        };
+
+        assert_eq!(first_boundary(tab.join(a, "example.com", 3, t0)), None);
+        tab.share(a, "example.com", plan(5));
+        tab.share(a, "example.com", plan(7));      // first plan stays
+        assert_eq!(first_boundary(tab.join(a, "example.com", 3, t0)), Some(5));
+        assert_eq!(first_boundary(tab.join(b, "example.com", 3, t0)), None, "per client");
+        assert_eq!(first_boundary(tab.join(a, "example.org", 3, t0)), None, "per host");
+
+        assert_eq!(first_boundary(tab.join(a, "example.com", 3, t0)), Some(5));
+        assert!(matches!(tab.join(a, "example.com", 3, t0), Join::Over));
+
+        // A new burst after the window
+        let t1 = t0 + WINDOW;
+        assert_eq!(first_boundary(tab.join(a, "example.com", 3, t1)), None);
+        assert_eq!(first_boundary(tab.join(a, "example.com", 0, t1)), None, "no limit");
+
+        // Full of bursts still on: the oldest makes room
+        for i in 0..CAP {
+            tab.join(b, &format!("host{i}"), 3, t1 + Duration::from_micros(i as u64));
+        }
+        assert_eq!(tab.bursts.len(), CAP);
+        tab.join(b, "new.example", 3, t1 + Duration::from_millis(100));
+        assert_eq!(tab.bursts.len(), CAP);
+        assert!(tab.bursts.contains_key(&(b, "new.example".to_string())));
+        assert!(!tab.bursts.contains_key(&(b, "host0".to_string())));
+    }
+}
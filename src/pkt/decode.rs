@@ -0,0 +1,290 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `dpibreak decode FILE [OPTIONS]`: offline look at captured packets
+//!
+//! FILE is a pcap capture or hex text (one packet per block of lines
+//! separated by a blank one; `#` starts a comment and offsets ending in
+//! `:` are skipped, so [`super::trace`] dumps can be pasted as is). Each
+//! packet is shown with its parsed headers, the ClientHello it carries
+//! if any, and the packets the given options would send in its place.
+//! Nothing is sent and no state is touched beyond the loaded hops.
+
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use super::{PktView, build_plain_syn, build_segment, dnsguard, fake, fit_order, hello_order, outcome,
+            pick_profile, pmtu, trace};
+use crate::{opt, tls};
+
+pub(super) const PCAP_MAGIC: u32 = 0xa1b2c3d4;
+const PCAP_MAGIC_NSEC: u32 = 0xa1b23c4d;
+const PCAPNG_MAGIC: u32 = 0x0a0d0d0a;
+
+/// Packet read from a file, with its capture time since the epoch
+/// (zero for hex text)
+pub(super) type Captured = (Duration, Vec<u8>);
+
+/// Length of the link-layer header before the IP packet, or None if
+/// the packet is not IP.
+fn link_header_len(linktype: u32, frame: &[u8]) -> Result<Option<usize>> {
+    const ETHERTYPE_IPV4: u16 = 0x0800;
+    const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+    Ok(match linktype {
+        0 => Some(4),                     // BSD loopback
+        12 | 101 | 228 | 229 => Some(0),  // raw IP
+        113 => Some(16),                  // Linux cooked
+        276 => Some(20),                  // Linux cooked v2
+        1 => {                            // Ethernet
+            let mut at = 12;
+            loop {
+                let Some(t) = frame.get(at..at + 2) else { return Ok(None) };
+                match u16::from_be_bytes([t[0], t[1]]) {
+                    0x8100 | 0x88a8 => at += 4,
+                    ETHERTYPE_IPV4 | ETHERTYPE_IPV6 => break Some(at + 2),
+                    _ => break None,
+                }
+            }
+        }
+        _ => return Err(anyhow!("unsupported pcap link type {linktype}")),
+    })
+}
+
+/// IP packets of pcap capture `data`
+fn parse_pcap(data: &[u8]) -> Result<Vec<Captured>> {
+    let word = |at: usize, le: bool| data.get(at..at + 4).map(|b| {
+        let b = [b[0], b[1], b[2], b[3]];
+        if le { u32::from_le_bytes(b) } else { u32::from_be_bytes(b) }
+    });
+
+    let le = match word(0, true) {
+        Some(PCAP_MAGIC | PCAP_MAGIC_NSEC) => true,
+        _ if matches!(word(0, false), Some(PCAP_MAGIC | PCAP_MAGIC_NSEC)) => false,
+        _ => return Err(anyhow!("not a pcap file")),
+    };
+    let nsec = word(0, le) == Some(PCAP_MAGIC_NSEC);
+    let linktype = word(20, le).ok_or_else(|| anyhow!("truncated pcap header"))? & 0x0fff_ffff;
+
+    let mut pkts = Vec::new();
+    let mut at = 24;
+    while at < data.len() {
+        let truncated = || anyhow!("truncated pcap record at {at}");
+        let sec = word(at, le).ok_or_else(truncated)?;
+        let frac = word(at + 4, le).ok_or_else(truncated)?;
+        let caplen = word(at + 8, le).ok_or_else(truncated)?;
+        let start = at + 16;
+        let frame = data.get(start..start + caplen as usize)
+            .ok_or_else(truncated)?;
+        at = start + caplen as usize;
+
+        let time = Duration::from_secs(sec.into())
+            + if nsec { Duration::from_nanos(frac.into()) } else { Duration::from_micros(frac.into()) };
+        if let Some(skip) = link_header_len(linktype, frame)?
+            && let Some(ip) = frame.get(skip..) {
+            pkts.push((time, ip.to_vec()));
+        }
+    }
+
+    Ok(pkts)
+}
+
+/// Packets of hex text `text`
+fn parse_hex(text: &str) -> Result<Vec<Vec<u8>>> {
+    let mut pkts = Vec::new();
+    let mut cur = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            if !cur.is_empty() {
+                pkts.push(std::mem::take(&mut cur));
+            }
+            continue;
+        }
+
+        for tok in line.split_whitespace().filter(|t| !t.ends_with(':')) {
+            if tok.len() % 2 != 0 {
+                return Err(anyhow!("line {}: odd number of hex digits in {tok}", n + 1));
+            }
+            for i in (0..tok.len()).step_by(2) {
+                let b = tok.get(i..i + 2)
+                    .and_then(|d| u8::from_str_radix(d, 16).ok())
+                    .ok_or_else(|| anyhow!("line {}: not hex: {tok}", n + 1))?;
+                cur.push(b);
+            }
+        }
+    }
+    if !cur.is_empty() {
+        pkts.push(cur);
+    }
+
+    Ok(pkts)
+}
+
+pub(super) fn read_packets(file: &Path) -> Result<Vec<Captured>> {
+    let data = std::fs::read(file).with_context(|| format!("cannot read {}", file.display()))?;
+
+    let is_pcap = |m: u32| [PCAP_MAGIC, PCAP_MAGIC_NSEC].contains(&m);
+
+    match data.get(..4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])) {
+        Some(PCAPNG_MAGIC) => Err(anyhow!("pcapng is not supported; convert with `editcap -F pcap`")),
+        Some(m) if is_pcap(m) || is_pcap(m.swap_bytes()) => parse_pcap(&data),
+        _ => Ok(parse_hex(&String::from_utf8_lossy(&data))?
+                .into_iter()
+                .map(|pkt| (Duration::ZERO, pkt))
+                .collect()),
+    }
+}
+
+fn print_hex(data: &[u8]) {
+    for line in trace::hex_lines(data) {
+        println!("    {line}");
+    }
+}
+
+fn decode(pkt: &[u8]) -> Result<()> {
+    if dnsguard::is_udp(pkt) {
+        println!("  UDP; looked at only by --dns-guard");
+        return Ok(());
+    }
+
+    let view = PktView::from_raw(pkt)?;
+    let tcp = &view.tcp;
+    let payload = tcp.payload();
+
+    println!("  ip: v{} {} > {} ttl={}{}",
+             if view.ip_id().is_some() { 4 } else { 6 },
+             view.saddr(), view.daddr(), view.ttl(),
+             view.ip_id().map_or(String::new(), |id| format!(" id={id}")));
+
+    let flags: String = [(tcp.syn(), 'S'), (tcp.ack(), 'A'), (tcp.psh(), 'P'),
+                         (tcp.fin(), 'F'), (tcp.rst(), 'R'), (tcp.urg(), 'U')]
+        .iter().filter(|f| f.0).map(|f| f.1).collect();
+    println!("  tcp: {} > {} seq={} ack={} flags={flags} win={} len={}",
+             tcp.source_port(), tcp.destination_port(), tcp.sequence_number(),
+             tcp.acknowledgment_number(), tcp.window_size(), payload.len());
+
+    if !tls::is_client_hello(payload) {
+        println!("  not a ClientHello; accepted unchanged");
+        return Ok(());
+    }
+    println!("  ClientHello sni={}", tls::server_name(payload).unwrap_or("none"));
+
+    let client = opt::client_profile().resolve(view.saddr());
+    if let Some(c) = client && c.profile.off {
+        println!("  client profile {c}; accepted unchanged");
+        return Ok(());
+    }
+
+    let mut buf = Vec::new();
+
+    if tcp.syn() {
+        build_plain_syn(&view, &mut buf)?;
+        println!("  on SYN (TCP Fast Open); sent as SYN without data: {} bytes", buf.len());
+        print_hex(&buf);
+        return Ok(());
+    }
+
+    // Not asking --verdict-hook, which may act on the query
+    let (profile, profile_order, _) = pick_profile(None, client);
+    if let Some(p) = profile {
+        println!("  profile {}", p.name);
+    }
+    let order = hello_order(profile_order, payload);
+    let (order, clamped) = fit_order(&order, payload);
+    if clamped {
+        println!("  split positions past the payload clamped");
+    }
+    let headers = (pkt.len() - payload.len()) as u32;
+    let order = pmtu::fit(&order, view.daddr(), payload.len() as u32, headers).unwrap_or(order);
+    let fake = profile.and_then(|p| p.fake).unwrap_or(opt::fake());
+    println!("  desync with {}, segments {}", outcome::strategy(),
+             order.iter().map(ToString::to_string).collect::<Vec<_>>().join(" "));
+
+    let payload_len = payload.len() as u32;
+    let mut nth = 0;
+
+    for &opt::Segment(start, end) in &order {
+        if start >= payload_len {
+            println!("  segment {} exceeds payload; skipped", opt::Segment(start, end));
+            continue;
+        }
+        let end = if end == u32::MAX || end > payload_len { None } else { Some(end) };
+        let range = |start: u32, end: Option<u32>| {
+            format!("[{start}, {})", end.map_or("end".into(), |e| e.to_string()))
+        };
+
+        if fake {
+            for (fake_start, fake_end) in fake::fake_ranges(start, end) {
+                fake::fake_clienthello(&view, fake_start, fake_end, nth, &mut buf)?;
+                println!("  fake {} for {}: {} bytes", range(fake_start, fake_end), range(start, end),
+                         buf.len());
+                print_hex(&buf);
+                nth += 1;
+            }
+        }
+        build_segment(&view, start, end, nth, &mut buf)?;
+        println!("  segment {}: {} bytes", range(start, end), buf.len());
+        print_hex(&buf);
+        nth += 1;
+    }
+
+    Ok(())
+}
+
+/// `dpibreak decode FILE [OPTIONS]`
+pub fn command(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let Some(file) = args.next() else {
+        return Err(anyhow!("decode: usage: dpibreak decode FILE [OPTIONS]"));
+    };
+    opt::Config::parse(args)?.install();
+
+    let pkts = read_packets(Path::new(&file)).context("decode")?;
+    for (i, (_, pkt)) in pkts.iter().enumerate() {
+        println!("packet {}: {} bytes", i + 1, pkt.len());
+        if let Err(e) = decode(pkt) {
+            println!("  cannot decode: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hex() {
+        let text = "# from --trace\n0000: 45 00 00\n0010: 14\n\n\n  de ad beef # x\n";
+        assert_eq!(parse_hex(text).unwrap(), vec![vec![0x45, 0, 0, 0x14], vec![0xde, 0xad, 0xbe, 0xef]]);
+        assert!(parse_hex("abc\n").is_err());
+        assert!(parse_hex("zz\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_pcap() {
+        let mut data = Vec::new();
+        for w in [PCAP_MAGIC, 0x0004_0002, 0, 0, 65535, 1] {
+            data.extend_from_slice(&w.to_le_bytes());
+        }
+
+        // Ethernet with a VLAN tag, then one that is not IP
+        let mut frame = vec![0; 12];
+        frame.extend_from_slice(&[0x81, 0x00, 0, 1, 0x08, 0x00, 0x45, 0x00]);
+        let arp = [vec![0; 12], vec![0x08, 0x06, 0, 1]].concat();
+        for f in [&frame, &arp] {
+            for w in [7, 250_000, f.len() as u32, f.len() as u32] {
+                data.extend_from_slice(&w.to_le_bytes());
+            }
+            data.extend_from_slice(f);
+        }
+
+        assert_eq!(parse_pcap(&data).unwrap(), vec![(Duration::from_millis(7250), vec![0x45, 0x00])]);
+        assert!(parse_pcap(&data[..data.len() - 1]).is_err());
+        assert!(parse_pcap(b"not a capture file").is_err());
+    }
+}
@@ -0,0 +1,280 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--dns-guard`: drop forged DNS responses
+//!
+//! An on-path injector answers a query faster than the real resolver,
+//! so the client takes the forged answer and ignores the genuine one
+//! arriving later. Outbound queries are recorded by (resolver, id,
+//! qname); a response to a recorded query is dropped if it
+//!
+//! - arrives much faster than the resolver has ever answered,
+//! - has a TTL implying a path length different from the resolver's, or
+//! - is IPv4 with identification 0 and DF unset, which real stacks
+//!   don't send but common injectors do,
+//!
+//! and the query is kept so that the genuine answer passes. Baselines
+//! of a resolver are learned from the answers passed, so the very first
+//! answer from a resolver is only checked by identification. Queries
+//! are not recorded (and their answers pass unchecked) while
+//! [`super::budget`] is exhausted.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use etherparse::{IpSlice, UdpSlice};
+
+use super::budget;
+use crate::stats;
+
+const DNS_PORT: u16 = 53;
+
+/// Unanswered queries are forgotten after this time.
+const QUERY_TTL: Duration = Duration::from_secs(5);
+
+/// Sweep expired queries at most once per this interval.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A response faster than 1/[`FAST_FACTOR`] of the fastest genuine one
+/// is implausible.
+const FAST_FACTOR: u32 = 4;
+
+/// Hops a response may differ from the resolver's.
+const HOP_DELTA: u8 = 2;
+
+#[derive(PartialEq, Eq, Hash, Clone)]
+struct QueryKey {
+    resolver: IpAddr,
+    id: u16,
+    qname: String,
+}
+
+impl QueryKey {
+    /// Estimated memory of a query entry
+    fn cost(&self) -> usize {
+        std::mem::size_of::<(QueryKey, Instant)>() + 16 + self.qname.len()
+    }
+}
+
+struct Resolver {
+    hop: u8,
+    min_rtt: Duration,
+}
+
+struct DnsTab {
+    queries: HashMap<QueryKey, Instant>,
+    resolvers: HashMap<IpAddr, Resolver>,
+    last_sweep: Instant,
+}
+
+impl DnsTab {
+    fn new() -> Self {
+        Self { queries: HashMap::new(), resolvers: HashMap::new(), last_sweep: Instant::now() }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+
+        let mut released = 0;
+        self.queries.retain(|key, sent| {
+            let keep = now.duration_since(*sent) < QUERY_TTL;
+            if !keep {
+                released += key.cost();
+            }
+            keep
+        });
+        budget::release(released);
+        self.last_sweep = now;
+    }
+
+    /// Record query `key`, evicting the oldest ones if over budget.
+    fn insert(&mut self, key: QueryKey, now: Instant) {
+        if let Some(sent) = self.queries.get_mut(&key) {
+            *sent = now;        // sent again; already charged
+            return;
+        }
+
+        while !budget::try_charge(key.cost()) {
+            let victims = budget::lru_victims(
+                self.queries.iter().map(|(k, t)| (*t, k.clone())).collect());
+            if victims.is_empty() {
+                stats::COUNTERS.budget_passed.inc();
+                return;
+            }
+
+            for k in &victims {
+                self.queries.remove(k);
+                budget::release(k.cost());
+            }
+            stats::COUNTERS.budget_evicted.add(victims.len() as u64);
+        }
+
+        self.queries.insert(key, now);
+    }
+
+    fn remove(&mut self, key: &QueryKey) {
+        if self.queries.remove(key).is_some() {
+            budget::release(key.cost());
+        }
+    }
+}
+
+static DNS_TAB: OnceLock<Mutex<DnsTab>> = OnceLock::new();
+
+fn dtab() -> MutexGuard<'static, DnsTab> {
+    DNS_TAB.get_or_init(|| Mutex::new(DnsTab::new()))
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Whether raw IP packet `pkt` is UDP, read directly from the header.
+pub fn is_udp(pkt: &[u8]) -> bool {
+    const IPPROTO_UDP: u8 = 17;
+
+    match pkt.first().map(|b| b >> 4) {
+        Some(4) => pkt.get(9) == Some(&IPPROTO_UDP),
+        Some(6) => pkt.get(6) == Some(&IPPROTO_UDP),
+        _ => false,
+    }
+}
+
+/// Return (id, is_response, lowercase qname) of DNS message `msg`.
+fn parse(msg: &[u8]) -> Option<(u16, bool, String)> {
+    let id = u16::from_be_bytes([*msg.first()?, *msg.get(1)?]);
+    let is_response = msg.get(2)? & 0x80 != 0;
+    let qdcount = u16::from_be_bytes([*msg.get(4)?, *msg.get(5)?]);
+
+    if qdcount == 0 {
+        return None;
+    }
+
+    let mut qname = String::new();
+    let mut i = 12;
+
+    loop {
+        let len = *msg.get(i)? as usize;
+        if len == 0 {
+            break;
+        }
+        if len & 0xc0 != 0 {
+            return None;        // no compression expected in question
+        }
+
+        let label = msg.get(i + 1..i + 1 + len)?;
+        if !qname.is_empty() {
+            qname.push('.');
+        }
+        qname.push_str(&String::from_utf8_lossy(label).to_ascii_lowercase());
+        i += 1 + len;
+    }
+
+    Some((id, is_response, qname))
+}
+
+/// Why response `ip` from `resolver` is regarded as forged, if it is.
+fn forged_reason(ip: &IpSlice, rtt: Duration, resolver: Option<&Resolver>, hop: u8) -> Option<String> {
+    if let IpSlice::Ipv4(v4) = ip {
+        let hdr = v4.header();
+        if hdr.identification() == 0 && !hdr.dont_fragment() {
+            return Some("ip id 0 without DF".into());
+        }
+    }
+
+    let r = resolver?;
+
+    if rtt < r.min_rtt / FAST_FACTOR {
+        return Some(format!("rtt {rtt:?}, fastest genuine {:?}", r.min_rtt));
+    }
+
+    if hop.abs_diff(r.hop) > HOP_DELTA {
+        return Some(format!("hop {hop}, expected {}", r.hop));
+    }
+
+    None
+}
+
+/// Record outbound DNS query or judge inbound response `pkt`. Return
+/// Ok(true) if it is a forged response to drop.
+pub fn handle(pkt: &[u8]) -> Result<bool> {
+    let ip = IpSlice::from_slice(pkt)?;
+    let udp = UdpSlice::from_slice(ip.payload().payload)?;
+
+    let Some((id, is_response, qname)) = parse(udp.payload()) else {
+        return Ok(false);
+    };
+
+    let now = Instant::now();
+    let mut tab = dtab();
+    tab.sweep(now);
+
+    if !is_response && udp.destination_port() == DNS_PORT {
+        let resolver = ip.destination_addr();
+        tab.insert(QueryKey { resolver, id, qname }, now);
+        return Ok(false);
+    }
+
+    if !is_response || udp.source_port() != DNS_PORT {
+        return Ok(false);
+    }
+
+    let key = QueryKey { resolver: ip.source_addr(), id, qname };
+    let Some(&sent) = tab.queries.get(&key) else {
+        return Ok(false);       // not ours, or already answered
+    };
+
+    let ttl = match &ip {
+        IpSlice::Ipv4(v4) => v4.header().ttl(),
+        IpSlice::Ipv6(v6) => v6.header().hop_limit(),
+    };
+    let hop = super::infer_hops(ttl);
+    let rtt = now.duration_since(sent);
+
+    if let Some(reason) = forged_reason(&ip, rtt, tab.resolvers.get(&key.resolver), hop) {
+        crate::debug!("dns-guard: {} from {}: {reason}; drop", key.qname, key.resolver);
+        stats::COUNTERS.dns_forged.inc();
+        return Ok(true);
+    }
+
+    tab.remove(&key);
+    tab.resolvers.entry(key.resolver)
+        .and_modify(|r| { r.hop = hop; r.min_rtt = r.min_rtt.min(rtt); })
+        .or_insert(Resolver { hop, min_rtt: rtt });
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut msg = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        msg.extend_from_slice(b"\x03www\x07Example\x03com\x00\x00\x01\x00\x01");
+
+        assert_eq!(parse(&msg), Some((0x1234, false, "www.example.com".into())));
+
+        msg[2] |= 0x80;
+        assert_eq!(parse(&msg), Some((0x1234, true, "www.example.com".into())));
+
+        assert_eq!(parse(&msg[..20]), None);    // truncated qname
+        msg[5] = 0;
+        assert_eq!(parse(&msg), None);          // no question
+    }
+
+    #[test]
+    fn test_is_udp() {
+        let mut v4 = [0u8; 28];
+        v4[0] = 0x45;
+        v4[9] = 17;
+        assert!(is_udp(&v4));
+        v4[9] = 6;
+        assert!(!is_udp(&v4));
+        assert!(!is_udp(&[]));
+    }
+}
@@ -5,12 +5,13 @@ use anyhow::Result;
 
 use crate::opt;
 use crate::pkt::hoptab;
+use crate::rand;
 
-use super::PktView;
+use super::{Override, PktView};
 
 /// www.microsoft.com
 /// Stolen from github.com/bol-van/zapret/blob/master/nfq/desync.c
-const DEFAULT_FAKE_TLS_CLIENTHELLO: &'static [u8] = &[
+pub const DEFAULT_FAKE_TLS_CLIENTHELLO: &'static [u8] = &[
     0x16, 0x03, 0x01, 0x02, 0xa3, 0x01, 0x00, 0x02, 0x9f, 0x03, 0x03, 0x41,
     0x88, 0x82, 0x2d, 0x4f, 0xfd, 0x81, 0x48, 0x9e, 0xe7, 0x90, 0x65, 0x1f,
     0xba, 0x05, 0x7b, 0xff, 0xa7, 0x5a, 0xf9, 0x5b, 0x8a, 0x8f, 0x45, 0x8b,
@@ -76,10 +77,80 @@ fn daddr_hop(view: &PktView) -> hoptab::HopResult<u8> {
     hoptab::find(view.daddr())
 }
 
+/// Window of the fake: the original one varied by up to 1/8, so that
+/// fakes do not share a constant value.
+fn random_window(orig: u16) -> u16 {
+    let d = u64::from(orig / 8);
+    (u64::from(orig) - d + rand::range(0, 2 * d)).min(u16::MAX.into()) as u16
+}
+
+/// Reorder TCP options `opts` at random keeping its length; NOP/EOL
+/// padding is put back on random option boundaries. Return None if
+/// `opts` is malformed.
+fn shuffle_tcp_options(opts: &[u8]) -> Option<Vec<u8>> {
+    const EOL: u8 = 0;
+    const NOP: u8 = 1;
+
+    let mut units: Vec<&[u8]> = Vec::new();
+    let mut i = 0;
+
+    while i < opts.len() {
+        match opts[i] {
+            EOL | NOP => { i += 1; }
+            _ => {
+                let len = *opts.get(i + 1)? as usize;
+                if len < 2 {
+                    return None;
+                }
+                units.push(opts.get(i..i + len)?);
+                i += len;
+            }
+        }
+    }
+
+    // Fisher-Yates
+    for i in (1..units.len()).rev() {
+        units.swap(i, rand::range(0, i as u64) as usize);
+    }
+
+    let n_pad = opts.len() - units.iter().map(|u| u.len()).sum::<usize>();
+    let mut pads = vec![0usize; units.len() + 1];
+    for _ in 0..n_pad {
+        pads[rand::range(0, units.len() as u64) as usize] += 1;
+    }
+
+    let mut out = Vec::with_capacity(opts.len());
+    for (pad, unit) in pads.iter().zip(units.iter().map(Some).chain([None])) {
+        out.extend(std::iter::repeat_n(NOP, *pad));
+        if let Some(unit) = unit {
+            out.extend_from_slice(unit);
+        }
+    }
+
+    Some(out)
+}
+
+/// [`start`, `end`) split at `at` if it falls within it; `len` is
+/// taken for a missing `end`.
+fn split_range(start: u32, end: Option<u32>, at: Option<u32>, len: u32) -> Vec<(u32, Option<u32>)> {
+    match at {
+        Some(at) if at > start && at < end.unwrap_or(len).min(len) => vec![(start, Some(at)), (at, end)],
+        _ => vec![(start, end)],
+    }
+}
+
+/// Ranges of the fakes sent before segment [`start`, `end`): two if
+/// `--fake-split` falls within it, for DPIs that reassemble fakes as
+/// they do the real segments, or else the whole range.
+pub fn fake_ranges(start: u32, end: Option<u32>) -> Vec<(u32, Option<u32>)> {
+    split_range(start, end, opt::fake_split(), DEFAULT_FAKE_TLS_CLIENTHELLO.len() as u32)
+}
+
 pub fn fake_clienthello(
     view: &PktView,
     start: u32,
     end: Option<u32>,
+    nth: u16,
     out_buf: &mut Vec<u8>
 ) -> Result<()> {
 
@@ -106,10 +177,62 @@ pub fn fake_clienthello(
         opt::fake_ttl()
     };
 
-    super::build_packet(
-        view, start, end, out_buf,
-        Some(DEFAULT_FAKE_TLS_CLIENTHELLO),
-        Some(ttl),
-        tcp_checksum
-    )
+    // With a fingerprint, look like the stack's own packets; otherwise
+    // at least don't let fakes share a constant fingerprint.
+    if super::fingerprint::get().is_some() {
+        return super::build_packet(view, start, end, out_buf, &Override {
+            payload: Some(DEFAULT_FAKE_TLS_CLIENTHELLO),
+            ttl: Some(ttl),
+            tcp_checksum,
+            tcp_flags: opt::fake_tcp_flags(),
+            ..super::fingerprint_override(view, nth)
+        });
+    }
+
+    let tcp_options = shuffle_tcp_options(view.tcp.options());
+
+    super::build_packet(view, start, end, out_buf, &Override {
+        payload: Some(DEFAULT_FAKE_TLS_CLIENTHELLO),
+        ttl: Some(ttl),
+        tcp_checksum,
+        ip_id: Some(rand::u64() as u16),
+        window: Some(random_window(view.tcp.window_size())),
+        tcp_options: tcp_options.as_deref(),
+        tcp_flags: opt::fake_tcp_flags(),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shuffle_tcp_options() {
+        // NOP, NOP, timestamps, MSS, EOL
+        let opts = [1, 1, 8, 10, 1, 2, 3, 4, 5, 6, 7, 8, 2, 4, 5, 0xb4, 0, 0];
+
+        for _ in 0..100 {
+            let out = shuffle_tcp_options(&opts).unwrap();
+            assert_eq!(out.len(), opts.len());
+
+            let ts = out.windows(10).filter(|w| *w == &opts[2..12]).count();
+            let mss = out.windows(4).filter(|w| *w == &opts[12..16]).count();
+            assert_eq!((ts, mss), (1, 1), "{out:?}");
+        }
+
+        assert_eq!(shuffle_tcp_options(&[]), Some(vec![]));
+        assert_eq!(shuffle_tcp_options(&[8, 10, 1]), None);
+        assert_eq!(shuffle_tcp_options(&[8, 0]), None);
+    }
+
+    #[test]
+    fn test_split_range() {
+        assert_eq!(split_range(0, Some(5), Some(2), 517), [(0, Some(2)), (2, Some(5))]);
+        assert_eq!(split_range(5, None, Some(100), 517), [(5, Some(100)), (100, None)]);
+        assert_eq!(split_range(0, Some(5), Some(5), 517), [(0, Some(5))]);
+        assert_eq!(split_range(5, None, Some(2), 517), [(5, None)]);
+        assert_eq!(split_range(5, None, Some(600), 517), [(5, None)]);
+        assert_eq!(split_range(0, None, None, 517), [(0, None)]);
+    }
 }
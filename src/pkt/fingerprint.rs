@@ -0,0 +1,75 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! IP header traits of the sending OS applied to generated packets
+//!
+//! Segments are rebuilt from the original packet, so most fields are
+//! already what the stack would send. What gives them away is what we
+//! have to pick ourselves: the IPv4 identification (copied as is to
+//! every segment, while the stack increments it per packet) and, for a
+//! preset other than the host OS, its initial TTL and DF bit. With a
+//! fingerprint, fakes also keep the original window and TCP options
+//! instead of randomized ones (see [`super::fake`]).
+
+use std::sync::OnceLock;
+
+use crate::opt::{self, FingerprintPreset};
+
+pub struct Fingerprint {
+    /// Initial IPv4 TTL
+    pub ttl: u8,
+
+    /// Initial IPv6 hop limit
+    pub hop_limit: u8,
+
+    /// IPv4 don't fragment
+    pub df: bool,
+}
+
+const LINUX: Fingerprint = Fingerprint { ttl: 64, hop_limit: 64, df: true };
+const WINDOWS: Fingerprint = Fingerprint { ttl: 128, hop_limit: 128, df: true };
+const MACOS: Fingerprint = Fingerprint { ttl: 64, hop_limit: 64, df: true };
+
+impl Fingerprint {
+    /// Read the settings of the host stack.
+    #[cfg(target_os = "linux")]
+    fn host() -> Self {
+        fn sysctl(path: &str) -> Option<u8> {
+            std::fs::read_to_string(path).ok()?.trim().parse().ok()
+        }
+
+        Self {
+            ttl: sysctl("/proc/sys/net/ipv4/ip_default_ttl").unwrap_or(LINUX.ttl),
+            hop_limit: sysctl("/proc/sys/net/ipv6/conf/default/hop_limit").unwrap_or(LINUX.hop_limit),
+            df: sysctl("/proc/sys/net/ipv4/ip_no_pmtu_disc").is_none_or(|v| v == 0),
+        }
+    }
+
+    #[cfg(windows)]
+    fn host() -> Self {
+        WINDOWS
+    }
+
+    fn from_preset(preset: FingerprintPreset) -> Option<Self> {
+        match preset {
+            FingerprintPreset::None => None,
+            FingerprintPreset::Auto => Some(Self::host()),
+            FingerprintPreset::Linux => Some(LINUX),
+            FingerprintPreset::Windows => Some(WINDOWS),
+            FingerprintPreset::MacOs => Some(MACOS),
+        }
+    }
+}
+
+static FINGERPRINT: OnceLock<Option<Fingerprint>> = OnceLock::new();
+
+/// Fingerprint selected by `--fingerprint`, if any
+pub fn get() -> Option<&'static Fingerprint> {
+    FINGERPRINT.get_or_init(|| {
+        let fp = Fingerprint::from_preset(opt::fingerprint());
+        if let Some(fp) = &fp {
+            crate::info!("fingerprint: ttl={} hop_limit={} df={}", fp.ttl, fp.hop_limit, fp.df);
+        }
+        fp
+    }).as_ref()
+}
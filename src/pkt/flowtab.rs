@@ -0,0 +1,212 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Short-lived per-flow state
+//!
+//! Unlike [`super::hoptab`], which only needs the latest hop per
+//! server, this keeps a small record per TCP flow (4-tuple) for
+//! decisions that depend on what we have already done on that flow,
+//! e.g. recognizing a retransmission of a ClientHello we are still
+//! handling. Entries expire after [`FLOW_TTL`] of inactivity.
+//!
+//! It also remembers which server names each source has recently
+//! connected to, for `--once-per-host`.
+//!
+//! Entries are charged to [`super::budget`]; when it is exhausted, the
+//! least recently used flows, then host entries, are evicted early.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::budget;
+use crate::stats;
+
+/// Entries not seen for this long are dropped on sweep.
+const FLOW_TTL: Duration = Duration::from_secs(30);
+
+/// Sweep expired entries at most once per this interval.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Estimated memory of a flow entry, including hash table overhead
+const FLOW_COST: usize = std::mem::size_of::<(FlowKey, Flow)>() + 16;
+
+/// Estimated memory of a host entry for server name `sni`
+fn host_cost(sni: &str) -> usize {
+    std::mem::size_of::<(String, Instant)>() + 16 + sni.len()
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct FlowKey {
+    pub saddr: IpAddr,
+    pub daddr: IpAddr,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+/// ClientHello handled on a flow
+#[derive(Clone, Copy, Debug)]
+pub struct HandledHello {
+    /// TCP sequence number of the original segment
+    pub seq: u32,
+
+    /// When the last segment has been sent; None while sending
+    pub finished: Option<Instant>,
+}
+
+#[derive(Debug)]
+pub struct Flow {
+    last_seen: Instant,
+    pub hello: Option<HandledHello>,
+
+    /// HTTP CONNECT request has been sent on this flow (to a proxy);
+    /// the next ClientHello is the tunneled one.
+    pub connect: bool,
+}
+
+impl Flow {
+    fn new(now: Instant) -> Self {
+        Self { last_seen: now, hello: None, connect: false }
+    }
+}
+
+pub struct FlowTab {
+    flows: HashMap<FlowKey, Flow>,
+    last_sweep: Instant,
+
+    /// When a ClientHello from source address to server name has been
+    /// handled last time
+    hosts: HashMap<IpAddr, HashMap<String, Instant>>,
+    host_ttl: Duration,
+}
+
+impl FlowTab {
+    fn new() -> Self {
+        Self {
+            flows: HashMap::new(),
+            last_sweep: Instant::now(),
+            hosts: HashMap::new(),
+            host_ttl: Duration::ZERO,
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.last_sweep) < SWEEP_INTERVAL {
+            return;
+        }
+
+        let before = self.flows.len();
+        self.flows.retain(|_, f| now.duration_since(f.last_seen) < FLOW_TTL);
+        budget::release((before - self.flows.len()) * FLOW_COST);
+
+        let mut released = 0;
+        for names in self.hosts.values_mut() {
+            names.retain(|name, t| {
+                let keep = now.duration_since(*t) < self.host_ttl;
+                if !keep {
+                    released += host_cost(name);
+                }
+                keep
+            });
+        }
+        self.hosts.retain(|_, names| !names.is_empty());
+        budget::release(released);
+
+        self.last_sweep = now;
+    }
+
+    /// Evict the least recently used flows. Return false if there is
+    /// none.
+    fn evict_flows(&mut self) -> bool {
+        let victims = budget::lru_victims(
+            self.flows.iter().map(|(k, f)| (f.last_seen, *k)).collect());
+
+        for key in &victims {
+            self.flows.remove(key);
+        }
+        budget::release(victims.len() * FLOW_COST);
+        stats::COUNTERS.budget_evicted.add(victims.len() as u64);
+
+        !victims.is_empty()
+    }
+
+    /// Evict the least recently handled host entries. Return false if
+    /// there is none.
+    fn evict_hosts(&mut self) -> bool {
+        let victims = budget::lru_victims(
+            self.hosts.iter()
+                .flat_map(|(saddr, names)| names.iter().map(|(n, t)| (*t, (*saddr, n.clone()))))
+                .collect());
+
+        for (saddr, name) in &victims {
+            if let Some(names) = self.hosts.get_mut(saddr) {
+                names.remove(name);
+            }
+            budget::release(host_cost(name));
+        }
+        self.hosts.retain(|_, names| !names.is_empty());
+        stats::COUNTERS.budget_evicted.add(victims.len() as u64);
+
+        !victims.is_empty()
+    }
+
+    /// Charge `bytes` to the budget, evicting entries as needed. If
+    /// nothing is left to evict, the rest of the budget is held by
+    /// others (e.g. queued packets); charge over it then.
+    fn make_room(&mut self, bytes: usize) {
+        while !budget::try_charge(bytes) {
+            if !self.evict_flows() && !self.evict_hosts() {
+                budget::charge(bytes);
+                return;
+            }
+        }
+    }
+
+    /// Return the flow of `key`, creating it if absent.
+    pub fn entry(&mut self, key: FlowKey) -> &mut Flow {
+        let now = Instant::now();
+        self.sweep(now);
+
+        if !self.flows.contains_key(&key) {
+            self.make_room(FLOW_COST);
+        }
+
+        let flow = self.flows.entry(key).or_insert_with(|| Flow::new(now));
+        flow.last_seen = now;
+        flow
+    }
+
+    pub fn get(&self, key: &FlowKey) -> Option<&Flow> {
+        self.flows.get(key)
+    }
+
+    /// Whether a ClientHello from `saddr` to `sni` has been handled
+    /// within `window`.
+    pub fn host_within(&mut self, saddr: IpAddr, sni: &str, window: Duration) -> bool {
+        let now = Instant::now();
+        self.host_ttl = window;
+        self.sweep(now);
+
+        self.hosts.get(&saddr)
+            .and_then(|names| names.get(sni))
+            .is_some_and(|t| now.duration_since(*t) < window)
+    }
+
+    pub fn put_host(&mut self, saddr: IpAddr, sni: &str) {
+        if !self.hosts.get(&saddr).is_some_and(|names| names.contains_key(sni)) {
+            self.make_room(host_cost(sni));
+        }
+
+        self.hosts.entry(saddr).or_default().insert(sni.to_string(), Instant::now());
+    }
+}
+
+static F_TAB: OnceLock<Mutex<FlowTab>> = OnceLock::new();
+
+#[inline]
+pub fn ftab() -> MutexGuard<'static, FlowTab> {
+    F_TAB.get_or_init(|| Mutex::new(FlowTab::new()))
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner) // a packet panicked while holding it
+}
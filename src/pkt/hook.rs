@@ -0,0 +1,351 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--verdict-hook`: a program of the user's deciding how ClientHellos
+//! are handled
+//!
+//! Policies the built-in matchers cannot express are left to a program
+//! in any language (a Lua script run by `lua`, say) rather than to an
+//! interpreter embedded here. It is started once and kept running. For
+//! each ClientHello about to be desynced, it is given a line
+//!
+//! ```text
+//! <id> <saddr> <daddr> <dport> <sni|-> <alpn,...|->
+//! ```
+//!
+//! on stdin, and answers on stdout with
+//!
+//! ```text
+//! <id> pass|default|<profile>
+//! ```
+//!
+//! `pass` accepts the ClientHello unchanged, `default` desyncs it as
+//! the options say, and a profile of `--list-client-profiles` desyncs
+//! it with that profile. Without an answer in [`TIMEOUT`], it is
+//! desynced as the options say; a late answer is skipped by its id.
+//! Workers ask at once, and each answer goes to the query of its id,
+//! in whatever order they come. Queries are written to it by a thread of their own, so that one
+//! which stops reading cannot hold up the workers; while its queue is
+//! full, queries go unanswered.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{opt, stats};
+
+/// Longest wait for an answer; the ClientHello is held meanwhile.
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Queries waiting to be written to the program; more go unanswered.
+const QUEUE_LEN: usize = 16;
+
+/// Wait before starting the program again after it exited, off the
+/// packet path
+const RESPAWN_DELAY: Duration = Duration::from_secs(5);
+
+pub enum Answer {
+    Pass,
+    Default,
+    Profile(&'static opt::RotatedProfile),
+}
+
+/// ClientHello to ask about
+pub struct Query<'a> {
+    pub saddr: IpAddr,
+    pub daddr: IpAddr,
+    pub dport: u16,
+    pub sni: Option<&'a str>,
+    pub alpn: Vec<&'a str>,
+}
+
+/// Whether `s` fits in a field of the query line
+fn is_word(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_graphic() && b != b',')
+}
+
+fn query_line(id: u64, q: &Query) -> String {
+    let alpn: Vec<&str> = q.alpn.iter().copied().filter(|p| is_word(p)).collect();
+    let alpn = if alpn.is_empty() { "-".to_string() } else { alpn.join(",") };
+    let sni = q.sni.filter(|s| is_word(s)).unwrap_or("-");
+    format!("{id} {} {} {} {sni} {alpn}\n", q.saddr, q.daddr, q.dport)
+}
+
+/// Answer in `reply` to query `id`; None if it is not one
+fn parse_answer(reply: &str, id: u64) -> Option<Answer> {
+    let (rid, word) = reply.trim().split_once(char::is_whitespace)?;
+    if rid.parse::<u64>().ok()? != id {
+        return None;
+    }
+
+    Some(match word.trim() {
+        "pass" => Answer::Pass,
+        "default" => Answer::Default,
+        name => match opt::named_profile(name) {
+            Some(p) if p.profile.off => Answer::Pass,
+            Some(p) => Answer::Profile(p),
+            None => {
+                crate::warn!("verdict hook: unknown profile '{name}'; desynced as the options say");
+                Answer::Default
+            }
+        },
+    })
+}
+
+/// Waiting queries, by id: where to send their answer line
+type Pending = Arc<Mutex<HashMap<u64, Sender<String>>>>;
+
+/// Running hook program
+struct Process {
+    child: Child,
+    queries: SyncSender<String>,
+    pending: Pending,
+
+    /// Its output has ended: it exited.
+    gone: Arc<AtomicBool>,
+    next_id: AtomicU64,
+}
+
+impl Process {
+    /// Start `argv`: program and its arguments
+    fn spawn<'a>(argv: impl IntoIterator<Item = &'a str>) -> Result<Self> {
+        let mut argv = argv.into_iter();
+        let program = argv.next().context("empty command")?;
+        let mut child = Command::new(program)
+            .args(argv)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("failed to spawn {program}"))?;
+
+        let mut stdin = child.stdin.take().expect("piped");
+        let stdout = child.stdout.take().expect("piped");
+        let (queries, rx) = mpsc::sync_channel::<String>(QUEUE_LEN);
+        std::thread::spawn(move || {
+            for line in rx {
+                if stdin.write_all(line.as_bytes()).and_then(|_| stdin.flush()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        // Each answer goes to the query of its id, if still waiting; once
+        // the program is gone, the waiting ones are told by their
+        // sender being dropped.
+        let pending = Pending::default();
+        let gone = Arc::new(AtomicBool::new(false));
+        let (replies, ended) = (pending.clone(), gone.clone());
+        std::thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else { break };
+                let Some(id) = line.split_whitespace().next().and_then(|id| id.parse().ok()) else {
+                    continue;
+                };
+                if let Some(tx) = lock(&replies).remove(&id) {
+                    _ = tx.send(line);
+                }
+            }
+            ended.store(true, Ordering::SeqCst);
+            lock(&replies).clear();
+        });
+
+        Ok(Self { child, queries, pending, gone, next_id: AtomicU64::new(0) })
+    }
+
+    /// Answer to `q`; None if not in time. Err if the program is gone.
+    /// Queries of several workers wait for their answers at once.
+    fn ask(&self, q: &Query) -> Result<Option<Answer>> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel();
+        lock(&self.pending).insert(id, tx);
+        let forget = || lock(&self.pending).remove(&id);
+        // Checked once registered: it is set before the senders are dropped,
+        // so that an exit is seen either here or by the wait below
+        if self.gone.load(Ordering::SeqCst) {
+            forget();
+            return Err(anyhow!("it exited"));
+        }
+
+        match self.queries.try_send(query_line(id, q)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                forget();
+                return Ok(None);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                forget();
+                return Err(anyhow!("cannot write to it"));
+            }
+        }
+
+        match rx.recv_timeout(TIMEOUT) {
+            Ok(reply) => Ok(parse_answer(&reply, id)),
+            Err(RecvTimeoutError::Timeout) => {
+                forget();
+                Ok(None)
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(anyhow!("it exited")),
+        }
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+fn lock<T>(m: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    m.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+struct Hook {
+    process: Option<Arc<Process>>,
+
+    /// A thread is starting the program again.
+    respawning: bool,
+}
+
+static HOOK: Mutex<Hook> = Mutex::new(Hook { process: None, respawning: false });
+
+fn hook() -> std::sync::MutexGuard<'static, Hook> {
+    lock(&HOOK)
+}
+
+/// Start the `--verdict-hook` program, if not running yet.
+pub fn init() -> Result<()> {
+    let Some(command) = opt::verdict_hook() else {
+        return Ok(());
+    };
+
+    let mut hook = hook();
+    if hook.process.is_none() && !hook.respawning {
+        let process = Process::spawn(command.split_whitespace()).context("--verdict-hook")?;
+        hook.process = Some(Arc::new(process));
+        crate::info!("verdict hook: started {command}");
+    }
+    Ok(())
+}
+
+/// Start `command` again after [`RESPAWN_DELAY`] on a thread of its
+/// own, trying until it starts, unless already being done.
+fn respawn(hook: &mut Hook, command: &'static str) {
+    if hook.respawning {
+        return;
+    }
+    hook.respawning = true;
+
+    std::thread::spawn(move || loop {
+        std::thread::sleep(RESPAWN_DELAY);
+        match Process::spawn(command.split_whitespace()) {
+            Ok(p) => {
+                let mut hook = self::hook();
+                hook.process = Some(Arc::new(p));
+                hook.respawning = false;
+                crate::info!("verdict hook: restarted {command}");
+                return;
+            }
+            Err(e) => crate::warn!("verdict hook: {e:#}"),
+        }
+    });
+}
+
+/// How to handle ClientHello `q`, as the `--verdict-hook` program says.
+/// Workers wait for their own answer only; while the program is being
+/// restarted, ClientHellos are desynced as the options say.
+pub fn ask(q: &Query) -> Answer {
+    let Some(command) = opt::verdict_hook() else {
+        return Answer::Default;
+    };
+
+    let process = {
+        let mut hook = hook();
+        match &hook.process {
+            Some(p) => p.clone(),
+            None => {
+                respawn(&mut hook, command);
+                stats::COUNTERS.hook_failed.inc();
+                return Answer::Default;
+            }
+        }
+    };
+
+    match process.ask(q) {
+        Ok(Some(answer)) => answer,
+        Ok(None) => {
+            crate::debug!("verdict hook: no answer about {} in {}ms", q.daddr, TIMEOUT.as_millis());
+            stats::COUNTERS.hook_failed.inc();
+            Answer::Default
+        }
+        Err(e) => {
+            let mut hook = hook();
+            // Once per program, however many workers saw it go
+            if hook.process.as_ref().is_some_and(|p| Arc::ptr_eq(p, &process)) {
+                crate::warn!("verdict hook: {e:#}; desyncing as the options say until it is restarted");
+                hook.process = None;
+                respawn(&mut hook, command);
+            }
+            stats::COUNTERS.hook_failed.inc();
+            Answer::Default
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query<'a>(sni: Option<&'a str>, alpn: Vec<&'a str>) -> Query<'a> {
+        Query {
+            saddr: "192.168.0.2".parse().unwrap(),
+            daddr: "2001:db8::1".parse().unwrap(),
+            dport: 443,
+            sni,
+            alpn,
+        }
+    }
+
+    #[test]
+    fn test_query_answer() {
+        assert_eq!(query_line(7, &query(Some("example.com"), vec!["h2", "http/1.1"])),
+                   "7 192.168.0.2 2001:db8::1 443 example.com h2,http/1.1\n");
+        assert_eq!(query_line(8, &query(None, vec!["a b", "x,y"])),
+                   "8 192.168.0.2 2001:db8::1 443 - -\n");
+
+        assert!(matches!(parse_answer("3 pass", 3), Some(Answer::Pass)));
+        assert!(matches!(parse_answer(" 3  default \r", 3), Some(Answer::Default)));
+        assert!(matches!(parse_answer("3 off", 3), Some(Answer::Pass)));
+        assert!(matches!(parse_answer("3 Aggressive", 3),
+                         Some(Answer::Profile(p)) if p.profile.name == "aggressive"));
+        assert!(matches!(parse_answer("3 nonexistent", 3), Some(Answer::Default)));
+        assert!(parse_answer("2 pass", 3).is_none());
+        assert!(parse_answer("pass", 3).is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process() {
+        let mut p = Process::spawn(["true"]).unwrap();
+        p.child.wait().unwrap();
+        assert!(p.ask(&query(None, Vec::new())).is_err());
+
+        // Passes example.com; answers about the rest only when asked
+        // the next time, long after the timeout
+        let script = "late=; while read id saddr daddr dport sni alpn; do \
+                      if [ -n \"$late\" ]; then echo $late light; late=; fi; \
+                      if [ $sni = example.com ]; then echo $id pass; else late=$id; fi; done";
+        let p = Process::spawn(["sh", "-c", script]).unwrap();
+        assert!(matches!(p.ask(&query(Some("example.com"), Vec::new())), Ok(Some(Answer::Pass))));
+        assert!(matches!(p.ask(&query(Some("example.org"), Vec::new())), Ok(None)));
+        // The late answer about example.org is not taken for this one
+        assert!(matches!(p.ask(&query(Some("example.com"), Vec::new())), Ok(Some(Answer::Pass))));
+    }
+}
@@ -26,12 +26,19 @@
 //! [`HopTab::STALE_AGE`] or more, there is a chance that
 //! [`HopLookupError::NotFound`] occurs. Other than these cases, it
 //! will not occur.
-
+//!
+//! Alongside, [`take_fakes`] keeps a token bucket per destination for
+//! `--fake-rate`, so that a burst of connections to one host (e.g. CDN
+//! sharding) does not emit a burst of fakes.
 
 use std::fmt;
 use std::net::IpAddr;
+use std::collections::HashMap;
 use std::sync::{Mutex, OnceLock};
 use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Instant;
+
+use crate::log::Bucket;
 
 /// Size of [`HopTab`]
 const CAP: usize = 1 << 7;      // 128
@@ -39,7 +46,7 @@ const CAP: usize = 1 << 7;      // 128
 /// 128-bit (IPv6-shaped) unified IP key for [`HopTab`] lookups (IPv4
 /// stored as ::ffff:a.b.c.d).
 #[repr(C)]
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 struct HopKey {
     hi: u64,
     lo: u64
@@ -329,7 +336,8 @@ impl<const CAP: usize> HopTab<CAP> {
         }
     }
 
-    fn find_hop(&mut self, ip: IpAddr) -> HopResult<u8> {
+    /// Index of the entry of `ip`, if any
+    fn lookup(&self, ip: IpAddr) -> Option<usize> {
         let key = HopKey::from_ipaddr(ip);
         let start = hash(key).to_idx::<CAP>();
 
@@ -342,15 +350,35 @@ impl<const CAP: usize> HopTab<CAP> {
             }
 
             if e.key() == key {
-                self.entries[idx].touch();
-
-                #[cfg(debug_assertions)]
-                crate::debug!("HopTab::find_hop: found {idx}; {:#?}", e);
-                return Ok(e.hop());
+                return Some(idx);
             }
         }
 
-        Err(HopLookupError::NotFound { ip })
+        None
+    }
+
+    fn find_hop(&mut self, ip: IpAddr) -> HopResult<u8> {
+        let idx = self.lookup(ip).ok_or(HopLookupError::NotFound { ip })?;
+        self.entries[idx].touch();
+
+        #[cfg(debug_assertions)]
+        crate::debug!("HopTab::find_hop: found {idx}; {:#?}", self.entries[idx]);
+        Ok(self.entries[idx].hop())
+    }
+
+    /// Same as [`Self::find_hop`], without consuming the entry
+    fn peek(&self, ip: IpAddr) -> Option<u8> {
+        self.lookup(ip).map(|idx| self.entries[idx].hop())
+    }
+
+    /// Occupied entries, oldest first
+    fn snapshot(&self) -> Vec<(IpAddr, u8)> {
+        let mut occupied: Vec<&HopTabEntry> = self.entries.iter()
+            .filter(|e| e.has(HopTabEntry::ST_OCCUPIED))
+            .collect();
+        occupied.sort_by_key(|e| std::cmp::Reverse(self.age(e)));
+
+        occupied.iter().map(|e| (e.key().to_ipaddr(), e.hop())).collect()
     }
 }
 
@@ -360,7 +388,7 @@ static H_TAB: OnceLock<Mutex<HopTab<CAP>>> = OnceLock::new();
 fn htab() -> std::sync::MutexGuard<'static, HopTab<CAP>> {
     H_TAB.get_or_init(|| Mutex::new(HopTab::new()))
         .lock()
-        .unwrap()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
 }
 
 pub fn put(ip: IpAddr, hop: u8) {
@@ -371,6 +399,107 @@ pub fn find(ip: IpAddr) -> HopResult<u8> {
     htab().find_hop(ip)
 }
 
+/// Hop of `ip`, if any, leaving it to be consumed by [`find`]
+pub fn peek(ip: IpAddr) -> Option<u8> {
+    htab().peek(ip)
+}
+
+/// Destinations with a bucket at most; full buckets are dropped
+/// beyond, being the same as none.
+const RATE_CAP: usize = 1 << 10;
+
+/// ClientHellos with fakes that may still go to each destination,
+/// refilled at `--fake-rate` per second up to one second worth
+struct RateTab {
+    buckets: HashMap<HopKey, Bucket>,
+}
+
+impl RateTab {
+    fn new() -> Self {
+        Self { buckets: HashMap::new() }
+    }
+
+    fn take(&mut self, ip: IpAddr, rate: u32, now: Instant) -> bool {
+        let key = HopKey::from_ipaddr(ip);
+
+        if self.buckets.len() >= RATE_CAP && !self.buckets.contains_key(&key) {
+            self.buckets.retain(|_, b| !b.is_full(now));
+            if self.buckets.len() >= RATE_CAP {
+                return true;    // too many busy destinations to tell
+            }
+        }
+
+        self.buckets.entry(key)
+            .or_insert_with(|| Bucket::new(f64::from(rate), f64::from(rate), now))
+            .take(now)
+            .is_some()
+    }
+}
+
+static R_TAB: OnceLock<Mutex<RateTab>> = OnceLock::new();
+
+/// Take the fakes of a ClientHello to `ip` out of its `--fake-rate`.
+/// False if it is exceeded.
+pub fn take_fakes(ip: IpAddr) -> bool {
+    let rate = crate::opt::fake_rate();
+    if rate == 0 {
+        return true;
+    }
+
+    R_TAB.get_or_init(|| Mutex::new(RateTab::new()))
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner)
+        .take(ip, rate, Instant::now())
+}
+
+pub const FILE: &str = "hops.tsv";
+const HEADER: &str = "# dpibreak hops v1";
+
+fn format(hops: &[(IpAddr, u8)]) -> String {
+    let mut out = format!("{HEADER}\n");
+    for (ip, hop) in hops {
+        out += &format!("{ip}\t{hop}\n");
+    }
+    out
+}
+
+/// `addr\thop` lines, skipping malformed ones
+fn parse(text: &str) -> Vec<(IpAddr, u8)> {
+    text.lines()
+        .filter(|l| !l.starts_with('#'))
+        .filter_map(|l| {
+            let (ip, hop) = l.split_once('\t')?;
+            Some((ip.parse().ok()?, hop.parse().ok()?))
+        })
+        .collect()
+}
+
+/// Hop table to save
+pub fn dump() -> Option<String> {
+    Some(format(&htab().snapshot()))
+}
+
+/// Put the hops saved in `text`.
+pub fn load(text: &str) {
+    let hops = parse(text);
+    crate::info!("hoptab: {} hops loaded", hops.len());
+    for (ip, hop) in hops {
+        put(ip, hop);
+    }
+}
+
+/// Hops of `old` updated with those of `new`
+pub fn merge(old: &str, new: &str) -> String {
+    let mut hops = parse(old);
+    let new = parse(new);
+    hops.retain(|(ip, _)| !new.iter().any(|(n, _)| n == ip));
+    hops.extend(new);
+
+    // The table keeps only the newest ones.
+    let excess = hops.len().saturating_sub(CAP);
+    format(&hops[excess..])
+}
+
 //
 // below are test/bench codes
 //
@@ -394,6 +523,7 @@ mod tests {
         let ip: IpAddr = "1.1.1.1".parse().unwrap();
         put(ip, 12);
 
+        assert_eq!(peek(ip), Some(12));
         let result = find(ip).expect("cannot find {ip}");
         assert_eq!(result, 12);
     }
@@ -483,6 +613,44 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_fake_rate() {
+        use std::time::Duration;
+
+        let mut tab = RateTab::new();
+        let t0 = Instant::now();
+        let a = u32_to_ipaddr(1);
+        let b = u32_to_ipaddr(2);
+
+        // 10/s: a second worth at once, then none
+        for _ in 0..10 {
+            assert!(tab.take(a, 10, t0));
+        }
+        assert!(!tab.take(a, 10, t0));
+        assert!(tab.take(b, 10, t0), "per destination");
+
+        let t1 = t0 + Duration::from_millis(300);
+        for _ in 0..3 {
+            assert!(tab.take(a, 10, t1));
+        }
+        assert!(!tab.take(a, 10, t1));
+
+        // Refilled up to one second worth only
+        let t2 = t1 + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert!(tab.take(a, 10, t2));
+        }
+        assert!(!tab.take(a, 10, t2));
+
+        // Full table: refilled buckets make room
+        for i in 0..RATE_CAP as u32 {
+            tab.take(u32_to_ipaddr(1000 + i), 10, t2);
+        }
+        let t3 = t2 + Duration::from_secs(1);
+        assert!(tab.take(u32_to_ipaddr(5), 10, t3));
+        assert!(tab.buckets.len() < RATE_CAP);
+    }
+
     #[test]
     fn test_age_overflow_handling() {
         let mut tab = HopTab::<CAP>::new();
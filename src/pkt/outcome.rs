@@ -0,0 +1,327 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Outcome of desynced connections, per strategy and server
+//!
+//! A handled ClientHello has failed if the client retransmits it, or if
+//! the connection is reset within [`WINDOW`] (seen only when inbound RST
+//! is queued, i.e. with `--drop-forged-rst` or `--adaptive-exempt`);
+//! otherwise it has got through. Tallies are kept across runs by
+//! [`super::state`], so that `dpibreak stats --by-strategy` can tell
+//! which strategy works on the network; a flow desynced with a profile
+//! of `--client-profile` or `--strategy-rotate` counts for that
+//! profile. The last few handled flows
+//! are kept too, for `dpibreak top`.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::net::IpAddr;
+use std::sync::{Mutex, MutexGuard, OnceLock, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow};
+
+use super::{budget, state};
+use super::flowtab::FlowKey;
+use crate::opt;
+
+/// A handled ClientHello not retransmitted or reset within this time
+/// has got through.
+const WINDOW: Duration = Duration::from_secs(2);
+
+/// Tallies kept; the least recently updated are dropped beyond this.
+const MAX_TALLIES: usize = 4096;
+
+pub const FILE: &str = "outcomes.tsv";
+const HEADER: &str = "# dpibreak outcomes v1";
+
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct Tally {
+    pub ok: u64,
+    pub failed: u64,
+
+    /// Unix time of the last update
+    last: u64,
+}
+
+/// Recent flows kept for the `flows` control command
+const MAX_RECENT: usize = 32;
+
+/// Flow handled recently
+struct Recent {
+    key: FlowKey,
+    sni: Option<String>,
+    strategy: String,
+    at: Instant,
+
+    /// None while within [`WINDOW`]
+    ok: Option<bool>,
+}
+
+#[derive(Default)]
+struct Outcomes {
+    tallies: HashMap<(String, IpAddr), Tally>,
+
+    /// Handled flows still within [`WINDOW`], with the strategy they
+    /// count for
+    pending: HashMap<FlowKey, (Instant, String)>,
+    dirty: bool,
+
+    /// Newest last
+    recent: VecDeque<Recent>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Short name of the desync in effect, e.g. `split=0,1+fake=autottl`
+pub fn strategy() -> &'static str {
+    static NAME: OnceLock<String> = OnceLock::new();
+
+    NAME.get_or_init(|| {
+        let mut s = match (opt::split_random(), opt::split_ext()) {
+            (Some(range), _) => format!("split=rand{range}"),
+            (None, Some(ext)) => format!("split={}@{ext}", opt::segment_order().raw()),
+            (None, None) => format!("split={}", opt::segment_order().raw()),
+        };
+        if opt::fake() {
+            if opt::fake_autottl() {
+                s += "+fake=autottl";
+            } else {
+                _ = write!(s, "+fake=ttl{}", opt::fake_ttl());
+            }
+            if opt::fake_badsum() {
+                s += "+badsum";
+            }
+        }
+        s
+    })
+}
+
+impl Outcomes {
+    fn tally(&mut self, strategy: String, dest: IpAddr, ok: bool) {
+        if self.tallies.len() >= MAX_TALLIES {
+            let victims = budget::lru_victims(
+                self.tallies.iter().map(|(k, t)| (t.last, k.clone())).collect());
+            for k in &victims {
+                self.tallies.remove(k);
+            }
+        }
+
+        let t = self.tallies.entry((strategy, dest)).or_default();
+        if ok { t.ok += 1 } else { t.failed += 1 }
+        t.last = unix_now();
+        self.dirty = true;
+    }
+
+    /// Count flows whose window is over as successful.
+    fn settle(&mut self, now: Instant) {
+        let done: Vec<FlowKey> = self.pending.iter()
+            .filter(|(_, (at, _))| now.duration_since(*at) >= WINDOW)
+            .map(|(k, _)| *k)
+            .collect();
+
+        for key in done {
+            let Some((_, strategy)) = self.pending.remove(&key) else { continue };
+            self.tally(strategy, key.daddr, true);
+            self.settle_recent(&key, true);
+        }
+    }
+
+    fn settle_recent(&mut self, key: &FlowKey, ok: bool) {
+        if let Some(r) = self.recent.iter_mut().rev().find(|r| r.key == *key && r.ok.is_none()) {
+            r.ok = Some(ok);
+        }
+    }
+
+    fn parse(text: &str) -> Self {
+        let mut o = Self::default();
+
+        for line in text.lines().filter(|l| !l.starts_with('#')) {
+            let f: Vec<&str> = line.split('\t').collect();
+            let [strategy, dest, ok, failed, last] = f[..] else { continue };
+            let (Ok(dest), Ok(ok), Ok(failed), Ok(last)) =
+                (dest.parse(), ok.parse(), failed.parse(), last.parse()) else { continue };
+
+            o.tallies.insert((strategy.to_string(), dest), Tally { ok, failed, last });
+        }
+
+        o
+    }
+
+    fn serialize(&self) -> String {
+        let mut out = format!("{HEADER}\n");
+        for ((strategy, dest), t) in &self.tallies {
+            _ = writeln!(out, "{strategy}\t{dest}\t{}\t{}\t{}", t.ok, t.failed, t.last);
+        }
+        out
+    }
+}
+
+static OUTCOMES: OnceLock<Mutex<Outcomes>> = OnceLock::new();
+
+fn outcomes() -> MutexGuard<'static, Outcomes> {
+    OUTCOMES.get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Name a flow desynced with `profile` counts for
+fn tally_name(profile: Option<&str>) -> String {
+    match profile {
+        Some(p) if p != "default" => format!("profile={p}"),
+        _ => strategy().to_string(),
+    }
+}
+
+/// ClientHello on `key` to `sni` has been desynced with `strategy`, of
+/// `profile` if any.
+pub fn handled(key: FlowKey, sni: Option<&str>, strategy: String, profile: Option<&str>) {
+    let now = Instant::now();
+    let mut o = outcomes();
+    o.settle(now);
+    o.pending.insert(key, (now, tally_name(profile)));
+
+    if o.recent.len() >= MAX_RECENT {
+        o.recent.pop_front();
+    }
+    o.recent.push_back(Recent { key, sni: sni.map(str::to_string), strategy, at: now, ok: None });
+}
+
+/// Connection `key` (client to server) shows that its desync did not
+/// work; no-op unless it is within its window.
+pub fn failed(key: &FlowKey) {
+    let mut o = outcomes();
+    if let Some((_, strategy)) = o.pending.remove(key) {
+        o.tally(strategy, key.daddr, false);
+        o.settle_recent(key, false);
+    }
+}
+
+/// Answer to the `flows` control command: the flows handled recently,
+/// newest first, one `flow: ` line each with tab separated client,
+/// server, server name, strategy, outcome (`ok`, `failed` or `pending`)
+/// and age in seconds.
+pub fn recent() -> String {
+    let now = Instant::now();
+    let mut o = outcomes();
+    o.settle(now);
+
+    let mut out = String::new();
+    for r in o.recent.iter().rev() {
+        let outcome = match r.ok {
+            Some(true) => "ok",
+            Some(false) => "failed",
+            None => "pending",
+        };
+        _ = writeln!(out, "flow: {}\t{}\t{}\t{}\t{outcome}\t{}",
+                     std::net::SocketAddr::new(r.key.saddr, r.key.sport),
+                     std::net::SocketAddr::new(r.key.daddr, r.key.dport),
+                     r.sni.as_deref().unwrap_or("-"), r.strategy,
+                     now.duration_since(r.at).as_secs());
+    }
+    out
+}
+
+/// Tallies to save, or None if unchanged since the last call
+pub fn dump() -> Option<String> {
+    let mut o = outcomes();
+    o.settle(Instant::now());
+    if !o.dirty {
+        return None;
+    }
+    o.dirty = false;
+    Some(o.serialize())
+}
+
+/// Replace the tallies with those saved in `text`.
+pub fn load(text: &str) {
+    let loaded = Outcomes::parse(text);
+    crate::info!("outcome: {} tallies loaded", loaded.tallies.len());
+    *outcomes() = loaded;
+}
+
+/// Tallies of `old` updated with those of `new`
+pub fn merge(old: &str, new: &str) -> String {
+    let mut o = Outcomes::parse(old);
+    o.tallies.extend(Outcomes::parse(new).tallies);
+    o.serialize()
+}
+
+/// `dpibreak stats --by-strategy`: success rate of each strategy over
+/// the servers it has been used on.
+pub fn stats_command(args: impl Iterator<Item = String>) -> Result<()> {
+    let mut by_strategy = false;
+    for arg in args {
+        match arg.as_str() {
+            "--by-strategy" => by_strategy = true,
+            _ => return Err(anyhow!("stats: unknown argument: {arg}")),
+        }
+    }
+    if !by_strategy {
+        return Err(anyhow!("stats: use --by-strategy"));
+    }
+
+    let text = state::read(FILE).context("stats")?;
+
+    let mut rows: Vec<(String, usize, Tally)> = Vec::new();
+    for ((strategy, _), t) in Outcomes::parse(&text).tallies {
+        let i = match rows.iter().position(|r| r.0 == strategy) {
+            Some(i) => i,
+            None => { rows.push((strategy, 0, Tally::default())); rows.len() - 1 }
+        };
+        rows[i].1 += 1;
+        rows[i].2.ok += t.ok;
+        rows[i].2.failed += t.failed;
+    }
+    rows.sort_by_key(|r| std::cmp::Reverse(r.2.ok + r.2.failed));
+
+    println!("{:<40} {:>8} {:>10} {:>10} {:>8}", "strategy", "servers", "ok", "failed", "success");
+    for (strategy, servers, t) in rows {
+        let rate = 100.0 * t.ok as f64 / (t.ok + t.failed).max(1) as f64;
+        println!("{strategy:<40} {servers:>8} {:>10} {:>10} {rate:>7.1}%", t.ok, t.failed);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut o = Outcomes::default();
+        o.tallies.insert(("split=0,1".into(), "192.0.2.1".parse().unwrap()),
+                         Tally { ok: 3, failed: 1, last: 100 });
+        o.tallies.insert(("split=0,1+fake=ttl8".into(), "2001:db8::1".parse().unwrap()),
+                         Tally { ok: 0, failed: 2, last: 200 });
+
+        let text = o.serialize();
+        assert!(text.starts_with(HEADER));
+
+        let parsed = Outcomes::parse(&(text + "garbage\tline\n"));
+        assert_eq!(parsed.tallies, o.tallies);
+    }
+
+    #[test]
+    fn test_recent() {
+        let key = FlowKey {
+            saddr: "192.0.2.1".parse().unwrap(),
+            daddr: "2001:db8::1".parse().unwrap(),
+            sport: 50000,
+            dport: 443,
+        };
+        let recent = |ok| Recent { key, sni: None, strategy: "split 1".into(), at: Instant::now(), ok };
+
+        // A reused port: only the unsettled entry is settled.
+        let mut o = Outcomes::default();
+        o.recent.extend([recent(None), recent(Some(true))]);
+        o.settle_recent(&key, false);
+        o.settle_recent(&key, true);
+
+        assert_eq!(o.recent[0].ok, Some(false));
+        assert_eq!(o.recent[1].ok, Some(true));
+    }
+}
@@ -0,0 +1,288 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Path MTU to servers, from ICMP "fragmentation needed" (`--watch-pmtu`)
+//!
+//! A segment of ours too big for a link on the way is answered with an
+//! ICMP "fragmentation needed" (type 3 code 4) or ICMPv6 "packet too
+//! big" (type 2) quoting it. The stack learns from it for its own
+//! segments, but ours are cut from the ClientHello it has already sent,
+//! and a firewall dropping ICMP that conntrack does not relate to a
+//! connection (e.g. quoting a segment we sent untracked) may keep even
+//! the stack from seeing it. Such reports quoting a handled flow are
+//! sniffed, never held, and the MTU they give is kept per destination
+//! for [`EXPIRY`], so that the next ClientHellos to it are cut into
+//! segments that fit.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::opt;
+use crate::stats;
+
+use super::flowtab::{self, FlowKey};
+
+/// Destinations kept at most
+const CAP: usize = 1024;
+
+/// How long a learned MTU holds, as the kernel's `mtu_expires`
+const EXPIRY: Duration = Duration::from_secs(600);
+
+/// Smallest MTU believed; less is most likely forged. The kernel's
+/// `min_pmtu` for IPv4, and the minimum link MTU of IPv6.
+const MIN_MTU_V4: u16 = 552;
+const MIN_MTU_V6: u16 = 1280;
+
+const IPPROTO_ICMP: u8 = 1;
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_ICMPV6: u8 = 58;
+
+/// What an ICMP "fragmentation needed" or "packet too big" tells
+#[derive(Debug, PartialEq, Eq)]
+struct TooBig {
+    /// Router that sent it
+    from: IpAddr,
+
+    /// Flow of the packet quoted
+    key: FlowKey,
+
+    mtu: u16,
+}
+
+/// `pkt` as a [`TooBig`] quoting a TCP packet; None if it is anything
+/// else, or a report without an MTU (from routers older than RFC 1191).
+/// `pkt` may be cut short after the quoted TCP ports.
+fn parse(pkt: &[u8]) -> Option<TooBig> {
+    let ports = |l4: &[u8]| Some((
+        u16::from_be_bytes(l4.get(0..2)?.try_into().ok()?),
+        u16::from_be_bytes(l4.get(2..4)?.try_into().ok()?),
+    ));
+
+    match pkt.first()? >> 4 {
+        4 => {
+            let ihl = usize::from(pkt[0] & 0x0f) * 4;
+            if ihl < 20 || *pkt.get(9)? != IPPROTO_ICMP {
+                return None;
+            }
+            let icmp = pkt.get(ihl..)?;
+            if icmp.get(0..2)? != [3, 4] {
+                return None;
+            }
+            let mtu = u16::from_be_bytes(icmp.get(6..8)?.try_into().ok()?);
+            if mtu == 0 {
+                return None;
+            }
+
+            let inner = icmp.get(8..)?;
+            let inner_ihl = usize::from(inner.first()? & 0x0f) * 4;
+            if inner[0] >> 4 != 4 || inner_ihl < 20 || *inner.get(9)? != IPPROTO_TCP {
+                return None;
+            }
+            let addr = |at: usize| Some(IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(inner.get(at..at + 4)?).ok()?)));
+            let (sport, dport) = ports(inner.get(inner_ihl..)?)?;
+
+            Some(TooBig {
+                from: IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(pkt.get(12..16)?).ok()?)),
+                key: FlowKey { saddr: addr(12)?, daddr: addr(16)?, sport, dport },
+                mtu: mtu.max(MIN_MTU_V4),
+            })
+        }
+        6 => {
+            // Extension headers are not followed, on either header
+            if *pkt.get(6)? != IPPROTO_ICMPV6 {
+                return None;
+            }
+            let icmp = pkt.get(40..)?;
+            if *icmp.first()? != 2 {
+                return None;
+            }
+            let mtu = u32::from_be_bytes(icmp.get(4..8)?.try_into().ok()?);
+
+            let inner = icmp.get(8..)?;
+            if inner.first()? >> 4 != 6 || *inner.get(6)? != IPPROTO_TCP {
+                return None;
+            }
+            let addr = |at: usize| Some(IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(inner.get(at..at + 16)?).ok()?)));
+            let (sport, dport) = ports(inner.get(40..)?)?;
+
+            Some(TooBig {
+                from: IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(pkt.get(8..24)?).ok()?)),
+                key: FlowKey { saddr: addr(8)?, daddr: addr(24)?, sport, dport },
+                mtu: u16::try_from(mtu).unwrap_or(u16::MAX).max(MIN_MTU_V6),
+            })
+        }
+        _ => None,
+    }
+}
+
+struct Learned {
+    mtu: u16,
+    at: Instant,
+}
+
+#[derive(Default)]
+struct PmtuTab {
+    mtus: HashMap<IpAddr, Learned>,
+}
+
+impl PmtuTab {
+    /// Keep `mtu` to `daddr`; false if one as low is known already.
+    fn put(&mut self, daddr: IpAddr, mtu: u16, now: Instant) -> bool {
+        if self.find(daddr, now).is_some_and(|known| known <= mtu) {
+            return false;
+        }
+
+        if self.mtus.len() >= CAP && !self.mtus.contains_key(&daddr) {
+            self.mtus.retain(|_, l| now.duration_since(l.at) < EXPIRY);
+        }
+        if self.mtus.len() >= CAP && !self.mtus.contains_key(&daddr) {
+            let oldest = self.mtus.iter().min_by_key(|(_, l)| l.at).map(|(ip, _)| *ip);
+            if let Some(ip) = oldest {
+                self.mtus.remove(&ip);
+            }
+        }
+
+        self.mtus.insert(daddr, Learned { mtu, at: now });
+        true
+    }
+
+    fn find(&self, daddr: IpAddr, now: Instant) -> Option<u16> {
+        self.mtus.get(&daddr)
+            .filter(|l| now.duration_since(l.at) < EXPIRY)
+            .map(|l| l.mtu)
+    }
+}
+
+static TAB: OnceLock<Mutex<PmtuTab>> = OnceLock::new();
+
+fn tab() -> std::sync::MutexGuard<'static, PmtuTab> {
+    TAB.get_or_init(Mutex::default).lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// If `pkt` is an ICMP "fragmentation needed" or "packet too big"
+/// quoting a flow we handled, keep the MTU it gives to the server.
+pub fn learn(pkt: &[u8]) {
+    let Some(report) = parse(pkt) else {
+        return;
+    };
+    let FlowKey { saddr, daddr, sport, dport } = report.key;
+    if flowtab::ftab().get(&report.key).is_none() {
+        crate::debug!("pmtu: {}: MTU {} for {saddr}:{sport} > {daddr}:{dport}, not ours; ignored",
+                      report.from, report.mtu);
+        return;
+    }
+
+    if tab().put(daddr, report.mtu, Instant::now()) {
+        crate::info!("pmtu: {}: fragmentation needed on {saddr}:{sport} > {daddr}:{dport}; \
+                      segments to {daddr} fit in MTU {} from now", report.from, report.mtu);
+        stats::COUNTERS.pmtu_learned.inc();
+    }
+}
+
+/// `order` with each of its segments of a `len` byte payload that is
+/// longer than `max` cut into pieces of at most `max`, in order.
+fn cut(order: &[opt::Segment], len: u32, max: u32) -> Vec<opt::Segment> {
+    let mut pieces = Vec::with_capacity(order.len());
+    for &opt::Segment(start, end) in order {
+        let mut at = start;
+        while end.min(len).saturating_sub(at) > max {
+            pieces.push(opt::Segment(at, at + max));
+            at += max;
+        }
+        pieces.push(opt::Segment(at, end));
+    }
+    pieces
+}
+
+/// `order` of a `len` byte payload to `daddr`, sent with `headers`
+/// bytes of IP and TCP headers, with its segments cut to the MTU
+/// learned to it; None if they fit already.
+pub fn fit(order: &[opt::Segment], daddr: IpAddr, len: u32, headers: u32) -> Option<Vec<opt::Segment>> {
+    if !opt::watch_pmtu() {
+        return None;
+    }
+    let mtu = tab().find(daddr, Instant::now())?;
+    let max = u32::from(mtu).checked_sub(headers).filter(|&m| m > 0)?;
+
+    let fits = order.iter().all(|&opt::Segment(start, end)| end.min(len).saturating_sub(start) <= max);
+    (!fits).then(|| cut(order, len, max))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use opt::Segment as S;
+
+    /// IPv4 ICMP "fragmentation needed" from 10.0.0.1 quoting
+    /// 192.168.0.2:40000 > 93.184.216.34:443, cut after the ports
+    const FRAG_NEEDED: &str = "4500 0038 0000 0000 4001 0000 0a00 0001 c0a8 0002 \
+                               0304 0000 0000 0578 \
+                               4500 05dc 0000 4000 4006 0000 c0a8 0002 5db8 d822 9c40 01bb";
+
+    fn hex(s: &str) -> Vec<u8> {
+        let s: String = s.split_whitespace().collect();
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let report = parse(&hex(FRAG_NEEDED)).unwrap();
+        assert_eq!(report.from, "10.0.0.1".parse::<IpAddr>().unwrap());
+        assert_eq!(report.key, FlowKey {
+            saddr: "192.168.0.2".parse().unwrap(),
+            daddr: "93.184.216.34".parse().unwrap(),
+            sport: 40000,
+            dport: 443,
+        });
+        assert_eq!(report.mtu, 1400);
+
+        // No MTU given; too small to believe, so the least; port unreachable;
+        // cut before the ports
+        assert_eq!(parse(&hex(&FRAG_NEEDED.replace("0578", "0000"))), None);
+        assert_eq!(parse(&hex(&FRAG_NEEDED.replace("0578", "0044"))).unwrap().mtu, MIN_MTU_V4);
+        assert_eq!(parse(&hex(&FRAG_NEEDED.replace("0304", "0303"))), None);
+        assert_eq!(parse(&hex(&FRAG_NEEDED[..FRAG_NEEDED.len() - 5])), None);
+
+        let too_big = hex("6000 0000 0038 3a40 2001 0db8 0000 0000 0000 0000 0000 00fe \
+                           2001 0db8 0000 0000 0000 0000 0000 0002 \
+                           0200 0000 0000 0500 \
+                           6000 0000 0500 0640 2001 0db8 0000 0000 0000 0000 0000 0002 \
+                           2001 0db8 0000 0000 0000 0000 0000 0001 9c40 01bb");
+        let report = parse(&too_big).unwrap();
+        assert_eq!(report.key.daddr, "2001:db8::1".parse::<IpAddr>().unwrap());
+        assert_eq!(report.key.sport, 40000);
+        assert_eq!(report.mtu, 1280);
+    }
+
+    #[test]
+    fn test_pmtu_tab() {
+        let t0 = Instant::now();
+        let ip = |i: u32| IpAddr::from(Ipv4Addr::from(i));
+        let mut tab = PmtuTab::default();
+
+        assert!(tab.put(ip(1), 1400, t0));
+        assert!(!tab.put(ip(1), 1450, t0), "higher while the lower holds");
+        assert!(tab.put(ip(1), 1300, t0));
+        assert_eq!(tab.find(ip(1), t0 + EXPIRY - Duration::from_secs(1)), Some(1300));
+        assert_eq!(tab.find(ip(1), t0 + EXPIRY), None);
+        assert!(tab.put(ip(1), 1450, t0 + EXPIRY));
+
+        for i in 2..=CAP as u32 + 1 {
+            tab.put(ip(i), 1400, t0 + Duration::from_millis(i as u64));
+        }
+        assert_eq!(tab.mtus.len(), CAP);
+        assert_eq!(tab.find(ip(2), t0), None);
+        assert_eq!(tab.find(ip(3), t0), Some(1400));
+    }
+
+    #[test]
+    fn test_cut() {
+        assert_eq!(cut(&[S(0, 1), S(1, u32::MAX)], 2500, 1000),
+                   [S(0, 1), S(1, 1001), S(1001, 2001), S(2001, u32::MAX)]);
+        assert_eq!(cut(&[S(5, u32::MAX), S(0, 5)], 1005, 1000), [S(5, u32::MAX), S(0, 5)]);
+        assert_eq!(cut(&[S(0, 2100)], 3000, 1000), [S(0, 1000), S(1000, 2000), S(2000, 2100)]);
+    }
+}
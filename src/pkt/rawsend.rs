@@ -0,0 +1,157 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--send-raw`: packets crafted by the user, sent among the segments
+//!
+//! For trying out new bypasses without writing a strategy: each is an
+//! IP packet in hex whose placeholders are filled in from the
+//! ClientHello being desynced. Fields left zero that the user is not
+//! likely to want wrong are filled in too: the IPv4 total length and
+//! header checksum, the IPv6 payload length and the TCP checksum.
+
+use std::net::IpAddr;
+
+use crate::opt::{RawField, RawPacket, RawPiece};
+
+/// Flow of the ClientHello, as the placeholders see it
+pub struct Flow {
+    pub saddr: IpAddr,
+    pub daddr: IpAddr,
+    pub sport: u16,
+    pub dport: u16,
+    pub seq: u32,
+    pub ack: u32,
+}
+
+/// `p` for `flow`; None if `flow` is of the other IP version.
+pub fn build(p: &RawPacket, flow: &Flow) -> Option<Vec<u8>> {
+    if p.ipv6 != flow.daddr.is_ipv6() {
+        return None;
+    }
+
+    let addr = |a: IpAddr| match a {
+        IpAddr::V4(a) => a.octets().to_vec(),
+        IpAddr::V6(a) => a.octets().to_vec(),
+    };
+
+    let mut pkt = Vec::with_capacity(p.expanded_len());
+    for piece in &p.pieces {
+        match *piece {
+            RawPiece::Bytes(ref b) => pkt.extend_from_slice(b),
+            RawPiece::Field(RawField::Saddr, _) => pkt.extend(addr(flow.saddr)),
+            RawPiece::Field(RawField::Daddr, _) => pkt.extend(addr(flow.daddr)),
+            RawPiece::Field(RawField::Sport, _) => pkt.extend(flow.sport.to_be_bytes()),
+            RawPiece::Field(RawField::Dport, _) => pkt.extend(flow.dport.to_be_bytes()),
+            RawPiece::Field(RawField::Seq, off) => pkt.extend(flow.seq.wrapping_add_signed(off).to_be_bytes()),
+            RawPiece::Field(RawField::Ack, off) => pkt.extend(flow.ack.wrapping_add_signed(off).to_be_bytes()),
+        }
+    }
+
+    fill(&mut pkt);
+    Some(pkt)
+}
+
+/// One's complement sum of `data` added to `sum`, not yet folded
+fn sum16(data: &[u8], mut sum: u32) -> u32 {
+    for word in data.chunks(2) {
+        sum += u32::from(u16::from_be_bytes([word[0], *word.get(1).unwrap_or(&0)]));
+    }
+    sum
+}
+
+fn fold(mut sum: u32) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Put `v` at `at` of `pkt` if zero there
+fn fill_if_zero(pkt: &mut [u8], at: usize, v: u16) {
+    if let Some(field) = pkt.get_mut(at..at + 2) && field == [0, 0] {
+        field.copy_from_slice(&v.to_be_bytes());
+    }
+}
+
+/// Fill in the lengths and checksums left zero.
+fn fill(pkt: &mut [u8]) {
+    const IPPROTO_TCP: u8 = 6;
+
+    let len = pkt.len();
+    let (l4, proto, pseudo) = if pkt[0] >> 4 == 4 {
+        let ihl = usize::from(pkt[0] & 0x0f) * 4;
+        if ihl < 20 || ihl > len {
+            return;
+        }
+        fill_if_zero(pkt, 2, len as u16);
+        fill_if_zero(pkt, 10, fold(sum16(&pkt[..ihl], 0)));
+
+        let pseudo = sum16(&pkt[12..20], u32::from(pkt[9]) + (len - ihl) as u32);
+        (ihl, pkt[9], pseudo)
+    } else {
+        fill_if_zero(pkt, 4, (len - 40) as u16);
+
+        // Extension headers are not followed
+        let pseudo = sum16(&pkt[8..40], u32::from(pkt[6]) + (len - 40) as u32);
+        (40, pkt[6], pseudo)
+    };
+
+    if proto == IPPROTO_TCP && len >= l4 + 20 {
+        let at = l4 + 16;
+        if pkt[at..at + 2] == [0, 0] {
+            let sum = fold(sum16(&pkt[l4..], pseudo));
+            pkt[at..at + 2].copy_from_slice(&sum.to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn flow(saddr: &str, daddr: &str) -> Flow {
+        Flow {
+            saddr: saddr.parse().unwrap(),
+            daddr: daddr.parse().unwrap(),
+            sport: 40000,
+            dport: 443,
+            seq: 1000,
+            ack: 7,
+        }
+    }
+
+    #[test]
+    fn test_build() {
+        // Empty ACK one byte before the ClientHello, lengths and
+        // checksums left to fill in
+        let p = RawPacket::new("4500 0000 0000 4000 4006 0000 {saddr}{daddr} {sport}{dport} \
+                                {seq-1}{ack} 5010 0200 0000 0000@before").unwrap();
+        let pkt = build(&p, &flow("192.168.0.2", "93.184.216.34")).unwrap();
+        assert_eq!(pkt.len(), 40);
+        assert_eq!(pkt[2..4], 40u16.to_be_bytes());
+        assert_eq!(pkt[12..20], [192, 168, 0, 2, 93, 184, 216, 34]);
+        assert_eq!(pkt[20..24], [0x9c, 0x40, 0x01, 0xbb]);
+        assert_eq!(pkt[24..32], [0, 0, 0x03, 0xe7, 0, 0, 0, 7]);
+        assert_eq!(fold(sum16(&pkt[..20], 0)), 0, "IP checksum");
+        let pseudo = sum16(&pkt[12..20], 6 + 20);
+        assert_eq!(fold(sum16(&pkt[20..], pseudo)), 0, "TCP checksum");
+
+        assert!(build(&p, &flow("2001:db8::2", "2001:db8::1")).is_none());
+
+        // Given checksums are kept, wrong or not
+        let p = RawPacket::new("4500 0028 0000 4000 4006 beef {saddr}{daddr} {sport}{dport} \
+                                {seq}{ack} 5010 0200 dead 0000@after").unwrap();
+        let pkt = build(&p, &flow("192.168.0.2", "93.184.216.34")).unwrap();
+        assert_eq!(pkt[10..12], [0xbe, 0xef]);
+        assert_eq!(pkt[36..38], [0xde, 0xad]);
+
+        let p = RawPacket::new("6000 0000 0000 0640 {saddr}{daddr} {sport}{dport} \
+                                {seq+1}{ack} 5014 0000 0000 0000@between").unwrap();
+        let pkt = build(&p, &flow("2001:db8::2", "2001:db8::1")).unwrap();
+        assert_eq!(pkt.len(), 60);
+        assert_eq!(pkt[4..6], 20u16.to_be_bytes());
+        assert_eq!(pkt[44..48], 1001u32.to_be_bytes());
+        let pseudo = sum16(&pkt[8..40], 6 + 20);
+        assert_eq!(fold(sum16(&pkt[40..], pseudo)), 0, "TCP checksum");
+    }
+}
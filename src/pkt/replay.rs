@@ -0,0 +1,186 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--replay`: run a capture through the packet pipeline offline
+//!
+//! Each packet of the capture is classified as if it had been queued,
+//! and whatever would go on the wire (the packet itself if accepted, or
+//! the fakes and segments sent in its place) is written to the
+//! `--replay-out` pcap instead. Time is virtual: a packet is written at
+//! its capture time plus the segment delays simulated before it, and
+//! those delays are waited only `--replay-scale` times as long in real
+//! time. Random choices are seeded, so that the same capture and
+//! options give the same output.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use super::{PktView, Verdict, classify, decode, isolate, put_hop, run_job};
+use crate::opt;
+
+/// LINKTYPE_RAW: packets start with their IP header
+const LINKTYPE_RAW: u32 = 101;
+
+/// Seed of [`crate::rand`] for replays
+const SEED: u64 = 0x5eed;
+
+struct Output {
+    file: BufWriter<File>,
+
+    /// Capture time of the packet being replayed, plus the delays
+    /// simulated since
+    clock: Duration,
+    written: u64,
+}
+
+static OUTPUT: Mutex<Option<Output>> = Mutex::new(None);
+
+fn output() -> std::sync::MutexGuard<'static, Option<Output>> {
+    OUTPUT.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn pcap_header() -> Vec<u8> {
+    let mut h = Vec::with_capacity(24);
+    h.extend_from_slice(&decode::PCAP_MAGIC.to_le_bytes());
+    h.extend_from_slice(&2u16.to_le_bytes());   // version 2.4
+    h.extend_from_slice(&4u16.to_le_bytes());
+    h.extend_from_slice(&[0; 8]);               // time zone, accuracy
+    h.extend_from_slice(&65535u32.to_le_bytes());
+    h.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+    h
+}
+
+fn pcap_record(at: Duration, pkt: &[u8]) -> Vec<u8> {
+    let mut r = Vec::with_capacity(16 + pkt.len());
+    for w in [at.as_secs() as u32, at.subsec_micros(), pkt.len() as u32, pkt.len() as u32] {
+        r.extend_from_slice(&w.to_le_bytes());
+    }
+    r.extend_from_slice(pkt);
+    r
+}
+
+/// Write `pkt` to the output at the current virtual time.
+pub fn emit(pkt: &[u8]) -> Result<()> {
+    let mut out = output();
+    let out = out.as_mut().context("replay: no output")?;
+
+    out.file.write_all(&pcap_record(out.clock, pkt))?;
+    out.written += 1;
+    Ok(())
+}
+
+/// Move the virtual clock `gap` ahead, waiting `--replay-scale` of it.
+pub fn advance(gap: Duration) {
+    if let Some(out) = output().as_mut() {
+        out.clock += gap;
+    }
+    wait(gap);
+}
+
+fn wait(gap: Duration) {
+    let scale = opt::replay_scale();
+    if scale > 0.0 {
+        std::thread::sleep(gap.mul_f64(scale));
+    }
+}
+
+/// Whether the hop sniffer would see `pkt`: from a server, and SYN/ACK
+/// unless `--autottl-any`.
+fn sniffed(pkt: &[u8]) -> bool {
+    let Ok(view) = PktView::from_raw(pkt) else { return false };
+    let tcp = &view.tcp;
+
+    opt::learn_hops()
+        && tcp.source_port() == 443
+        && (opt::autottl_any() || (tcp.syn() && tcp.ack()))
+}
+
+/// Replay one packet; return whether it was desynced.
+fn replay_packet(pkt: &[u8], buf: &mut Vec<u8>) -> Result<bool> {
+    if sniffed(pkt) {
+        put_hop(pkt);
+    }
+
+    match isolate(|| classify(pkt)) {
+        Ok(Verdict::Accept) => emit(pkt).map(|_| false),
+        Ok(Verdict::Drop) => Ok(false),
+        Ok(Verdict::Desync(job)) => {
+            if let Err(e) = run_job(&job, buf) {
+                crate::warn!("replay: {e}");
+            }
+            Ok(true)
+        }
+        Err(e) => {
+            // Accepted, as the packet loop does
+            crate::warn!("replay: {e}");
+            emit(pkt).map(|_| false)
+        }
+    }
+}
+
+/// Replay `--replay` to `--replay-out`, then print what was done.
+pub fn run() -> Result<()> {
+    let input = opt::replay().context("replay: no capture")?;
+    let output_path = opt::replay_out();
+
+    let pkts = decode::read_packets(Path::new(input)).context("replay")?;
+    let mut file = BufWriter::new(File::create(&output_path)
+        .with_context(|| format!("replay: cannot create {output_path}"))?);
+    file.write_all(&pcap_header())?;
+    let start = pkts.first().map_or(Duration::ZERO, |(at, _)| *at);
+    *output() = Some(Output { file, clock: start, written: 0 });
+
+    crate::rand::reseed(SEED);
+
+    let mut buf = Vec::new();
+    let mut desynced = 0;
+    for (at, pkt) in &pkts {
+        let behind = {
+            let mut out = output();
+            let out = out.as_mut().context("replay: no output")?;
+            let behind = at.saturating_sub(out.clock);
+            out.clock = out.clock.max(*at);
+            behind
+        };
+        // The gap between packets of the capture, less the delays
+        // simulated within it
+        wait(behind);
+
+        if replay_packet(pkt, &mut buf)? {
+            desynced += 1;
+        }
+    }
+
+    let mut out = output().take().context("replay: no output")?;
+    out.file.flush()?;
+
+    println!("replay: {} packets from {input}, {desynced} desynced; {} written to {output_path}",
+             pkts.len(), out.written);
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcap_output() {
+        let mut data = pcap_header();
+        data.extend(pcap_record(Duration::from_micros(1_500_002), &[0x45, 0x00, 0x00]));
+        data.extend(pcap_record(Duration::from_secs(2), &[0x60]));
+
+        let path = std::env::temp_dir().join(format!("dpibreak-replay-{}.pcap", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let pkts = decode::read_packets(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(pkts, [(Duration::from_micros(1_500_002), vec![0x45, 0x00, 0x00]),
+                          (Duration::from_secs(2), vec![0x60])]);
+    }
+}
@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Server names by destination address, for ClientHellos without one
+//!
+//! A client resuming a session (with a PSK or a session ticket) may
+//! leave the server name out of its ClientHello, which `--hostlist` and
+//! `--once-per-host` then cannot classify. The server name of each
+//! ClientHello that has one is bound to its destination address for
+//! `--sni-binding-ttl` seconds, and a ClientHello without one to the
+//! same address is classified by it.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::opt;
+
+/// Addresses bound at most
+const CAP: usize = 4096;
+
+struct Binding {
+    sni: String,
+    at: Instant,
+}
+
+#[derive(Default)]
+struct SniTab {
+    bindings: HashMap<IpAddr, Binding>,
+}
+
+impl SniTab {
+    fn bind(&mut self, daddr: IpAddr, sni: &str, now: Instant, ttl: Duration) {
+        if let Some(b) = self.bindings.get_mut(&daddr) {
+            if b.sni != sni {
+                b.sni = sni.to_string();
+            }
+            b.at = now;
+            return;
+        }
+
+        if self.bindings.len() >= CAP {
+            self.bindings.retain(|_, b| now.duration_since(b.at) < ttl);
+        }
+        if self.bindings.len() >= CAP {
+            let oldest = self.bindings.iter().min_by_key(|(_, b)| b.at).map(|(ip, _)| *ip);
+            if let Some(ip) = oldest {
+                self.bindings.remove(&ip);
+            }
+        }
+
+        self.bindings.insert(daddr, Binding { sni: sni.to_string(), at: now });
+    }
+
+    fn lookup(&self, daddr: IpAddr, now: Instant, ttl: Duration) -> Option<&str> {
+        self.bindings.get(&daddr)
+            .filter(|b| now.duration_since(b.at) < ttl)
+            .map(|b| b.sni.as_str())
+    }
+}
+
+static TAB: OnceLock<Mutex<SniTab>> = OnceLock::new();
+
+fn tab() -> std::sync::MutexGuard<'static, SniTab> {
+    TAB.get_or_init(Mutex::default).lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn ttl() -> Duration {
+    Duration::from_secs(opt::sni_binding_ttl())
+}
+
+/// Bind `sni` to `daddr`, the destination of a ClientHello naming it.
+pub fn bind(daddr: IpAddr, sni: &str) {
+    if opt::sni_binding_ttl() > 0 {
+        tab().bind(daddr, sni, Instant::now(), ttl());
+    }
+}
+
+/// Server name last bound to `daddr`, unless expired
+pub fn lookup(daddr: IpAddr) -> Option<String> {
+    if opt::sni_binding_ttl() == 0 {
+        return None;
+    }
+    tab().lookup(daddr, Instant::now(), ttl()).map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sni_tab() {
+        let ttl = Duration::from_secs(60);
+        let t0 = Instant::now();
+        let ip = |i: u32| IpAddr::from(std::net::Ipv4Addr::from(i));
+        let mut tab = SniTab::default();
+
+        tab.bind(ip(1), "a.example", t0, ttl);
+        assert_eq!(tab.lookup(ip(1), t0 + Duration::from_secs(59), ttl), Some("a.example"));
+        assert_eq!(tab.lookup(ip(1), t0 + ttl, ttl), None);
+        assert_eq!(tab.lookup(ip(2), t0, ttl), None);
+
+        tab.bind(ip(1), "b.example", t0 + ttl, ttl);
+        assert_eq!(tab.lookup(ip(1), t0 + ttl, ttl), Some("b.example"));
+
+        for i in 2..=CAP as u32 + 1 {
+            tab.bind(ip(i), "c.example", t0 + ttl + Duration::from_millis(i as u64), ttl);
+        }
+        assert_eq!(tab.bindings.len(), CAP);
+        assert_eq!(tab.lookup(ip(1), t0 + ttl, ttl), None);
+        assert_eq!(tab.lookup(ip(2), t0 + ttl, ttl), Some("c.example"));
+    }
+}
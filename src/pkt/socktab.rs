@@ -0,0 +1,115 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Connections as seen by the socket layer (`--socket-layer`, Windows)
+//!
+//! WinDivert can report socket events (connect, close) with the
+//! owning process, without diverting any packet. This keeps one entry
+//! per outgoing TCP connection for its whole lifetime, unlike
+//! [`super::flowtab`] whose entries expire with inactivity, so that:
+//!
+//! - a flow can be attributed to its process;
+//! - when a connection whose ClientHello we handled closes, we learn
+//!   how long it lived. A connection closed right after the handshake
+//!   is a likely sign that the desync did not get it through: it counts
+//!   as failed for [`super::outcome`], and as a reset for
+//!   [`super::adaptive`], so that a server passed unchanged by
+//!   `--adaptive-exempt` is desynced again.
+//!
+//! Entries are charged to [`super::budget`]; connections that cannot be
+//! recorded are simply not tracked.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use super::flowtab::FlowKey;
+use super::{adaptive, budget, outcome};
+use crate::{opt, stats};
+
+/// A handled connection closed within this time after connect is
+/// counted as failed.
+const EARLY_CLOSE: Duration = Duration::from_secs(2);
+
+/// Estimated memory of an entry, excluding the server name
+const CONN_COST: usize = std::mem::size_of::<(FlowKey, Conn)>() + 16;
+
+struct Conn {
+    pid: u32,
+    opened: Instant,
+
+    /// Server name of the ClientHello we handled on this connection
+    /// (empty if it has none); None if not handled.
+    handled: Option<String>,
+}
+
+impl Conn {
+    fn cost(&self) -> usize {
+        CONN_COST + self.handled.as_ref().map_or(0, String::len)
+    }
+}
+
+static CONNS: Mutex<Option<HashMap<FlowKey, Conn>>> = Mutex::new(None);
+
+fn conns() -> MutexGuard<'static, Option<HashMap<FlowKey, Conn>>> {
+    CONNS.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn flow_key(local: SocketAddr, remote: SocketAddr) -> FlowKey {
+    FlowKey { saddr: local.ip(), daddr: remote.ip(), sport: local.port(), dport: remote.port() }
+}
+
+/// Process `pid` is connecting from `local` to `remote`.
+pub fn connect(local: SocketAddr, remote: SocketAddr, pid: u32) {
+    let key = flow_key(local, remote);
+    if !budget::try_charge(CONN_COST) {
+        stats::COUNTERS.budget_passed.inc();
+        return;
+    }
+
+    let conn = Conn { pid, opened: Instant::now(), handled: None };
+    if let Some(old) = conns().get_or_insert_default().insert(key, conn) {
+        budget::release(old.cost());
+    }
+}
+
+/// ClientHello to `sni` has been handled on `key`, desynced or passed
+/// by [`adaptive`].
+pub fn handled(key: &FlowKey, sni: Option<&str>) {
+    let mut conns = conns();
+    let Some(conn) = conns.as_mut().and_then(|c| c.get_mut(key)) else {
+        return;
+    };
+
+    let sni = sni.unwrap_or_default();
+    if conn.handled.is_none() {
+        crate::debug!("socket: {sni} ({}) handled for pid {}", key.daddr, conn.pid);
+        budget::charge(sni.len());
+        conn.handled = Some(sni.to_string());
+    }
+}
+
+/// The connection from `local` to `remote` is closed.
+pub fn close(local: SocketAddr, remote: SocketAddr) {
+    let key = flow_key(local, remote);
+    let Some(conn) = conns().as_mut().and_then(|c| c.remove(&key)) else {
+        return;
+    };
+    budget::release(conn.cost());
+
+    let Some(sni) = &conn.handled else { return };
+    let lived = conn.opened.elapsed();
+
+    if lived < EARLY_CLOSE {
+        stats::COUNTERS.sock_closed_early.inc();
+        crate::debug!("socket: {sni} ({}, pid {}) closed after {}ms",
+                      remote.ip(), conn.pid, lived.as_millis());
+        if opt::adaptive_exempt() {
+            adaptive::reset(&key);
+        }
+        outcome::failed(&key);
+    } else {
+        stats::COUNTERS.sock_completed.inc();
+    }
+}
@@ -0,0 +1,175 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Learned state kept across runs
+//!
+//! Each table is a file in the state [`datadir`], loaded on start
+//! and saved every [`SAVE_INTERVAL`] and on exit. `dpibreak state
+//! export` bundles them into one file: a first line carrying
+//! [`VERSION`], then for each table a `[name]` line followed by the
+//! lines of its file. `dpibreak state import` merges such a file into
+//! the tables of this machine.
+
+use std::path::Path;
+use std::sync::Once;
+use std::time::Duration;
+
+use anyhow::{Context, Result, anyhow};
+
+use super::{hoptab, outcome};
+use crate::platform::datadir;
+
+const SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Version of the export format; bump on incompatible changes.
+const VERSION: u32 = 1;
+const MAGIC: &str = "# dpibreak state v";
+
+struct Table {
+    /// Section name in the export format
+    name: &'static str,
+    file: &'static str,
+
+    /// Contents to save, or None if unchanged
+    dump: fn() -> Option<String>,
+    load: fn(&str),
+
+    /// Contents of a file updated with some exported lines
+    merge: fn(&str, &str) -> String,
+}
+
+const TABLES: &[Table] = &[
+    Table { name: "hops", file: hoptab::FILE, dump: hoptab::dump, load: hoptab::load, merge: hoptab::merge },
+    Table { name: "outcomes", file: outcome::FILE, dump: outcome::dump, load: outcome::load, merge: outcome::merge },
+];
+
+/// Contents of state file `file`
+pub fn read(file: &str) -> Result<String> {
+    let path = datadir::dir(datadir::Kind::State).join(file);
+    std::fs::read_to_string(&path).with_context(|| format!("cannot read {}", path.display()))
+}
+
+fn write(file: &str, text: &str) -> Result<()> {
+    let path = datadir::create(datadir::Kind::State)?.join(file);
+    let tmp = path.with_extension("tmp");
+
+    std::fs::write(&tmp, text)
+        .and_then(|_| std::fs::rename(&tmp, &path))
+        .with_context(|| format!("cannot write {}", path.display()))
+}
+
+/// Load the tables saved by previous runs and save them periodically.
+/// Only the first call does; on a restart by `--watch` the tables in
+/// memory are newer than the saved ones.
+pub fn init() {
+    static INIT: Once = Once::new();
+
+    INIT.call_once(|| {
+        for t in TABLES {
+            if let Ok(text) = read(t.file) {
+                (t.load)(&text);
+            }
+        }
+
+        std::thread::spawn(|| loop {
+            std::thread::sleep(SAVE_INTERVAL);
+            save();
+        });
+    });
+}
+
+/// Write the tables that have changed.
+pub fn save() {
+    for t in TABLES {
+        if let Some(text) = (t.dump)()
+            && let Err(e) = write(t.file, &text) {
+            crate::warn!("state: {e:#}");
+        }
+    }
+}
+
+fn export(to: &Path) -> Result<()> {
+    let mut out = format!("{MAGIC}{VERSION}\n");
+
+    for t in TABLES {
+        let Ok(text) = read(t.file) else { continue };
+        out += &format!("[{}]\n", t.name);
+        for line in text.lines().filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            out += line;
+            out.push('\n');
+        }
+    }
+
+    std::fs::write(to, out).with_context(|| format!("cannot write {}", to.display()))
+}
+
+/// Split an exported file into its sections.
+fn sections(text: &str) -> Result<Vec<(&str, String)>> {
+    let mut lines = text.lines();
+    let version = lines.next()
+        .and_then(|l| l.strip_prefix(MAGIC))
+        .ok_or_else(|| anyhow!("not a dpibreak state file"))?;
+    if version.trim().parse() != Ok(VERSION) {
+        return Err(anyhow!("unsupported state version {version}; expected {VERSION}"));
+    }
+
+    let mut sections: Vec<(&str, String)> = Vec::new();
+    for line in lines {
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            sections.push((name, String::new()));
+        } else if let Some((_, body)) = sections.last_mut() {
+            body.push_str(line);
+            body.push('\n');
+        }
+    }
+
+    Ok(sections)
+}
+
+fn import(from: &Path) -> Result<()> {
+    let text = std::fs::read_to_string(from)
+        .with_context(|| format!("cannot read {}", from.display()))?;
+
+    for (name, body) in sections(&text)? {
+        let Some(t) = TABLES.iter().find(|t| t.name == name) else {
+            println!("state: skipping unknown section [{name}]");
+            continue;
+        };
+
+        let old = read(t.file).unwrap_or_default();
+        write(t.file, &(t.merge)(&old, &body))?;
+        println!("state: imported [{name}] {} entries", body.lines().count());
+    }
+
+    Ok(())
+}
+
+/// `dpibreak state export|import FILE`
+pub fn command(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let (Some(cmd), Some(file), None) = (args.next(), args.next(), args.next()) else {
+        return Err(anyhow!("state: usage: dpibreak state export|import FILE"));
+    };
+
+    match cmd.as_str() {
+        "export" => export(Path::new(&file)),
+        "import" => import(Path::new(&file)),
+        _ => Err(anyhow!("state: unknown command: {cmd}")),
+    }.context("state")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sections() {
+        let text = "# dpibreak state v1\n[hops]\n192.0.2.1\t12\n[outcomes]\n[future]\nx\n";
+        let s = sections(text).unwrap();
+        assert_eq!(s, vec![("hops", "192.0.2.1\t12\n".to_string()),
+                           ("outcomes", String::new()),
+                           ("future", "x\n".to_string())]);
+
+        assert!(sections("# dpibreak state v2\n").is_err());
+        assert!(sections("192.0.2.1\t12\n").is_err());
+    }
+}
@@ -0,0 +1,75 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--trace`: log every step of handling selected flows
+//!
+//! A flow is selected on its ClientHello, by destination address, port
+//! and server name, and followed through [`super::classify`] and the
+//! sending of its segments, which are dumped in hex. Everything else
+//! stays at `--log-level`.
+
+use super::PktView;
+use crate::opt;
+
+/// Whether `pattern` matches all of `s`; `*` matches any run of
+/// characters.
+fn glob(pattern: &str, s: &str) -> bool {
+    let Some((head, rest)) = pattern.split_once('*') else {
+        return pattern == s;
+    };
+    let Some(mut s) = s.strip_prefix(head) else {
+        return false;
+    };
+
+    let mut parts: Vec<&str> = rest.split('*').collect();
+    let tail = parts.pop().unwrap_or_default();
+    for part in parts {
+        let Some(at) = s.find(part) else { return false };
+        s = &s[at + part.len()..];
+    }
+
+    s.len() >= tail.len() && s.ends_with(tail)
+}
+
+/// Whether ClientHello `view` to `sni` is to be traced
+pub fn matches(view: &PktView, sni: Option<&str>) -> bool {
+    opt::trace().iter().any(|f| {
+        f.addr.is_none_or(|a| a == view.daddr())
+            && f.port.is_none_or(|p| p == view.tcp.destination_port())
+            && f.sni.as_ref().is_none_or(|g| sni.is_some_and(|s| glob(g, &s.to_ascii_lowercase())))
+    })
+}
+
+/// `data` in hex, 16 bytes per line prefixed by the offset
+pub fn hex_lines(data: &[u8]) -> impl Iterator<Item = String> + '_ {
+    data.chunks(16).enumerate().map(|(i, line)| {
+        let hex: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+        format!("{:04x}: {}", i * 16, hex.join(" "))
+    })
+}
+
+/// Dump `data` in hex.
+pub fn hexdump(what: &str, data: &[u8]) {
+    crate::trace!("{what}: {} bytes", data.len());
+    for line in hex_lines(data) {
+        crate::trace!("  {line}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob() {
+        assert!(glob("example.com", "example.com"));
+        assert!(!glob("example.com", "www.example.com"));
+        assert!(glob("*.example.com", "www.example.com"));
+        assert!(!glob("*.example.com", "example.com"));
+        assert!(glob("*", ""));
+        assert!(glob("a*b*c", "abc"));
+        assert!(glob("a*b*c", "aXbYc"));
+        assert!(!glob("a*b*c", "aXcYb"));
+        assert!(!glob("ab*ba", "aba"));
+    }
+}
@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--v6-degrade`: push dual-stack clients of listed servers to IPv4
+//!
+//! Where no strategy gets through over IPv6 but one does over IPv4,
+//! a dual-stack client is better off without IPv6 for the servers in
+//! `--hostlist`. The IPv6 address of each ClientHello naming a listed
+//! server is remembered for [`DEGRADE_TTL`], and SYNs to port 443 of
+//! that address are dropped meanwhile (the rules queue IPv6 SYNs to
+//! port 443 for this), so that the client's Happy Eyeballs falls back
+//! to IPv4. The connection that taught the address goes on as it is;
+//! the next ones go over IPv4.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::stats;
+
+/// Addresses degraded at most
+const CAP: usize = 4096;
+
+/// An address is degraded for this long after its last ClientHello.
+const DEGRADE_TTL: Duration = Duration::from_secs(6 * 3600);
+
+#[derive(Default)]
+struct DegradeTab {
+    addrs: HashMap<Ipv6Addr, Instant>,
+}
+
+impl DegradeTab {
+    /// Degrade `addr`; false if it already was.
+    fn put(&mut self, addr: Ipv6Addr, now: Instant) -> bool {
+        if let Some(at) = self.addrs.get_mut(&addr) {
+            let fresh = now.duration_since(*at) >= DEGRADE_TTL;
+            *at = now;
+            return fresh;
+        }
+
+        if self.addrs.len() >= CAP {
+            self.addrs.retain(|_, at| now.duration_since(*at) < DEGRADE_TTL);
+        }
+        if self.addrs.len() >= CAP {
+            let oldest = self.addrs.iter().min_by_key(|(_, at)| **at).map(|(ip, _)| *ip);
+            if let Some(ip) = oldest {
+                self.addrs.remove(&ip);
+            }
+        }
+
+        self.addrs.insert(addr, now);
+        true
+    }
+
+    fn contains(&self, addr: &Ipv6Addr, now: Instant) -> bool {
+        self.addrs.get(addr).is_some_and(|at| now.duration_since(*at) < DEGRADE_TTL)
+    }
+}
+
+static TAB: OnceLock<Mutex<DegradeTab>> = OnceLock::new();
+
+fn tab() -> std::sync::MutexGuard<'static, DegradeTab> {
+    TAB.get_or_init(Mutex::default).lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Destination of `pkt` if it is an IPv6 SYN (without ACK) to port 443;
+/// not looking past extension headers
+fn syn_to_443(pkt: &[u8]) -> Option<Ipv6Addr> {
+    const L4: usize = 40;
+
+    if pkt.len() < L4 + 20 || pkt[0] >> 4 != 6 || pkt[6] != 6 {
+        return None;
+    }
+    let dport = u16::from_be_bytes([pkt[L4 + 2], pkt[L4 + 3]]);
+    let flags = pkt[L4 + 13];
+    if dport != 443 || flags & 0x12 != 0x02 {
+        return None;
+    }
+
+    let daddr: [u8; 16] = pkt[24..40].try_into().ok()?;
+    Some(Ipv6Addr::from(daddr))
+}
+
+/// Degrade `daddr`, the destination of a ClientHello naming `sni` in
+/// `--hostlist`, if it is IPv6.
+pub fn learn(daddr: IpAddr, sni: &str) {
+    let IpAddr::V6(addr) = daddr else { return };
+
+    if tab().put(addr, Instant::now()) {
+        crate::info!("v6-degrade: {addr} ({sni}) degraded; SYNs to it dropped for {}s",
+                     DEGRADE_TTL.as_secs());
+        stats::COUNTERS.v6_degraded.inc();
+    }
+}
+
+/// Whether `pkt` is a SYN to a degraded address, to drop
+pub fn drops(pkt: &[u8]) -> bool {
+    let Some(daddr) = syn_to_443(pkt) else { return false };
+
+    let drop = tab().contains(&daddr, Instant::now());
+    if drop {
+        crate::debug!("v6-degrade: SYN to {daddr} dropped");
+        stats::COUNTERS.v6_syn_dropped.inc();
+    }
+    drop
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_degrade_tab() {
+        let t0 = Instant::now();
+        let ip = |i: u128| Ipv6Addr::from(i);
+        let mut tab = DegradeTab::default();
+
+        assert!(tab.put(ip(1), t0));
+        assert!(!tab.put(ip(1), t0 + Duration::from_secs(1)));
+        assert!(tab.contains(&ip(1), t0 + DEGRADE_TTL));
+        assert!(!tab.contains(&ip(1), t0 + Duration::from_secs(1) + DEGRADE_TTL));
+        assert!(!tab.contains(&ip(2), t0));
+        assert!(tab.put(ip(1), t0 + 2 * DEGRADE_TTL));
+
+        for i in 2..=CAP as u128 + 1 {
+            tab.put(ip(i), t0 + 2 * DEGRADE_TTL + Duration::from_millis(i as u64));
+        }
+        assert_eq!(tab.addrs.len(), CAP);
+        assert!(!tab.contains(&ip(1), t0 + 2 * DEGRADE_TTL));
+    }
+
+    #[test]
+    fn test_syn_to_443() {
+        let mut pkt = vec![0u8; 60];
+        pkt[0] = 0x60;
+        pkt[6] = 6;
+        pkt[39] = 1;
+        pkt[42..44].copy_from_slice(&443u16.to_be_bytes());
+        pkt[53] = 0x02;
+        assert_eq!(syn_to_443(&pkt), Some(Ipv6Addr::from(1)));
+
+        pkt[53] = 0x12;         // SYN/ACK
+        assert_eq!(syn_to_443(&pkt), None);
+        pkt[53] = 0x02;
+        pkt[42..44].copy_from_slice(&80u16.to_be_bytes());
+        assert_eq!(syn_to_443(&pkt), None);
+        pkt[42..44].copy_from_slice(&443u16.to_be_bytes());
+        pkt[0] = 0x45;
+        assert_eq!(syn_to_443(&pkt), None);
+    }
+}
@@ -0,0 +1,101 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Strategy worker: runs [`Job`]s off the receive thread
+//!
+//! The receive loop only classifies a packet and gives its verdict;
+//! sending the segments, with `--delay`, fakes and whatever else a
+//! strategy does, happens here. A slow strategy therefore delays only
+//! other desyncs, not the verdicts of every packet queued behind it.
+//! Jobs go through a bounded channel with the receive loop as its only
+//! producer. If the worker falls that far behind, the job is run on the
+//! receive thread instead of being lost.
+//!
+//! On shutdown, [`stop`] makes jobs in flight skip their remaining
+//! delays, and dropping the [`Worker`] sends out what is queued, so that
+//! no ClientHello whose original has been dropped is left unsent.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Context, Result};
+
+use super::{Job, run_job};
+use crate::platform::PACKET_SIZE_CAP;
+use crate::stats;
+
+/// Jobs that may wait for the worker
+const QUEUE_LEN: usize = 256;
+
+/// Workers running jobs; one, for now
+pub const COUNT: usize = 1;
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+/// Tell jobs to send the rest of their segments without delay.
+pub fn stop() {
+    STOPPING.store(true, Ordering::Relaxed);
+}
+
+pub fn stopping() -> bool {
+    STOPPING.load(Ordering::Relaxed)
+}
+
+pub struct Worker {
+    tx: Option<SyncSender<Job>>,
+    thread: Option<JoinHandle<()>>,
+
+    /// For jobs run inline when the queue is full
+    buf: Vec<u8>,
+}
+
+impl Worker {
+    pub fn spawn() -> Result<Self> {
+        let (tx, rx) = mpsc::sync_channel::<Job>(QUEUE_LEN);
+
+        let thread = thread::Builder::new()
+            .name("dpibreak-worker".into())
+            .spawn(move || {
+                let mut buf = Vec::with_capacity(PACKET_SIZE_CAP);
+                for job in rx {
+                    if let Err(e) = run_job(&job, &mut buf) {
+                        crate::warn!("worker: {e}");
+                    }
+                }
+            })
+            .context("failed to spawn worker thread")?;
+
+        Ok(Self { tx: Some(tx), thread: Some(thread), buf: Vec::with_capacity(PACKET_SIZE_CAP) })
+    }
+
+    pub fn submit(&mut self, job: Job) {
+        let Some(tx) = &self.tx else { return };
+
+        let job = match tx.try_send(job) {
+            Ok(()) => return,
+            Err(TrySendError::Full(job)) => {
+                stats::COUNTERS.worker_overflow.inc();
+                job
+            }
+            Err(TrySendError::Disconnected(job)) => {
+                crate::warn!("worker: thread has exited; running job inline");
+                job
+            }
+        };
+
+        if let Err(e) = run_job(&job, &mut self.buf) {
+            crate::warn!("worker: {e}");
+        }
+    }
+}
+
+impl Drop for Worker {
+    /// Let the worker finish queued jobs, then join it.
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() && thread.join().is_err() {
+            crate::warn!("worker: thread panicked");
+        }
+    }
+}
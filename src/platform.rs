@@ -15,22 +15,159 @@
 // You should have received a copy of the GNU General Public License
 // along with DPIBreak. If not, see <https://www.gnu.org/licenses/>.
 
-const PACKET_SIZE_CAP: usize = 2048;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+pub const PACKET_SIZE_CAP: usize = 2048;
 const MESSAGE_AT_RUN: &str = r#"DPIBreak is now running.
 Press Ctrl+C or close this window to stop.
 "#;
 
+pub mod datadir;
+pub mod rulespec;
+
 #[cfg(windows)]
 pub mod windows;
 
 #[cfg(windows)]
-pub use windows::{bootstrap, run, local_time, send_to_raw, pause};
+pub use windows::{bootstrap, run, local_time, send_to_raw, pause, map_shared};
 
 #[cfg(target_os = "linux")]
 pub mod linux;
 
 #[cfg(target_os = "linux")]
-pub use linux::{bootstrap, run, local_time, send_to_raw, is_kernel_filtered_clienthello};
+pub use linux::{bootstrap, run, local_time, send_to_raw, is_kernel_filtered_clienthello, capabilities,
+                map_shared, CLEANUP_HELPER, cleanup_helper};
+
+/// Destinations exempted from handling unless `--no-private-exempt`:
+/// loopback, private (RFC 1918, unique local) and link-local networks.
+/// No DPI sits on the way to them; desyncing connections to a NAS or the
+/// router's admin page only risks breaking them.
+pub const PRIVATE_NETS: &[(IpAddr, u8)] = &[
+    (IpAddr::V4(Ipv4Addr::new(127, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(10, 0, 0, 0)), 8),
+    (IpAddr::V4(Ipv4Addr::new(172, 16, 0, 0)), 12),
+    (IpAddr::V4(Ipv4Addr::new(192, 168, 0, 0)), 16),
+    (IpAddr::V4(Ipv4Addr::new(169, 254, 0, 0)), 16),
+    (IpAddr::V6(Ipv6Addr::LOCALHOST), 128),
+    (IpAddr::V6(Ipv6Addr::new(0xfc00, 0, 0, 0, 0, 0, 0, 0)), 7),
+    (IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 0)), 10),
+];
+
+/// Netmask of `prefix` bits in the family of `net`
+pub fn prefix_mask(net: IpAddr, prefix: u8) -> IpAddr {
+    match net {
+        IpAddr::V4(_) => IpAddr::V4(u32::MAX.checked_shl(32 - u32::from(prefix.min(32))).unwrap_or(0).into()),
+        IpAddr::V6(_) => IpAddr::V6(u128::MAX.checked_shl(128 - u32::from(prefix.min(128))).unwrap_or(0).into()),
+    }
+}
+
+/// Delay before the first restart of a failed [`run`] on `--watch`,
+/// doubled on each failure in a row up to [`RESTART_BACKOFF_MAX`].
+const RESTART_BACKOFF_MIN: std::time::Duration = std::time::Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// A run lasting this long is healthy again; the restart count and
+/// backoff start over after it.
+const RESTART_STABLE: std::time::Duration = std::time::Duration::from_secs(600);
+
+/// [`run`], restarted when it fails on `--watch`. Each run tears down
+/// what it has set up on error, so the next one bootstraps the backend
+/// from scratch.
+pub fn supervise() -> anyhow::Result<()> {
+    use std::time::{Duration, Instant};
+    use crate::{opt, pkt::worker::stopping};
+
+    // Once for the process, outliving restarts
+    crate::stats::start();
+    crate::stats::spawn_heartbeat();
+    crate::stats::shm::spawn()?;
+    crate::schedule::spawn();
+    crate::exit::report_running();
+
+    let mut restarts = 0;
+    let mut backoff = RESTART_BACKOFF_MIN;
+
+    loop {
+        let started = Instant::now();
+        let Err(e) = run() else { return Ok(()) };
+
+        if !opt::watch() || stopping() {
+            return Err(e);
+        }
+        if started.elapsed() >= RESTART_STABLE {
+            restarts = 0;
+            backoff = RESTART_BACKOFF_MIN;
+        }
+
+        let max = opt::max_restarts();
+        if max != 0 && restarts >= max {
+            crate::error!("watch: giving up after {restarts} restarts");
+            return Err(e);
+        }
+        restarts += 1;
+        crate::warn!("watch: {e:#}; restart {restarts}{} in {}s",
+                     if max != 0 { format!("/{max}") } else { String::new() },
+                     backoff.as_secs());
+
+        // In steps, so that a shutdown request does not wait for it
+        let until = Instant::now() + backoff;
+        while Instant::now() < until {
+            if stopping() {
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        backoff = (backoff * 2).min(RESTART_BACKOFF_MAX);
+    }
+}
+
+/// Capabilities in effect, reported once on startup by [`Health::report`].
+pub struct Health {
+    /// Firewall/diversion backend in use (nftables, iptables, WinDivert)
+    pub backend: &'static str,
+
+    /// How ClientHello is filtered before reaching userspace, if it is.
+    pub prefilter: Option<&'static str>,
+
+    /// How SYN/ACK is captured for `--fake-autottl`, if it is.
+    pub autottl: Option<&'static str>,
+
+    /// How desynced segments are sent
+    pub injection: &'static str,
+    pub ipv6: bool,
+    pub workers: usize,
+}
+
+static BACKEND: std::sync::Mutex<&str> = std::sync::Mutex::new("none (starting)");
+
+/// Backend of the current run, as last reported by [`Health::report`]
+pub fn backend() -> &'static str {
+    *BACKEND.lock().unwrap()
+}
+
+impl Health {
+    pub fn report(&self) {
+        *BACKEND.lock().unwrap() = self.backend;
+
+        let yes_no = |b: bool| if b { "yes" } else { "no" };
+        let lines = [
+            ("backend", self.backend.to_string()),
+            ("prefilter", self.prefilter.map_or("none (all port 443 packets are queued)".into(),
+                                                |p| format!("ClientHello ({p})"))),
+            ("autottl", self.autottl.unwrap_or("disabled").to_string()),
+            ("injection", self.injection.to_string()),
+            ("ipv6", yes_no(self.ipv6).to_string()),
+            ("workers", self.workers.to_string()),
+        ];
+
+        crate::splash!("Health check:");
+        for (k, v) in &lines {
+            crate::splash!("  {:<10} {v}", format!("{k}:"));
+            crate::info!("health: {k}: {v}");
+        }
+        crate::splash!("");
+    }
+}
 
 /// pause before exit on windows to print information in console before it is closed.
 pub fn paexit(code: i32) -> ! {
@@ -41,3 +178,20 @@ pub fn paexit(code: i32) -> ! {
     }
     std::process::exit(code);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_mask() {
+        let v4 = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+        let v6 = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+
+        assert_eq!(prefix_mask(v4, 12), "255.240.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(prefix_mask(v4, 32), "255.255.255.255".parse::<IpAddr>().unwrap());
+        assert_eq!(prefix_mask(v4, 0), "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(prefix_mask(v6, 10), "ffc0::".parse::<IpAddr>().unwrap());
+        assert_eq!(prefix_mask(v6, 128), IpAddr::V6(Ipv6Addr::from(u128::MAX)));
+    }
+}
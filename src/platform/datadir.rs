@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Where data kept across runs lives
+//!
+//! DPIBreak runs as root or as a Windows service, and is also run from
+//! elevated consoles (`dpibreak state export`, a foreground run); all of
+//! them must find the same files. So these are system-wide directories
+//! writable only by the system and administrators: learned state reveals
+//! the sites visited, and a planted cache or state file would be trusted
+//! by the service. A Linux user without root, who cannot run DPIBreak
+//! itself but may look at exported state, gets the XDG directories.
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Kind {
+    /// Learned at runtime: hops, outcomes
+    State,
+
+    /// Downloaded, fetched again if lost: remote hostlists
+    Cache,
+}
+
+/// `$XDG_*` given in `var` (ignored unless absolute, as the spec says)
+/// or `fallback` under `home`, with our directory in it
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn xdg_dir(var: Option<PathBuf>, home: Option<PathBuf>, fallback: &str) -> Option<PathBuf> {
+    var.filter(|v| v.is_absolute())
+        .or_else(|| home.filter(|h| h.is_absolute()).map(|h| h.join(fallback)))
+        .map(|d| d.join("dpibreak"))
+}
+
+/// Directory of `kind`: `/var/lib/dpibreak` and `/var/cache/dpibreak`,
+/// or the XDG ones of a user other than root
+#[cfg(target_os = "linux")]
+pub fn dir(kind: Kind) -> PathBuf {
+    let (system, var, fallback) = match kind {
+        Kind::State => ("/var/lib/dpibreak", "XDG_STATE_HOME", ".local/state"),
+        Kind::Cache => ("/var/cache/dpibreak", "XDG_CACHE_HOME", ".cache"),
+    };
+    if super::linux::is_root() {
+        return PathBuf::from(system);
+    }
+
+    let env = |name| std::env::var_os(name).map(PathBuf::from);
+    xdg_dir(env(var), env("HOME"), fallback).unwrap_or_else(|| PathBuf::from(system))
+}
+
+/// Directory of `kind`: `%ProgramData%\dpibreak` for both. Never under
+/// the temporary directory, which any user can write to.
+#[cfg(windows)]
+pub fn dir(_kind: Kind) -> PathBuf {
+    std::env::var_os("ProgramData").map(PathBuf::from)
+        .filter(|d| d.is_absolute())
+        .unwrap_or_else(|| PathBuf::from(r"C:\ProgramData"))
+        .join("dpibreak")
+}
+
+/// [`dir`] of `kind`, created if missing, with its permissions set.
+pub fn create(kind: Kind) -> Result<PathBuf> {
+    let dir = dir(kind);
+    std::fs::create_dir_all(&dir).with_context(|| format!("cannot create {}", dir.display()))?;
+    restrict(&dir, kind).with_context(|| format!("cannot set permissions of {}", dir.display()))?;
+    Ok(dir)
+}
+
+/// State readable by its owner only, the cache by anyone
+#[cfg(target_os = "linux")]
+fn restrict(dir: &std::path::Path, kind: Kind) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = if kind == Kind::State { 0o700 } else { 0o755 };
+    std::fs::set_permissions(dir, std::fs::Permissions::from_mode(mode))?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn restrict(dir: &std::path::Path, _kind: Kind) -> Result<()> {
+    super::windows::restrict_dir(dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_xdg_dir() {
+        let p = |s: &str| Some(PathBuf::from(s));
+        assert_eq!(xdg_dir(p("/x/state"), p("/home/u"), ".local/state"), p("/x/state/dpibreak"));
+        assert_eq!(xdg_dir(None, p("/home/u"), ".local/state"), p("/home/u/.local/state/dpibreak"));
+        assert_eq!(xdg_dir(p("relative"), p("/home/u"), ".cache"), p("/home/u/.cache/dpibreak"));
+        assert_eq!(xdg_dir(None, None, ".cache"), None);
+    }
+}
@@ -3,7 +3,8 @@
 
 use std::{
     os::fd::{AsRawFd, OwnedFd},
-    sync::{LazyLock, atomic}
+    sync::{Arc, LazyLock, OnceLock, atomic, mpsc},
+    thread::JoinHandle,
 };
 use std::fs::OpenOptions;
 use std::io::Write;
@@ -11,14 +12,18 @@ use std::io::Write;
 use anyhow::{Result, Context};
 use socket2::{Domain, Protocol, Socket, Type};
 
+mod cleanup;
+mod features;
 mod rules;
 mod rxring;
 #[macro_use] mod libc_s;
 
 use crate::pkt;
 use crate::opt;
+use crate::exit::{Status, WithStatus};
+
+pub use cleanup::{COMMAND as CLEANUP_HELPER, helper as cleanup_helper};
 
-const INJECT_MARK: u32 = 0xD001;
 const PID_FILE: &str = "/run/dpibreak.pid"; // TODO: unmagic this
 const PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
@@ -33,7 +38,8 @@ fn lock_pid_file() -> Result<()> {
 
     if flock(pid_file.as_raw_fd(), libc::LOCK_NB | libc::LOCK_EX).is_err() {
         let existing_pid = std::fs::read_to_string(PID_FILE)?;
-        anyhow::bail!("Fail to lock {PID_FILE}: {PKG_NAME} already running with PID {}", existing_pid.trim());
+        return Err(anyhow::anyhow!("Fail to lock {PID_FILE}: {PKG_NAME} already running with PID {}",
+                                   existing_pid.trim())).status(Status::Running);
     }
 
     pid_file.set_len(0)?;
@@ -45,47 +51,129 @@ fn lock_pid_file() -> Result<()> {
     Ok(())
 }
 
-fn exit_if_not_root() {
-    if libc_s::geteuid() != 0 {
-        crate::error!("{PKG_NAME} must be run as root. Try sudo.");
-        std::process::exit(3);
-    }
+pub fn is_root() -> bool {
+    libc_s::geteuid() == 0
 }
 
-/// Bootstraps that don't require cleanup after load global opts
-pub fn bootstrap() -> Result<()> {
-    exit_if_not_root();
-    if !opt::daemon() {
-        lock_pid_file()?;
-    } else {
-        daemonize();
+fn check_root() -> Result<()> {
+    if !is_root() {
+        return Err(anyhow::anyhow!("{PKG_NAME} must be run as root. Try sudo.")).status(Status::Permission);
     }
-
     Ok(())
 }
 
-static RAW4: LazyLock<Socket> = LazyLock::new(|| {
-    let sock = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP))
-        .expect("create raw4");
+/// Set if nothing can be sent; see [`pkt::mangle_packet`].
+static MANGLE_ONLY: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+pub fn mangle_only() -> bool {
+    MANGLE_ONLY.load(atomic::Ordering::Relaxed)
+}
 
-    sock.set_header_included_v4(true).expect("IP_HDRINCL");
-    sock.set_mark(INJECT_MARK).expect("SO_MARK");
+static RAW4: OnceLock<Socket> = OnceLock::new();
 
-    sock
-});
+/// Unset if IPv6 is disabled on this host
+static RAW6: OnceLock<Socket> = OnceLock::new();
 
-static RAW6: LazyLock<Socket> = LazyLock::new(|| {
-    let sock = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::TCP))
-        .expect("create raw6");
+fn open_raw4() -> std::io::Result<Socket> {
+    let sock = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP))?;
+    sock.set_header_included_v4(true)?;
+    Ok(sock)
+}
 
+fn open_raw6() -> std::io::Result<Socket> {
+    let sock = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::TCP))?;
     if let Err(e) = sock.set_header_included_v6(true) {
         crate::warn!("Failed to set IPV6_HDRINCL. Maybe old kernel version? IPv6 header manipulation disabled.");
         crate::warn!("Cause: {e}");
     }
-    sock.set_mark(INJECT_MARK).expect("SO_MARK");
+    Ok(sock)
+}
+
+/// Open the raw sockets segments are sent with, before any rules are
+/// installed. Fall back to mangling queued packets in place if they
+/// are denied, as on hardened kernels or in containers without NET_RAW.
+fn open_raw_sockets() -> Result<()> {
+    use std::io::ErrorKind;
+
+    let set_mark = |sock: &Socket, family: &str| sock.set_mark(opt::mark().value)
+        .with_context(|| format!("cannot set SO_MARK on {family} raw socket; \
+                                  is CAP_NET_ADMIN missing?"));
+
+    if !opt::mangle_only() {
+        match open_raw4() {
+            Ok(sock) => {
+                set_mark(&sock, "IPv4")?;
+                _ = RAW4.set(sock);
+
+                match open_raw6() {
+                    Ok(sock) => {
+                        set_mark(&sock, "IPv6")?;
+                        _ = RAW6.set(sock);
+                    }
+                    Err(e) if e.raw_os_error() == Some(libc::EAFNOSUPPORT) => {
+                        // No IPv6 traffic to handle either
+                        crate::warn!("cannot open IPv6 raw socket ({e}); is IPv6 disabled?");
+                    }
+                    Err(e) => return Err(e).context("cannot open IPv6 raw socket"),
+                }
+                return Ok(());
+            }
+            Err(e) if e.kind() == ErrorKind::PermissionDenied => {
+                crate::warn!("cannot open raw socket ({e}); is CAP_NET_RAW missing? \
+                              falling back to mangle-only mode");
+            }
+            Err(e) => return Err(e).context("cannot open IPv4 raw socket"),
+        }
+    }
+
+    MANGLE_ONLY.store(true, atomic::Ordering::Relaxed);
+    if opt::fake() || opt::segment_order().segments().first().is_some_and(|s| s.0 != 0) {
+        crate::warn!("mangle-only: fakes and out-of-order segments need a raw socket; \
+                      only the first segment is cut in place");
+    }
+
+    Ok(())
+}
+
+/// `--container`: tell what to add to the container when a capability
+/// we need is missing, instead of failing later on some syscall.
+fn check_container_caps() -> Result<()> {
+    use features::{CAP_NET_ADMIN, CAP_NET_RAW};
+
+    let f = features::get();
+    if !f.has_cap(CAP_NET_ADMIN) {
+        return Err(anyhow::anyhow!("container: CAP_NET_ADMIN is missing; it is needed to install rules \
+                                    and bind the queue (docker run --cap-add=NET_ADMIN --network=host)"))
+            .status(Status::Permission);
+    }
+    if !f.has_cap(CAP_NET_RAW) && !opt::mangle_only() {
+        return Err(anyhow::anyhow!("container: CAP_NET_RAW is missing; it is needed to send segments \
+                                    (docker run --cap-add=NET_RAW), or pass --mangle-only"))
+            .status(Status::Permission);
+    }
+    if !f.modules {
+        crate::info!("container: no /proc/modules; kernel modules must be loaded on the host");
+    }
+
+    Ok(())
+}
+
+/// Bootstraps that don't require cleanup after load global opts
+pub fn bootstrap() -> Result<()> {
+    block_signals().context("cannot block signals")?;
+    check_root()?;
+    if opt::container() {
+        check_container_caps()?;
+    }
+    open_raw_sockets()?;
+    if !opt::daemon() {
+        lock_pid_file()?;
+    } else {
+        daemonize().context("fail to start as daemon").status(Status::Daemon)?;
+    }
 
-    sock
-});
+    Ok(())
+}
 
 pub fn send_to_raw(pkt: &[u8], dst: std::net::IpAddr) -> Result<()> {
     use std::net::*;
@@ -94,25 +182,79 @@ pub fn send_to_raw(pkt: &[u8], dst: std::net::IpAddr) -> Result<()> {
         IpAddr::V4(dst) => {
             let addr = SocketAddr::from((dst, 0u16));
 
-            RAW4.send_to(pkt, &addr.into())?;
+            RAW4.get().context("no IPv4 raw socket")?.send_to(pkt, &addr.into())?;
         }
         IpAddr::V6(dst) => {
             let addr = SocketAddr::from((dst, 0u16));
 
-            RAW6.send_to(pkt, &addr.into())?;
+            RAW6.get().context("no IPv6 raw socket")?.send_to(pkt, &addr.into())?;
         }
     }
 
     Ok(())
 }
 
+/// Number of queue numbers to try from `opt::queue_num()` if it is not
+/// pinned by `--queue-num`.
+const QUEUE_NUM_PROBE_MAX: u16 = 16;
+
+/// Queue number actually bound by [`open_nfqueue`]
+static QUEUE_NUM: std::sync::OnceLock<u16> = std::sync::OnceLock::new();
+
+pub fn queue_num() -> u16 {
+    *QUEUE_NUM.get().unwrap_or(&opt::queue_num())
+}
+
+/// Bind `q` to `opt::queue_num()`. If it is taken (e.g. by another
+/// program) and not pinned by user, try the following numbers.
+fn bind_nfqueue(q: &mut nfq::Queue) -> Result<u16> {
+    let start = opt::queue_num();
+
+    if opt::queue_num_pinned() {
+        q.bind(start).with_context(|| {
+            format!("nfqueue: cannot bind to queue number {start}; is it used by another program?")
+        })?;
+        return Ok(start);
+    }
+
+    for n in (0..QUEUE_NUM_PROBE_MAX).filter_map(|i| start.checked_add(i)) {
+        match q.bind(n) {
+            Ok(()) => {
+                if n != start {
+                    crate::warn!("nfqueue: queue number {start} is busy; selected {n}");
+                }
+                return Ok(n);
+            }
+            Err(e) => crate::debug!("nfqueue: cannot bind to queue number {n}: {e}"),
+        }
+    }
+
+    anyhow::bail!("nfqueue: no free queue number in {start}..{}; use --queue-num",
+                  start.saturating_add(QUEUE_NUM_PROBE_MAX));
+}
+
+/// Queue length per worker without `--queue-maxlen`: four times the
+/// kernel's default, so that a burst of ClientHellos (a page load, the
+/// clients behind a gateway reconnecting at once) waits for us rather
+/// than being dropped
+const QUEUE_MAXLEN_PER_WORKER: u32 = 4096;
+
+fn queue_maxlen() -> u32 {
+    opt::queue_maxlen().unwrap_or(QUEUE_MAXLEN_PER_WORKER * crate::pkt::worker::COUNT as u32)
+}
+
 fn open_nfqueue() -> Result<nfq::Queue> {
     use std::os::fd::AsRawFd;
     use libc_s::{fcntl, FcntlArg};
 
-    let mut q = nfq::Queue::open()?;
-    q.bind(opt::queue_num())?;
-    crate::info!("nfqueue: bound to queue number {}", opt::queue_num());
+    let mut q = nfq::Queue::open().context("nfqueue: cannot open netlink socket")?;
+    let n = bind_nfqueue(&mut q)?;
+    _ = QUEUE_NUM.set(n);
+    crate::info!("nfqueue: bound to queue number {n}");
+
+    let maxlen = queue_maxlen();
+    q.set_queue_max_len(n, maxlen).context("nfqueue: cannot set the queue length")?;
+    crate::info!("nfqueue: up to {maxlen} packets held");
 
     // to check inturrupts
     let fd = q.as_raw_fd();
@@ -122,6 +264,41 @@ fn open_nfqueue() -> Result<nfq::Queue> {
     Ok(q)
 }
 
+/// Packets of queue `num` the kernel has dropped, from
+/// [`rules::NFNETLINK_QUEUE_PROC`]: as the queue was full, and as they
+/// could not be delivered to our socket (its buffer full)
+fn parse_queue_drops(text: &str, num: u16) -> Option<(u64, u64)> {
+    // queue_num portid queue_total copy_mode copy_range queue_dropped
+    // user_dropped id_sequence 1
+    text.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.first()?.parse::<u16>().ok()? != num {
+            return None;
+        }
+        Some((fields.get(5)?.parse().ok()?, fields.get(6)?.parse().ok()?))
+    })
+}
+
+/// Count the packets the kernel has dropped from our queue since `last`
+/// was taken, and warn of them.
+fn sync_queue_drops(last: &mut (u64, u64)) {
+    let Ok(text) = std::fs::read_to_string(rules::NFNETLINK_QUEUE_PROC) else { return };
+    let Some((full, user)) = parse_queue_drops(&text, queue_num()) else { return };
+
+    let (new_full, new_user) = (full.saturating_sub(last.0), user.saturating_sub(last.1));
+    *last = (full, user);
+
+    if new_full > 0 {
+        crate::warn!("nfqueue: {new_full} packets dropped by the kernel, the queue being full \
+                      ({} packets); see --queue-maxlen", queue_maxlen());
+        crate::stats::COUNTERS.queue_full_dropped.add(new_full);
+    }
+    if new_user > 0 {
+        crate::warn!("nfqueue: {new_user} packets dropped by the kernel, our socket buffer being full");
+        crate::stats::COUNTERS.queue_user_dropped.add(new_user);
+    }
+}
+
 /// Open AF_PACKET RX ring for syn/ack packets
 fn open_rxring() -> Result<rxring::RxRing> {
     use libc::sock_filter;
@@ -155,22 +332,198 @@ fn open_rxring() -> Result<rxring::RxRing> {
         sock_filter { code: 0x6,  jt: 0,  jf: 0,  k: 0x00040000 },
         sock_filter { code: 0x6,  jt: 0,  jf: 0,  k: 0x00000000 },
     ];
+
+    /// cBPF filter for TCP and sport=443 packets, for `--autottl-any`
+    ///
+    /// Same as [`SYNACK_443_CBPF`] without the flags tests:
+    /// '(ip and tcp src port 443) or (ip6 and tcp src port 443)'
+    const ANY_443_CBPF: &[sock_filter] = &[
+        sock_filter { code: 0x28, jt: 0,  jf: 0,  k: 0x0000000c },
+        sock_filter { code: 0x15, jt: 0,  jf: 7,  k: 0x00000800 },
+        sock_filter { code: 0x30, jt: 0,  jf: 0,  k: 0x00000017 },
+        sock_filter { code: 0x15, jt: 0,  jf: 11, k: 0x00000006 },
+        sock_filter { code: 0x28, jt: 0,  jf: 0,  k: 0x00000014 },
+        sock_filter { code: 0x45, jt: 9,  jf: 0,  k: 0x00001fff },
+        sock_filter { code: 0xb1, jt: 0,  jf: 0,  k: 0x0000000e },
+        sock_filter { code: 0x48, jt: 0,  jf: 0,  k: 0x0000000e },
+        sock_filter { code: 0x15, jt: 5,  jf: 6,  k: 0x000001bb },
+        sock_filter { code: 0x15, jt: 0,  jf: 5,  k: 0x000086dd },
+        sock_filter { code: 0x30, jt: 0,  jf: 0,  k: 0x00000014 },
+        sock_filter { code: 0x15, jt: 0,  jf: 3,  k: 0x00000006 },
+        sock_filter { code: 0x28, jt: 0,  jf: 0,  k: 0x00000036 },
+        sock_filter { code: 0x15, jt: 0,  jf: 1,  k: 0x000001bb },
+        sock_filter { code: 0x6,  jt: 0,  jf: 0,  k: 0x00040000 },
+        sock_filter { code: 0x6,  jt: 0,  jf: 0,  k: 0x00000000 },
+    ];
+
     const BLOCK_SIZE: u32 = 4096 * 4; // 16 KB
     const BLOCK_NR:   u32 = 4;
 
     /// tpacket_hdr (~66) + eth(14) + ipv6(40) + tcp with options(60) = ~180
     const FRAME_SIZE: u32 = 256;
 
-    let rx = rxring::RxRing::new(SYNACK_443_CBPF, BLOCK_SIZE, BLOCK_NR, FRAME_SIZE)?;
+    let filter = if opt::autottl_any() { ANY_443_CBPF } else { SYNACK_443_CBPF };
+    let rx = rxring::RxRing::new(filter, BLOCK_SIZE, BLOCK_NR, FRAME_SIZE)?;
     crate::info!("rxring: initialized");
 
     Ok(rx)
 }
 
-/// open signalfd for SIGINT and SIGTERM
-fn open_signalfd() -> Result<OwnedFd> {
+/// Ring of ICMP "fragmentation needed" and ICMPv6 "packet too big", for
+/// `--watch-pmtu`. Copies, as the SYN/ACKs; they go on to the stack
+/// whatever we make of them.
+fn open_pmtu_ring() -> Result<rxring::RxRing> {
+    use libc::sock_filter;
+
+    /// cBPF filter for '(icmp[icmptype] == 3 and icmp[icmpcode] == 4)
+    /// or (ip6 and ip6[6] == 58 and ip6[40] == 2)', not following IPv6
+    /// extension headers
+    const TOO_BIG_CBPF: &[sock_filter] = &[
+        sock_filter { code: 0x28, jt: 0,  jf: 0,  k: 0x0000000c },
+        sock_filter { code: 0x15, jt: 0,  jf: 7,  k: 0x00000800 },
+        sock_filter { code: 0x30, jt: 0,  jf: 0,  k: 0x00000017 },
+        sock_filter { code: 0x15, jt: 0,  jf: 11, k: 0x00000001 },
+        sock_filter { code: 0x28, jt: 0,  jf: 0,  k: 0x00000014 },
+        sock_filter { code: 0x45, jt: 9,  jf: 0,  k: 0x00001fff },
+        sock_filter { code: 0xb1, jt: 0,  jf: 0,  k: 0x0000000e },
+        sock_filter { code: 0x48, jt: 0,  jf: 0,  k: 0x0000000e },
+        sock_filter { code: 0x15, jt: 5,  jf: 6,  k: 0x00000304 },
+        sock_filter { code: 0x15, jt: 0,  jf: 5,  k: 0x000086dd },
+        sock_filter { code: 0x30, jt: 0,  jf: 0,  k: 0x00000014 },
+        sock_filter { code: 0x15, jt: 0,  jf: 3,  k: 0x0000003a },
+        sock_filter { code: 0x30, jt: 0,  jf: 0,  k: 0x00000036 },
+        sock_filter { code: 0x15, jt: 0,  jf: 1,  k: 0x00000002 },
+        sock_filter { code: 0x6,  jt: 0,  jf: 0,  k: 0x00040000 },
+        sock_filter { code: 0x6,  jt: 0,  jf: 0,  k: 0x00000000 },
+    ];
+
+    /// Rare; one block will do
+    const BLOCK_SIZE: u32 = 4096 * 4; // 16 KB
+    const BLOCK_NR:   u32 = 1;
+
+    /// Up to the quoted TCP ports of IPv6:
+    /// tpacket_hdr (~66) + eth(14) + ipv6(40) + icmpv6(8) + ipv6(40) + 4 = ~172
+    const FRAME_SIZE: u32 = 256;
+
+    let rx = rxring::RxRing::new(TOO_BIG_CBPF, BLOCK_SIZE, BLOCK_NR, FRAME_SIZE)?;
+    crate::info!("rxring: watching path MTU");
+
+    Ok(rx)
+}
+
+/// Time we give ourselves to shut down in `--container` mode, well
+/// within the 10 seconds `docker stop` waits between SIGTERM and
+/// SIGKILL. A SIGKILL would leave our rules on the host network.
+const CONTAINER_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// Remove the rules and exit if shutting down takes longer than
+/// [`CONTAINER_GRACE`], e.g. because a worker is stuck sending.
+fn spawn_grace_timer() {
+    std::thread::spawn(|| {
+        std::thread::sleep(CONTAINER_GRACE);
+        crate::warn!("container: not shut down within {}s; removing rules and exiting",
+                     CONTAINER_GRACE.as_secs());
+        _ = rules::nft_cleanup();
+        _ = rules::ipt6_cleanup(false);
+        _ = rules::ipt6_cleanup(true);
+        std::process::exit(0);
+    });
+}
+
+/// Seconds the packet loop may stay without a heartbeat before
+/// [`spawn_watchdog`] gives up on it.
+const WATCHDOG_TIMEOUT_SECS: u64 = 10;
+
+/// Poll timeout of the packet loop on `--fail-closed`, so that it
+/// beats at least once per this interval even when idle.
+const HEARTBEAT_INTERVAL_MS: i32 = 1000;
+
+static LAST_HEARTBEAT: LazyLock<std::sync::Mutex<std::time::Instant>> =
+    LazyLock::new(|| std::sync::Mutex::new(std::time::Instant::now()));
+
+fn heartbeat() {
+    *LAST_HEARTBEAT.lock().unwrap() = std::time::Instant::now();
+}
+
+/// Whether the packet loop is running, so that [`spawn_watchdog`] does
+/// not take the backoff of `--watch` for a stall.
+static WATCHDOG_ARMED: atomic::AtomicBool = atomic::AtomicBool::new(false);
+
+/// Disarms the watchdog when the packet loop returns, on error or not
+struct WatchdogGuard;
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        WATCHDOG_ARMED.store(false, atomic::Ordering::Relaxed);
+    }
+}
+
+/// On `--fail-closed`, rules are installed without queue bypass, so
+/// HTTPS is blocked whenever nobody consumes the queue. A stalled
+/// packet loop would however keep the process alive and the queue
+/// bound while doing nothing useful. Abort in that case so that the
+/// service manager can notice and restart us; the rules are left
+/// installed on purpose. The thread is spawned once and armed for
+/// each run.
+fn spawn_watchdog() -> WatchdogGuard {
+    use std::time::Duration;
+    static SPAWN: std::sync::Once = std::sync::Once::new();
+
+    heartbeat();
+    WATCHDOG_ARMED.store(true, atomic::Ordering::Relaxed);
+    SPAWN.call_once(|| {
+        std::thread::spawn(|| loop {
+            std::thread::sleep(Duration::from_secs(1));
+
+            let stalled = LAST_HEARTBEAT.lock().unwrap().elapsed();
+            if WATCHDOG_ARMED.load(atomic::Ordering::Relaxed)
+                && stalled >= Duration::from_secs(WATCHDOG_TIMEOUT_SECS) {
+                crate::error!("watchdog: packet loop stalled for {}s; aborting \
+                               (fail-closed rules stay installed)", stalled.as_secs());
+                std::process::abort();
+            }
+        });
+    });
+
+    WatchdogGuard
+}
+
+/// Checks our rules every `--rule-check-interval` and reinstalls them
+/// if removed, on a thread of its own as hostlist refreshes are: `nft`
+/// and `iptables` take their time, which ClientHellos in the queue (and,
+/// on `--fail-closed`, the watchdog) would otherwise wait for. Stopped
+/// and joined on drop, so that it does not reinstall rules being removed.
+struct RuleChecker {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RuleChecker {
+    fn spawn(rule: Arc<rules::InstalledRules>, interval: std::time::Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::spawn(move || {
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                if let Err(e) = rule.repair() {
+                    crate::error!("rules: cannot reinstall: {e:#}");
+                }
+            }
+        });
+        Self { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for RuleChecker {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+/// SIGINT, SIGTERM, SIGQUIT, SIGHUP and SIGUSR1, read from signalfd
+fn signal_set() -> libc::sigset_t {
     use libc::*;
-    use std::os::fd::FromRawFd;
 
     // SAFETY: sigaddset fails only when signum is invalid
     unsafe {
@@ -178,29 +531,111 @@ fn open_signalfd() -> Result<OwnedFd> {
         sigemptyset(&mut mask);
         sigaddset(&mut mask, SIGTERM);
         sigaddset(&mut mask, SIGINT);
+        sigaddset(&mut mask, SIGQUIT);
+        sigaddset(&mut mask, SIGHUP);
+        sigaddset(&mut mask, SIGUSR1);
+        mask
+    }
+}
 
-        syscall!(pthread_sigmask(SIG_BLOCK, &mask, core::ptr::null_mut()))?;
-        let raw = syscall!(signalfd(-1, &mask, 0))?;
+/// Block [`signal_set`] in the calling thread, and so in the threads it
+/// spawns afterwards (and across fork). Must run before any thread is
+/// spawned: a signal not blocked in some thread is delivered to it, and
+/// its default action kills the process without removing the rules.
+fn block_signals() -> Result<()> {
+    let mask = signal_set();
+    // SAFETY: mask is initialized; it returns the error number itself
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &mask, core::ptr::null_mut()) } {
+        0 => Ok(()),
+        e => Err(std::io::Error::from_raw_os_error(e).into()),
+    }
+}
 
+/// open signalfd for [`signal_set`], blocked by [`bootstrap`]
+fn open_signalfd() -> Result<OwnedFd> {
+    use std::os::fd::FromRawFd;
+
+    let mask = signal_set();
+    // SAFETY: mask is initialized; the fd returned is ours
+    unsafe {
+        let raw = syscall!(libc::signalfd(-1, &mask, 0))?;
         Ok(OwnedFd::from_raw_fd(raw))
     }
 }
 
+/// Read a pending signal number from signalfd `sfd`.
+fn read_signal(sfd: &OwnedFd) -> Result<i32> {
+    let mut info: libc::signalfd_siginfo = unsafe { std::mem::zeroed() };
+    let size = std::mem::size_of::<libc::signalfd_siginfo>();
+
+    // SAFETY: info is valid for writes of its size
+    let n = syscall!(unsafe {
+        libc::read(sfd.as_raw_fd(), (&mut info as *mut libc::signalfd_siginfo).cast(), size)
+    })?;
+    if n as usize != size {
+        anyhow::bail!("signalfd: short read");
+    }
+
+    Ok(info.ssi_signo as i32)
+}
+
+/// Packets to take from the queue before polling signals again
+const QUEUE_BATCH: usize = 64;
+
+/// How long `--poll-mode hybrid` keeps spinning after the last packet
+const HYBRID_SPIN: std::time::Duration = std::time::Duration::from_millis(1);
+
+/// How often the kernel's drops from our queue are counted
+const QUEUE_DROPS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// SYN/ACKs to learn from in a round while ClientHellos are waiting
+const SYNACK_BATCH: usize = 16;
+
+/// Learn hops from the SYN/ACKs in `rx`. If ClientHellos are still
+/// waiting (`busy`), only from the first [`SYNACK_BATCH`]; the rest are
+/// shed, so that a flood of them does not hold the ClientHellos up.
+fn learn_hops(rx: &mut rxring::RxRing, busy: bool) {
+    let mut learned = 0;
+    while let Some(pkt) = rx.current_packet() {
+        if busy && learned == SYNACK_BATCH {
+            crate::stats::COUNTERS.synack_shed.inc();
+            continue;           // the frame goes back to the kernel
+        }
+        learned += 1;
+
+        match pkt.net() {
+            Ok(p) => pkt::put_hop(p),
+            Err(e) => crate::warn!("Failed to recv from rxring: {e}")
+        };
+    }
+}
+
 pub fn run() -> Result<()> {
     use crate::handle_packet;
-    use super::PACKET_SIZE_CAP;
 
     // In case the previous execution was not cleaned properly
     _ = rules::nft_cleanup();
     _ = rules::ipt6_cleanup(false);
     _ = rules::ipt6_cleanup(true);
 
-    let _rule = rules::install()?;
+    rules::ensure_nfnetlink_queue().status(Status::Backend)?;
 
+    let mut helper = cleanup::spawn();
     let sfd = open_signalfd()?;
-    let mut q = open_nfqueue()?;
-    let mut rx = if opt::fake_autottl() { Some(open_rxring()?) } else { None };
-    let mut buf = Vec::<u8>::with_capacity(PACKET_SIZE_CAP);
+    let mut q = open_nfqueue().status(Status::Backend)?; // before rules, to use the bound queue number
+    let rule = Arc::new(rules::install().status(Status::Backend)?);
+    if let Some(helper) = &mut helper {
+        helper.installed(rule.backend());
+    }
+    crate::doh::spawn()?;
+    crate::hostlist::init()?;
+    crate::pkt::hook::init()?;
+    crate::pkt::state::init();
+    let mut rx = if opt::learn_hops() { Some(open_rxring()?) } else { None };
+    let mut pmtu_rx = if opt::watch_pmtu() { Some(open_pmtu_ring()?) } else { None };
+    let mut worker = crate::pkt::worker::Worker::spawn()?;
+    let mangling = mangle_only();
+    let mut buf = Vec::new();
 
     let mut fds = [
         libc::pollfd { fd: sfd.as_raw_fd(), events: libc::POLLIN, revents: 0 },
@@ -210,52 +645,147 @@ pub fn run() -> Result<()> {
             events: libc::POLLIN,
             revents: 0
         },
+        libc::pollfd {
+            fd: pmtu_rx.as_ref().map_or(-1, |r| r.as_raw_fd()),
+            events: libc::POLLIN,
+            revents: 0
+        },
     ];
 
+    super::Health {
+        backend: rule.backend(),
+        prefilter: rule.prefilter(),
+        autottl: rx.as_ref().map(|_| "AF_PACKET rx ring"),
+        injection: if mangle_only() { "none (mangle-only; ClientHello cut in place)" } else { "raw socket" },
+        ipv6: rule.is_ipv6_covered(),
+        workers: crate::pkt::worker::COUNT,
+    }.report();
+
+    // Dropped before `rule`, which removes the rules
+    let _rule_check = (opt::rule_check_interval() > 0)
+        .then(|| RuleChecker::spawn(rule.clone(), std::time::Duration::from_secs(opt::rule_check_interval())));
+
+    // Drops of a previous run on the same queue number are not ours
+    let mut queue_drops = (0, 0);
+    sync_queue_drops(&mut queue_drops);
+    let mut last_drops_check = std::time::Instant::now();
+
+    let _watchdog = opt::fail_closed().then(spawn_watchdog);
+    let timeout = if opt::fail_closed() { HEARTBEAT_INTERVAL_MS } else { -1 };
+
     crate::splash!("{}", super::MESSAGE_AT_RUN);
 
+    let poll_mode = opt::poll_mode();
+    let mut last_ready = std::time::Instant::now();
+
     loop {
-        libc_s::poll(&mut fds, -1)?;
+        let spinning = match poll_mode {
+            opt::PollMode::Busy => true,
+            opt::PollMode::Hybrid => last_ready.elapsed() < HYBRID_SPIN,
+            opt::PollMode::Block => false,
+        };
 
-        let is_intr: bool = fds[0].revents & libc::POLLIN != 0;
+        libc_s::poll(&mut fds, if spinning { 0 } else { timeout })?;
+        heartbeat();
+
+        let is_sig: bool = fds[0].revents & libc::POLLIN != 0;
         let q_ready: bool = fds[1].revents & libc::POLLIN != 0;
         let rx_ready: bool = fds[2].revents & libc::POLLIN != 0;
+        let pmtu_ready: bool = fds[3].revents & libc::POLLIN != 0;
 
-        if is_intr {
-            break;
+        if q_ready || rx_ready || pmtu_ready {
+            last_ready = std::time::Instant::now();
+        } else if spinning {
+            std::hint::spin_loop();
         }
 
-        if rx_ready && let Some(ref mut rx) = rx {
-            while let Some(pkt) = rx.current_packet() {
-                match pkt.net() {
-                    Ok(p) => pkt::put_hop(p),
-                    Err(e) => crate::warn!("Failed to recv from rxring: {e}")
-                };
+        if last_drops_check.elapsed() >= QUEUE_DROPS_INTERVAL {
+            last_drops_check = std::time::Instant::now();
+            sync_queue_drops(&mut queue_drops);
+        }
+
+        if is_sig {
+            match read_signal(&sfd)? {
+                libc::SIGUSR1 => {
+                    sync_queue_drops(&mut queue_drops);
+                    crate::stats::report();
+                }
+                libc::SIGHUP => {
+                    crate::info!("SIGHUP received; reloading hostlist");
+                    crate::hostlist::reload();
+                }
+                sig => {
+                    crate::info!("signal {sig} received; shutting down");
+                    if opt::container() {
+                        spawn_grace_timer();
+                    }
+                    crate::pkt::worker::stop();
+                    break;
+                }
             }
         }
 
+        // ClientHellos first; SYN/ACKs only wait to be learned from
+        let mut queued = 0;
         if q_ready {
-            while let Ok(mut msg) = q.recv() {
-                let verdict = handle_packet!(
-                    &msg.get_payload(),
-                    &mut buf,
-                    handled => nfq::Verdict::Drop,
-                    rejected => nfq::Verdict::Accept,
-                );
+            // Bounded, so that a flood of packets does not keep us from
+            // noticing a signal
+            while queued < QUEUE_BATCH {
+                let Ok(mut msg) = q.recv() else { break };
+                queued += 1;
+
+                let verdict = if mangling {
+                    match pkt::mangle_packet(msg.get_payload(), &mut buf) {
+                        Ok(pkt::Mangled::Accept) => nfq::Verdict::Accept,
+                        Ok(pkt::Mangled::Drop) => nfq::Verdict::Drop,
+                        Ok(pkt::Mangled::Replace) => {
+                            msg.set_payload(buf.as_slice());
+                            nfq::Verdict::Accept
+                        }
+                        Err(e) => {
+                            crate::warn!("handle_packet: {e}");
+                            nfq::Verdict::Accept
+                        }
+                    }
+                } else {
+                    handle_packet!(
+                        &msg.get_payload(),
+                        worker: &mut worker,
+                        handled => nfq::Verdict::Drop,
+                        rejected => nfq::Verdict::Accept,
+                    )
+                };
 
                 msg.set_verdict(verdict);
                 q.verdict(msg)?;
             }
         }
+
+        if rx_ready && let Some(ref mut rx) = rx {
+            learn_hops(rx, queued == QUEUE_BATCH);
+        }
+
+        if pmtu_ready && let Some(ref mut pmtu_rx) = pmtu_rx {
+            while let Some(pkt) = pmtu_rx.current_packet() {
+                match pkt.net() {
+                    Ok(p) => pkt::pmtu::learn(p),
+                    Err(e) => crate::warn!("Failed to recv from rxring: {e}")
+                };
+            }
+        }
     }
 
-    q.unbind(opt::queue_num())?;
+    drop(worker);               // flush jobs in flight
+    sync_queue_drops(&mut queue_drops);
+    q.unbind(queue_num())?;
+    crate::stats::report();
+    crate::pkt::state::save();
 
     Ok(())
 }
 
 // TODO: detach daemonize crate and lock pid file with lock_pid_file
-fn daemonize_1() -> Result<()> {
+fn daemonize() -> Result<()> {
     use std::fs;
     use daemonize::Daemonize;
 
@@ -281,16 +811,36 @@ fn daemonize_1() -> Result<()> {
     Ok(())
 }
 
-fn daemonize() {
-    const EXIT_DAEMON_FAIL: i32 = 2;
+/// `len` bytes of file `path` (on tmpfs, e.g. under `/run`), shared
+/// with other processes mapping it and mapped for the life of the
+/// process. Readable by anyone. The file is created anew, so that a
+/// link planted at `path` is replaced rather than followed.
+pub fn map_shared(path: &str, len: usize) -> Result<*mut u8> {
+    use std::os::fd::AsRawFd;
+    use std::os::unix::fs::OpenOptionsExt;
 
-    match daemonize_1() {
-        Ok(_) => {},
-        Err(e) => {
-            crate::error!("fail to start as daemon: {e}");
-            std::process::exit(EXIT_DAEMON_FAIL);
-        }
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => (),
     }
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .mode(0o644)
+        .open(path)?;
+    file.set_len(len as u64)?;
+
+    // SAFETY: fresh shared mapping of a file of len bytes
+    let ptr = unsafe {
+        libc_s::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE,
+                     libc::MAP_SHARED, file.as_raw_fd(), 0)?
+    };
+    Ok(ptr.cast())
 }
 
 pub fn local_time() -> (i32, u8, u8, u8, u8, u8) {
@@ -308,3 +858,52 @@ pub fn local_time() -> (i32, u8, u8, u8, u8, u8) {
 pub fn is_kernel_filtered_clienthello() -> bool {
     rules::IS_U32_SUPPORTED.load(atomic::Ordering::Relaxed)
 }
+
+/// What this build and system support, as `key: yes|no` lines, for
+/// frontends to grey out options that cannot work here. Answer to the
+/// `capabilities` control command.
+pub fn capabilities() -> String {
+    let f = features::get();
+    let raw4 = RAW4.get().is_some();
+    let raw6 = RAW6.get().is_some();
+    let caps = [
+        ("backend-nftables", f.nft),
+        ("backend-nftables-netlink", true),
+        ("backend-iptables", f.iptables),
+        ("backend-ip6tables", f.ip6tables),
+        ("nfqueue", std::path::Path::new(rules::NFNETLINK_QUEUE_PROC).exists()),
+        ("kernel-filter-u32", is_kernel_filtered_clienthello()),
+        ("raw-socket", raw4),
+        ("raw-socket-ipv6", raw6),
+        ("cap-net-admin", f.has_cap(features::CAP_NET_ADMIN)),
+        ("cap-net-raw", f.has_cap(features::CAP_NET_RAW)),
+        ("strategy-split", true),
+        ("strategy-segment-order", raw4),
+        ("strategy-fake", raw4),
+        ("strategy-mangle-only", true),
+        ("learn-hops", true),
+        ("ebpf", false),
+        ("quic", false),
+    ];
+
+    let mut out = format!("version: {}\n", env!("CARGO_PKG_VERSION"));
+    for (k, v) in caps {
+        out += &format!("{k}: {}\n", if v { "yes" } else { "no" });
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_queue_drops() {
+        let text = "    0  31337     0 2 65531     0     0        0  1\n    \
+                        1   4242     3 2  4096    17     2     1234  1\n";
+        assert_eq!(parse_queue_drops(text, 1), Some((17, 2)));
+        assert_eq!(parse_queue_drops(text, 0), Some((0, 0)));
+        assert_eq!(parse_queue_drops(text, 2), None);
+        assert_eq!(parse_queue_drops("    1   4242     3\n", 1), None);
+    }
+}
@@ -0,0 +1,135 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Cleanup helper: removes our rules when we die without doing so
+//!
+//! The rules outlive the process if it is killed with SIGKILL or aborts,
+//! and are left over until the next start. Before installing them, a
+//! helper process is spawned from our own executable with a pipe as its
+//! stdin. It is told the backends the rules were installed with, and
+//! `done` once they have been removed; if the pipe is closed before
+//! that, whoever closed it is gone, and the helper removes them.
+//!
+//! Not with `--fail-closed`, whose rules are to stay and block HTTPS
+//! when nobody consumes the queue.
+
+use std::io::{BufRead, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use super::rules;
+
+/// Hidden command the helper is run with
+pub const COMMAND: &str = "cleanup-helper";
+
+/// Line telling the helper that the rules have been removed
+const DONE: &str = "done";
+
+pub struct Helper {
+    child: Child,
+    stdin: ChildStdin,
+}
+
+/// Spawn the helper, unless `--fail-closed`. To be done before opening
+/// the queue, so that it does not inherit the netlink socket.
+pub fn spawn() -> Option<Helper> {
+    if crate::opt::fail_closed() {
+        return None;
+    }
+
+    spawn_1().map_err(|e| crate::warn!("cleanup helper: {e:#}; rules will be left \
+                                        over if we are killed")).ok()
+}
+
+fn spawn_1() -> Result<Helper> {
+    let exe = std::env::current_exe().context("cannot find own executable")?;
+    let mut child = Command::new(exe)
+        .arg(COMMAND)
+        .stdin(Stdio::piped())
+        // Out of our process group, so that a Ctrl-C on the terminal
+        // does not reach it
+        .process_group(0)
+        .spawn()
+        .context("cannot spawn")?;
+    let stdin = child.stdin.take().context("no stdin")?;
+
+    crate::info!("cleanup helper: started with pid {}", child.id());
+    Ok(Helper { child, stdin })
+}
+
+impl Helper {
+    /// Tell the helper the rules have been installed with `backend`, as
+    /// given by `InstalledRules::backend`.
+    pub fn installed(&mut self, backend: &str) {
+        if let Err(e) = writeln!(self.stdin, "{backend}") {
+            crate::warn!("cleanup helper: {e}");
+        }
+    }
+}
+
+/// The rules have been removed, or never installed, by now: rules are
+/// declared after the helper and dropped first.
+impl Drop for Helper {
+    fn drop(&mut self) {
+        _ = writeln!(self.stdin, "{DONE}");
+        _ = self.child.wait();
+    }
+}
+
+/// Backends named in the lines read until `done` or the end of input;
+/// None if `done`.
+fn installed_backends(input: impl BufRead) -> Option<Vec<String>> {
+    let mut backends = Vec::new();
+
+    for line in input.lines() {
+        let Ok(line) = line else { break };
+        if line == DONE {
+            return None;
+        }
+        backends.extend(line.split(", ").map(str::to_string));
+    }
+
+    Some(backends)
+}
+
+/// `dpibreak cleanup-helper`: wait for the end of stdin, and remove the
+/// rules if we were not told they had been.
+pub fn helper() -> Result<()> {
+    let Some(backends) = installed_backends(std::io::stdin().lock()) else {
+        return Ok(());
+    };
+    if backends.is_empty() {
+        return Ok(());
+    }
+
+    crate::warn!("cleanup helper: {} exited without removing its rules; removing them",
+                 super::PKG_NAME);
+    for backend in &backends {
+        let ret = match backend.as_str() {
+            "nftables" => rules::nft_cleanup(),
+            "iptables" => rules::ipt6_cleanup(false),
+            "ip6tables" => rules::ipt6_cleanup(true),
+            _ => continue,
+        };
+        if let Err(e) = ret {
+            crate::error!("cleanup helper: {backend}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_installed_backends() {
+        assert_eq!(installed_backends("iptables, ip6tables\n".as_bytes()),
+                   Some(vec!["iptables".to_string(), "ip6tables".to_string()]));
+        assert_eq!(installed_backends("nftables\ndone\n".as_bytes()), None);
+        assert_eq!(installed_backends("".as_bytes()), Some(vec![]));
+    }
+}
@@ -0,0 +1,97 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! What the running system offers, probed once at startup
+//!
+//! The release binary is static (musl) and meant to run as is on
+//! routers and minimal distributions, where `nft`, `iptables` or
+//! `modprobe` may be missing, and the kernel may be built without
+//! module support (no `/proc/modules`). Check for them instead of
+//! assuming, so that we pick a working backend without first failing
+//! on a missing executable, and don't load or unload modules we cannot
+//! see.
+
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::opt;
+
+pub struct Features {
+    pub nft: bool,
+    pub iptables: bool,
+    pub ip6tables: bool,
+    pub iptables_restore: bool,
+    pub ip6tables_restore: bool,
+    pub modprobe: bool,
+
+    /// Kernel has loadable module support (`/proc/modules`)
+    pub modules: bool,
+
+    /// Effective capabilities, as in `CapEff` of `/proc/self/status`
+    pub caps: u64,
+}
+
+pub const CAP_NET_ADMIN: u32 = 12;
+pub const CAP_NET_RAW: u32 = 13;
+
+fn effective_caps() -> u64 {
+    std::fs::read_to_string("/proc/self/status").ok()
+        .and_then(|status| status.lines()
+                  .find_map(|l| l.strip_prefix("CapEff:"))
+                  .and_then(|hex| u64::from_str_radix(hex.trim(), 16).ok()))
+        .unwrap_or(u64::MAX)    // unknown; let the syscalls tell
+}
+
+/// Whether `program` can be executed: as a path if it contains `/`,
+/// otherwise looked up in `PATH` as `Command` does.
+fn has_program(program: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    let is_executable = |p: &Path| {
+        p.metadata().is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+    };
+
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+
+    std::env::var_os("PATH")
+        .is_some_and(|paths| std::env::split_paths(&paths).any(|dir| is_executable(&dir.join(program))))
+}
+
+impl Features {
+    fn probe() -> Self {
+        let f = Self {
+            nft: has_program(opt::nft_command()),
+            iptables: has_program("iptables"),
+            ip6tables: has_program("ip6tables"),
+            iptables_restore: has_program("iptables-restore"),
+            ip6tables_restore: has_program("ip6tables-restore"),
+            modprobe: has_program("modprobe"),
+            modules: Path::new("/proc/modules").exists(),
+            caps: effective_caps(),
+        };
+
+        crate::info!("features: nft={} iptables={} ip6tables={} iptables-restore={} \
+                      ip6tables-restore={} modprobe={} modules={} caps={:#x}",
+                     f.nft, f.iptables, f.ip6tables, f.iptables_restore,
+                     f.ip6tables_restore, f.modprobe, f.modules, f.caps);
+        f
+    }
+
+    /// Whether `modprobe` is worth trying. Not in a container, whose
+    /// modules, if any, are not those of the running kernel.
+    pub fn can_load_modules(&self) -> bool {
+        self.modprobe && self.modules && !opt::container()
+    }
+
+    pub fn has_cap(&self, cap: u32) -> bool {
+        self.caps & (1 << cap) != 0
+    }
+}
+
+static FEATURES: OnceLock<Features> = OnceLock::new();
+
+pub fn get() -> &'static Features {
+    FEATURES.get_or_init(Features::probe)
+}
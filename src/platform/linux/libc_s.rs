@@ -54,6 +54,7 @@ unsafe fn setsockopt_1<T>(sockfd: RawFd, level: c_int, optname: c_int, optval: &
 #[allow(non_camel_case_types)]
 pub enum SockOpt<'a> {
     SO_ATTACH_FILTER(&'a [libc::sock_filter]),
+    SO_RCVTIMEO(&'a libc::timeval),
     PACKET_RX_RING(&'a libc::tpacket_req),
 }
 
@@ -67,6 +68,9 @@ pub fn setsockopt(sockfd: RawFd, opt: SockOpt) -> Result<(), Error> {
 
             unsafe {setsockopt_1(sockfd, libc::SOL_SOCKET, libc::SO_ATTACH_FILTER, &prog)}
         },
+        SockOpt::SO_RCVTIMEO(optval) => unsafe {
+            setsockopt_1(sockfd, libc::SOL_SOCKET, libc::SO_RCVTIMEO, optval)
+        },
         SockOpt::PACKET_RX_RING(optval) => unsafe {
             setsockopt_1(sockfd, libc::SOL_PACKET, libc::PACKET_RX_RING, optval)
         }
@@ -80,6 +84,14 @@ pub fn socket(domain: c_int, so_type: c_int, protocol: c_int) -> Result<OwnedFd,
     }
 }
 
+pub fn send(fd: RawFd, buf: &[u8]) -> Result<usize, Error> {
+    syscall!(unsafe { libc::send(fd, buf.as_ptr().cast(), buf.len(), 0) }).map(|n| n as usize)
+}
+
+pub fn recv(fd: RawFd, buf: &mut [u8]) -> Result<usize, Error> {
+    syscall!(unsafe { libc::recv(fd, buf.as_mut_ptr().cast(), buf.len(), 0) }).map(|n| n as usize)
+}
+
 pub unsafe fn mmap(
     addr: *mut c_void, length: usize, prot: c_int,
     flags: c_int, fd: RawFd, offset: libc::off_t
@@ -1,23 +1,30 @@
 // SPDX-FileCopyrightText: 2026 Dilluti0n <hskim@dilluti0n.com>
 // SPDX-License-Identifier: GPL-3.0-or-later
 
+use std::net::IpAddr;
 use std::sync::atomic;
 use std::process::{Command, Stdio};
 use std::io::Write;
 use anyhow::{Result, Context, anyhow};
 
 mod iptables;
+mod nftnl;
 
-use iptables::{IPTables, cleanup_xt_u32};
+use iptables::{Chain, IPTables, cleanup_xt_u32};
+use nftnl::{Batch, Expr, expr};
 
 use crate::opt;
-use super::INJECT_MARK;
+use crate::platform::{PRIVATE_NETS, prefix_mask};
+use crate::platform::rulespec::RuleSpec;
+use super::features;
 
 const DPIBREAK_CHAIN: &str = "DPIBREAK";
+const DPIBREAK_IN_CHAIN: &str = "DPIBREAK_IN";
+const DPIBREAK_RAW_CHAIN: &str = "DPIBREAK_RAW";
 const DPIBREAK_TABLE: &str = "dpibreak";
 pub static IS_U32_SUPPORTED: atomic::AtomicBool = atomic::AtomicBool::new(false);
 
-fn exec_process(args: &[&str], input: Option<&str>) -> Result<()> {
+fn exec_process_1(args: &[&str], input: Option<&str>, stdout_mode: Stdio) -> Result<Vec<u8>> {
     if args.is_empty() {
         return Err(anyhow!("command args cannot be empty"));
     }
@@ -28,7 +35,7 @@ fn exec_process(args: &[&str], input: Option<&str>) -> Result<()> {
     let mut child = Command::new(program)
         .args(&args[1..])
         .stdin(stdin_mode)
-        .stdout(Stdio::null())
+        .stdout(stdout_mode)
         .stderr(Stdio::piped())
         .spawn()
         .with_context(|| format!("failed to spawn {}", program))?;
@@ -44,60 +51,198 @@ fn exec_process(args: &[&str], input: Option<&str>) -> Result<()> {
         .with_context(|| format!("failed to wait for {}", program))?;
 
     match output.status.code() {
-        Some(0) => Ok(()),
+        Some(0) => Ok(output.stdout),
         Some(code) => Err(anyhow!("{} exited with status {}: {}", program, code,
             String::from_utf8_lossy(&output.stderr))),
         None => Err(anyhow!("{} terminated by signal", program))
     }
 }
 
-/// Apply nft rules with `nft_command() -f -`.
-fn nft(rule: &str) -> Result<()> {
-    crate::info!("nft: {rule}");
-    exec_process(&[opt::nft_command(), "-f", "-"], Some(rule))
+fn exec_process(args: &[&str], input: Option<&str>) -> Result<()> {
+    exec_process_1(args, input, Stdio::null()).map(drop)
+}
+
+/// Same as [`exec_process`], but return stdout of the process.
+fn exec_process_output(args: &[&str]) -> Result<String> {
+    let out = exec_process_1(args, None, Stdio::piped())?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+pub const NFNETLINK_QUEUE_PROC: &str = "/proc/net/netfilter/nfnetlink_queue";
+
+/// Make sure the kernel can deliver packets to userspace queue before
+/// installing any rule. Load `nfnetlink_queue` if it is built as a
+/// module and not loaded yet.
+///
+/// Unlike `xt_u32`, the module is not unloaded on cleanup; it is
+/// commonly shared with other netfilter users.
+pub fn ensure_nfnetlink_queue() -> Result<()> {
+    let is_available = || std::path::Path::new(NFNETLINK_QUEUE_PROC).exists();
+
+    if is_available() {
+        return Ok(());
+    }
+
+    if features::get().can_load_modules() {
+        _ = exec_process(&["modprobe", "-q", "nfnetlink_queue"], None);
+    }
+
+    if !is_available() {
+        anyhow::bail!("nfnetlink_queue is not available on this kernel; \
+                       it must be built with CONFIG_NETFILTER_NETLINK_QUEUE \
+                       (and CONFIG_NFT_QUEUE or CONFIG_NETFILTER_XT_TARGET_NFQUEUE)");
+    }
+
+    crate::info!("nfnetlink_queue loaded");
+
+    Ok(())
 }
 
 pub struct InstalledRules {
     is_nft_not_supported: bool,
+    spec: RuleSpec,
+
+    /// nftables rules match ClientHellos with `@ih`; see
+    /// [`install_nft_rules`]
+    nft_inner: bool,
     ipt: Option<IPTables>,
     ip6: Option<IPTables>
 }
 
-fn install_ipt6(is_ipv6: bool) -> Option<IPTables> {
-    let ipt = IPTables::new(is_ipv6).map_err(|e| crate::warn!("iptables: {e}")).ok()?;
-    if let Err(e) = ipt.install() {
-        crate::warn!("iptables: {e}");
+/// Install rules of one family. Ok(None) if its tool is not installed,
+/// which is not an error (e.g. no ip6tables on an IPv4-only router). On
+/// error, whatever was added is removed again.
+fn install_ipt6(spec: &RuleSpec, is_ipv6: bool) -> Result<Option<IPTables>> {
+    let features = features::get();
+    if !(if is_ipv6 { features.ip6tables } else { features.iptables }) {
+        crate::warn!("{} not found", if is_ipv6 { "ip6tables" } else { "iptables" });
+        return Ok(None);
+    }
+
+    let ipt = IPTables::new(is_ipv6)?;
+    if let Err(e) = ipt.install(spec) {
         _ = ipt.cleanup(); // partial rules
-        return None;
+        return Err(e.context(ipt.cmd()));
     }
-    Some(ipt)
+    Ok(Some(ipt))
 }
 
+/// Install the rules: all of them or, on error, none.
+///
+/// nftables rules are one transaction. With iptables, IPv4 and IPv6 are
+/// separate commits; if the second fails, the first is rolled back
+/// rather than running with half of the traffic covered.
 pub fn install() -> Result<InstalledRules> {
-    let mut is_nft_not_supported = false;
-    let mut ipt = None;
-    let mut ip6 = None;
+    warn_conflicting_queue_rules();
+
+    let rules = install_1(RuleSpec::from_opts())?;
+    if rules.spec.notrack && !IS_U32_SUPPORTED.load(atomic::Ordering::Relaxed) {
+        crate::warn!("--notrack: ClientHellos are not matched in kernel, so handled \
+                      connections cannot be marked; all their packets will be queued");
+    }
+    Ok(rules)
+}
+
+fn install_1(spec: RuleSpec) -> Result<InstalledRules> {
+    let Err(e) = install_nft_rules(&spec, true) else {
+        return Ok(InstalledRules { is_nft_not_supported: false, spec, nft_inner: true, ipt: None, ip6: None });
+    };
+
+    crate::warn!("nftables: {e}");
 
-    if let Err(e) = install_nft_rules() {
-        is_nft_not_supported = true;
-        crate::warn!("nftables: {}", e.to_string());
-        crate::warn!("fallback to iptables");
+    // Older kernels reject inner payload expressions; a ruleset without
+    // them still beats iptables.
+    if install_nft_rules(&spec, false).is_ok() {
+        crate::warn!("nftables: installed without @ih matches, probably not supported by \
+                      this kernel; ClientHellos are told apart in userspace");
+        return Ok(InstalledRules { is_nft_not_supported: false, spec, nft_inner: false, ipt: None, ip6: None });
+    }
 
-        ipt = install_ipt6(false);
-        ip6 = install_ipt6(true);
+    crate::warn!("fallback to iptables");
 
-        if ipt.is_none() && ip6.is_none() {
-            anyhow::bail!("failed to install rules");
+    let rollback = |ipt: Option<&IPTables>| {
+        if let Some(ipt) = ipt {
+            ipt.cleanup().map_err(|e| crate::warn!("rollback: {}: {e}", ipt.cmd())).ok();
         }
+        cleanup_xt_u32().map_err(|e| crate::warn!("rollback: xt_u32: {e}")).ok();
+    };
+
+    let ipt = install_ipt6(&spec, false).inspect_err(|_| rollback(None))?;
+    let ip6 = install_ipt6(&spec, true).inspect_err(|_| rollback(ipt.as_ref()))?;
+
+    if ipt.is_none() && ip6.is_none() {
+        rollback(None);
+        anyhow::bail!("failed to install rules: neither nftables nor iptables is usable");
     }
 
-    Ok(InstalledRules{
-        is_nft_not_supported,
+    Ok(InstalledRules {
+        is_nft_not_supported: true,
+        spec,
+        nft_inner: false,
         ipt,
         ip6
     })
 }
 
+impl InstalledRules {
+    pub fn backend(&self) -> &'static str {
+        match (self.is_nft_not_supported, &self.ipt, &self.ip6) {
+            (false, _, _) => "nftables",
+            (true, Some(_), Some(_)) => "iptables, ip6tables",
+            (true, Some(_), None) => "iptables",
+            (true, None, _) => "ip6tables",
+        }
+    }
+
+    pub fn is_ipv6_covered(&self) -> bool {
+        !self.is_nft_not_supported || self.ip6.is_some()
+    }
+
+    /// Whether our hook is still in place; a firewall reload (firewalld,
+    /// ufw, fw4) may flush the whole ruleset under us.
+    fn is_present(&self) -> Result<bool> {
+        if !self.is_nft_not_supported {
+            return nftnl::chain_exists(nftnl::NFPROTO_INET, DPIBREAK_TABLE, "OUTPUT");
+        }
+
+        Ok(self.ipt.iter().chain(&self.ip6).all(|ipt| ipt.is_hooked("mangle", "POSTROUTING", DPIBREAK_CHAIN)))
+    }
+
+    /// Install the rules again if they have been removed by someone
+    /// else. Return whether they had to be.
+    pub fn repair(&self) -> Result<bool> {
+        if self.is_present()? {
+            return Ok(false);
+        }
+
+        crate::warn!("{}: our rules have been removed, probably by a firewall reload; \
+                      reinstalling", self.backend());
+
+        if !self.is_nft_not_supported {
+            _ = nft_cleanup();
+            install_nft_rules(&self.spec, self.nft_inner)?;
+        } else {
+            for ipt in self.ipt.iter().chain(&self.ip6) {
+                _ = ipt.cleanup();
+                ipt.install(&self.spec).with_context(|| ipt.cmd())?;
+            }
+        }
+
+        crate::info!("{}: rules reinstalled", self.backend());
+        Ok(true)
+    }
+
+    pub fn prefilter(&self) -> Option<&'static str> {
+        if !IS_U32_SUPPORTED.load(atomic::Ordering::Relaxed) {
+            None
+        } else if self.is_nft_not_supported {
+            Some("xt_u32")
+        } else {
+            Some("nft payload")
+        }
+    }
+}
+
 impl Drop for InstalledRules {
     fn drop(&mut self) {
         if self.is_nft_not_supported {
@@ -115,66 +260,562 @@ impl Drop for InstalledRules {
 }
 
 pub fn ipt6_cleanup(is_ipv6: bool) -> Result<()> {
+    let features = features::get();
+    if !(if is_ipv6 { features.ip6tables } else { features.iptables }) {
+        return Ok(());
+    }
+
     let ipt6 = IPTables::new(is_ipv6)?;
     ipt6.cleanup()
 }
 
 pub fn nft_cleanup() -> Result<()> {
-    let rule = format!("delete table inet {DPIBREAK_TABLE}");
-    nft(&rule)?;
+    let mut batch = Batch::new(nftnl::NFPROTO_INET);
+    batch.del_table(DPIBREAK_TABLE);
+    batch.commit()?;
+
+    crate::info!("nftables: delete table inet {DPIBREAK_TABLE}");
 
     Ok(())
 }
 
-fn install_nft_rules() -> Result<()> {
-    let queue_num = opt::queue_num();
-    let rule = format!(
-    r#"add table inet {DPIBREAK_TABLE}
-add chain inet {DPIBREAK_TABLE} OUTPUT {{ type filter hook output priority 0; policy accept; }}
-add rule inet {DPIBREAK_TABLE} OUTPUT meta mark {INJECT_MARK} return
-add rule inet {DPIBREAK_TABLE} OUTPUT tcp dport 443 @ih,0,8 0x16 @ih,40,8 0x01 queue num {queue_num} bypass"#
-    );
-    nft(&rule)?;
+/// Warn about queue rules owned by other tables (e.g. another DPI
+/// circumvention tool or IPS) on the current ruleset. Depending on
+/// the chain priority, they can take the packets before ours or
+/// consume the same queue number.
+fn warn_conflicting_queue_rules() {
+    if !features::get().nft {
+        return;                 // only for listing; rules are installed over netlink
+    }
+
+    let Ok(ruleset) = exec_process_output(&[opt::nft_command(), "list", "ruleset"]) else {
+        return;                 // nft not available; nothing to inspect
+    };
+
+    let ours = format!(" {DPIBREAK_TABLE}");
+    let same_queue = format!("queue num {} ", super::queue_num());
+    let mut table = "";
+
+    for line in ruleset.lines().map(str::trim) {
+        if let Some(name) = line.strip_prefix("table ") {
+            table = name.trim_end_matches(" {");
+            continue;
+        }
+
+        if table.ends_with(&ours) || !line.contains("queue") {
+            continue;
+        }
+
+        if format!("{line} ").contains(&same_queue) {
+            crate::warn!("nftables: table {table} uses the same queue number {}: {line}",
+                         super::queue_num());
+        } else {
+            crate::warn!("nftables: table {table} has a queue rule, \
+                          which may take packets before ours: {line}");
+        }
+    }
+}
+
+/// First 4 bytes of HTTP CONNECT request ("CONN")
+const CONNECT_U32: u32 = 0x434f4e4e;
+
+/// `meta l4proto {proto} th {sport|dport} {port}`
+fn nft_port(proto: u8, offset: u32, port: u16) -> [Expr; 4] {
+    [
+        expr::meta(expr::META_L4PROTO),
+        expr::eq(&[proto]),
+        expr::payload(expr::TRANSPORT, offset, 2),
+        expr::eq(&port.to_be_bytes()),
+    ]
+}
+
+const IPPROTO_TCP: u8 = 6;
+const IPPROTO_UDP: u8 = 17;
+const SPORT: u32 = 0;
+const DPORT: u32 = 2;
+
+/// `meta l4proto tcp ip[6] daddr <net>/<prefix> return` for each of
+/// `nets`; `saddr` instead if `source`.
+fn nft_net_return(nets: &[(IpAddr, u8)], source: bool) -> Vec<Vec<Expr>> {
+    nets.iter().map(|&(net, prefix)| {
+        let mask = prefix_mask(net, prefix);
+        let (nfproto, offset, net, mask) = match (net, mask) {
+            (IpAddr::V4(n), IpAddr::V4(m)) => (nftnl::NFPROTO_IPV4, if source { 12 } else { 16 },
+                                               n.octets().to_vec(), m.octets().to_vec()),
+            (IpAddr::V6(n), IpAddr::V6(m)) => (nftnl::NFPROTO_IPV6, if source { 8 } else { 24 },
+                                               n.octets().to_vec(), m.octets().to_vec()),
+            _ => unreachable!("mask is of the family of net"),
+        };
+
+        vec![
+            expr::meta(expr::META_NFPROTO),
+            expr::eq(&[nfproto]),
+            expr::meta(expr::META_L4PROTO),
+            expr::eq(&[IPPROTO_TCP]),
+            expr::payload(expr::NETWORK, offset, net.len() as u32),
+            expr::bitwise(&mask, &vec![0; mask.len()]),
+            expr::eq(&net),
+            expr::ret(),
+        ]
+    }).collect()
+}
+
+/// `@ih,0,8 0x16 @ih,40,8 0x01`: TLS handshake record, ClientHello
+fn nft_client_hello() -> [Expr; 4] {
+    [
+        expr::payload(expr::INNER, 0, 1),
+        expr::eq(&[0x16]),
+        expr::payload(expr::INNER, 5, 1),
+        expr::eq(&[0x01]),
+    ]
+}
+
+/// `@ih,0,1 1 @ih,16,8 0x01`: SSLv2-compatible ClientHello
+fn nft_sslv2_hello() -> [Expr; 5] {
+    [
+        expr::payload(expr::INNER, 0, 1),
+        expr::bitwise(&[0x80], &[0]),
+        expr::eq(&[0x80]),
+        expr::payload(expr::INNER, 2, 1),
+        expr::eq(&[0x01]),
+    ]
+}
+
+/// `ct mark set ct mark and ~<mask> xor <value>`: handled, as
+/// `--connmark` says
+fn nft_set_handled(connmark: opt::Mark) -> [Expr; 3] {
+    [
+        expr::ct(expr::CT_MARK),
+        expr::bitwise(&(!connmark.mask).to_ne_bytes(), &connmark.value.to_ne_bytes()),
+        expr::ct_set(expr::CT_MARK),
+    ]
+}
+
+/// `meta mark and <mask> == <mark>`: sent by us
+fn nft_our_mark(mark: opt::Mark) -> [Expr; 3] {
+    [
+        expr::meta(expr::META_MARK),
+        expr::bitwise(&mark.mask.to_ne_bytes(), &0u32.to_ne_bytes()),
+        expr::eq(&mark.value.to_ne_bytes()),
+    ]
+}
+
+/// `tcp flags & syn == 0`
+fn nft_not_syn() -> [Expr; 3] {
+    [
+        expr::payload(expr::TRANSPORT, 13, 1),
+        expr::bitwise(&[0x02], &[0]),
+        expr::eq(&[0]),
+    ]
+}
+
+/// `meta nfproto ipv6 tcp dport 443 tcp flags & (syn | ack) == syn`
+fn nft_v6_syn() -> Vec<Expr> {
+    [expr::meta(expr::META_NFPROTO), expr::eq(&[nftnl::NFPROTO_IPV6])].into_iter()
+        .chain(nft_port(IPPROTO_TCP, DPORT, 443))
+        .chain([
+            expr::payload(expr::TRANSPORT, 13, 1),
+            expr::bitwise(&[0x02 | 0x10], &[0]),       // SYN | ACK
+            expr::eq(&[0x02]),
+        ])
+        .collect()
+}
+
+/// `ct bytes > <bytes> return`: connections past `--bulk-bytes`
+fn nft_bulk_return(bytes: u64) -> [Expr; 4] {
+    [
+        expr::ct(expr::CT_BYTES),
+        expr::hton64(),
+        expr::gt(&bytes.to_be_bytes()),
+        expr::ret(),
+    ]
+}
+
+/// Install our table in one nf_tables transaction over netlink:
+///
+/// ```text
+/// table inet dpibreak {
+///     chain OUTPUT {
+///         type filter hook output priority <--nft-priority>; policy accept;
+///                                         # postrouting with --client-profile
+///         meta mark and <mask> == <mark> ct mark set <connmark> return
+///         ct mark and <connmask> == <connmark> return
+///         ct bytes > <--bulk-bytes> return                   # without inner
+///         meta l4proto tcp ip daddr 10.0.0.0/8 return        # and the
+///         ...                                                # other PRIVATE_NETS
+///         meta l4proto tcp ip saddr 192.168.2.0/24 return    # --client-profile off
+///         meta nfproto ipv6 tcp dport 443 tcp flags & (syn | ack) == syn \
+///             queue num N bypass                             # --v6-degrade
+///         tcp dport 443 @ih,0,8 0x16 @ih,40,8 0x01 queue num N bypass
+///         tcp dport 443 @ih,0,1 1 @ih,16,8 0x01 queue num N bypass
+///         tcp dport <proxy port> @ih,0,32 "CONN" queue num N bypass
+///         tcp dport <proxy port> @ih,0,8 0x16 @ih,40,8 0x01 queue num N bypass
+///         tcp dport <proxy port> @ih,0,1 1 @ih,16,8 0x01 queue num N bypass
+///         udp dport 53 queue num N bypass                    # --dns-guard
+///     }
+///     chain INPUT {                                          # if needed
+///         type filter hook input priority <--nft-priority>; policy accept;
+///         tcp sport 443 tcp flags & (fin | rst) != 0 queue num N bypass
+///         udp sport 53 queue num N bypass
+///     }
+///     chain RAW {                                            # --notrack
+///         type filter hook output priority raw; policy accept;
+///         meta mark and <mask> == <mark> notrack
+///     }
+/// }
+/// ```
+///
+/// With `--notrack`, conntrack does not see our packets, so each
+/// ClientHello rule is preceded by the same match with `tcp flags & syn
+/// == 0 ct mark set <connmark>`: the connection is
+/// marked as its ClientHello is queued. Not on a TFO SYN, whose data is
+/// sent again after the handshake.
+///
+/// `<mark>/<mask>` is `--mark`, `<connmark>/<connmask>` `--connmark`
+/// (set within its mask, leaving the other bits). `bypass` is omitted on OUTPUT
+/// ClientHello rules with `--fail-closed`, the private network rules
+/// with `--no-private-exempt`, and the SSLv2 ones with `--pass-sslv2`.
+///
+/// Without `inner`, for kernels rejecting `@ih`, each port gets a
+/// single `tcp dport <port> queue num N bypass` instead of its
+/// ClientHello and CONNECT rules. Every packet to it is queued until
+/// its connection is handled (and marked), or has carried
+/// `--bulk-bytes` (a connection left as is, e.g. not in `--hostlist`,
+/// would otherwise be queued for as long as it lasts; by then its
+/// ClientHello is long gone), and ClientHellos are told apart in
+/// userspace.
+fn install_nft_rules(spec: &RuleSpec, inner: bool) -> Result<()> {
+    let t = DPIBREAK_TABLE;
+
+    let mut batch = Batch::new(nftnl::NFPROTO_INET);
+    batch.add_table(t);
+    for chain in nft_ruleset(spec, inner) {
+        batch.add_chain(t, chain.name, chain.hook, chain.priority);
+        for rule in &chain.rules {
+            batch.add_rule(t, chain.name, rule);
+        }
+    }
+
+    batch.commit()?;
+    crate::info!("nftables: table inet {t} installed over netlink");
 
     // clienthello filtered by nft
-    IS_U32_SUPPORTED.store(true, atomic::Ordering::Relaxed);
+    IS_U32_SUPPORTED.store(inner, atomic::Ordering::Relaxed);
 
     Ok(())
 }
 
-impl IPTables {
-    fn install(&self) -> Result<()> {
-        let q_num = crate::opt::queue_num().to_string();
-        // prevent inf loop
-        let mark = format!("{:#x}", INJECT_MARK);
+/// Base chain of our nftables table
+struct NftChain {
+    name: &'static str,
+    hook: u32,
+    priority: i32,
+    rules: Vec<Vec<Expr>>,
+}
 
-        let mut rule = vec![
-            "-p", "tcp", "--dport", "443",
-            "-j", "NFQUEUE", "--queue-num", &q_num, "--queue-bypass"
-        ];
+/// Chains of our table, as described on [`install_nft_rules`]
+fn nft_ruleset(spec: &RuleSpec, inner: bool) -> Vec<NftChain> {
+    let queue_num = spec.queue_num;
+    let bypass = !spec.fail_closed;
+    let mark = spec.mark;
+    let connmark = spec.connmark;
 
-        if iptables::is_u32_supported(self) {
-            const U32: &str = "0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0xFF=0x16 && \
-                           0>>22&0x3C @ 12>>26&0x3C @ 2>>24&0xFF=0x01";
+    // On a gateway, ClientHellos of the clients are forwarded, not sent
+    // by us; see them after routing as the iptables rules do.
+    let hook = if spec.gateway {
+        nftnl::NF_INET_POST_ROUTING
+    } else {
+        nftnl::NF_INET_LOCAL_OUT
+    };
+
+    let mut out: Vec<Vec<Expr>> = vec![
+        nft_our_mark(mark).into_iter().chain(nft_set_handled(connmark)).chain([expr::ret()]).collect(),
+        vec![
+            expr::ct(expr::CT_MARK),
+            expr::bitwise(&connmark.mask.to_ne_bytes(), &0u32.to_ne_bytes()),
+            expr::eq(&connmark.value.to_ne_bytes()),
+            expr::ret(),
+        ],
+    ];
+
+    if !inner && spec.bulk_bytes > 0 {
+        out.push(nft_bulk_return(spec.bulk_bytes).into());
+    }
 
-            rule.extend_from_slice(&["-m", "u32", "--u32", U32]);
+    if spec.private_exempt {
+        out.extend(nft_net_return(PRIVATE_NETS, false));
+    }
+
+    out.extend(nft_net_return(&spec.off_clients, true));
+
+    // Queued along with the rest without inner
+    if spec.v6_degrade && inner {
+        out.push(nft_v6_syn().into_iter().chain([expr::queue(queue_num, true)]).collect());
+    }
+
+    let hello_rule = |out: &mut Vec<Vec<Expr>>, port: u16, hello: fn() -> Vec<Expr>| {
+        let matches = || nft_port(IPPROTO_TCP, DPORT, port).into_iter().chain(hello());
+        if spec.notrack && inner {
+            out.push(matches().chain(nft_not_syn()).chain(nft_set_handled(connmark)).collect());
+        }
+        out.push(matches().chain([expr::queue(queue_num, bypass)]).collect());
+    };
+    let add_hello_rules = |out: &mut Vec<Vec<Expr>>, port: u16| {
+        if !inner {
+            hello_rule(out, port, Vec::new);
+            return;
+        }
+        hello_rule(out, port, || nft_client_hello().into());
+        if !spec.pass_sslv2 {
+            hello_rule(out, port, || nft_sslv2_hello().into());
+        }
+    };
+
+    add_hello_rules(&mut out, 443);
+
+    for &port in &spec.proxy_ports {
+        // Queued along with the rest without inner
+        if inner {
+            out.push(nft_port(IPPROTO_TCP, DPORT, port).into_iter()
+                .chain([
+                    expr::payload(expr::INNER, 0, 4),
+                    expr::eq(&CONNECT_U32.to_be_bytes()),
+                    expr::queue(queue_num, bypass),
+                ])
+                .collect());
+        }
+        add_hello_rules(&mut out, port);
+    }
+
+    // Always with bypass below; without us, connections must still be
+    // able to close and names to resolve.
+    let mut inbound: Vec<Vec<Expr>> = Vec::new();
+
+    if spec.watch_teardown {
+        inbound.push(nft_port(IPPROTO_TCP, SPORT, 443).into_iter()
+            .chain([
+                expr::payload(expr::TRANSPORT, 13, 1),
+                expr::bitwise(&[0x01 | 0x04], &[0]),        // FIN | RST
+                expr::neq(&[0]),
+            ])
+            .collect());
+    }
+
+    if spec.dns_guard {
+        inbound.push(nft_port(IPPROTO_UDP, SPORT, 53).into());
+
+        let mut query: Vec<Expr> = nft_port(IPPROTO_UDP, DPORT, 53).into();
+        query.push(expr::queue(queue_num, true));
+        out.push(query);
+    }
+
+    let priority = spec.nft_priority;
+    let mut chains = vec![NftChain { name: "OUTPUT", hook, priority, rules: out }];
+    if !inbound.is_empty() {
+        for rule in &mut inbound {
+            rule.push(expr::queue(queue_num, true));
+        }
+        chains.push(NftChain { name: "INPUT", hook: nftnl::NF_INET_LOCAL_IN, priority, rules: inbound });
+    }
+
+    if spec.notrack {
+        chains.push(NftChain {
+            name: "RAW",
+            hook: nftnl::NF_INET_LOCAL_OUT,
+            priority: nftnl::NF_IP_PRI_RAW,
+            rules: vec![nft_our_mark(mark).into_iter().chain([expr::notrack()]).collect()],
+        });
+    }
+    chains
+}
+
+/// Rules of our chains in the mangle table of iptables (`is_ipv6`:
+/// ip6tables), in order. Without `has_u32`, ClientHellos cannot be
+/// matched in kernel; without `with_connmark`, neither can handled
+/// connections, nor those past `--bulk-bytes`.
+fn ipt_ruleset(spec: &RuleSpec, is_ipv6: bool, has_u32: bool, with_connmark: bool) -> Vec<Chain> {
+    let q_num = spec.queue_num.to_string();
+    // prevent inf loop
+    let mark = spec.mark.to_string();
+    let connmark = spec.connmark.to_string();
+    let bulk_bytes = format!("{}:", spec.bulk_bytes);
+
+    let mut target = vec!["-j", "NFQUEUE", "--queue-num", &q_num];
+
+    if !spec.fail_closed {
+        target.push("--queue-bypass");
+    }
+
+    const U32_HELLO: &str = "0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0xFF=0x16 && \
+                             0>>22&0x3C @ 12>>26&0x3C @ 2>>24&0xFF=0x01";
+    const U32_SSLV2_HELLO: &str = "0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0x80=0x80 && \
+                                   0>>22&0x3C @ 12>>26&0x3C @ 0>>8&0xFF=0x01";
+    let sslv2 = has_u32 && !spec.pass_sslv2;
+    let u32_connect = format!("0>>22&0x3C @ 12>>26&0x3C @ 0={CONNECT_U32:#x}");
+
+    let proxy_ports = spec.proxy_ports.iter().map(u16::to_string).collect::<Vec<_>>().join(",");
+
+    // (destination ports, u32 match if supported, whether it is of
+    // ClientHellos)
+    let mut queued: Vec<(Vec<&str>, Option<&str>, bool)> = vec![
+        (vec!["--dport", "443"], Some(U32_HELLO), true),
+    ];
+    if sslv2 {
+        queued.push((vec!["--dport", "443"], Some(U32_SSLV2_HELLO), true));
+    }
+
+    if !proxy_ports.is_empty() {
+        let dports = vec!["-m", "multiport", "--dports", &proxy_ports];
+        if has_u32 {
+            queued.push((dports.clone(), Some(U32_HELLO), true));
+            if sslv2 {
+                queued.push((dports.clone(), Some(U32_SSLV2_HELLO), true));
+            }
+            queued.push((dports, Some(&u32_connect), false));
+        } else {
+            queued.push((dports, None, false));
         }
+    }
 
-        self.new_chain("mangle", DPIBREAK_CHAIN)?;
+    // Always with bypass; see nft_ruleset()
+    let queue = ["-j", "NFQUEUE", "--queue-num", &q_num, "--queue-bypass"];
 
-        self.insert(
-            "mangle",
-            DPIBREAK_CHAIN,
-            &["-m", "mark", "--mark", &mark, "-j", "RETURN"],
-            1
-        )?;
+    let mut out: Vec<Vec<&str>> = Vec::new();
 
-        self.append("mangle", DPIBREAK_CHAIN, &rule)?;
-        crate::info!("{}: new chain {} on table mangle", self.cmd(), DPIBREAK_CHAIN);
+    if spec.dns_guard {
+        out.push([&["-p", "udp", "--dport", "53"][..], &queue].concat());
+    }
+
+    // Packets injected by us belong to a handled connection; mark it
+    // so that the rest of the connection is not queued anymore.
+    if with_connmark {
+        out.push(vec!["-m", "mark", "--mark", &mark, "-j", "CONNMARK", "--set-mark", &connmark]);
+    }
+    out.push(vec!["-m", "mark", "--mark", &mark, "-j", "RETURN"]);
+    if with_connmark {
+        out.push(vec!["-m", "connmark", "--mark", &connmark, "-j", "RETURN"]);
+    }
+    // Every packet to the ports is queued otherwise; see install_nft_rules()
+    if with_connmark && !has_u32 && spec.bulk_bytes > 0 {
+        out.push(vec!["-m", "connbytes", "--connbytes", &bulk_bytes,
+                      "--connbytes-dir", "both", "--connbytes-mode", "bytes", "-j", "RETURN"]);
+    }
+
+    let private: Vec<String> = PRIVATE_NETS.iter()
+        .filter(|(net, _)| net.is_ipv6() == is_ipv6)
+        .map(|(net, prefix)| format!("{net}/{prefix}"))
+        .collect();
+    if spec.private_exempt {
+        for net in &private {
+            out.push(vec!["-p", "tcp", "-d", net, "-j", "RETURN"]);
+        }
+    }
+
+    let off: Vec<String> = spec.off_clients.iter()
+        .filter(|(net, _)| net.is_ipv6() == is_ipv6)
+        .map(|(net, prefix)| format!("{net}/{prefix}"))
+        .collect();
+    for net in &off {
+        out.push(vec!["-p", "tcp", "-s", net, "-j", "RETURN"]);
+    }
+
+    // Before the rule keeping empty packets in kernel without u32.
+    // With bypass even on --fail-closed, as in nft_ruleset(): a SYN
+    // left unanswered would stall every IPv6 connection.
+    if spec.v6_degrade && is_ipv6 {
+        out.push([&["-p", "tcp", "--dport", "443", "--syn"][..], &queue].concat());
+    }
 
-        self.insert("mangle", "POSTROUTING", &["-j", DPIBREAK_CHAIN], 1)?;
-        crate::info!("{}: add jump to {} chain on POSTROUTING", self.cmd(), DPIBREAK_CHAIN);
+    for (dports, u32_match, is_hello) in &queued {
+        let mut rule = vec!["-p", "tcp"];
+        rule.extend_from_slice(dports);
 
+        // Our packets are not tracked with --notrack; mark the
+        // connection as its ClientHello is queued, but not on a TFO
+        // SYN. See install_nft_rules().
+        if spec.notrack && *is_hello && let Some(u32_match) = u32_match {
+            let mut set = rule.clone();
+            set.extend_from_slice(&["--tcp-flags", "SYN", "NONE", "-m", "u32", "--u32", u32_match,
+                                    "-j", "CONNMARK", "--set-mark", &connmark]);
+            out.push(set);
+        }
+
+        if !has_u32 {
+            // Without u32, every packet to the port is queued. At least
+            // keep in kernel the ones too short to carry a ClientHello:
+            // no longer than the IP and TCP headers with the most options,
+            // so at most a few dozen bytes of payload, less than any
+            // ClientHello.
+            let max_empty = if is_ipv6 { "0:100" } else { "0:80" };
+            let mut ret = rule.clone();
+            ret.extend_from_slice(&["-m", "length", "--length", max_empty, "-j", "RETURN"]);
+            out.push(ret);
+        } else if let Some(u32_match) = u32_match {
+            rule.extend_from_slice(&["-m", "u32", "--u32", u32_match]);
+        }
+
+        rule.extend_from_slice(&target);
+        out.push(rule);
+    }
+
+    let mut inbound: Vec<Vec<&str>> = Vec::new();
+
+    if spec.watch_teardown {
+        for flag in ["RST", "FIN"] {
+            inbound.push([&["-p", "tcp", "--sport", "443", "--tcp-flags", flag, flag][..], &queue].concat());
+        }
+    }
+
+    if spec.dns_guard {
+        inbound.push([&["-p", "udp", "--sport", "53"][..], &queue].concat());
+    }
+
+    let owned = |rules: Vec<Vec<&str>>| {
+        rules.into_iter().map(|r| r.into_iter().map(String::from).collect()).collect()
+    };
+
+    let mut chains = vec![Chain { name: DPIBREAK_CHAIN, hook: "POSTROUTING", rules: owned(out) }];
+    if !inbound.is_empty() {
+        chains.push(Chain { name: DPIBREAK_IN_CHAIN, hook: "INPUT", rules: owned(inbound) });
+    }
+    chains
+}
+
+/// Rules of our chain in the raw table, evaluated before conntrack:
+/// none without `--notrack`
+fn ipt_raw_ruleset(spec: &RuleSpec) -> Vec<Chain> {
+    if !spec.notrack {
+        return Vec::new();
+    }
+
+    let rule = ["-m", "mark", "--mark", &spec.mark.to_string(), "-j", "CT", "--notrack"];
+    vec![Chain {
+        name: DPIBREAK_RAW_CHAIN,
+        hook: "OUTPUT",
+        rules: vec![rule.into_iter().map(String::from).collect()],
+    }]
+}
+
+impl IPTables {
+    /// Rules of our chains for `spec`, as this iptables supports them
+    fn ruleset(&self, spec: &RuleSpec, with_connmark: bool) -> Vec<Chain> {
+        ipt_ruleset(spec, self.is_ipv6(), iptables::is_u32_supported(self), with_connmark)
+    }
+
+    fn install(&self, spec: &RuleSpec) -> Result<()> {
+        if let Err(e) = self.apply("mangle", &self.ruleset(spec, true)) {
+            // xt_connmark may not be available
+            crate::warn!("{}: {e}", self.cmd());
+            crate::warn!("{}: retrying without connmark; every packet of handled \
+                          connections will be queued", self.cmd());
+            _ = self.cleanup();
+            self.apply("mangle", &self.ruleset(spec, false))?;
+        }
+
+        let raw = ipt_raw_ruleset(spec);
+        if !raw.is_empty() {
+            self.apply("raw", &raw)?;
+        }
         Ok(())
     }
 
@@ -191,6 +832,376 @@ impl IPTables {
             crate::info!("{}: delete chain {}", self.cmd(), DPIBREAK_CHAIN);
         }
 
+        // May not exist without inbound options; errors are expected.
+        if self.delete("mangle", "INPUT", &["-j", DPIBREAK_IN_CHAIN]).is_ok() {
+            crate::info!("{}: delete jump to {} from mangle/INPUT", self.cmd(), DPIBREAK_IN_CHAIN);
+        }
+        _ = self.flush_chain("mangle", DPIBREAK_IN_CHAIN);
+        if self.delete_chain("mangle", DPIBREAK_IN_CHAIN).is_ok() {
+            crate::info!("{}: delete chain {}", self.cmd(), DPIBREAK_IN_CHAIN);
+        }
+
+        // Only with --notrack
+        if self.delete("raw", "OUTPUT", &["-j", DPIBREAK_RAW_CHAIN]).is_ok() {
+            crate::info!("{}: delete jump to {} from raw/OUTPUT", self.cmd(), DPIBREAK_RAW_CHAIN);
+        }
+        _ = self.flush_chain("raw", DPIBREAK_RAW_CHAIN);
+        if self.delete_chain("raw", DPIBREAK_RAW_CHAIN).is_ok() {
+            crate::info!("{}: delete chain {}", self.cmd(), DPIBREAK_RAW_CHAIN);
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::iptables::restore_script;
+
+    /// Each chain with its hook, then its rules one per line
+    fn nft_text(chains: &[NftChain]) -> String {
+        chains.iter().map(|c| {
+            let rules: String = c.rules.iter().map(|r| {
+                format!("{}\n", r.iter().map(Expr::to_string).collect::<Vec<_>>().join(" "))
+            }).collect();
+            let hook = match c.hook {
+                nftnl::NF_INET_LOCAL_IN => "input",
+                nftnl::NF_INET_LOCAL_OUT => "output",
+                _ => "postrouting",
+            };
+            format!("chain {} hook {hook}\n{rules}", c.name)
+        }).collect()
+    }
+
+    // Marks are loaded in host order
+    #[cfg(target_endian = "little")]
+    #[test]
+    fn test_nft_ruleset() {
+        let spec = RuleSpec { private_exempt: false, ..RuleSpec::from_opts() };
+        assert_eq!(nft_text(&nft_ruleset(&spec, true)),
+                   "chain OUTPUT hook output\n\
+                    [ meta load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0xffffffff ) ^ 0x00000000 ] \
+                      [ cmp eq reg 1 0x01d00000 ] \
+                      [ ct load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0xffffffbf ) ^ 0x00000040 ] \
+                      [ ct set mark with reg 1 ] \
+                      [ immediate reg 0 return ]\n\
+                    [ ct load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0x00000040 ) ^ 0x00000000 ] \
+                      [ cmp eq reg 1 0x00000040 ] \
+                      [ immediate reg 0 return ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x01bb ] \
+                      [ payload load 1b @ inner header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x16 ] \
+                      [ payload load 1b @ inner header + 5 => reg 1 ] \
+                      [ cmp eq reg 1 0x01 ] \
+                      [ queue num 1 bypass ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x01bb ] \
+                      [ payload load 1b @ inner header + 0 => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0x80 ) ^ 0x00 ] \
+                      [ cmp eq reg 1 0x80 ] \
+                      [ payload load 1b @ inner header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x01 ] \
+                      [ queue num 1 bypass ]\n");
+
+        let spec = RuleSpec {
+            proxy_ports: vec![8080],
+            private_exempt: false,
+            pass_sslv2: true,
+            watch_teardown: true,
+            dns_guard: true,
+            fail_closed: true,
+            gateway: true,
+            ..RuleSpec::from_opts()
+        };
+        assert_eq!(nft_text(&nft_ruleset(&spec, true)),
+                   "chain OUTPUT hook postrouting\n\
+                    [ meta load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0xffffffff ) ^ 0x00000000 ] \
+                      [ cmp eq reg 1 0x01d00000 ] \
+                      [ ct load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0xffffffbf ) ^ 0x00000040 ] \
+                      [ ct set mark with reg 1 ] \
+                      [ immediate reg 0 return ]\n\
+                    [ ct load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0x00000040 ) ^ 0x00000000 ] \
+                      [ cmp eq reg 1 0x00000040 ] \
+                      [ immediate reg 0 return ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x01bb ] \
+                      [ payload load 1b @ inner header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x16 ] \
+                      [ payload load 1b @ inner header + 5 => reg 1 ] \
+                      [ cmp eq reg 1 0x01 ] \
+                      [ queue num 1 ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x1f90 ] \
+                      [ payload load 4b @ inner header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x434f4e4e ] \
+                      [ queue num 1 ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x1f90 ] \
+                      [ payload load 1b @ inner header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x16 ] \
+                      [ payload load 1b @ inner header + 5 => reg 1 ] \
+                      [ cmp eq reg 1 0x01 ] \
+                      [ queue num 1 ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x11 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x0035 ] \
+                      [ queue num 1 bypass ]\n\
+                    chain INPUT hook input\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x01bb ] \
+                      [ payload load 1b @ transport header + 13 => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0x05 ) ^ 0x00 ] \
+                      [ cmp neq reg 1 0x00 ] \
+                      [ queue num 1 bypass ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x11 ] \
+                      [ payload load 2b @ transport header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x0035 ] \
+                      [ queue num 1 bypass ]\n");
+        assert_eq!(nft_text(&nft_ruleset(&spec, false)),
+                   "chain OUTPUT hook postrouting\n\
+                    [ meta load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0xffffffff ) ^ 0x00000000 ] \
+                      [ cmp eq reg 1 0x01d00000 ] \
+                      [ ct load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0xffffffbf ) ^ 0x00000040 ] \
+                      [ ct set mark with reg 1 ] \
+                      [ immediate reg 0 return ]\n\
+                    [ ct load mark => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0x00000040 ) ^ 0x00000000 ] \
+                      [ cmp eq reg 1 0x00000040 ] \
+                      [ immediate reg 0 return ]\n\
+                    [ ct load bytes => reg 1 ] \
+                      [ byteorder reg 1 = hton(reg 1, 8, 8) ] \
+                      [ cmp gt reg 1 0x0000000000010000 ] \
+                      [ immediate reg 0 return ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x01bb ] \
+                      [ queue num 1 ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x1f90 ] \
+                      [ queue num 1 ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x11 ] \
+                      [ payload load 2b @ transport header + 2 => reg 1 ] \
+                      [ cmp eq reg 1 0x0035 ] \
+                      [ queue num 1 bypass ]\n\
+                    chain INPUT hook input\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x06 ] \
+                      [ payload load 2b @ transport header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x01bb ] \
+                      [ payload load 1b @ transport header + 13 => reg 1 ] \
+                      [ bitwise reg 1 = ( reg 1 & 0x05 ) ^ 0x00 ] \
+                      [ cmp neq reg 1 0x00 ] \
+                      [ queue num 1 bypass ]\n\
+                    [ meta load l4proto => reg 1 ] \
+                      [ cmp eq reg 1 0x11 ] \
+                      [ payload load 2b @ transport header + 0 => reg 1 ] \
+                      [ cmp eq reg 1 0x0035 ] \
+                      [ queue num 1 bypass ]\n");
+
+        let spec = RuleSpec { private_exempt: false, pass_sslv2: true, notrack: true, ..RuleSpec::from_opts() };
+        let chains = nft_ruleset(&spec, true);
+        assert_eq!(chains.iter().map(|c| c.priority).collect::<Vec<_>>(), [0, nftnl::NF_IP_PRI_RAW]);
+        assert_eq!(nft_text(&chains[..]).lines().skip(3).collect::<Vec<_>>(), [
+            "[ meta load l4proto => reg 1 ] \
+             [ cmp eq reg 1 0x06 ] \
+             [ payload load 2b @ transport header + 2 => reg 1 ] \
+             [ cmp eq reg 1 0x01bb ] \
+             [ payload load 1b @ inner header + 0 => reg 1 ] \
+             [ cmp eq reg 1 0x16 ] \
+             [ payload load 1b @ inner header + 5 => reg 1 ] \
+             [ cmp eq reg 1 0x01 ] \
+             [ payload load 1b @ transport header + 13 => reg 1 ] \
+             [ bitwise reg 1 = ( reg 1 & 0x02 ) ^ 0x00 ] \
+             [ cmp eq reg 1 0x00 ] \
+             [ ct load mark => reg 1 ] \
+             [ bitwise reg 1 = ( reg 1 & 0xffffffbf ) ^ 0x00000040 ] \
+             [ ct set mark with reg 1 ]",
+            "[ meta load l4proto => reg 1 ] \
+             [ cmp eq reg 1 0x06 ] \
+             [ payload load 2b @ transport header + 2 => reg 1 ] \
+             [ cmp eq reg 1 0x01bb ] \
+             [ payload load 1b @ inner header + 0 => reg 1 ] \
+             [ cmp eq reg 1 0x16 ] \
+             [ payload load 1b @ inner header + 5 => reg 1 ] \
+             [ cmp eq reg 1 0x01 ] \
+             [ queue num 1 bypass ]",
+            "chain RAW hook output",
+            "[ meta load mark => reg 1 ] \
+             [ bitwise reg 1 = ( reg 1 & 0xffffffff ) ^ 0x00000000 ] \
+             [ cmp eq reg 1 0x01d00000 ] \
+             [ notrack ]",
+        ]);
+        // Cannot be told apart from the other packets without @ih
+        assert_eq!(nft_ruleset(&spec, false)[0].rules.len(), 4);
+        assert_eq!(nft_ruleset(&RuleSpec { bulk_bytes: 0, ..spec }, false)[0].rules.len(), 3);
+
+        let spec = RuleSpec { private_exempt: false, v6_degrade: true, ..RuleSpec::from_opts() };
+        assert_eq!(nft_text(&nft_ruleset(&spec, true)).lines().nth(3),
+                   Some("[ meta load nfproto => reg 1 ] \
+                         [ cmp eq reg 1 0x0a ] \
+                         [ meta load l4proto => reg 1 ] \
+                         [ cmp eq reg 1 0x06 ] \
+                         [ payload load 2b @ transport header + 2 => reg 1 ] \
+                         [ cmp eq reg 1 0x01bb ] \
+                         [ payload load 1b @ transport header + 13 => reg 1 ] \
+                         [ bitwise reg 1 = ( reg 1 & 0x12 ) ^ 0x00 ] \
+                         [ cmp eq reg 1 0x02 ] \
+                         [ queue num 1 bypass ]"));
+        assert_eq!(nft_ruleset(&spec, false)[0].rules.len(), 4);
+        let closed = nft_text(&nft_ruleset(&RuleSpec { fail_closed: true, ..spec }, true));
+        assert!(closed.lines().nth(3).unwrap().ends_with("[ queue num 1 bypass ]"));
+
+        let spec = RuleSpec { off_clients: vec![("192.168.2.0".parse().unwrap(), 24)], ..RuleSpec::from_opts() };
+        let chains = nft_ruleset(&spec, true);
+        assert_eq!(chains.len(), 1);
+        assert_eq!(chains[0].rules.len(), 2 + PRIVATE_NETS.len() + 1 + 2);
+        assert_eq!(chains[0].rules[2 + PRIVATE_NETS.len()].iter().map(Expr::to_string).collect::<Vec<_>>(), [
+            "[ meta load nfproto => reg 1 ]",
+            "[ cmp eq reg 1 0x02 ]",
+            "[ meta load l4proto => reg 1 ]",
+            "[ cmp eq reg 1 0x06 ]",
+            "[ payload load 4b @ network header + 12 => reg 1 ]",
+            "[ bitwise reg 1 = ( reg 1 & 0xffffff00 ) ^ 0x00000000 ]",
+            "[ cmp eq reg 1 0xc0a80200 ]",
+            "[ immediate reg 0 return ]",
+        ]);
+    }
+
+    #[test]
+    fn test_ipt_ruleset() {
+        let spec = RuleSpec {
+            off_clients: vec![("192.168.2.0".parse().unwrap(), 24), ("fd00::".parse().unwrap(), 64)],
+            ..RuleSpec::from_opts()
+        };
+        assert_eq!(restore_script("mangle", &ipt_ruleset(&spec, false, true, true)),
+                   "*mangle\n\
+                    :DPIBREAK - [0:0]\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j CONNMARK --set-mark 0x40000000/0x40000000\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j RETURN\n\
+                    -A DPIBREAK -m connmark --mark 0x40000000/0x40000000 -j RETURN\n\
+                    -A DPIBREAK -p tcp -d 127.0.0.0/8 -j RETURN\n\
+                    -A DPIBREAK -p tcp -d 10.0.0.0/8 -j RETURN\n\
+                    -A DPIBREAK -p tcp -d 172.16.0.0/12 -j RETURN\n\
+                    -A DPIBREAK -p tcp -d 192.168.0.0/16 -j RETURN\n\
+                    -A DPIBREAK -p tcp -d 169.254.0.0/16 -j RETURN\n\
+                    -A DPIBREAK -p tcp -s 192.168.2.0/24 -j RETURN\n\
+                    -A DPIBREAK -p tcp --dport 443 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0xFF=0x16 && 0>>22&0x3C @ 12>>26&0x3C @ 2>>24&0xFF=0x01\" -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -A DPIBREAK -p tcp --dport 443 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0x80=0x80 && 0>>22&0x3C @ 12>>26&0x3C @ 0>>8&0xFF=0x01\" -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -I POSTROUTING 1 -j DPIBREAK\n\
+                    COMMIT\n");
+        assert_eq!(restore_script("mangle", &ipt_ruleset(&spec, true, false, false)),
+                   "*mangle\n\
+                    :DPIBREAK - [0:0]\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j RETURN\n\
+                    -A DPIBREAK -p tcp -d ::1/128 -j RETURN\n\
+                    -A DPIBREAK -p tcp -d fc00::/7 -j RETURN\n\
+                    -A DPIBREAK -p tcp -d fe80::/10 -j RETURN\n\
+                    -A DPIBREAK -p tcp -s fd00::/64 -j RETURN\n\
+                    -A DPIBREAK -p tcp --dport 443 -m length --length 0:100 -j RETURN\n\
+                    -A DPIBREAK -p tcp --dport 443 -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -I POSTROUTING 1 -j DPIBREAK\n\
+                    COMMIT\n");
+
+        let spec = RuleSpec {
+            proxy_ports: vec![8080, 3128],
+            private_exempt: false,
+            watch_teardown: true,
+            dns_guard: true,
+            fail_closed: true,
+            ..RuleSpec::from_opts()
+        };
+        assert_eq!(restore_script("mangle", &ipt_ruleset(&spec, false, true, true)),
+                   "*mangle\n\
+                    :DPIBREAK - [0:0]\n\
+                    -A DPIBREAK -p udp --dport 53 -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j CONNMARK --set-mark 0x40000000/0x40000000\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j RETURN\n\
+                    -A DPIBREAK -m connmark --mark 0x40000000/0x40000000 -j RETURN\n\
+                    -A DPIBREAK -p tcp --dport 443 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0xFF=0x16 && 0>>22&0x3C @ 12>>26&0x3C @ 2>>24&0xFF=0x01\" -j NFQUEUE --queue-num 1\n\
+                    -A DPIBREAK -p tcp --dport 443 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0x80=0x80 && 0>>22&0x3C @ 12>>26&0x3C @ 0>>8&0xFF=0x01\" -j NFQUEUE --queue-num 1\n\
+                    -A DPIBREAK -p tcp -m multiport --dports 8080,3128 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0xFF=0x16 && 0>>22&0x3C @ 12>>26&0x3C @ 2>>24&0xFF=0x01\" -j NFQUEUE --queue-num 1\n\
+                    -A DPIBREAK -p tcp -m multiport --dports 8080,3128 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0x80=0x80 && 0>>22&0x3C @ 12>>26&0x3C @ 0>>8&0xFF=0x01\" -j NFQUEUE --queue-num 1\n\
+                    -A DPIBREAK -p tcp -m multiport --dports 8080,3128 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0=0x434f4e4e\" -j NFQUEUE --queue-num 1\n\
+                    :DPIBREAK_IN - [0:0]\n\
+                    -A DPIBREAK_IN -p tcp --sport 443 --tcp-flags RST RST -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -A DPIBREAK_IN -p tcp --sport 443 --tcp-flags FIN FIN -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -A DPIBREAK_IN -p udp --sport 53 -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -I POSTROUTING 1 -j DPIBREAK\n\
+                    -I INPUT 1 -j DPIBREAK_IN\n\
+                    COMMIT\n");
+        assert_eq!(restore_script("mangle", &ipt_ruleset(&spec, false, false, true)),
+                   "*mangle\n\
+                    :DPIBREAK - [0:0]\n\
+                    -A DPIBREAK -p udp --dport 53 -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j CONNMARK --set-mark 0x40000000/0x40000000\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j RETURN\n\
+                    -A DPIBREAK -m connmark --mark 0x40000000/0x40000000 -j RETURN\n\
+                    -A DPIBREAK -m connbytes --connbytes 65536: --connbytes-dir both --connbytes-mode bytes -j RETURN\n\
+                    -A DPIBREAK -p tcp --dport 443 -m length --length 0:80 -j RETURN\n\
+                    -A DPIBREAK -p tcp --dport 443 -j NFQUEUE --queue-num 1\n\
+                    -A DPIBREAK -p tcp -m multiport --dports 8080,3128 -m length --length 0:80 -j RETURN\n\
+                    -A DPIBREAK -p tcp -m multiport --dports 8080,3128 -j NFQUEUE --queue-num 1\n\
+                    :DPIBREAK_IN - [0:0]\n\
+                    -A DPIBREAK_IN -p tcp --sport 443 --tcp-flags RST RST -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -A DPIBREAK_IN -p tcp --sport 443 --tcp-flags FIN FIN -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -A DPIBREAK_IN -p udp --sport 53 -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -I POSTROUTING 1 -j DPIBREAK\n\
+                    -I INPUT 1 -j DPIBREAK_IN\n\
+                    COMMIT\n");
+
+        let spec = RuleSpec { private_exempt: false, pass_sslv2: true, notrack: true, ..RuleSpec::from_opts() };
+        assert_eq!(restore_script("mangle", &ipt_ruleset(&spec, false, true, true)),
+                   "*mangle\n\
+                    :DPIBREAK - [0:0]\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j CONNMARK --set-mark 0x40000000/0x40000000\n\
+                    -A DPIBREAK -m mark --mark 0xd001/0xffffffff -j RETURN\n\
+                    -A DPIBREAK -m connmark --mark 0x40000000/0x40000000 -j RETURN\n\
+                    -A DPIBREAK -p tcp --dport 443 --tcp-flags SYN NONE -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0xFF=0x16 && 0>>22&0x3C @ 12>>26&0x3C @ 2>>24&0xFF=0x01\" -j CONNMARK --set-mark 0x40000000/0x40000000\n\
+                    -A DPIBREAK -p tcp --dport 443 -m u32 --u32 \"0>>22&0x3C @ 12>>26&0x3C @ 0>>24&0xFF=0x16 && 0>>22&0x3C @ 12>>26&0x3C @ 2>>24&0xFF=0x01\" -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                    -I POSTROUTING 1 -j DPIBREAK\n\
+                    COMMIT\n");
+        assert_eq!(restore_script("raw", &ipt_raw_ruleset(&spec)),
+                   "*raw\n\
+                    :DPIBREAK_RAW - [0:0]\n\
+                    -A DPIBREAK_RAW -m mark --mark 0xd001/0xffffffff -j CT --notrack\n\
+                    -I OUTPUT 1 -j DPIBREAK_RAW\n\
+                    COMMIT\n");
+        assert!(ipt_raw_ruleset(&RuleSpec { notrack: false, ..spec }).is_empty());
+
+        let spec = RuleSpec { private_exempt: false, v6_degrade: true, ..RuleSpec::from_opts() };
+        let syn = "-A DPIBREAK -p tcp --dport 443 --syn -j NFQUEUE --queue-num 1 --queue-bypass\n\
+                   -A DPIBREAK -p tcp --dport 443 -m length --length 0:100 -j RETURN\n";
+        assert!(restore_script("mangle", &ipt_ruleset(&spec, true, false, true)).contains(syn));
+        assert!(!restore_script("mangle", &ipt_ruleset(&spec, false, false, true)).contains("--syn"));
+        let spec = RuleSpec { fail_closed: true, ..spec };
+        assert!(restore_script("mangle", &ipt_ruleset(&spec, true, false, true)).contains(syn));
+    }
+}
@@ -7,7 +7,7 @@ use std::sync::{
     atomic::{AtomicBool, Ordering}
 };
 
-use super::{exec_process, IS_U32_SUPPORTED};
+use super::{exec_process, features, IS_U32_SUPPORTED};
 
 static IS_XT_U32_LOADED_BY_US: AtomicBool = AtomicBool::new(false);
 
@@ -15,6 +15,40 @@ pub struct IPTables {
     cmd: &'static str,
 }
 
+/// User chain in a table, jumped to from the start of builtin `hook`
+pub struct Chain {
+    pub name: &'static str,
+    pub hook: &'static str,
+    pub rules: Vec<Vec<String>>,
+}
+
+/// Quote `arg` for iptables-restore if needed.
+fn quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.to_string()
+    }
+}
+
+/// iptables-restore input creating `chains` in `table` and hooking them
+pub fn restore_script(table: &str, chains: &[Chain]) -> String {
+    let mut script = format!("*{table}\n");
+
+    for c in chains {
+        script += &format!(":{} - [0:0]\n", c.name);
+        for rule in &c.rules {
+            let args = rule.iter().map(|a| quote(a)).collect::<Vec<_>>().join(" ");
+            script += &format!("-A {} {args}\n", c.name);
+        }
+    }
+    for c in chains {
+        script += &format!("-I {} 1 -j {}\n", c.hook, c.name);
+    }
+
+    script + "COMMIT\n"
+}
+
 impl IPTables {
     pub fn new(is_ipv6: bool) -> Result<Self> {
         Ok(Self {
@@ -62,9 +96,51 @@ impl IPTables {
         self.run(&args)
     }
 
+    /// Whether `hook` in `table` jumps to `chain`
+    pub fn is_hooked(&self, table: &str, hook: &str, chain: &str) -> bool {
+        self.run(&["-t", table, "-C", hook, "-j", chain]).is_ok()
+    }
+
+    /// Create `chains` in `table` and hook them. With iptables-restore,
+    /// this is one process and one commit of the table: all or nothing,
+    /// and the xtables lock is taken once. Otherwise each rule is added
+    /// with its own process.
+    pub fn apply(&self, table: &str, chains: &[Chain]) -> Result<()> {
+        let features = features::get();
+        let has_restore = if self.is_ipv6() { features.ip6tables_restore } else { features.iptables_restore };
+
+        if has_restore {
+            let restore = format!("{}-restore", self.cmd);
+            let script = restore_script(table, chains);
+            crate::debug!("{restore}:\n{script}");
+            exec_process(&[&restore, "--noflush", "--wait"], Some(&script))?;
+        } else {
+            for c in chains {
+                self.new_chain(table, c.name)?;
+                for rule in &c.rules {
+                    let rule: Vec<&str> = rule.iter().map(String::as_str).collect();
+                    self.append(table, c.name, &rule)?;
+                }
+            }
+            for c in chains {
+                self.insert(table, c.hook, &["-j", c.name], 1)?;
+            }
+        }
+
+        for c in chains {
+            crate::info!("{}: chain {} ({} rules) on {table}/{}", self.cmd, c.name, c.rules.len(), c.hook);
+        }
+
+        Ok(())
+    }
+
     pub fn cmd(&self) -> &'static str {
         self.cmd
     }
+
+    pub fn is_ipv6(&self) -> bool {
+        self.cmd == "ip6tables"
+    }
 }
 
 fn is_xt_u32_loaded() -> bool {
@@ -74,6 +150,12 @@ fn is_xt_u32_loaded() -> bool {
 }
 
 fn ensure_xt_u32() -> Result<()> {
+    // Without module support, xt_u32 is either built in or absent;
+    // probing the match below tells which.
+    if !features::get().can_load_modules() {
+        return Ok(());
+    }
+
     let before = is_xt_u32_loaded();
     _ = exec_process(&["modprobe", "-q", "xt_u32"], None);
     let after = is_xt_u32_loaded();
@@ -117,3 +199,28 @@ pub fn cleanup_xt_u32() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_restore_script() {
+        let chains = [Chain {
+            name: "C",
+            hook: "POSTROUTING",
+            rules: vec![
+                vec!["-m".into(), "u32".into(), "--u32".into(), "0>>22&0x3C @ 0=0x1".into()],
+                vec!["-j".into(), "RETURN".into()],
+            ],
+        }];
+
+        assert_eq!(restore_script("mangle", &chains),
+                   "*mangle\n\
+                    :C - [0:0]\n\
+                    -A C -m u32 --u32 \"0>>22&0x3C @ 0=0x1\"\n\
+                    -A C -j RETURN\n\
+                    -I POSTROUTING 1 -j C\n\
+                    COMMIT\n");
+    }
+}
@@ -0,0 +1,541 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! nf_tables programming over netlink, without the `nft` executable
+//!
+//! Only what our ruleset needs: tables, base chains and rules made of
+//! a handful of expressions. Messages are sent as one nfnetlink batch,
+//! which the kernel applies as a single transaction: either all of it
+//! takes effect or none. Errors come back as errno of the offending
+//! message.
+//!
+//! Attribute layouts follow `linux/netfilter/nf_tables.h`; integer
+//! attributes are big endian, while register data is kept in the byte
+//! order the kernel loads it (host order for marks, network order for
+//! packet payload).
+
+use std::os::fd::{AsRawFd, OwnedFd};
+
+use anyhow::{Context, Result, anyhow};
+
+use super::super::libc_s::{self, SockOpt};
+
+const NETLINK_NETFILTER: i32 = 12;
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 16;
+const NFNL_MSG_BATCH_END: u16 = 17;
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_ACK: u16 = 0x4;
+const NLM_F_CREATE: u16 = 0x400;
+const NLM_F_APPEND: u16 = 0x800;
+const NLA_F_NESTED: u16 = 0x8000;
+
+const NFT_MSG_NEWTABLE: u16 = 0;
+const NFT_MSG_DELTABLE: u16 = 2;
+const NFT_MSG_NEWCHAIN: u16 = 3;
+const NFT_MSG_GETCHAIN: u16 = 4;
+const NFT_MSG_NEWRULE: u16 = 6;
+
+const NFTA_TABLE_NAME: u16 = 1;
+
+const NFTA_CHAIN_TABLE: u16 = 1;
+const NFTA_CHAIN_NAME: u16 = 3;
+const NFTA_CHAIN_HOOK: u16 = 4;
+const NFTA_CHAIN_POLICY: u16 = 5;
+const NFTA_CHAIN_TYPE: u16 = 7;
+const NFTA_HOOK_HOOKNUM: u16 = 1;
+const NFTA_HOOK_PRIORITY: u16 = 2;
+
+const NFTA_RULE_TABLE: u16 = 1;
+const NFTA_RULE_CHAIN: u16 = 2;
+const NFTA_RULE_EXPRESSIONS: u16 = 4;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_EXPR_NAME: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_DATA_VERDICT: u16 = 2;
+const NFTA_VERDICT_CODE: u16 = 1;
+
+const NFT_REG_VERDICT: u32 = 0;
+const NFT_REG_1: u32 = 1;
+
+pub const NFPROTO_INET: u8 = 1;
+pub const NFPROTO_IPV4: u8 = 2;
+pub const NFPROTO_IPV6: u8 = 10;
+pub const NF_INET_LOCAL_IN: u32 = 1;
+pub const NF_INET_LOCAL_OUT: u32 = 3;
+pub const NF_INET_POST_ROUTING: u32 = 4;
+
+/// Chain priority before conntrack, as of the raw table
+pub const NF_IP_PRI_RAW: i32 = -300;
+const NF_ACCEPT: u32 = 1;
+const NFT_RETURN: i32 = -5;
+
+/// Netlink attribute writer; nests are closed by [`Attrs::end`].
+struct Attrs<'a> {
+    buf: &'a mut Vec<u8>,
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+impl Attrs<'_> {
+    fn put(&mut self, ty: u16, data: &[u8]) {
+        let len = 4 + data.len();
+        self.buf.extend_from_slice(&(len as u16).to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(data);
+        self.buf.resize(align4(self.buf.len()), 0);
+    }
+
+    fn put_be32(&mut self, ty: u16, v: u32) {
+        self.put(ty, &v.to_be_bytes());
+    }
+
+    /// NUL-terminated string
+    fn put_str(&mut self, ty: u16, s: &str) {
+        let mut data = s.as_bytes().to_vec();
+        data.push(0);
+        self.put(ty, &data);
+    }
+
+    /// Open a nested attribute; return its offset for [`Attrs::end`].
+    fn begin(&mut self, ty: u16) -> usize {
+        let at = self.buf.len();
+        self.buf.extend_from_slice(&0u16.to_ne_bytes());
+        self.buf.extend_from_slice(&(ty | NLA_F_NESTED).to_ne_bytes());
+        at
+    }
+
+    fn end(&mut self, at: usize) {
+        let len = (self.buf.len() - at) as u16;
+        self.buf[at..at + 2].copy_from_slice(&len.to_ne_bytes());
+    }
+}
+
+/// One expression of a rule: name and its encoded attributes
+pub struct Expr {
+    name: &'static str,
+    data: Vec<u8>,
+
+    /// As `nft --debug=netlink` lists it, with data as bytes in
+    /// register order rather than host order words
+    text: String,
+}
+
+impl Expr {
+    fn new(name: &'static str, text: String, f: impl FnOnce(&mut Attrs)) -> Self {
+        let mut data = Vec::new();
+        f(&mut Attrs { buf: &mut data });
+        Self { name, data, text }
+    }
+}
+
+impl std::fmt::Display for Expr {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[ {} ]", self.text)
+    }
+}
+
+/// `0x` and `data` in hex, as it is in the register
+fn hex(data: &[u8]) -> String {
+    data.iter().fold("0x".to_string(), |s, b| s + &format!("{b:02x}"))
+}
+
+/// Register data value
+fn put_value(a: &mut Attrs, ty: u16, value: &[u8]) {
+    let at = a.begin(ty);
+    a.put(NFTA_DATA_VALUE, value);
+    a.end(at);
+}
+
+/// Expressions, all on register 1
+pub mod expr {
+    use super::*;
+
+    pub const META_MARK: u32 = 3;
+    pub const META_NFPROTO: u32 = 15;
+    pub const META_L4PROTO: u32 = 16;
+    pub const CT_MARK: u32 = 3;
+    pub const CT_BYTES: u32 = 15;
+
+    /// Network header
+    pub const NETWORK: u32 = 1;
+
+    /// Transport header
+    pub const TRANSPORT: u32 = 2;
+
+    /// Payload after the transport header (`@ih`)
+    pub const INNER: u32 = 3;
+
+    const CMP_EQ: u32 = 0;
+    const CMP_NEQ: u32 = 1;
+    const CMP_GT: u32 = 4;
+
+    fn meta_key(key: u32) -> &'static str {
+        match key {
+            META_MARK => "mark",
+            META_NFPROTO => "nfproto",
+            META_L4PROTO => "l4proto",
+            _ => "unknown",
+        }
+    }
+
+    fn ct_key(key: u32) -> &'static str {
+        match key {
+            CT_MARK => "mark",
+            CT_BYTES => "bytes",
+            _ => "unknown",
+        }
+    }
+
+    fn base_name(base: u32) -> &'static str {
+        match base {
+            NETWORK => "network",
+            TRANSPORT => "transport",
+            INNER => "inner",
+            _ => "unknown",
+        }
+    }
+
+    pub fn meta(key: u32) -> Expr {
+        Expr::new("meta", format!("meta load {} => reg 1", meta_key(key)), |a| {
+            a.put_be32(1, NFT_REG_1);                       // NFTA_META_DREG
+            a.put_be32(2, key);                             // NFTA_META_KEY
+        })
+    }
+
+    pub fn ct(key: u32) -> Expr {
+        Expr::new("ct", format!("ct load {} => reg 1", ct_key(key)), |a| {
+            a.put_be32(1, NFT_REG_1);                       // NFTA_CT_DREG
+            a.put_be32(2, key);                             // NFTA_CT_KEY
+        })
+    }
+
+    pub fn ct_set(key: u32) -> Expr {
+        Expr::new("ct", format!("ct set {} with reg 1", ct_key(key)), |a| {
+            a.put_be32(2, key);                             // NFTA_CT_KEY
+            a.put_be32(4, NFT_REG_1);                       // NFTA_CT_SREG
+        })
+    }
+
+    pub fn payload(base: u32, offset: u32, len: u32) -> Expr {
+        let text = format!("payload load {len}b @ {} header + {offset} => reg 1", base_name(base));
+        Expr::new("payload", text, |a| {
+            a.put_be32(1, NFT_REG_1);                       // NFTA_PAYLOAD_DREG
+            a.put_be32(2, base);                            // NFTA_PAYLOAD_BASE
+            a.put_be32(3, offset);                          // NFTA_PAYLOAD_OFFSET
+            a.put_be32(4, len);                             // NFTA_PAYLOAD_LEN
+        })
+    }
+
+    fn cmp(op: u32, data: &[u8]) -> Expr {
+        let op_name = match op {
+            CMP_EQ => "eq",
+            CMP_NEQ => "neq",
+            _ => "gt",
+        };
+        Expr::new("cmp", format!("cmp {op_name} reg 1 {}", hex(data)), |a| {
+            a.put_be32(1, NFT_REG_1);                       // NFTA_CMP_SREG
+            a.put_be32(2, op);                              // NFTA_CMP_OP
+            put_value(a, 3, data);                          // NFTA_CMP_DATA
+        })
+    }
+
+    pub fn eq(data: &[u8]) -> Expr {
+        cmp(CMP_EQ, data)
+    }
+
+    pub fn neq(data: &[u8]) -> Expr {
+        cmp(CMP_NEQ, data)
+    }
+
+    /// Compared as bytes: big endian values only
+    pub fn gt(data: &[u8]) -> Expr {
+        cmp(CMP_GT, data)
+    }
+
+    /// reg = hton(reg), of a 64-bit value loaded in host order
+    pub fn hton64() -> Expr {
+        const NFT_BYTEORDER_HTON: u32 = 1;
+
+        Expr::new("byteorder", "byteorder reg 1 = hton(reg 1, 8, 8)".to_string(), |a| {
+            a.put_be32(1, NFT_REG_1);                       // NFTA_BYTEORDER_SREG
+            a.put_be32(2, NFT_REG_1);                       // NFTA_BYTEORDER_DREG
+            a.put_be32(3, NFT_BYTEORDER_HTON);              // NFTA_BYTEORDER_OP
+            a.put_be32(4, 8);                               // NFTA_BYTEORDER_LEN
+            a.put_be32(5, 8);                               // NFTA_BYTEORDER_SIZE
+        })
+    }
+
+    /// reg = (reg & mask) ^ xor
+    pub fn bitwise(mask: &[u8], xor: &[u8]) -> Expr {
+        let text = format!("bitwise reg 1 = ( reg 1 & {} ) ^ {}", hex(mask), hex(xor));
+        Expr::new("bitwise", text, |a| {
+            a.put_be32(1, NFT_REG_1);                       // NFTA_BITWISE_SREG
+            a.put_be32(2, NFT_REG_1);                       // NFTA_BITWISE_DREG
+            a.put_be32(3, mask.len() as u32);               // NFTA_BITWISE_LEN
+            put_value(a, 4, mask);                          // NFTA_BITWISE_MASK
+            put_value(a, 5, xor);                           // NFTA_BITWISE_XOR
+        })
+    }
+
+    fn verdict(code: i32, name: &str) -> Expr {
+        Expr::new("immediate", format!("immediate reg 0 {name}"), |a| {
+            a.put_be32(1, NFT_REG_VERDICT);                 // NFTA_IMMEDIATE_DREG
+            let data = a.begin(2);                          // NFTA_IMMEDIATE_DATA
+            let verdict = a.begin(NFTA_DATA_VERDICT);
+            a.put_be32(NFTA_VERDICT_CODE, code as u32);
+            a.end(verdict);
+            a.end(data);
+        })
+    }
+
+    pub fn ret() -> Expr {
+        verdict(NFT_RETURN, "return")
+    }
+
+    /// Leave the packet untracked by conntrack
+    pub fn notrack() -> Expr {
+        Expr::new("notrack", "notrack".to_string(), |_| {})
+    }
+
+    pub fn queue(num: u16, bypass: bool) -> Expr {
+        const NFT_QUEUE_FLAG_BYPASS: u16 = 0x1;
+
+        let text = format!("queue num {num}{}", if bypass { " bypass" } else { "" });
+        Expr::new("queue", text, |a| {
+            a.put(1, &num.to_be_bytes());                   // NFTA_QUEUE_NUM
+            a.put(2, &1u16.to_be_bytes());                  // NFTA_QUEUE_TOTAL
+            let flags = if bypass { NFT_QUEUE_FLAG_BYPASS } else { 0 };
+            a.put(3, &flags.to_be_bytes());                 // NFTA_QUEUE_FLAGS
+        })
+    }
+}
+
+/// Messages to be committed as one transaction
+pub struct Batch {
+    buf: Vec<u8>,
+    seq: u32,
+    family: u8,
+}
+
+impl Batch {
+    pub fn new(family: u8) -> Self {
+        let mut b = Self { buf: Vec::new(), seq: 0, family };
+        b.msg(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST, libc::AF_UNSPEC as u8,
+              NFNL_SUBSYS_NFTABLES, |_| {});
+        b
+    }
+
+    /// Append a message; return its sequence number.
+    fn msg(&mut self, ty: u16, flags: u16, family: u8, res_id: u16,
+           f: impl FnOnce(&mut Attrs)) -> u32 {
+        self.seq += 1;
+        let at = self.buf.len();
+
+        // struct nlmsghdr; length is filled below
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+        self.buf.extend_from_slice(&ty.to_ne_bytes());
+        self.buf.extend_from_slice(&flags.to_ne_bytes());
+        self.buf.extend_from_slice(&self.seq.to_ne_bytes());
+        self.buf.extend_from_slice(&0u32.to_ne_bytes());
+
+        // struct nfgenmsg
+        self.buf.extend_from_slice(&[family, 0]);
+        self.buf.extend_from_slice(&res_id.to_be_bytes());
+
+        f(&mut Attrs { buf: &mut self.buf });
+
+        let len = (self.buf.len() - at) as u32;
+        self.buf[at..at + 4].copy_from_slice(&len.to_ne_bytes());
+        self.seq
+    }
+
+    fn nft_msg(&mut self, ty: u16, flags: u16, f: impl FnOnce(&mut Attrs)) -> u32 {
+        let family = self.family;
+        self.msg((NFNL_SUBSYS_NFTABLES << 8) | ty, NLM_F_REQUEST | NLM_F_ACK | flags, family, 0, f)
+    }
+
+    pub fn add_table(&mut self, table: &str) {
+        self.nft_msg(NFT_MSG_NEWTABLE, NLM_F_CREATE, |a| a.put_str(NFTA_TABLE_NAME, table));
+    }
+
+    pub fn del_table(&mut self, table: &str) {
+        self.nft_msg(NFT_MSG_DELTABLE, 0, |a| a.put_str(NFTA_TABLE_NAME, table));
+    }
+
+    /// Base filter chain on `hook` with policy accept
+    pub fn add_chain(&mut self, table: &str, chain: &str, hook: u32, priority: i32) {
+        self.nft_msg(NFT_MSG_NEWCHAIN, NLM_F_CREATE, |a| {
+            a.put_str(NFTA_CHAIN_TABLE, table);
+            a.put_str(NFTA_CHAIN_NAME, chain);
+            let h = a.begin(NFTA_CHAIN_HOOK);
+            a.put_be32(NFTA_HOOK_HOOKNUM, hook);
+            a.put_be32(NFTA_HOOK_PRIORITY, priority as u32);
+            a.end(h);
+            a.put_be32(NFTA_CHAIN_POLICY, NF_ACCEPT);
+            a.put_str(NFTA_CHAIN_TYPE, "filter");
+        });
+    }
+
+    pub fn add_rule(&mut self, table: &str, chain: &str, exprs: &[Expr]) {
+        self.nft_msg(NFT_MSG_NEWRULE, NLM_F_CREATE | NLM_F_APPEND, |a| {
+            a.put_str(NFTA_RULE_TABLE, table);
+            a.put_str(NFTA_RULE_CHAIN, chain);
+            let list = a.begin(NFTA_RULE_EXPRESSIONS);
+            for e in exprs {
+                let elem = a.begin(NFTA_LIST_ELEM);
+                a.put_str(NFTA_EXPR_NAME, e.name);
+                let data = a.begin(NFTA_EXPR_DATA);
+                a.buf.extend_from_slice(&e.data);
+                a.end(data);
+                a.end(elem);
+            }
+            a.end(list);
+        });
+    }
+
+    /// Send the batch and wait for the kernel to ack every message.
+    pub fn commit(mut self) -> Result<()> {
+        let last = self.seq;
+        self.msg(NFNL_MSG_BATCH_END, NLM_F_REQUEST, libc::AF_UNSPEC as u8,
+                 NFNL_SUBSYS_NFTABLES, |_| {});
+
+        let sock = open_socket()?;
+        libc_s::send(sock.as_raw_fd(), &self.buf).context("netlink: send")?;
+
+        // BATCH_BEGIN (seq 1) is not acked
+        let mut pending = last - 1;
+        let mut buf = vec![0u8; 16 * 1024];
+
+        while pending > 0 {
+            let n = libc_s::recv(sock.as_raw_fd(), &mut buf).context("netlink: recv")?;
+            let mut msgs = &buf[..n];
+
+            while let Some((seq, errno)) = next_msg(&mut msgs)? {
+                match errno {
+                    Some(0) => pending -= 1,
+                    Some(errno) => return Err(anyhow!("netlink: message {seq}: {}",
+                                                      std::io::Error::from_raw_os_error(errno))),
+                    None => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Take the next message off `msgs`: its sequence number and, if it is
+/// an ack or error, the errno (0 for an ack).
+fn next_msg(msgs: &mut &[u8]) -> Result<Option<(u32, Option<i32>)>> {
+    if msgs.len() < 16 {
+        return Ok(None);
+    }
+
+    let len = u32::from_ne_bytes(msgs[0..4].try_into().unwrap()) as usize;
+    let ty = u16::from_ne_bytes(msgs[4..6].try_into().unwrap());
+    let seq = u32::from_ne_bytes(msgs[8..12].try_into().unwrap());
+    if len < 16 || len > msgs.len() {
+        return Err(anyhow!("netlink: malformed reply"));
+    }
+
+    let errno = (ty == NLMSG_ERROR && len >= 20)
+        .then(|| -i32::from_ne_bytes(msgs[16..20].try_into().unwrap()));
+
+    *msgs = &msgs[align4(len).min(msgs.len())..];
+    Ok(Some((seq, errno)))
+}
+
+/// Whether `chain` exists in `table`; false also when the table does
+/// not, e.g. after someone ran `nft flush ruleset`.
+pub fn chain_exists(family: u8, table: &str, chain: &str) -> Result<bool> {
+    let mut req = Batch { buf: Vec::new(), seq: 0, family };
+    req.nft_msg(NFT_MSG_GETCHAIN, 0, |a| {
+        a.put_str(NFTA_CHAIN_TABLE, table);
+        a.put_str(NFTA_CHAIN_NAME, chain);
+    });
+
+    let sock = open_socket()?;
+    libc_s::send(sock.as_raw_fd(), &req.buf).context("netlink: send")?;
+
+    // The chain itself comes first if it exists, then the ack
+    let mut buf = vec![0u8; 16 * 1024];
+    loop {
+        let n = libc_s::recv(sock.as_raw_fd(), &mut buf).context("netlink: recv")?;
+        let mut msgs = &buf[..n];
+
+        while let Some((_, errno)) = next_msg(&mut msgs)? {
+            match errno {
+                Some(0) => return Ok(true),
+                Some(libc::ENOENT) => return Ok(false),
+                Some(errno) => return Err(anyhow!("netlink: get chain: {}",
+                                                  std::io::Error::from_raw_os_error(errno))),
+                None => {}
+            }
+        }
+    }
+}
+
+fn open_socket() -> Result<OwnedFd> {
+    let sock = libc_s::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, NETLINK_NETFILTER)
+        .context("netlink: socket")?;
+
+    // Don't hang if the kernel never answers (e.g. no nf_tables)
+    let timeout = libc::timeval { tv_sec: 2, tv_usec: 0 };
+    libc_s::setsockopt(sock.as_raw_fd(), SockOpt::SO_RCVTIMEO(&timeout))
+        .context("netlink: SO_RCVTIMEO")?;
+
+    Ok(sock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_batch_layout() {
+        let mut b = Batch::new(NFPROTO_INET);
+        b.add_table("t");
+
+        // BATCH_BEGIN: header + nfgenmsg, res_id nftables
+        assert_eq!(u32::from_ne_bytes(b.buf[0..4].try_into().unwrap()), 20);
+        assert_eq!(&b.buf[18..20], &NFNL_SUBSYS_NFTABLES.to_be_bytes());
+
+        // NEWTABLE: header + nfgenmsg + name attribute ("t\0", padded)
+        let m = &b.buf[20..];
+        assert_eq!(u32::from_ne_bytes(m[0..4].try_into().unwrap()), 28);
+        assert_eq!(u16::from_ne_bytes(m[4..6].try_into().unwrap()), NFNL_SUBSYS_NFTABLES << 8);
+        assert_eq!(m[16], NFPROTO_INET);
+        assert_eq!(&m[20..22], &6u16.to_ne_bytes());
+        assert_eq!(&m[22..24], &NFTA_TABLE_NAME.to_ne_bytes());
+        assert_eq!(&m[24..28], b"t\0\0\0");
+    }
+
+    #[test]
+    fn test_nested_len() {
+        let e = expr::queue(3, true);
+        // three 2-byte attributes padded to 8 bytes each
+        assert_eq!(e.data.len(), 24);
+        assert_eq!(&e.data[4..6], &3u16.to_be_bytes());
+
+        let r = expr::ret();
+        // dreg (8) + data nest (4 + verdict nest (4 + code (8)))
+        assert_eq!(r.data.len(), 24);
+        assert_eq!(u16::from_ne_bytes(r.data[8..10].try_into().unwrap()), 16);
+    }
+
+    #[test]
+    fn test_expr_text() {
+        assert_eq!(expr::payload(expr::INNER, 5, 1).to_string(), "[ payload load 1b @ inner header + 5 => reg 1 ]");
+        assert_eq!(expr::neq(&[0x01, 0xbb]).to_string(), "[ cmp neq reg 1 0x01bb ]");
+        assert_eq!(expr::queue(3, false).to_string(), "[ queue num 3 ]");
+        assert_eq!(expr::notrack().to_string(), "[ notrack ]");
+        assert!(expr::notrack().data.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! What our packet filter rules select, whatever the backend
+//!
+//! [`RuleSpec`] holds the options the rules depend on, read once. Each
+//! backend turns it into its own form with pure functions: nftables
+//! expressions and iptables arguments in `linux::rules`, WinDivert
+//! filters here, so that they are tested on every platform. Tests pin
+//! their exact output for a few combinations of options.
+
+#[cfg(target_os = "linux")]
+use std::net::IpAddr;
+
+use crate::opt;
+
+pub struct RuleSpec {
+    /// `--proxy-ports` other than 443, which is always queued
+    pub proxy_ports: Vec<u16>,
+    pub private_exempt: bool,
+    pub pass_sslv2: bool,
+    pub watch_teardown: bool,
+    pub dns_guard: bool,
+
+    /// IPv6 SYNs to port 443 are queued for `--v6-degrade`.
+    pub v6_degrade: bool,
+    #[cfg(any(windows, test))] pub autottl_any: bool,
+    #[cfg(target_os = "linux")] pub queue_num: u16,
+    #[cfg(target_os = "linux")] pub nft_priority: i32,
+    #[cfg(target_os = "linux")] pub mark: opt::Mark,
+
+    /// Set on handled connections, whose packets then skip the queue
+    #[cfg(target_os = "linux")] pub connmark: opt::Mark,
+    #[cfg(target_os = "linux")] pub fail_closed: bool,
+    #[cfg(target_os = "linux")] pub notrack: bool,
+
+    /// Connections past these bytes are not queued where every packet
+    /// to the port is; 0 for none
+    #[cfg(target_os = "linux")] pub bulk_bytes: u64,
+
+    /// Forwarding for `--client-profile` clients; ClientHellos are
+    /// caught after routing.
+    #[cfg(target_os = "linux")] pub gateway: bool,
+
+    /// Clients whose `--client-profile` is off, not to be queued at all
+    #[cfg(target_os = "linux")] pub off_clients: Vec<(IpAddr, u8)>,
+}
+
+impl RuleSpec {
+    pub fn from_opts() -> Self {
+        Self {
+            proxy_ports: opt::proxy_ports().ports().iter().copied().filter(|&p| p != 443).collect(),
+            private_exempt: opt::private_exempt(),
+            pass_sslv2: opt::pass_sslv2(),
+            watch_teardown: opt::watch_teardown(),
+            dns_guard: opt::dns_guard(),
+            v6_degrade: opt::v6_degrade(),
+            #[cfg(any(windows, test))] autottl_any: opt::autottl_any(),
+            #[cfg(target_os = "linux")] queue_num: super::linux::queue_num(),
+            #[cfg(target_os = "linux")] nft_priority: opt::nft_priority(),
+            #[cfg(target_os = "linux")] mark: opt::mark(),
+            #[cfg(target_os = "linux")] connmark: opt::connmark(),
+            #[cfg(target_os = "linux")] fail_closed: opt::fail_closed(),
+            #[cfg(target_os = "linux")] notrack: opt::notrack(),
+            #[cfg(target_os = "linux")] bulk_bytes: opt::bulk_bytes(),
+            #[cfg(target_os = "linux")] gateway: !opt::client_profile().is_empty(),
+            #[cfg(target_os = "linux")] off_clients: off_clients(),
+        }
+    }
+}
+
+/// Only when no longer prefix gives them another profile; otherwise
+/// [`crate::pkt::classify`] passes them.
+#[cfg(target_os = "linux")]
+fn off_clients() -> Vec<(IpAddr, u8)> {
+    let profiles = opt::client_profile();
+    profiles.rules().iter()
+        .filter(|r| r.profile.off)
+        .filter(|r| !profiles.rules().iter().any(|o| !o.profile.off && o.prefix > r.prefix
+                                                 && r.contains(o.net)))
+        .map(|r| (r.net, r.prefix))
+        .collect()
+}
+
+#[cfg(any(windows, test))]
+impl RuleSpec {
+    /// Outbound ClientHellos to port 443 and the `--proxy-ports`, and
+    /// CONNECT requests to the proxies; not to private networks unless
+    /// `--no-private-exempt`. SSLv2-compatible ClientHellos are included
+    /// unless `--pass-sslv2`, IPv6 SYNs to port 443 with `--v6-degrade`.
+    pub fn divert_filter(&self) -> String {
+        const TLS_HELLO: &str = "tcp.Payload[0] == 22 and tcp.Payload[5] == 1";
+        const SSLV2_HELLO: &str = "tcp.Payload[0] >= 128 and tcp.Payload[2] == 1";
+        const CONNECT: &str = "tcp.Payload32[0] == 0x434f4e4e"; // "CONN"
+
+        let hello = if self.pass_sslv2 {
+            format!("({TLS_HELLO})")
+        } else {
+            format!("(({TLS_HELLO}) or ({SSLV2_HELLO}))")
+        };
+
+        let mut filter = format!("outbound and !impostor and tcp and \
+                                  ((tcp.DstPort == 443 and {hello})");
+
+        for port in &self.proxy_ports {
+            filter += &format!(" or (tcp.DstPort == {port} and ({hello} or {CONNECT}))");
+        }
+        if self.v6_degrade {
+            filter += " or (ipv6 and tcp.DstPort == 443 and tcp.Syn and !tcp.Ack)";
+        }
+        filter += ")";
+
+        if self.private_exempt {
+            filter += &format!(" and !({})", private_filter());
+        }
+
+        filter
+    }
+
+    /// Inbound packets to handle, if any: teardowns of port 443
+    /// connections with `--watch-teardown`, DNS responses with
+    /// `--dns-guard`
+    pub fn inbound_filter(&self) -> Option<String> {
+        let mut inbound = Vec::new();
+        if self.watch_teardown {
+            inbound.push("(tcp and tcp.SrcPort == 443 and (tcp.Rst or tcp.Fin))");
+        }
+        if self.dns_guard {
+            inbound.push("(udp and udp.SrcPort == 53)");
+        }
+
+        (!inbound.is_empty()).then(|| format!("!outbound and ({})", inbound.join(" or ")))
+    }
+
+    /// Packets sniffed for `--learn-hops`: SYN/ACKs from port 443, or
+    /// anything from it with `--autottl-any`
+    pub fn sniff_filter(&self) -> &'static str {
+        if self.autottl_any {
+            "!outbound and tcp and tcp.SrcPort == 443"
+        } else {
+            "!outbound and tcp and tcp.SrcPort == 443 and tcp.Syn and tcp.Ack"
+        }
+    }
+
+    /// Packets sniffed for `--watch-pmtu`: ICMP "fragmentation needed"
+    /// and ICMPv6 "packet too big"
+    pub fn pmtu_filter(&self) -> &'static str {
+        "!outbound and ((icmp and icmp.Type == 3 and icmp.Code == 4) or \
+         (icmpv6 and icmpv6.Type == 2))"
+    }
+}
+
+/// Destinations in [`super::PRIVATE_NETS`], as address ranges
+#[cfg(any(windows, test))]
+fn private_filter() -> String {
+    use std::net::IpAddr;
+
+    super::PRIVATE_NETS.iter().map(|&(net, prefix)| {
+        match (net, super::prefix_mask(net, prefix)) {
+            (IpAddr::V4(n), IpAddr::V4(m)) => {
+                let last = std::net::Ipv4Addr::from(n.to_bits() | !m.to_bits());
+                format!("(ip.DstAddr >= {n} and ip.DstAddr <= {last})")
+            }
+            (IpAddr::V6(n), IpAddr::V6(m)) => {
+                let last = std::net::Ipv6Addr::from(n.to_bits() | !m.to_bits());
+                format!("(ipv6.DstAddr >= {n} and ipv6.DstAddr <= {last})")
+            }
+            _ => unreachable!("mask is of the family of net"),
+        }
+    }).collect::<Vec<_>>().join(" or ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLO: &str = "((tcp.Payload[0] == 22 and tcp.Payload[5] == 1) or \
+                         (tcp.Payload[0] >= 128 and tcp.Payload[2] == 1))";
+
+    #[test]
+    fn test_divert_filter() {
+        let spec = RuleSpec::from_opts();
+        assert_eq!(spec.divert_filter(),
+                   format!("outbound and !impostor and tcp and ((tcp.DstPort == 443 and {HELLO})) and \
+                            !((ip.DstAddr >= 127.0.0.0 and ip.DstAddr <= 127.255.255.255) or \
+                            (ip.DstAddr >= 10.0.0.0 and ip.DstAddr <= 10.255.255.255) or \
+                            (ip.DstAddr >= 172.16.0.0 and ip.DstAddr <= 172.31.255.255) or \
+                            (ip.DstAddr >= 192.168.0.0 and ip.DstAddr <= 192.168.255.255) or \
+                            (ip.DstAddr >= 169.254.0.0 and ip.DstAddr <= 169.254.255.255) or \
+                            (ipv6.DstAddr >= ::1 and ipv6.DstAddr <= ::1) or \
+                            (ipv6.DstAddr >= fc00:: and ipv6.DstAddr <= fdff:ffff:ffff:ffff:ffff:ffff:ffff:ffff) or \
+                            (ipv6.DstAddr >= fe80:: and ipv6.DstAddr <= febf:ffff:ffff:ffff:ffff:ffff:ffff:ffff))"));
+
+        let spec = RuleSpec { proxy_ports: vec![8080], private_exempt: false, ..RuleSpec::from_opts() };
+        assert_eq!(spec.divert_filter(),
+                   format!("outbound and !impostor and tcp and ((tcp.DstPort == 443 and {HELLO}) or \
+                            (tcp.DstPort == 8080 and ({HELLO} or tcp.Payload32[0] == 0x434f4e4e)))"));
+
+        let spec = RuleSpec { pass_sslv2: true, private_exempt: false, ..RuleSpec::from_opts() };
+        assert_eq!(spec.divert_filter(),
+                   "outbound and !impostor and tcp and \
+                    ((tcp.DstPort == 443 and (tcp.Payload[0] == 22 and tcp.Payload[5] == 1)))");
+
+        let spec = RuleSpec { v6_degrade: true, private_exempt: false, ..RuleSpec::from_opts() };
+        assert_eq!(spec.divert_filter(),
+                   format!("outbound and !impostor and tcp and ((tcp.DstPort == 443 and {HELLO}) or \
+                            (ipv6 and tcp.DstPort == 443 and tcp.Syn and !tcp.Ack))"));
+    }
+
+    #[test]
+    fn test_inbound_filter() {
+        let spec = RuleSpec { watch_teardown: false, dns_guard: false, ..RuleSpec::from_opts() };
+        assert_eq!(spec.inbound_filter(), None);
+
+        let spec = RuleSpec { watch_teardown: true, dns_guard: true, ..RuleSpec::from_opts() };
+        assert_eq!(spec.inbound_filter().as_deref(),
+                   Some("!outbound and ((tcp and tcp.SrcPort == 443 and (tcp.Rst or tcp.Fin)) or \
+                         (udp and udp.SrcPort == 53))"));
+
+        let spec = RuleSpec { autottl_any: true, ..RuleSpec::from_opts() };
+        assert_eq!(spec.sniff_filter(), "!outbound and tcp and tcp.SrcPort == 443");
+        assert_eq!(spec.pmtu_filter(),
+                   "!outbound and ((icmp and icmp.Type == 3 and icmp.Code == 4) or \
+                    (icmpv6 and icmpv6.Type == 2))");
+    }
+}
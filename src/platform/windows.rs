@@ -23,6 +23,10 @@ use std::thread;
 use crate::{opt, pkt};
 use super::paexit;
 
+mod iface;
+mod rival;
+mod socket;
+
 pub fn pause() {
     println!("Press any key to exit...");
 
@@ -33,18 +37,26 @@ pub fn pause() {
 static RECV_HANDLES: LazyLock<Mutex<Vec<Arc<WinDivert<NetworkLayer>>>>> =
     LazyLock::new(|| Mutex::new(Vec::new()));
 
-fn open_recv_handle(filter: &str, flags: prelude::WinDivertFlags) -> Arc<WinDivert<NetworkLayer>> {
-    let h = Arc::new(open_handle(filter, flags));
+fn open_recv_handle(filter: &str, priority: i16, flags: prelude::WinDivertFlags) -> Arc<WinDivert<NetworkLayer>> {
+    let h = Arc::new(open_handle(filter, priority, flags));
     RECV_HANDLES.lock().expect("mutex poisoned").push(h.clone());
     h
 }
 
 fn shutdown_all() {
+    crate::pkt::worker::stop();
+    shutdown_handles();
+}
+
+/// Make the receiving threads return, without telling jobs to hurry
+/// as on a shutdown request.
+fn shutdown_handles() {
     for h in RECV_HANDLES.lock().expect("mutex poisoned").iter() {
         if let Err(e) = h.shutdown(WinDivertShutdownMode::Both) {
             crate::warn!("windivert: shutdown: {e}");
         }
     }
+    socket::shutdown();
 }
 
 fn cleanup_all() {
@@ -59,25 +71,32 @@ fn cleanup_all() {
             }
         }
     }
+    socket::close();
 }
 
-fn open_handle(filter: &str, flags: prelude::WinDivertFlags) -> WinDivert<NetworkLayer> {
+fn open_handle(filter: &str, priority: i16, flags: prelude::WinDivertFlags) -> WinDivert<NetworkLayer> {
     use windivert::*;
 
-    let h = match WinDivert::network(&filter, 0, flags) {
+    let h = match WinDivert::network(&filter, priority, flags) {
         Ok(h) => {
-            crate::info!("windivert: open filter {filter}");
+            crate::info!("windivert: open filter {filter} at priority {priority}");
             h
         },
         Err(e) => {
             crate::error!("windivert: cannot open {filter}: {e}");
-            paexit(1);
+            // Roll back the handles opened so far; otherwise their
+            // traffic stays diverted to nobody while paexit() waits.
+            shutdown_all();
+            cleanup_all();
+            paexit(crate::exit::Status::Backend.code());
         }
     };
     h
 }
 
 pub fn bootstrap() -> Result<()> {
+    install_crash_filter();
+
     if opt::daemon() {
         service_main();
     }
@@ -87,12 +106,43 @@ pub fn bootstrap() -> Result<()> {
     Ok(())
 }
 
+/// Write the crash log on an exception nothing handles (an access
+/// violation in WinDivert or a stack overflow, which are not panics),
+/// then let Windows report it as usual.
+fn install_crash_filter() {
+    const EXCEPTION_CONTINUE_SEARCH: i32 = 0;
+
+    #[repr(C)]
+    struct ExceptionRecord { code: u32, flags: u32, _record: *const ExceptionRecord, address: usize }
+    #[repr(C)]
+    struct ExceptionPointers { record: *const ExceptionRecord, _context: *const u8 }
+
+    unsafe extern "system" {
+        fn SetUnhandledExceptionFilter(
+            filter: Option<unsafe extern "system" fn(*const ExceptionPointers) -> i32>,
+        ) -> usize;
+    }
+
+    unsafe extern "system" fn filter(info: *const ExceptionPointers) -> i32 {
+        // SAFETY: Windows passes the exception being dispatched
+        let what = match unsafe { info.as_ref().and_then(|i| i.record.as_ref()) } {
+            Some(r) => format!("exception {:#010x} (flags {:#x}) at {:#x}", r.code, r.flags, r.address),
+            None => "unhandled exception".to_string(),
+        };
+        crate::log::write_crash(&what);
+        EXCEPTION_CONTINUE_SEARCH
+    }
+
+    // SAFETY: the filter is a plain function living as long as the process
+    unsafe { SetUnhandledExceptionFilter(Some(filter)) };
+}
+
 static SEND_HANDLE: OnceLock<Mutex<WinDivert<NetworkLayer>>> = OnceLock::new();
 
 fn send_handle() -> &'static Mutex<WinDivert<NetworkLayer>> {
     SEND_HANDLE.get_or_init(|| {
         let flags = prelude::WinDivertFlags::new().set_send_only();
-        Mutex::new(open_handle("false", flags))
+        Mutex::new(open_handle("false", opt::windivert_priority(), flags))
     })
 }
 
@@ -140,6 +190,22 @@ macro_rules! recv_loop {
     };
 }
 
+/// Let the calling thread yield the CPU to the ClientHello path, so that
+/// a flood of SYN/ACKs to learn from does not hold it up.
+fn lower_thread_priority() {
+    const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+
+    unsafe extern "system" {
+        fn GetCurrentThread() -> isize;
+        fn SetThreadPriority(thread: isize, priority: i32) -> i32;
+    }
+
+    // SAFETY: the pseudo handle of the current thread needs no closing
+    if unsafe { SetThreadPriority(GetCurrentThread(), THREAD_PRIORITY_BELOW_NORMAL) } == 0 {
+        crate::warn!("cannot lower thread priority: {}", std::io::Error::last_os_error());
+    }
+}
+
 fn install_ctrl_handler() {
     unsafe extern "system" {
         fn SetConsoleCtrlHandler(
@@ -210,45 +276,160 @@ fn touch_windivert() {
 }
 
 pub fn run() -> Result<()> {
+    let ret = run_1();
+    if ret.is_err() {
+        // Release the handles the failed run left open, so that their
+        // traffic is not diverted to nobody, and a restart by --watch
+        // can open them again.
+        shutdown_handles();
+        cleanup_all();
+    }
+    ret
+}
+
+fn run_1() -> Result<()> {
     touch_windivert();
+    crate::doh::spawn()?;
+    crate::hostlist::init()?;
+    crate::pkt::hook::init()?;
+    crate::pkt::state::init();
+
+    let mut worker = crate::pkt::worker::Worker::spawn()?;
+
+    let spec = super::rulespec::RuleSpec::from_opts();
+    let iface = iface::filter()?;
+    let scoped = |filter: &str| match &iface {
+        Some(iface) => format!("({filter}) and {iface}"),
+        None => filter.to_string(),
+    };
 
-    let mut buf = Vec::<u8>::with_capacity(super::PACKET_SIZE_CAP);
+    let sniff_thread = if opt::learn_hops() {
+        let handle = open_recv_handle(
+            &scoped(spec.sniff_filter()),
+            opt::windivert_priority(),
+            prelude::WinDivertFlags::new().set_sniff()
+        );
+        Some(thread::spawn(move || {
+            lower_thread_priority();
+            recv_loop!(handle, pkt => pkt::put_hop(&pkt.data));
+        }))
+    } else {
+        None
+    };
 
-    let sniff_thread = if opt::fake_autottl() {
+    let pmtu_thread = if opt::watch_pmtu() {
         let handle = open_recv_handle(
-            "!outbound and tcp and tcp.SrcPort == 443 and tcp.Syn and tcp.Ack",
+            &scoped(spec.pmtu_filter()),
+            opt::windivert_priority(),
             prelude::WinDivertFlags::new().set_sniff()
         );
-        Some(thread::spawn(move || { recv_loop!(handle, pkt => pkt::put_hop(&pkt.data)); }))
+        Some(thread::spawn(move || {
+            lower_thread_priority();
+            recv_loop!(handle, pkt => pkt::pmtu::learn(&pkt.data));
+        }))
+    } else {
+        None
+    };
+
+    let socket_thread = if opt::socket_layer() {
+        socket::spawn().map_err(|e| crate::warn!("{e:#}; continuing without --socket-layer")).ok()
+    } else {
+        None
+    };
+
+    let filter = scoped(&spec.divert_filter());
+    let divert = open_recv_handle(&filter, opt::windivert_priority(), prelude::WinDivertFlags::new());
+
+    let rival_thread = if rival::enabled() {
+        let handle = open_recv_handle(&filter, rival::PRIORITY, prelude::WinDivertFlags::new().set_sniff());
+        Some(thread::spawn(move || { recv_loop!(handle, _pkt => rival::seen()); }))
+    } else {
+        None
+    };
+
+    let query_thread = if spec.dns_guard {
+        let handle = open_recv_handle(
+            &scoped("outbound and udp and udp.DstPort == 53"),
+            opt::windivert_priority(),
+            prelude::WinDivertFlags::new().set_sniff()
+        );
+        Some(thread::spawn(move || {
+            let mut buf = Vec::new();
+            recv_loop!(handle, pkt => {
+                crate::handle_packet!(&pkt.data, &mut buf, handled => {}, rejected => {})
+            });
+        }))
+    } else {
+        None
+    };
+
+    let inbound_thread = if let Some(inbound) = spec.inbound_filter() {
+        let handle = open_recv_handle(
+            &scoped(&inbound),
+            opt::windivert_priority(),
+            prelude::WinDivertFlags::new()
+        );
+        Some(thread::spawn(move || {
+            let mut buf = Vec::new();
+            recv_loop!(handle, pkt => {
+                crate::handle_packet!(
+                    &pkt.data,
+                    &mut buf,
+                    handled => {},
+                    rejected => if let Err(e) = handle.send(&pkt) {
+                        crate::warn!("windivert: reinject: {e}");
+                    }
+                )
+            });
+        }))
     } else {
         None
     };
 
-    let divert = open_recv_handle(
-        concat!(
-            "outbound and tcp and tcp.DstPort == 443",
-            " ", "and tcp.Payload[0] == 22",
-            " ", "and tcp.Payload[5] == 1 and !impostor"
-        ),
-        prelude::WinDivertFlags::new()
-    );
+    super::Health {
+        backend: "WinDivert",
+        prefilter: Some("WinDivert filter"),
+        autottl: sniff_thread.as_ref().map(|_| "WinDivert sniff handle"),
+        injection: "WinDivert send handle",
+        ipv6: true,
+        workers: crate::pkt::worker::COUNT,
+    }.report();
 
     crate::splash!("{}", super::MESSAGE_AT_RUN);
 
     recv_loop!(divert, pkt => {
+        rival::received();
         crate::handle_packet!(
             &pkt.data,
-            &mut buf,
+            worker: &mut worker,
             handled => {},
             rejected => send_to_raw_1(&pkt.data)?
         )
     });
     drop(divert);
+    drop(worker);               // flush jobs in flight before closing the send handle
     if let Some(jh) = sniff_thread && jh.join().is_err() {
         crate::warn!("join for sniff thread failed: thread paniced");
     }
+    if let Some(jh) = pmtu_thread && jh.join().is_err() {
+        crate::warn!("join for pmtu thread failed: thread paniced");
+    }
+    if let Some(jh) = rival_thread && jh.join().is_err() {
+        crate::warn!("join for rival thread failed: thread paniced");
+    }
+    if let Some(jh) = inbound_thread && jh.join().is_err() {
+        crate::warn!("join for inbound thread failed: thread paniced");
+    }
+    if let Some(jh) = query_thread && jh.join().is_err() {
+        crate::warn!("join for dns query thread failed: thread paniced");
+    }
+    if let Some(jh) = socket_thread && jh.join().is_err() {
+        crate::warn!("join for socket thread failed: thread paniced");
+    }
     cleanup_all();
     close_send_handle();
+    crate::stats::report();
+    crate::pkt::state::save();
     if let Err(e) = windivert::WinDivert::uninstall() {
         crate::warn!("windivert: uninstall failed: {e}");
     }
@@ -257,12 +438,9 @@ pub fn run() -> Result<()> {
 }
 
 fn service_run() {
-    use std::process::exit;
-
-    if run().is_err() {
-        exit(1);
-    }
-    exit(0);
+    let result = super::supervise().map_err(crate::exit::Error::from);
+    crate::exit::report_exit(&result);
+    std::process::exit(result.map_or_else(|e| e.status.code(), |()| 0));
 }
 
 fn service_main()  {
@@ -280,11 +458,133 @@ fn service_main()  {
             Ok(_) => {}
             Err(e) => {
                 println!("{e}");
-                paexit(1);
+                paexit(crate::exit::Status::Daemon.code());
             }
         };
 }
 
+/// NUL-terminated UTF-16 of `s`
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain([0]).collect()
+}
+
+/// Security descriptor of `sddl`; leaked, as it is kept for the life of
+/// the process anyway
+fn security_descriptor(sddl: &str) -> Result<*mut std::ffi::c_void> {
+    const SDDL_REVISION_1: u32 = 1;
+
+    #[link(name = "advapi32")]
+    unsafe extern "system" {
+        fn ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            sddl: *const u16, revision: u32, descriptor: *mut *mut std::ffi::c_void, len: *mut u32) -> i32;
+    }
+
+    let mut descriptor = std::ptr::null_mut();
+    // SAFETY: sddl is NUL-terminated; descriptor is written on success
+    let ok = unsafe {
+        ConvertStringSecurityDescriptorToSecurityDescriptorW(
+            wide(sddl).as_ptr(), SDDL_REVISION_1, &mut descriptor, std::ptr::null_mut())
+    };
+    if ok == 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(descriptor)
+}
+
+/// `len` bytes of file mapping `name` (e.g. `Global\dpibreak-stats`),
+/// backed by the paging file and mapped for the life of the process.
+/// Readable by any signed-in user, writable by SYSTEM and
+/// administrators.
+pub fn map_shared(name: &str, len: usize) -> Result<*mut u8> {
+    use std::ffi::c_void;
+
+    #[repr(C)]
+    struct SECURITY_ATTRIBUTES { len: u32, descriptor: *mut c_void, inherit: i32 }
+
+    const PAGE_READWRITE: u32 = 0x04;
+    const FILE_MAP_WRITE: u32 = 0x02;
+    const INVALID_HANDLE_VALUE: isize = -1;
+    /// Full control to SYSTEM and administrators, read to authenticated users
+    const SDDL: &str = "D:(A;;GA;;;SY)(A;;GA;;;BA)(A;;GR;;;AU)";
+
+    unsafe extern "system" {
+        fn CreateFileMappingW(file: isize, attrs: *const SECURITY_ATTRIBUTES, protect: u32,
+                              size_high: u32, size_low: u32, name: *const u16) -> isize;
+        fn MapViewOfFile(mapping: isize, access: u32, offset_high: u32, offset_low: u32,
+                         len: usize) -> *mut c_void;
+    }
+
+    let attrs = SECURITY_ATTRIBUTES {
+        len: std::mem::size_of::<SECURITY_ATTRIBUTES>() as u32,
+        descriptor: security_descriptor(SDDL)?,
+        inherit: 0,
+    };
+
+    unsafe {
+        let mapping = CreateFileMappingW(INVALID_HANDLE_VALUE, &attrs, PAGE_READWRITE,
+                                         (len as u64 >> 32) as u32, len as u32, wide(name).as_ptr());
+        if mapping == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let view = MapViewOfFile(mapping, FILE_MAP_WRITE, 0, 0, len);
+        if view.is_null() {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        Ok(view.cast())
+    }
+}
+
+/// Make BUILTIN\Administrators the owner of directory `dir`, and
+/// replace its DACL, and that of what is in it, with a protected one
+/// giving full control to SYSTEM and administrators only. The DACL
+/// inherited from `%ProgramData%` lets any user add files, which the
+/// service would then trust; and the owner of a directory a user made
+/// there first could give them access back. Fails, rather than use such
+/// a directory, if either cannot be set.
+pub fn restrict_dir(dir: &std::path::Path) -> Result<()> {
+    use std::ffi::c_void;
+
+    const SE_FILE_OBJECT: u32 = 1;
+    const OWNER_SECURITY_INFORMATION: u32 = 0x0000_0001;
+    const DACL_SECURITY_INFORMATION: u32 = 0x0000_0004;
+    const PROTECTED_DACL_SECURITY_INFORMATION: u32 = 0x8000_0000;
+    /// Owned by administrators; protected, inherited by files and
+    /// subdirectories
+    const SDDL: &str = "O:BAD:P(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)";
+
+    #[link(name = "advapi32")]
+    unsafe extern "system" {
+        fn GetSecurityDescriptorOwner(descriptor: *mut c_void, owner: *mut *mut c_void,
+                                      defaulted: *mut i32) -> i32;
+        fn GetSecurityDescriptorDacl(descriptor: *mut c_void, present: *mut i32,
+                                     dacl: *mut *mut c_void, defaulted: *mut i32) -> i32;
+        fn SetNamedSecurityInfoW(name: *const u16, ty: u32, info: u32, owner: *mut c_void,
+                                 group: *mut c_void, dacl: *mut c_void, sacl: *mut c_void) -> u32;
+    }
+
+    let descriptor = security_descriptor(SDDL)?;
+    let (mut present, mut defaulted) = (0, 0);
+    let mut owner = std::ptr::null_mut();
+    let mut dacl = std::ptr::null_mut();
+
+    // SAFETY: descriptor is valid; name is NUL-terminated
+    unsafe {
+        if GetSecurityDescriptorOwner(descriptor, &mut owner, &mut defaulted) == 0
+            || GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted) == 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        let err = SetNamedSecurityInfoW(wide(&dir.to_string_lossy()).as_ptr(), SE_FILE_OBJECT,
+                                        OWNER_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION
+                                        | PROTECTED_DACL_SECURITY_INFORMATION,
+                                        owner, std::ptr::null_mut(), dacl,
+                                        std::ptr::null_mut());
+        if err != 0 {
+            return Err(std::io::Error::from_raw_os_error(err as i32).into());
+        }
+    }
+    Ok(())
+}
+
 pub fn local_time() -> (i32, u8, u8, u8, u8, u8) {
     use std::mem::zeroed;
     #[repr(C)]
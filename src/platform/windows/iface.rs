@@ -0,0 +1,103 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--iface`: adapters to WinDivert interface indexes
+//!
+//! WinDivert filters see only the interface index (`ifIdx`) of a
+//! packet, while users know their adapters by the friendly name shown
+//! in the network settings, or by GUID. Both are resolved here with
+//! `GetAdaptersAddresses()`.
+
+use anyhow::{Result, anyhow};
+use windows::Win32::NetworkManagement::IpHelper::*;
+use windows::Win32::Networking::WinSock::AF_UNSPEC;
+
+use crate::opt;
+
+/// `ERROR_BUFFER_OVERFLOW`; the required size has been stored.
+const ERROR_BUFFER_OVERFLOW: u32 = 111;
+
+struct Adapter {
+    index: u32,
+    guid: String,
+    name: String,
+    description: String,
+}
+
+impl Adapter {
+    /// Whether `--iface` value `s` refers to this adapter
+    fn matches(&self, s: &str) -> bool {
+        let guid = s.trim_start_matches('{').trim_end_matches('}');
+
+        s.parse::<u32>().is_ok_and(|i| i == self.index)
+            || self.name.eq_ignore_ascii_case(s)
+            || self.guid.trim_start_matches('{').trim_end_matches('}').eq_ignore_ascii_case(guid)
+    }
+}
+
+fn adapters() -> Result<Vec<Adapter>> {
+    let flags = GAA_FLAG_SKIP_UNICAST | GAA_FLAG_SKIP_ANYCAST
+        | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER;
+    let mut size: u32 = 16 * 1024;
+
+    loop {
+        // u64, for the alignment of IP_ADAPTER_ADDRESSES_LH
+        let mut buf = vec![0u64; (size as usize).div_ceil(8)];
+        let head = buf.as_mut_ptr().cast::<IP_ADAPTER_ADDRESSES_LH>();
+
+        // SAFETY: head is valid for writes of size bytes
+        let ret = unsafe {
+            GetAdaptersAddresses(AF_UNSPEC.0 as u32, flags, None, Some(head), &mut size)
+        };
+        match ret {
+            0 => {}
+            ERROR_BUFFER_OVERFLOW => continue,
+            e => return Err(anyhow!("GetAdaptersAddresses: {}",
+                                    std::io::Error::from_raw_os_error(e as i32))),
+        }
+
+        let mut list = Vec::new();
+        let mut p = head.cast_const();
+        while !p.is_null() {
+            // SAFETY: a list filled by GetAdaptersAddresses() in buf,
+            // which outlives it
+            let a = unsafe { &*p };
+            let (index, v6_index) = (unsafe { a.Anonymous1.Anonymous.IfIndex }, a.Ipv6IfIndex);
+
+            list.push(Adapter {
+                index: if index != 0 { index } else { v6_index },
+                guid: unsafe { a.AdapterName.to_string() }.unwrap_or_default(),
+                name: unsafe { a.FriendlyName.to_string() }.unwrap_or_default(),
+                description: unsafe { a.Description.to_string() }.unwrap_or_default(),
+            });
+            p = a.Next;
+        }
+
+        return Ok(list);
+    }
+}
+
+/// Filter clause restricting packets to `--iface` adapters, or None if
+/// not given.
+pub fn filter() -> Result<Option<String>> {
+    if opt::iface().is_empty() {
+        return Ok(None);
+    }
+
+    let adapters = adapters()?;
+    let mut indexes = Vec::new();
+
+    for s in opt::iface().iter() {
+        let Some(a) = adapters.iter().find(|a| a.matches(s)) else {
+            for a in &adapters {
+                crate::info!("iface: {} \"{}\" {} ({})", a.index, a.name, a.guid, a.description);
+            }
+            return Err(anyhow!("--iface: no adapter named {s}"));
+        };
+
+        crate::info!("iface: {s} -> ifIdx {} (\"{}\", {})", a.index, a.name, a.description);
+        indexes.push(format!("ifIdx == {}", a.index));
+    }
+
+    Ok(Some(format!("({})", indexes.join(" or "))))
+}
@@ -0,0 +1,84 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Another WinDivert program taking our packets
+//!
+//! WinDivert hands a packet to the handles whose filter matches it in
+//! order of priority, highest first. A handle that does not reinject it
+//! (a VPN client tunneling it, say) hides it from those below, and
+//! DPIBreak would silently do nothing. To tell, a sniff handle with our
+//! filter at [`PRIORITY`] counts the packets that match it, and they are
+//! compared with those our handle receives (see
+//! `--windivert-priority`).
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::opt;
+
+/// Priority of the sniff handle: `WINDIVERT_PRIORITY_HIGHEST`
+pub const PRIORITY: i16 = 30000;
+
+/// Packets are compared over windows this long.
+const WINDOW: Duration = Duration::from_secs(10);
+
+/// Matching packets in a window, none of which reached us, to warn
+const MIN_SEEN: u64 = 5;
+
+/// Packets received by our handle
+static RECEIVED: AtomicU64 = AtomicU64::new(0);
+
+struct Window {
+    start: Instant,
+    seen: u64,
+
+    /// [`RECEIVED`] at `start`
+    received: u64,
+
+    /// Whether the packets are being taken, as last warned
+    taken: bool,
+}
+
+static WINDOW_STATE: Mutex<Option<Window>> = Mutex::new(None);
+
+/// Whether to watch; not if we are at the highest priority already,
+/// where the order against the sniff handle is undefined.
+pub fn enabled() -> bool {
+    opt::windivert_priority() < PRIORITY
+}
+
+/// Count a packet received by our handle.
+pub fn received() {
+    RECEIVED.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Count a packet seen by the sniff handle, and at the end of a window,
+/// warn if none of those reached us.
+pub fn seen() {
+    let now = Instant::now();
+    let received = RECEIVED.load(Ordering::Relaxed);
+    let mut state = WINDOW_STATE.lock().unwrap_or_else(PoisonError::into_inner);
+    let w = state.get_or_insert(Window { start: now, seen: 0, received, taken: false });
+
+    w.seen += 1;
+    if now.duration_since(w.start) < WINDOW {
+        return;
+    }
+
+    let got = received - w.received;
+    if got == 0 && w.seen >= MIN_SEEN && !w.taken {
+        crate::warn!("windivert: {} packets matched our filter in the last {}s, but none \
+                      reached DPIBreak; another WinDivert program at a higher priority \
+                      than ours ({}) takes them. Raise --windivert-priority to go first.",
+                     w.seen, WINDOW.as_secs(), opt::windivert_priority());
+        w.taken = true;
+    } else if got > 0 && w.taken {
+        crate::info!("windivert: packets reach DPIBreak again");
+        w.taken = false;
+    }
+
+    w.start = now;
+    w.seen = 0;
+    w.received = received;
+}
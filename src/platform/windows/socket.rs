@@ -0,0 +1,95 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--socket-layer`: connection events from a WinDivert SOCKET handle
+//!
+//! The handle is sniff-only and carries no packet data; it tells which
+//! process connects to which server and when the connection is closed,
+//! and [`crate::pkt::socktab`] matches that against the ClientHellos
+//! seen by the NETWORK handle.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use anyhow::{Context, Result};
+use windivert::{WinDivert, layer::SocketLayer, prelude};
+use windivert::prelude::{WinDivertError, WinDivertEvent, WinDivertRecvError, WinDivertShutdownMode};
+
+use crate::opt;
+use crate::pkt::socktab;
+
+static HANDLE: Mutex<Option<Arc<WinDivert<SocketLayer>>>> = Mutex::new(None);
+
+/// Connects and closes of TCP sockets to the ports we handle
+fn filter() -> String {
+    let ports = std::iter::once(443)
+        .chain(opt::proxy_ports().ports().iter().copied().filter(|&p| p != 443))
+        .map(|p| format!("remotePort == {p}"))
+        .collect::<Vec<_>>()
+        .join(" or ");
+
+    format!("tcp and !loopback and (event == CONNECT or event == CLOSE) and ({ports})")
+}
+
+fn recv_events(handle: &WinDivert<SocketLayer>) {
+    loop {
+        let ev = match handle.recv(None) {
+            Ok(ev) => ev,
+            Err(WinDivertError::Recv(WinDivertRecvError::NoData)) => {
+                crate::info!("windivert: socket recv shutdown");
+                break;
+            }
+            Err(e) => {
+                crate::warn!("windivert: socket recv: {e}");
+                continue;
+            }
+        };
+
+        let a = &ev.address;
+        let local = SocketAddr::new(a.local_address(), a.local_port());
+        let remote = SocketAddr::new(a.remote_address(), a.remote_port());
+
+        match a.event() {
+            WinDivertEvent::SocketConnect => socktab::connect(local, remote, a.process_id()),
+            WinDivertEvent::SocketClose => socktab::close(local, remote),
+            _ => {}
+        }
+    }
+}
+
+/// Open the SOCKET handle and start receiving its events.
+pub fn spawn() -> Result<JoinHandle<()>> {
+    let filter = filter();
+    let handle = WinDivert::socket(&filter, opt::windivert_priority(), prelude::WinDivertFlags::new().set_sniff())
+        .with_context(|| format!("windivert: cannot open socket filter {filter}"))?;
+    crate::info!("windivert: open socket filter {filter}");
+
+    let handle = Arc::new(handle);
+    *HANDLE.lock().expect("mutex poisoned") = Some(handle.clone());
+
+    thread::Builder::new()
+        .name("dpibreak-socket".into())
+        .spawn(move || recv_events(&handle))
+        .context("failed to spawn socket thread")
+}
+
+/// Make the receiving thread return.
+pub fn shutdown() {
+    if let Some(h) = HANDLE.lock().expect("mutex poisoned").as_ref()
+        && let Err(e) = h.shutdown(WinDivertShutdownMode::Both) {
+        crate::warn!("windivert: socket shutdown: {e}");
+    }
+}
+
+/// Close the handle once the receiving thread has returned.
+pub fn close() {
+    let Some(h) = HANDLE.lock().expect("mutex poisoned").take() else { return };
+
+    match Arc::try_unwrap(h) {
+        Ok(mut wd) => {
+            _ = wd.close(windivert::CloseAction::Nothing);
+        }
+        Err(_still_shared) => crate::warn!("windivert: socket handle still referenced, skipping close"),
+    }
+}
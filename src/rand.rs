@@ -0,0 +1,50 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Non-cryptographic pseudo random numbers
+//!
+//! Used to vary timings and sizes of what we send so that they are not
+//! a fixed signature; nothing here needs to be unpredictable to an
+//! attacker. xorshift64* seeded from std's per-process random hasher
+//! keys, to avoid an extra dependency.
+
+use std::cell::Cell;
+use std::hash::{BuildHasher, RandomState};
+
+thread_local! {
+    static STATE: Cell<u64> = Cell::new(seed());
+}
+
+fn seed() -> u64 {
+    // RandomState keys are taken from the OS on first use per thread.
+    RandomState::new().hash_one(std::time::Instant::now()) | 1
+}
+
+/// Restart the numbers of this thread from `seed`, so that a run can be
+/// repeated (`--replay`).
+pub fn reseed(seed: u64) {
+    STATE.with(|s| s.set(seed | 1));
+}
+
+pub fn u64() -> u64 {
+    STATE.with(|s| {
+        let mut x = s.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        s.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// Uniform value in `lo..=hi`. Return `lo` if `hi < lo`.
+pub fn range(lo: u64, hi: u64) -> u64 {
+    if hi <= lo {
+        return lo;
+    }
+
+    match (hi - lo).checked_add(1) {
+        Some(n) => lo + u64() % n,
+        None => u64(),          // full range
+    }
+}
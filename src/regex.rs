@@ -0,0 +1,426 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Regular expressions for `--hostlist-regex`, without an extra
+//! dependency
+//!
+//! The usual syntax of hostlist patterns: literals, `.`, classes
+//! (`[a-z0-9-]`, `[^.]`, `\d`, `\w`, `\s`), anchors `^` and `$`, groups
+//! with `|`, and the quantifiers `*`, `+`, `?` and `{m,n}`. No
+//! backreferences or lookaround. A pattern is compiled once and run as
+//! a Pike VM, in time linear in the name, whatever the pattern; there
+//! is no backtracking for a crafted name to blow up.
+
+use anyhow::{Result, anyhow};
+
+/// Instructions a compiled pattern may take, to bound memory and time
+const MAX_PROGRAM: usize = 4096;
+
+/// Largest count of `{m,n}`
+const MAX_REPEAT: u32 = 255;
+
+/// Deepest nesting of groups, to bound the recursion of the parser
+const MAX_DEPTH: usize = 32;
+
+#[derive(Clone)]
+enum Node {
+    Byte(u8),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Concat(Vec<Node>),
+    Alt(Vec<Node>),
+    Repeat { node: Box<Node>, min: u32, max: Option<u32> },
+}
+
+#[derive(Clone)]
+struct Class {
+    ranges: Vec<(u8, u8)>,
+    negated: bool,
+}
+
+impl Class {
+    fn of(ranges: &[(u8, u8)], negated: bool) -> Self {
+        Self { ranges: ranges.to_vec(), negated }
+    }
+
+    fn matches(&self, b: u8) -> bool {
+        self.ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&b)) != self.negated
+    }
+}
+
+const DIGIT: &[(u8, u8)] = &[(b'0', b'9')];
+const WORD: &[(u8, u8)] = &[(b'0', b'9'), (b'A', b'Z'), (b'_', b'_'), (b'a', b'z')];
+const SPACE: &[(u8, u8)] = &[(b'\t', b'\r'), (b' ', b' ')];
+
+struct Parser<'a> {
+    s: &'a [u8],
+    pos: usize,
+
+    /// Groups open at `pos`
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.s.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<u8> {
+        let b = self.peek().ok_or_else(|| anyhow!("unexpected end of pattern"))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn eat(&mut self, b: u8) -> bool {
+        let ate = self.peek() == Some(b);
+        if ate {
+            self.pos += 1;
+        }
+        ate
+    }
+
+    fn alt(&mut self) -> Result<Node> {
+        let mut alts = vec![self.concat()?];
+        while self.eat(b'|') {
+            alts.push(self.concat()?);
+        }
+        Ok(if alts.len() == 1 { alts.pop().unwrap() } else { Node::Alt(alts) })
+    }
+
+    fn concat(&mut self) -> Result<Node> {
+        let mut nodes = Vec::new();
+        while let Some(b) = self.peek() && b != b'|' && b != b')' {
+            nodes.push(self.repeat()?);
+        }
+        Ok(Node::Concat(nodes))
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.s[start..self.pos]).ok()?.parse().ok()
+    }
+
+    fn repeat(&mut self) -> Result<Node> {
+        let mut node = self.atom()?;
+
+        loop {
+            let (min, max) = match self.peek() {
+                Some(b'*') => (0, None),
+                Some(b'+') => (1, None),
+                Some(b'?') => (0, Some(1)),
+                Some(b'{') => {
+                    self.pos += 1;
+                    let min = self.number().ok_or_else(|| anyhow!("invalid repetition"))?;
+                    let max = if self.eat(b',') { self.number() } else { Some(min) };
+                    if self.peek() != Some(b'}') || max.is_some_and(|m| m < min)
+                        || min.max(max.unwrap_or(0)) > MAX_REPEAT {
+                        return Err(anyhow!("invalid repetition"));
+                    }
+                    (min, max)
+                }
+                _ => return Ok(node),
+            };
+            self.pos += 1;
+            self.eat(b'?');     // lazy or not, the match is the same
+            node = Node::Repeat { node: Box::new(node), min, max };
+        }
+    }
+
+    /// Class of escape `\b`, or None if `b` stands for itself
+    fn escape_class(b: u8) -> Option<Class> {
+        match b {
+            b'd' | b'D' => Some(Class::of(DIGIT, b == b'D')),
+            b'w' | b'W' => Some(Class::of(WORD, b == b'W')),
+            b's' | b'S' => Some(Class::of(SPACE, b == b'S')),
+            _ => None,
+        }
+    }
+
+    fn atom(&mut self) -> Result<Node> {
+        match self.next()? {
+            b'(' => {
+                if self.eat(b'?') && !self.eat(b':') {
+                    return Err(anyhow!("unsupported group"));
+                }
+                if self.depth == MAX_DEPTH {
+                    return Err(anyhow!("groups nested deeper than {MAX_DEPTH}"));
+                }
+                self.depth += 1;
+                let node = self.alt()?;
+                if !self.eat(b')') {
+                    return Err(anyhow!("missing )"));
+                }
+                self.depth -= 1;
+                Ok(node)
+            }
+            b'[' => self.class(),
+            b'.' => Ok(Node::Any),
+            b'^' => Ok(Node::Start),
+            b'$' => Ok(Node::End),
+            b'\\' => {
+                let b = self.next()?;
+                Ok(Self::escape_class(b).map_or(Node::Byte(b), Node::Class))
+            }
+            b'*' | b'+' | b'?' | b'{' => Err(anyhow!("nothing to repeat")),
+            b => Ok(Node::Byte(b.to_ascii_lowercase())),
+        }
+    }
+
+    fn class(&mut self) -> Result<Node> {
+        let negated = self.eat(b'^');
+        let mut ranges = Vec::new();
+        let mut first = true;
+
+        loop {
+            let b = self.next().map_err(|_| anyhow!("missing ]"))?;
+            if b == b']' && !first {
+                break;
+            }
+            first = false;
+
+            let lo = if b == b'\\' {
+                let e = self.next()?;
+                if let Some(c) = Self::escape_class(e) && !c.negated {
+                    ranges.extend(c.ranges);
+                    continue;
+                }
+                e
+            } else {
+                b
+            };
+
+            if self.peek() == Some(b'-') && self.s.get(self.pos + 1).is_some_and(|&b| b != b']') {
+                self.pos += 1;
+                let hi = match self.next()? {
+                    b'\\' => self.next()?,
+                    hi => hi,
+                };
+                if hi < lo {
+                    return Err(anyhow!("invalid class range"));
+                }
+                ranges.push((lo, hi));
+            } else {
+                ranges.push((lo, lo));
+            }
+        }
+
+        Ok(Node::Class(Class { ranges, negated }))
+    }
+}
+
+enum Inst {
+    Byte(u8),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    Split(usize, usize),
+    Jmp(usize),
+    Match,
+}
+
+struct Compiler {
+    prog: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize> {
+        if self.prog.len() >= MAX_PROGRAM {
+            return Err(anyhow!("pattern too large"));
+        }
+        self.prog.push(inst);
+        Ok(self.prog.len() - 1)
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<()> {
+        match node {
+            Node::Byte(b) => { self.push(Inst::Byte(*b))?; }
+            Node::Any => { self.push(Inst::Any)?; }
+            Node::Class(c) => { self.push(Inst::Class(c.clone()))?; }
+            Node::Start => { self.push(Inst::Start)?; }
+            Node::End => { self.push(Inst::End)?; }
+            Node::Concat(nodes) => {
+                for n in nodes {
+                    self.compile(n)?;
+                }
+            }
+            Node::Alt(alts) => {
+                let mut jumps = Vec::new();
+                for (i, alt) in alts.iter().enumerate() {
+                    if i + 1 == alts.len() {
+                        self.compile(alt)?;
+                        break;
+                    }
+                    let split = self.push(Inst::Split(0, 0))?;
+                    self.compile(alt)?;
+                    jumps.push(self.push(Inst::Jmp(0))?);
+                    self.prog[split] = Inst::Split(split + 1, self.prog.len());
+                }
+                let end = self.prog.len();
+                for j in jumps {
+                    self.prog[j] = Inst::Jmp(end);
+                }
+            }
+            Node::Repeat { node, min, max } => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.push(Inst::Split(0, 0))?;
+                        self.compile(node)?;
+                        self.push(Inst::Jmp(split))?;
+                        self.prog[split] = Inst::Split(split + 1, self.prog.len());
+                    }
+                    Some(max) => {
+                        let mut splits = Vec::new();
+                        for _ in *min..*max {
+                            splits.push(self.push(Inst::Split(0, 0))?);
+                            self.compile(node)?;
+                        }
+                        let end = self.prog.len();
+                        for s in splits {
+                            self.prog[s] = Inst::Split(s + 1, end);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct Regex {
+    prog: Vec<Inst>,
+}
+
+/// Threads of the VM at one position of the input, without duplicates
+struct Threads {
+    pcs: Vec<usize>,
+    on: Vec<bool>,
+}
+
+impl Threads {
+    fn new(n: usize) -> Self {
+        Self { pcs: Vec::with_capacity(n), on: vec![false; n] }
+    }
+
+    fn clear(&mut self) {
+        for &pc in &self.pcs {
+            self.on[pc] = false;
+        }
+        self.pcs.clear();
+    }
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut parser = Parser { s: pattern.as_bytes(), pos: 0, depth: 0 };
+        let node = parser.alt()?;
+        if parser.pos != pattern.len() {
+            return Err(anyhow!("unmatched )"));
+        }
+
+        let mut c = Compiler { prog: Vec::new() };
+        c.compile(&node)?;
+        c.push(Inst::Match)?;
+        Ok(Self { prog: c.prog })
+    }
+
+    /// Add thread `pc` at position `pos` of `s` to `list`, following
+    /// the instructions that consume nothing.
+    fn add(&self, list: &mut Threads, pc: usize, pos: usize, s: &[u8], stack: &mut Vec<usize>) {
+        stack.push(pc);
+        while let Some(pc) = stack.pop() {
+            if list.on[pc] {
+                continue;
+            }
+            list.on[pc] = true;
+            list.pcs.push(pc);
+
+            match self.prog[pc] {
+                Inst::Jmp(to) => stack.push(to),
+                Inst::Split(a, b) => {
+                    stack.push(b);
+                    stack.push(a);
+                }
+                Inst::Start if pos == 0 => stack.push(pc + 1),
+                Inst::End if pos == s.len() => stack.push(pc + 1),
+                _ => {}
+            }
+        }
+    }
+
+    /// Whether the pattern matches somewhere in `s`
+    pub fn is_match(&self, s: &[u8]) -> bool {
+        let n = self.prog.len();
+        let (mut clist, mut nlist) = (Threads::new(n), Threads::new(n));
+        let mut stack = Vec::new();
+
+        for pos in 0..=s.len() {
+            self.add(&mut clist, 0, pos, s, &mut stack);
+            nlist.clear();
+
+            for i in 0..clist.pcs.len() {
+                let pc = clist.pcs[i];
+                let step = match &self.prog[pc] {
+                    Inst::Match => return true,
+                    Inst::Byte(b) => s.get(pos) == Some(b),
+                    Inst::Any => pos < s.len(),
+                    Inst::Class(c) => s.get(pos).is_some_and(|&b| c.matches(b)),
+                    _ => false,
+                };
+                if step {
+                    self.add(&mut nlist, pc + 1, pos + 1, s, &mut stack);
+                }
+            }
+
+            std::mem::swap(&mut clist, &mut nlist);
+        }
+
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn m(pattern: &str, s: &str) -> bool {
+        Regex::new(pattern).unwrap().is_match(s.as_bytes())
+    }
+
+    #[test]
+    fn test_regex() {
+        assert!(m(r"^(www\.)?example\.com$", "example.com"));
+        assert!(m(r"^(www\.)?example\.com$", "www.example.com"));
+        assert!(!m(r"^(www\.)?example\.com$", "wwwxexample.com"));
+        assert!(!m(r"^(www\.)?example\.com$", "a.example.com"));
+        assert!(m(r"(^|\.)googlevideo\.com$", "rr3---sn-abc.googlevideo.com"));
+        assert!(!m(r"(^|\.)googlevideo\.com$", "notgooglevideo.com"));
+        assert!(m(r"^r\d+---sn-[a-z0-9]{4,8}\.", "r12---sn-ab12cd.x"));
+        assert!(!m(r"^r\d+---sn-[a-z0-9]{4,8}\.", "r---sn-ab12cd.x"));
+        assert!(m(r"^[^.]+\.example\.(com|org)$", "cdn.example.org"));
+        assert!(!m(r"^[^.]+\.example\.(com|org)$", "a.cdn.example.org"));
+        assert!(m("Example", "example"));
+        assert!(m("a*", ""));
+        assert!(m("(a|)+b", "aaab"));
+
+        // Linear, not exponential
+        assert!(!m("^(a*)*$", &format!("{}b", "a".repeat(64))));
+
+        for bad in ["(", "a)", "[a", "*a", "a{2,1}", "a{999}", "(?=a)", "[z-a]"] {
+            assert!(Regex::new(bad).is_err(), "{bad}");
+        }
+
+        let nested = |n| format!("{}a{}", "(".repeat(n), ")".repeat(n));
+        assert!(m(&nested(MAX_DEPTH), "a"));
+        assert!(Regex::new(&nested(MAX_DEPTH + 1)).is_err());
+        assert!(Regex::new(&nested(100_000)).is_err());
+    }
+}
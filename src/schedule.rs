@@ -0,0 +1,71 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Pausing and resuming, by hand or on `--schedule`
+//!
+//! While paused, every packet reaching us is accepted unchanged; rules
+//! stay installed, so that resuming takes effect on the next packet.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::opt;
+
+/// How often the schedule is looked at
+const CHECK_INTERVAL: Duration = Duration::from_secs(20);
+
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+pub fn paused() -> bool {
+    PAUSED.load(Ordering::Relaxed)
+}
+
+/// Pause or resume; `why` is logged if it changes anything.
+pub fn set_paused(paused: bool, why: &str) {
+    if PAUSED.swap(paused, Ordering::Relaxed) != paused {
+        crate::info!("{} ({why})", if paused { "paused" } else { "resumed" });
+    }
+}
+
+/// Day of the week of a date, 0 for Monday
+fn weekday(year: i32, month: u8, day: u8) -> u8 {
+    const T: [i32; 12] = [0, 3, 2, 5, 0, 3, 5, 1, 4, 6, 2, 4];
+
+    let y = if month < 3 { year - 1 } else { year };
+    let sunday_based = (y + y / 4 - y / 100 + y / 400
+                        + T[usize::from(month.clamp(1, 12)) - 1] + i32::from(day)).rem_euclid(7);
+    ((sunday_based + 6) % 7) as u8
+}
+
+fn apply_schedule() {
+    let (y, mo, d, h, mi, _) = crate::platform::local_time();
+    let open = opt::schedule().contains(weekday(y, mo, d), u16::from(h) * 60 + u16::from(mi));
+    set_paused(!open, "schedule");
+}
+
+/// Follow `--schedule` on a background thread, if given. The current
+/// window is applied before returning.
+pub fn spawn() {
+    if opt::schedule().is_empty() {
+        return;
+    }
+
+    apply_schedule();
+    std::thread::spawn(|| loop {
+        std::thread::sleep(CHECK_INTERVAL);
+        apply_schedule();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_weekday() {
+        assert_eq!(weekday(2024, 1, 1), 0);     // Monday
+        assert_eq!(weekday(2024, 2, 29), 3);    // leap day, Thursday
+        assert_eq!(weekday(2026, 10, 16), 4);   // Friday
+        assert_eq!(weekday(2000, 3, 5), 6);     // Sunday
+    }
+}
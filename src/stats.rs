@@ -0,0 +1,437 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! Runtime counters and timings
+//!
+//! Everything here is lock-free and updated from the packet path with
+//! relaxed atomics; values are only meant to be read for reporting
+//! (see [`report`]), so no ordering between counters is guaranteed.
+
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+pub mod shm;
+
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    #[inline]
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    #[inline]
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+macro_rules! counters {
+    ($($(#[$meta:meta])* $name:ident),* $(,)?) => {
+        pub struct Counters {
+            $($(#[$meta])* pub $name: Counter,)*
+        }
+
+        pub static COUNTERS: Counters = Counters {
+            $($name: Counter::new(),)*
+        };
+
+        impl Counters {
+            pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+                vec![$((stringify!($name), self.$name.get()),)*]
+            }
+        }
+    };
+}
+
+counters! {
+    /// ClientHello handled (original dropped and desynced)
+    handled,
+
+    /// Packet without TCP payload, accepted without parsing
+    empty_payload,
+
+    /// Packet with a TCP payload shorter than `--min-payload`, accepted
+    /// without parsing
+    short_payload,
+
+    /// Packet other than a ClientHello, queued as ClientHellos cannot be
+    /// matched in kernel, and accepted; `--bulk-bytes` keeps the bulk of
+    /// long connections off the queue
+    unfiltered_passed,
+
+    /// Bytes of those
+    unfiltered_bytes,
+
+    /// Packet to port 443 opening HTTP/2 with prior knowledge, accepted
+    /// unchanged
+    non_tls_http2,
+
+    /// Packet to port 443 with an HTTP/1 request, accepted unchanged
+    non_tls_http,
+
+    /// Packet matched by the ClientHello prefilter that is neither TLS
+    /// nor HTTP, accepted unchanged
+    non_tls_other,
+
+    /// TLS record other than a ClientHello that the prefilter matched,
+    /// accepted unchanged
+    tls_not_hello,
+
+    /// ClientHello whose split positions went past its payload, and were
+    /// moved to its end
+    split_clamped,
+
+    /// Packet whose handling panicked, accepted unchanged
+    panics,
+
+    /// ClientHello passed unchanged, or DNS query not recorded, because
+    /// `--max-buffer-mb` was exhausted
+    budget_passed,
+
+    /// Flow, host or DNS query entry evicted early to stay within
+    /// `--max-buffer-mb`
+    budget_evicted,
+
+    /// Job run on the receive thread because the worker queue was full
+    worker_overflow,
+
+    /// SYN/ACK not learned from, to keep up with the ClientHellos queued
+    /// meanwhile
+    synack_shed,
+
+    /// Packets the kernel dropped as our queue was full (Linux)
+    queue_full_dropped,
+
+    /// Packets the kernel dropped as our socket buffer was full (Linux)
+    queue_user_dropped,
+
+    /// ClientHello retransmitted while we were still handling it, dropped
+    retrans_suppressed,
+
+    /// ClientHello retransmitted after we had handled it, accepted unchanged
+    retrans_passed,
+
+    /// ClientHello to a host handled recently (`--once-per-host`), accepted
+    /// unchanged
+    host_skipped,
+
+    /// ClientHello to a server name not in `--hostlist`, accepted unchanged
+    host_unlisted,
+
+    /// ClientHello without a server name (e.g. resuming a session)
+    /// classified by the one last seen to its address
+    sni_bound,
+
+    /// IPv6 address of a `--hostlist` server degraded (`--v6-degrade`)
+    v6_degraded,
+
+    /// IPv6 SYN to a degraded address dropped, for the client to fall
+    /// back to IPv4
+    v6_syn_dropped,
+
+    /// ClientHello of a client with `--client-profile` off that got past
+    /// the kernel rules, accepted unchanged
+    client_off,
+
+    /// ClientHello accepted unchanged as `--verdict-hook` said
+    hook_passed,
+
+    /// ClientHello `--verdict-hook` did not answer about in time, or
+    /// could not be asked about; handled as the options say
+    hook_failed,
+
+    /// SSLv2-compatible ClientHello accepted unchanged (`--pass-sslv2`)
+    sslv2_passed,
+
+    /// ClientHello passed unchanged to probe its server (`--adaptive-exempt`)
+    adaptive_probes,
+
+    /// ClientHello to a server exempted by `--adaptive-exempt`, accepted
+    /// unchanged
+    adaptive_exempted,
+
+    /// Probe or exempted ClientHello that did not get through
+    adaptive_failed,
+
+    /// ClientHello on a SYN (TCP Fast Open) sent as a plain SYN, for the
+    /// client to send it again after the handshake
+    tfo_stripped,
+
+    /// ClientHello to a host over `--burst-limit`, accepted unchanged
+    burst_passed,
+
+    /// ClientHello split without fakes, its destination being over
+    /// `--fake-rate`
+    fake_limited,
+
+    /// Inbound RST/FIN dropped as forged by a middlebox
+    forged_dropped,
+
+    /// Path MTU to a server learned from ICMP quoting a handled flow
+    /// (`--watch-pmtu`)
+    pmtu_learned,
+
+    /// ClientHello cut into more segments to fit the path MTU learned
+    pmtu_cut,
+
+    /// DNS response dropped as forged (`--dns-guard`)
+    dns_forged,
+
+    /// DNS query forwarded to DoH resolver (`--doh-forward`)
+    doh_queries,
+
+    /// DoH query failed or dropped while busy; no answer sent to the
+    /// client
+    doh_failed,
+
+    /// Event POSTed to `--webhook`
+    webhook_sent,
+
+    /// Event not POSTed to `--webhook`: queue full or POST failed
+    webhook_failed,
+
+    /// Handled connection closed soon after connect (`--socket-layer`)
+    sock_closed_early,
+
+    /// Handled connection that lived longer (`--socket-layer`)
+    sock_completed,
+}
+
+/// Number of sub-buckets per power of two; relative error of recorded
+/// values is bounded by 1/2^[`SUB_BITS`] (~6%).
+const SUB_BITS: u32 = 4;
+const SUB_MASK: u64 = (1 << SUB_BITS) - 1;
+const N_BUCKETS: usize = (64 - SUB_BITS as usize + 1) << SUB_BITS;
+
+/// Log-linear (HDR-style) histogram of u64 values.
+///
+/// Values below 2^[`SUB_BITS`] have their own bucket; above that each
+/// power of two range is split into 2^[`SUB_BITS`] equal sub-buckets.
+pub struct Histogram {
+    buckets: [AtomicU64; N_BUCKETS],
+    count: AtomicU64,
+    max: AtomicU64,
+}
+
+#[inline]
+fn bucket_index(v: u64) -> usize {
+    if v <= SUB_MASK {
+        return v as usize;
+    }
+
+    let msb = 63 - v.leading_zeros();
+    let shift = msb - SUB_BITS;
+    let sub = (v >> shift) & SUB_MASK;
+
+    (((shift + 1) as usize) << SUB_BITS) | sub as usize
+}
+
+/// Smallest value that falls into bucket `idx`
+#[inline]
+fn bucket_lower(idx: usize) -> u64 {
+    let exp = idx >> SUB_BITS;
+    let sub = (idx as u64) & SUB_MASK;
+
+    if exp == 0 {
+        sub
+    } else {
+        ((1 << SUB_BITS) | sub) << (exp - 1)
+    }
+}
+
+impl Histogram {
+    pub const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; N_BUCKETS],
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, v: u64) {
+        self.buckets[bucket_index(v)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(v, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Upper bound of the value at quantile `q` (0.0..=1.0), or None if
+    /// nothing has been recorded.
+    pub fn quantile(&self, q: f64) -> Option<u64> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+
+        for (idx, b) in self.buckets.iter().enumerate() {
+            seen += b.load(Ordering::Relaxed);
+            if seen >= rank {
+                let upper = if idx + 1 < N_BUCKETS { bucket_lower(idx + 1) - 1 } else { u64::MAX };
+                return Some(upper.min(self.max()));
+            }
+        }
+
+        Some(self.max())
+    }
+}
+
+/// Time from receiving a ClientHello to sending its last segment, in
+/// microseconds.
+pub static LATENCY_US: Histogram = Histogram::new();
+
+pub fn record_latency(d: Duration) {
+    LATENCY_US.record(d.as_micros().try_into().unwrap_or(u64::MAX));
+}
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+
+/// Start counting [`uptime`]; later calls do nothing.
+pub fn start() {
+    STARTED.get_or_init(Instant::now);
+}
+
+pub fn uptime() -> Duration {
+    STARTED.get().map_or(Duration::ZERO, Instant::elapsed)
+}
+
+/// `d` as e.g. `2d 3h 4m 5s`, without leading zero units
+pub fn format_uptime(d: Duration) -> String {
+    let s = d.as_secs();
+    let units = [(s / 86400, "d"), (s / 3600 % 24, "h"), (s / 60 % 60, "m")];
+
+    let mut out = String::new();
+    for (n, unit) in units.into_iter().skip_while(|&(n, _)| n == 0) {
+        out += &format!("{n}{unit} ");
+    }
+    out + &format!("{}s", s % 60)
+}
+
+/// Every `--heartbeat` minutes, log that we are alive and what has been
+/// counted since the last beat.
+pub fn spawn_heartbeat() {
+    let minutes = crate::opt::heartbeat();
+    if minutes == 0 {
+        return;
+    }
+
+    std::thread::spawn(move || {
+        let mut last = COUNTERS.snapshot();
+        loop {
+            std::thread::sleep(Duration::from_secs(minutes * 60));
+
+            let now = COUNTERS.snapshot();
+            let mut handled = 0;
+            let mut rest = String::new();
+            for (&(k, v), &(_, prev)) in now.iter().zip(&last) {
+                match (k, v.saturating_sub(prev)) {
+                    ("handled", d) => handled = d,
+                    (_, 0) => {}
+                    (k, d) => rest += &format!(" {k}=+{d}"),
+                }
+            }
+
+            crate::info!("heartbeat: up {}; handled=+{handled}{rest}", format_uptime(uptime()));
+            last = now;
+        }
+    });
+}
+
+/// Uptime, backend and counters, one `key: value` per line
+pub fn status() -> String {
+    let mut out = format!("uptime: {}\nbackend: {}\npaused: {}\n",
+                          format_uptime(uptime()), crate::platform::backend(),
+                          if crate::schedule::paused() { "yes" } else { "no" });
+    for (k, v) in COUNTERS.snapshot() {
+        out += &format!("{k}: {v}\n");
+    }
+
+    let h = &LATENCY_US;
+    if let (Some(p50), Some(p99)) = (h.quantile(0.5), h.quantile(0.99)) {
+        out += &format!("latency_us: p50={p50} p99={p99} max={}\n", h.max());
+    }
+    out += &format!("buffer_kib: {}/{}\n",
+                    crate::pkt::budget::used() / 1024, crate::opt::max_buffer_bytes() / 1024);
+    out
+}
+
+/// Log current counters and latency percentiles with info level.
+pub fn report() {
+    let counters = COUNTERS.snapshot()
+        .iter()
+        .map(|(k, v)| format!("{k}={v}"))
+        .collect::<Vec<_>>()
+        .join(" ");
+    crate::info!("stats: uptime {}", format_uptime(uptime()));
+    crate::info!("stats: {counters}");
+
+    let h = &LATENCY_US;
+    if let (Some(p50), Some(p95), Some(p99)) = (h.quantile(0.5), h.quantile(0.95), h.quantile(0.99)) {
+        crate::info!("stats: latency(us) n={} p50={p50} p95={p95} p99={p99} max={}",
+                     h.count(), h.max());
+    }
+
+    crate::info!("stats: buffer(KiB) used={} limit={}",
+                 crate::pkt::budget::used() / 1024, crate::opt::max_buffer_bytes() / 1024);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_roundtrip() {
+        for v in (0..4096u64).chain([u64::MAX >> 1, u64::MAX]) {
+            let idx = bucket_index(v);
+            assert!(idx < N_BUCKETS);
+            assert!(bucket_lower(idx) <= v, "v={v} idx={idx}");
+            if idx + 1 < N_BUCKETS {
+                assert!(v < bucket_lower(idx + 1), "v={v} idx={idx}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_format_uptime() {
+        assert_eq!(format_uptime(Duration::from_secs(0)), "0s");
+        assert_eq!(format_uptime(Duration::from_secs(59)), "59s");
+        assert_eq!(format_uptime(Duration::from_secs(3600 + 5)), "1h 0m 5s");
+        assert_eq!(format_uptime(Duration::from_secs(2 * 86400 + 3 * 3600 + 4 * 60 + 5)), "2d 3h 4m 5s");
+    }
+
+    #[test]
+    fn test_quantile() {
+        let h = Histogram::new();
+        assert_eq!(h.quantile(0.5), None);
+
+        for v in 1..=1000 {
+            h.record(v);
+        }
+
+        let p50 = h.quantile(0.5).unwrap();
+        let p99 = h.quantile(0.99).unwrap();
+        assert!((500..=532).contains(&p50), "p50={p50}");
+        assert!((990..=1000).contains(&p99), "p99={p99}");
+        assert_eq!(h.quantile(1.0), Some(1000));
+    }
+}
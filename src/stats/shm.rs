@@ -0,0 +1,180 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--stats-shm`: counters in shared memory for external readers
+//!
+//! Router UIs and tray apps can poll it instead of asking `dpibreak
+//! stats` over the control socket each time. It is a file on Linux
+//! (put it on tmpfs, e.g. under `/run`) and a named file mapping on
+//! Windows. Layout, integers in host byte order:
+//!
+//! ```text
+//! offset  size    field
+//! 0       8       magic "DPIBSTAT"
+//! 8       4       layout version, 1
+//! 12      4       number of entries N
+//! 16      8       sequence, odd while being written
+//! 24      8       time of the last update, ms since the Unix epoch
+//! 32      8       uptime, seconds
+//! 40      40 * N  entries: name (32 bytes, ASCII padded with NUL),
+//!                 value (8 bytes)
+//! ```
+//!
+//! Entries are the counters of `dpibreak stats`, then `latency_us_p50`,
+//! `latency_us_p99`, `latency_us_max` and `buffer_bytes`; look them up
+//! by name, as entries come and go between versions. Values are
+//! refreshed every second. A reader copies them while the sequence is
+//! even, and again if it changed meanwhile. An update older than a few
+//! seconds means that DPIBreak is not running.
+
+use std::sync::atomic::{AtomicU64, Ordering, fence};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+use super::{COUNTERS, LATENCY_US};
+
+const MAGIC: &[u8; 8] = b"DPIBSTAT";
+const VERSION: u32 = 1;
+const SEQ: usize = 16;
+const UPDATED: usize = 24;
+const UPTIME: usize = 32;
+const HEADER: usize = 40;
+const NAME_LEN: usize = 32;
+const ENTRY: usize = NAME_LEN + 8;
+
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Names and values of the entries
+fn entries() -> Vec<(&'static str, u64)> {
+    let h = &LATENCY_US;
+    let mut entries = COUNTERS.snapshot();
+    entries.extend([
+        ("latency_us_p50", h.quantile(0.5).unwrap_or(0)),
+        ("latency_us_p99", h.quantile(0.99).unwrap_or(0)),
+        ("latency_us_max", h.max()),
+        ("buffer_bytes", crate::pkt::budget::used() as u64),
+    ]);
+    entries
+}
+
+/// Segment with the header and entry `names`, all values zero
+fn image(names: &[&str]) -> Vec<u8> {
+    let mut buf = vec![0; HEADER + ENTRY * names.len()];
+    buf[..8].copy_from_slice(MAGIC);
+    buf[8..12].copy_from_slice(&VERSION.to_ne_bytes());
+    buf[12..16].copy_from_slice(&(names.len() as u32).to_ne_bytes());
+    for (i, name) in names.iter().enumerate() {
+        let name = &name.as_bytes()[..name.len().min(NAME_LEN - 1)];
+        buf[HEADER + ENTRY * i..][..name.len()].copy_from_slice(name);
+    }
+    buf
+}
+
+/// Mapped segment of `len` bytes, 8-byte aligned
+struct Segment {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// SAFETY: only the thread of spawn() writes to it
+unsafe impl Send for Segment {}
+
+impl Segment {
+    fn put(&self, offset: usize, v: u64) {
+        assert!(offset.is_multiple_of(8) && offset + 8 <= self.len);
+        // SAFETY: aligned and within the mapping
+        unsafe { self.ptr.add(offset).cast::<u64>().write_volatile(v) }
+    }
+
+    fn seq(&self) -> &AtomicU64 {
+        // SAFETY: aligned and within the mapping, accessed atomically only
+        unsafe { AtomicU64::from_ptr(self.ptr.add(SEQ).cast()) }
+    }
+
+    /// Write `values` of the entries, as a seqlock writer.
+    fn update(&self, values: impl Iterator<Item = u64>, updated_ms: u64, uptime: u64) {
+        let seq = self.seq();
+        let s = seq.load(Ordering::Relaxed);
+        seq.store(s.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        self.put(UPDATED, updated_ms);
+        self.put(UPTIME, uptime);
+        for (i, v) in values.enumerate() {
+            self.put(HEADER + ENTRY * i + NAME_LEN, v);
+        }
+
+        seq.store(s.wrapping_add(2), Ordering::Release);
+    }
+}
+
+/// Map `--stats-shm` and refresh it every second, if given.
+pub fn spawn() -> Result<()> {
+    let Some(name) = crate::opt::stats_shm() else {
+        return Ok(());
+    };
+
+    let image = image(&entries().iter().map(|&(k, _)| k).collect::<Vec<_>>());
+    let ptr = crate::platform::map_shared(name, image.len())
+        .with_context(|| format!("--stats-shm: {name}"))?;
+    // SAFETY: mapped with image.len() bytes, page aligned
+    unsafe { ptr.copy_from_nonoverlapping(image.as_ptr(), image.len()) };
+    let segment = Segment { ptr, len: image.len() };
+
+    crate::info!("stats: published in {name}");
+
+    std::thread::spawn(move || loop {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        segment.update(entries().into_iter().map(|(_, v)| v), now.as_millis() as u64,
+                       super::uptime().as_secs());
+        std::thread::sleep(INTERVAL);
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Entries as a reader would take them, if not being written
+    fn read(buf: &[u8]) -> Option<Vec<(String, u64)>> {
+        let u64_at = |off: usize| u64::from_ne_bytes(buf[off..off + 8].try_into().unwrap());
+        if &buf[..8] != MAGIC || u64_at(SEQ) % 2 != 0 {
+            return None;
+        }
+
+        let n = u32::from_ne_bytes(buf[12..16].try_into().unwrap()) as usize;
+        Some((0..n).map(|i| {
+            let entry = &buf[HEADER + ENTRY * i..][..ENTRY];
+            let name = entry[..NAME_LEN].split(|&b| b == 0).next().unwrap();
+            (String::from_utf8(name.to_vec()).unwrap(), u64_at(HEADER + ENTRY * i + NAME_LEN))
+        }).collect())
+    }
+
+    #[test]
+    fn test_segment() {
+        let names: Vec<&str> = entries().iter().map(|&(k, _)| k).collect();
+        assert!(names.iter().all(|k| k.len() < NAME_LEN));
+
+        let image = image(&["handled", "buffer_bytes"]);
+        assert_eq!(image.len(), 120);
+        assert_eq!(read(&image).unwrap(), [("handled".to_string(), 0), ("buffer_bytes".to_string(), 0)]);
+
+        // u64 backing for alignment
+        let mut backing = vec![0u64; image.len() / 8];
+        let ptr = backing.as_mut_ptr().cast::<u8>();
+        let bytes = || unsafe { std::slice::from_raw_parts(ptr, image.len()) };
+        unsafe { ptr.copy_from_nonoverlapping(image.as_ptr(), image.len()) };
+
+        let segment = Segment { ptr, len: image.len() };
+        segment.update([3, 4096].into_iter(), 1_700_000_000_000, 42);
+        assert_eq!(u64::from_ne_bytes(bytes()[SEQ..SEQ + 8].try_into().unwrap()), 2);
+        assert_eq!(u64::from_ne_bytes(bytes()[UPTIME..UPTIME + 8].try_into().unwrap()), 42);
+        assert_eq!(read(bytes()).unwrap(), [("handled".to_string(), 3), ("buffer_bytes".to_string(), 4096)]);
+
+        segment.seq().store(3, Ordering::Relaxed);
+        assert_eq!(read(bytes()), None);
+    }
+}
@@ -64,23 +64,377 @@ impl<'a> TLSMsg<'a> {
     }
 }
 
-pub fn is_client_hello(payload: &[u8]) -> bool {
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Hello {
+    /// In a TLS handshake record of any legacy_record_version
+    Tls,
+
+    /// SSLv2-compatible (RFC 6101 E.2), sent by some legacy clients to
+    /// negotiate SSLv3 or TLS; it carries no extensions, hence no SNI.
+    SslV2,
+}
+
+fn is_sslv2_client_hello(payload: &[u8]) -> bool {
+    let mut msg = TLSMsg::new(payload);
+    let Some(len) = msg.get_uint(2) else { return false };
+    if len & 0x8000 == 0 || len & 0x7fff < 9 { // 2-byte header, no padding
+        return false;
+    }
+
+    if msg.get_uint(1) != Some(1) {    // msg_type
+        return false;
+    }
+
+    matches!(msg.get_bytes(2), Some([0x00, 0x02] | [0x03, _])) // version
+}
+
+/// Kind of ClientHello `payload` starts with, if any
+pub fn hello_kind(payload: &[u8]) -> Option<Hello> {
     let mut record = TLSMsg::new(payload);
-    if record.get_uint(1) != Some(22) { // type
-        return false;                   // not handshake
+    match record.get_uint(1)? {
+        22 => {}                        // handshake
+        b if b & 0x80 != 0 => return is_sslv2_client_hello(payload).then_some(Hello::SslV2),
+        _ => return None,
     }
 
     record.pass(2);                 // legacy_record_version
     record.pass(2);                 // length
 
     if record.get_ptr() >= payload.len() {
-        return false;
+        return None;
     }
 
     let fragment = &record.payload[record.get_ptr()..]; // fragment
     if TLSMsg::new(fragment).get_uint(1) != Some(1) { // msg_type
-        return false;                     // not clienthello
+        return None;                      // not clienthello
     }
 
-    true
+    Some(Hello::Tls)
+}
+
+pub fn is_client_hello(payload: &[u8]) -> bool {
+    hello_kind(payload).is_some()
+}
+
+/// Protocol of a payload to a TLS port that is not TLS
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonTls {
+    /// HTTP/2 with prior knowledge (RFC 9113 3.3), starting with the
+    /// connection preface
+    Http2,
+
+    /// HTTP/1.x request
+    Http,
+
+    /// Anything else not starting with a TLS record
+    Other,
+}
+
+impl std::fmt::Display for NonTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            NonTls::Http2 => "HTTP/2 (prior knowledge)",
+            NonTls::Http => "HTTP/1",
+            NonTls::Other => "not TLS",
+        })
+    }
+}
+
+/// First bytes of an HTTP/2 connection preface
+const H2_PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n";
+
+const HTTP_METHODS: &[&[u8]] = &[
+    b"GET ", b"HEAD ", b"POST ", b"PUT ", b"DELETE ", b"CONNECT ", b"OPTIONS ", b"TRACE ", b"PATCH ",
+];
+
+/// What `payload` is if it starts neither with a ClientHello nor with
+/// a TLS record (of content type 20 to 24, version 3.x)
+pub fn non_tls(payload: &[u8]) -> Option<NonTls> {
+    if matches!(payload, [20..=24, 3, ..]) || is_client_hello(payload) {
+        return None;
+    }
+
+    Some(if payload.starts_with(H2_PREFACE) {
+        NonTls::Http2
+    } else if HTTP_METHODS.iter().any(|m| payload.starts_with(m)) {
+        NonTls::Http
+    } else {
+        NonTls::Other
+    })
+}
+
+/// End of the ClientHello record `payload` starts with, wherever it is
+fn record_end(payload: &[u8]) -> Option<usize> {
+    if hello_kind(payload)? != Hello::Tls {
+        return None;
+    }
+
+    let mut record = TLSMsg::new(payload);
+    record.pass(3);                 // content_type, legacy_record_version
+    Some(record.get_ptr() + 2 + record.get_uint(2)?)
+}
+
+/// End of the ClientHello record `payload` starts with, if more follows
+/// it in `payload` (e.g. TLS 1.3 early data)
+pub fn hello_record_end(payload: &[u8]) -> Option<usize> {
+    record_end(payload).filter(|&end| end < payload.len())
+}
+
+/// Whether ClientHello `payload`, with no server name in it, has none
+/// at all: it resumes a session, or the whole record is there. A large
+/// one (post-quantum key shares) may carry its name in a later segment.
+pub fn lacks_server_name(payload: &[u8]) -> bool {
+    is_resumption(payload) || record_end(payload).is_some_and(|end| end <= payload.len())
+}
+
+const EXT_SERVER_NAME: usize = 0;
+const EXT_ALPN: usize = 16;
+const EXT_SESSION_TICKET: usize = 35;
+const EXT_PRE_SHARED_KEY: usize = 41;
+const NAME_TYPE_HOST_NAME: usize = 0;
+
+/// `payload` read up to the first extension of the ClientHello it
+/// starts with, and the end of its extensions
+fn extensions(payload: &[u8]) -> Option<(TLSMsg<'_>, usize)> {
+    if hello_kind(payload) != Some(Hello::Tls) {
+        return None;
+    }
+
+    let mut msg = TLSMsg::new(payload);
+    msg.pass(5);                    // record header
+    msg.pass(4);                    // msg_type, length
+    msg.pass(2);                    // legacy_version
+    msg.pass(32);                   // random
+
+    let len = msg.get_uint(1)?;     // legacy_session_id
+    msg.pass(len);
+    let len = msg.get_uint(2)?;     // cipher_suites
+    msg.pass(len);
+    let len = msg.get_uint(1)?;     // legacy_compression_methods
+    msg.pass(len);
+
+    let ext_end = msg.get_uint(2)? + msg.get_ptr(); // extensions
+    Some((msg, ext_end))
+}
+
+/// Body of extension `ext_type` of ClientHello `payload`, or None if it
+/// is absent, empty or not in this segment
+fn extension(payload: &[u8], ext_type: usize) -> Option<&[u8]> {
+    let (mut msg, ext_end) = extensions(payload)?;
+
+    while msg.get_ptr() < ext_end {
+        let ty = msg.get_uint(2)?;
+        let ext_len = msg.get_uint(2)?;
+
+        if ty == ext_type {
+            return msg.get_bytes(ext_len);
+        }
+        msg.pass(ext_len);
+    }
+
+    None
+}
+
+/// Offset in ClientHello `payload` where extension `ext_type` starts
+/// (its type field), or None if it is absent or not in this segment
+pub fn extension_offset(payload: &[u8], ext_type: u16) -> Option<usize> {
+    let (mut msg, ext_end) = extensions(payload)?;
+
+    while msg.get_ptr() < ext_end {
+        let at = msg.get_ptr();
+        let ty = msg.get_uint(2)?;
+        let ext_len = msg.get_uint(2)?;
+
+        if ty == usize::from(ext_type) {
+            return Some(at);
+        }
+        msg.pass(ext_len);
+    }
+
+    None
+}
+
+/// Return the host name of server_name extension (SNI) of ClientHello
+/// `payload`, or None if it is absent or not in this segment (e.g.
+/// ClientHello spanning multiple segments).
+pub fn server_name(payload: &[u8]) -> Option<&str> {
+    let mut msg = TLSMsg::new(extension(payload, EXT_SERVER_NAME)?);
+
+    msg.pass(2);                    // server_name_list length
+    if msg.get_uint(1)? != NAME_TYPE_HOST_NAME {
+        return None;
+    }
+    let len = msg.get_uint(2)?;
+
+    std::str::from_utf8(msg.get_bytes(len)?).ok()
+}
+
+/// Protocols offered in the ALPN extension of ClientHello `payload`, in
+/// the client's order; empty if it is absent or not in this segment.
+/// Names that are not UTF-8 are left out.
+pub fn alpn(payload: &[u8]) -> Vec<&str> {
+    let Some(ext) = extension(payload, EXT_ALPN) else {
+        return Vec::new();
+    };
+    let mut msg = TLSMsg::new(ext);
+    let Some(list_end) = msg.get_uint(2).map(|len| len + 2) else {
+        return Vec::new();
+    };
+
+    let mut protocols = Vec::new();
+    while msg.get_ptr() < list_end {
+        let Some(name) = msg.get_uint(1).and_then(|len| msg.get_bytes(len)) else {
+            break;
+        };
+        if let Ok(name) = std::str::from_utf8(name) {
+            protocols.push(name);
+        }
+    }
+    protocols
+}
+
+/// Whether ClientHello `payload` resumes a session: it offers a PSK
+/// (TLS 1.3) or a session ticket (TLS 1.2).
+pub fn is_resumption(payload: &[u8]) -> bool {
+    extension(payload, EXT_PRE_SHARED_KEY).is_some()
+        || extension(payload, EXT_SESSION_TICKET).is_some()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Minimal ClientHello record with given extensions
+    fn client_hello(exts: &[u8]) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];        // legacy_version
+        body.extend_from_slice(&[0; 32]);       // random
+        body.push(0);                           // legacy_session_id
+        body.extend_from_slice(&[0, 2, 0x13, 0x01]); // cipher_suites
+        body.extend_from_slice(&[1, 0]);        // compression
+        body.extend_from_slice(&(exts.len() as u16).to_be_bytes());
+        body.extend_from_slice(exts);
+
+        let mut hs = vec![1, 0];                // client_hello, length
+        hs.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        hs.extend_from_slice(&body);
+
+        let mut rec = vec![22, 0x03, 0x01];
+        rec.extend_from_slice(&(hs.len() as u16).to_be_bytes());
+        rec.extend_from_slice(&hs);
+        rec
+    }
+
+    fn sni_ext(name: &str) -> Vec<u8> {
+        let n = name.len() as u16;
+        let mut ext = vec![0, 0];
+        ext.extend_from_slice(&(n + 5).to_be_bytes());
+        ext.extend_from_slice(&(n + 3).to_be_bytes());
+        ext.push(0);
+        ext.extend_from_slice(&n.to_be_bytes());
+        ext.extend_from_slice(name.as_bytes());
+        ext
+    }
+
+    #[test]
+    fn test_server_name() {
+        let mut exts = vec![0x00, 0x2b, 0, 3, 2, 0x03, 0x04]; // supported_versions
+        exts.extend(sni_ext("example.com"));
+        let ch = client_hello(&exts);
+
+        assert!(is_client_hello(&ch));
+        assert_eq!(server_name(&ch), Some("example.com"));
+
+        // truncated before SNI (e.g. first segment of a split ClientHello)
+        assert_eq!(server_name(&ch[..ch.len() - 4]), None);
+
+        assert_eq!(server_name(&client_hello(&[0x00, 0x2b, 0, 3, 2, 0x03, 0x04])), None);
+        assert_eq!(server_name(b"GET / HTTP/1.1\r\n"), None);
+    }
+
+    #[test]
+    fn test_extension_offset() {
+        let mut exts = vec![0x00, 0x2b, 0, 3, 2, 0x03, 0x04]; // supported_versions
+        exts.extend(sni_ext("example.com"));
+        exts.extend([0x00, 0x17, 0, 0]);                   // extended_master_secret
+        let ch = client_hello(&exts);
+        let first = ch.len() - exts.len();
+
+        assert_eq!(extension_offset(&ch, 0x2b), Some(first));
+        assert_eq!(extension_offset(&ch, 0x00), Some(first + 7));
+        assert_eq!(&ch[first + 7..first + 9], [0, 0]);
+        assert_eq!(extension_offset(&ch, 0x17), Some(ch.len() - 4));
+        assert_eq!(extension_offset(&ch, 0x33), None);
+        assert_eq!(extension_offset(&ch[..first + 9], 0x17), None);
+    }
+
+    #[test]
+    fn test_alpn() {
+        let ext = [0x00, 0x10, 0, 14, 0, 12, 2, b'h', b'2', 8, b'h', b't', b't', b'p', b'/', b'1', b'.', b'1'];
+        assert_eq!(alpn(&client_hello(&ext)), ["h2", "http/1.1"]);
+        assert!(alpn(&client_hello(&sni_ext("example.com"))).is_empty());
+
+        // list length past the extension
+        let ext = [0x00, 0x10, 0, 5, 0, 9, 2, b'h', b'2'];
+        assert_eq!(alpn(&client_hello(&ext)), ["h2"]);
+    }
+
+    #[test]
+    fn test_is_resumption() {
+        assert!(!is_resumption(&client_hello(&sni_ext("example.com"))));
+        assert!(is_resumption(&client_hello(&[0x00, 0x29, 0, 2, 0xaa, 0xbb])));
+        assert!(is_resumption(&client_hello(&[0x00, 0x23, 0, 1, 0xaa])));
+        // Empty session_ticket asks for a new one
+        assert!(!is_resumption(&client_hello(&[0x00, 0x23, 0, 0])));
+    }
+
+    #[test]
+    fn test_hello_record_end() {
+        let ch = client_hello(&sni_ext("example.com"));
+        assert_eq!(hello_record_end(&ch), None);
+        assert_eq!(hello_record_end(&ch[..ch.len() - 1]), None);
+
+        let mut with_early = ch.clone();
+        with_early.extend_from_slice(&[23, 0x03, 0x03, 0, 2, 0xaa, 0xbb]); // application_data
+        assert_eq!(hello_record_end(&with_early), Some(ch.len()));
+    }
+
+    #[test]
+    fn test_lacks_server_name() {
+        let ch = client_hello(&[0x00, 0x2b, 0, 3, 2, 0x03, 0x04]); // supported_versions
+        assert!(lacks_server_name(&ch));
+        // First segment of a hello split before its name
+        assert!(!lacks_server_name(&ch[..ch.len() - 4]));
+        assert!(lacks_server_name(&client_hello(&[0x00, 0x29, 0, 2, 0xaa, 0xbb])));
+    }
+
+    #[test]
+    fn test_hello_kind() {
+        let mut ch = client_hello(&sni_ext("example.com"));
+        assert_eq!(hello_kind(&ch), Some(Hello::Tls));
+        ch[1..3].copy_from_slice(&[0x02, 0x00]); // unusual record version
+        assert_eq!(hello_kind(&ch), Some(Hello::Tls));
+
+        // SSLv2-compatible, negotiating TLS 1.0: header, type, version,
+        // cipher spec, session id and challenge lengths
+        let mut v2 = vec![0x80, 0x2e, 1, 0x03, 0x01, 0, 0x15, 0, 0, 0, 0x10];
+        v2.extend_from_slice(&[0; 0x15 + 0x10]);
+        assert_eq!(hello_kind(&v2), Some(Hello::SslV2));
+        assert_eq!(server_name(&v2), None);
+
+        v2[2] = 4;                                  // server_hello
+        assert_eq!(hello_kind(&v2), None);
+        assert_eq!(hello_kind(&[0x80, 0x02, 1, 0x03, 0x01]), None);
+        assert_eq!(hello_kind(&[0x80]), None);
+    }
+
+    #[test]
+    fn test_non_tls() {
+        assert_eq!(non_tls(&client_hello(&sni_ext("example.com"))), None);
+        assert_eq!(non_tls(&[23, 3, 3, 0, 1, 0]), None);        // application data
+        assert_eq!(non_tls(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), Some(NonTls::Http2));
+        assert_eq!(non_tls(b"GET / HTTP/1.1\r\n"), Some(NonTls::Http));
+        assert_eq!(non_tls(&[22, 0x7f, 0, 0, 0, 2]), Some(NonTls::Other));
+        assert_eq!(non_tls(&[0x80, 0x2e, 1, 0x7f]), Some(NonTls::Other));
+        assert_eq!(non_tls(&[]), Some(NonTls::Other));
+    }
 }
@@ -0,0 +1,120 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `dpibreak top`: live view of the running instance
+//!
+//! Asks `status` and `flows` on the control socket every [`REFRESH`]
+//! and redraws the terminal with them, until interrupted.
+
+use std::io::Write;
+use std::time::Duration;
+
+use anyhow::Result;
+
+use crate::{control, opt};
+
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// Clear the screen and move the cursor home
+const CLEAR: &str = "\x1b[H\x1b[2J";
+
+/// Width of a `name value` counter cell
+const CELL: usize = 26;
+
+/// Lines of the status answer that are not counters
+const HEADER_KEYS: &[&str] = &["uptime", "backend", "paused", "latency_us", "buffer_kib"];
+
+fn width() -> usize {
+    std::env::var("COLUMNS").ok()
+        .and_then(|c| c.parse().ok())
+        .filter(|&w| w >= CELL)
+        .unwrap_or(80)
+}
+
+/// Cut `s` to `n` characters, marking the cut.
+fn fit(s: &str, n: usize) -> String {
+    if s.chars().count() <= n {
+        return s.to_string();
+    }
+    let mut cut: String = s.chars().take(n.saturating_sub(1)).collect();
+    cut.push('~');
+    cut
+}
+
+/// Screen for the answers to `status` and `flows`
+fn render(status: &str, flows: &str, width: usize) -> String {
+    let fields: Vec<(&str, &str)> = status.lines()
+        .filter_map(|l| l.split_once(": "))
+        .collect();
+    let field = |k: &str| fields.iter().find(|(name, _)| *name == k).map_or("-", |(_, v)| v);
+
+    let mut out = format!("DPIBreak  uptime {}  backend {}  paused {}\n",
+                          field("uptime"), field("backend"), field("paused"));
+    out += &format!("latency(us) {}  buffer(KiB) {}\n\n",
+                    field("latency_us"), field("buffer_kib"));
+
+    // Zero counters are left out to keep the screen short.
+    let counters: Vec<String> = fields.iter()
+        .filter(|(k, v)| !HEADER_KEYS.contains(k) && *v != "0")
+        .map(|(k, v)| format!("{:<w$}", fit(&format!("{k} {v}"), CELL - 1), w = CELL))
+        .collect();
+    out += "COUNTERS\n";
+    for row in counters.chunks((width / CELL).max(1)) {
+        out += row.concat().trim_end();
+        out += "\n";
+    }
+
+    out += &format!("\nRECENT FLOWS\n{:<23} {:<23} {:<24} {:<14} {:<8} {:>4}\n",
+                    "CLIENT", "SERVER", "SNI", "STRATEGY", "OUTCOME", "AGE");
+    for flow in flows.lines().filter_map(|l| l.strip_prefix("flow: ")) {
+        let f: Vec<&str> = flow.split('\t').collect();
+        let [client, server, sni, strategy, outcome, age] = f[..] else {
+            continue;
+        };
+        out += &format!("{:<23} {:<23} {:<24} {:<14} {:<8} {:>3}s\n",
+                        fit(client, 23), fit(server, 23), fit(sni, 24), fit(strategy, 14),
+                        outcome, age);
+    }
+
+    out
+}
+
+/// `dpibreak top [OPTIONS]`
+pub fn command(args: impl Iterator<Item = String>) -> Result<()> {
+    opt::Config::parse(args)?.install();
+
+    loop {
+        let screen = render(&control::query("status")?, &control::query("flows")?, width());
+
+        let mut stdout = std::io::stdout().lock();
+        write!(stdout, "{CLEAR}{screen}")?;
+        stdout.flush()?;
+        drop(stdout);
+
+        std::thread::sleep(REFRESH);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let status = "uptime: 1m 2s\nbackend: nftables\npaused: no\nhandled: 3\nfailed: 0\n\
+                      buffer_kib: 0/4096\n";
+        let flows = "flow: 192.0.2.1:50000\t198.51.100.7:443\twww.example.com\tsplit 1 +fake\tok\t2\n\
+                     flow: bad line\n";
+        let screen = render(status, flows, 80);
+
+        assert!(screen.starts_with("DPIBreak  uptime 1m 2s  backend nftables  paused no\n"));
+        assert!(screen.contains("\nhandled 3\n"));
+        assert!(!screen.contains("failed"));
+        assert!(screen.contains("buffer(KiB) 0/4096"));
+        assert_eq!(screen.matches("www.example.com").count(), 1);
+        assert!(screen.trim_end().ends_with("ok         2s"));
+
+        assert_eq!(fit("www.example.com", 8), "www.exa~");
+        assert_eq!(fit("a.io", 8), "a.io");
+    }
+}
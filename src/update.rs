@@ -0,0 +1,262 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `dpibreak self-update`: replace the binary with the latest release
+//!
+//! The latest release is looked up on the GitHub API, as `install.sh`
+//! does. Its `sha256sum.txt` is signed by the maintainer with GnuPG
+//! (`scripts/sign.sh`); the signature is checked with `gpgv` against
+//! the release key (`release-key.asc` in the repository), the tarball against the
+//! checksum, and only then is the binary replaced. Downloads are left
+//! to `curl`, as for remote hostlists; run while DPIBreak is, they are
+//! desynced like any other connection.
+
+use anyhow::{Context, Result, anyhow};
+
+const LATEST_API: &str = "https://api.github.com/repos/dilluti0n/dpibreak/releases/latest";
+#[cfg(target_os = "linux")]
+const DOWNLOAD: &str = "https://github.com/dilluti0n/dpibreak/releases/download";
+#[cfg(target_os = "linux")]
+const SUMS: &str = "sha256sum.txt";
+
+/// Release key, unless `--keyring`
+const DEFAULT_KEYRING: &str = "/etc/dpibreak/release-key.asc";
+
+/// Numeric components of version `v`, with or without a leading `v`
+fn version(v: &str) -> Option<Vec<u64>> {
+    v.trim_start_matches('v').split('.').map(|n| n.parse().ok()).collect()
+}
+
+/// `tag_name` of a GitHub release object
+fn tag_name(json: &str) -> Option<&str> {
+    let rest = json.split_once("\"tag_name\"")?.1;
+    rest.split('"').nth(1).filter(|t| !t.is_empty())
+}
+
+/// Tag of the latest release
+fn latest() -> Result<String> {
+    let body = crate::hostlist::curl(&["--header", "X-GitHub-Api-Version: 2022-11-28", LATEST_API])?;
+    tag_name(&String::from_utf8_lossy(&body)).map(str::to_string)
+        .ok_or_else(|| anyhow!("no tag_name in {LATEST_API}"))
+}
+
+/// Temporary directory removed on drop
+#[cfg(target_os = "linux")]
+struct TempDir(std::path::PathBuf);
+
+#[cfg(target_os = "linux")]
+impl TempDir {
+    /// Create a fresh directory only we can enter, like mkdtemp(3): one
+    /// that already exists is never reused.
+    fn new() -> Result<Self> {
+        use std::os::unix::fs::DirBuilderExt;
+
+        let mut tries = 0;
+        loop {
+            let dir = std::env::temp_dir().join(format!("dpibreak-update-{:016x}", crate::rand::u64()));
+            match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+                Ok(()) => return Ok(Self(dir)),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists && tries < 8 => tries += 1,
+                Err(e) => return Err(e).with_context(|| format!("cannot create {}", dir.display())),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Bytes of base64 `text`, ignoring whitespace; None if invalid
+#[cfg(target_os = "linux")]
+fn base64(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for b in text.bytes().filter(|b| !b.is_ascii_whitespace()) {
+        let v = match b {
+            b'A'..=b'Z' => b - b'A',
+            b'a'..=b'z' => b - b'a' + 26,
+            b'0'..=b'9' => b - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        acc = acc << 6 | u32::from(v);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Binary key of ASCII-armored `armor` (`gpg --export --armor`), which
+/// `gpgv` does not read; None if it is not armored
+#[cfg(target_os = "linux")]
+fn dearmor(armor: &str) -> Option<Vec<u8>> {
+    let rest = armor.trim_start().strip_prefix("-----BEGIN PGP PUBLIC KEY BLOCK-----")?;
+    // Headers, then a blank line, then the data up to the `=` checksum
+    let (_, body) = rest.split_once("\n\n").or_else(|| rest.split_once("\r\n\r\n"))?;
+    let body = body.split("-----END").next()?;
+    let data: String = body.lines().take_while(|l| !l.starts_with('=')).collect();
+    base64(&data).filter(|key| !key.is_empty())
+}
+
+/// Checksum of `file` listed in `sha256sum` output `sums`
+#[cfg(target_os = "linux")]
+fn listed_digest<'a>(sums: &'a str, file: &str) -> Option<&'a str> {
+    sums.lines().find_map(|l| {
+        let (digest, name) = l.split_once(char::is_whitespace)?;
+        (name.trim_start().trim_start_matches('*') == file).then_some(digest)
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn install(tag: &str, keyring: &str) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{Command, Stdio};
+
+    if !std::path::Path::new(keyring).is_file() {
+        return Err(anyhow!("no release key at {keyring}; reinstall with install.sh or \
+                            `make install` from a release tarball, or give --keyring"));
+    }
+
+    let ver = tag.trim_start_matches('v');
+    let tarball = format!("dpibreak-{ver}-{}-unknown-linux-musl.tar.gz", std::env::consts::ARCH);
+    let tmp = TempDir::new()?;
+    let path = |name: &str| tmp.0.join(name).to_string_lossy().into_owned();
+
+    for name in [SUMS, &format!("{SUMS}.sig"), &tarball] {
+        crate::hostlist::curl(&["--output", &path(name), &format!("{DOWNLOAD}/{tag}/{name}")])
+            .with_context(|| format!("cannot download {name}"))?;
+    }
+
+    let armored = std::fs::read_to_string(keyring).ok().and_then(|k| dearmor(&k));
+    let keyring = match armored {
+        Some(key) => {
+            std::fs::write(path("release-key.gpg"), key)?;
+            path("release-key.gpg")
+        }
+        None => keyring.to_string(),
+    };
+
+    let output = Command::new("gpgv")
+        .args(["--keyring", &keyring, &path(&format!("{SUMS}.sig")), &path(SUMS)])
+        .stdin(Stdio::null())
+        .output()
+        .context("failed to spawn gpgv")?;
+    if !output.status.success() {
+        return Err(anyhow!("{SUMS}: bad signature: {}",
+                           String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    let sums = std::fs::read_to_string(path(SUMS))?;
+    let expected = listed_digest(&sums, &tarball)
+        .ok_or_else(|| anyhow!("{tarball} is not in {SUMS}; no build for this machine?"))?;
+    // Read once: the bytes checked are the bytes extracted
+    let bytes = std::fs::read(path(&tarball))?;
+    let actual = crate::sha256::hex_digest(&bytes);
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(anyhow!("{tarball}: sha256 mismatch: expected {expected}, got {actual}"));
+    }
+
+    let mut child = Command::new("tar")
+        .args(["-xzf", "-", "-C", &path("")])
+        .stdin(Stdio::piped())
+        .spawn()
+        .context("failed to spawn tar")?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&bytes).context("failed to write tarball to tar")?;
+    }
+    let status = child.wait().context("failed to wait for tar")?;
+    if !status.success() {
+        return Err(anyhow!("tar: cannot extract {tarball}"));
+    }
+
+    // Next to the binary, so that the rename does not cross filesystems
+    let exe = std::env::current_exe().context("cannot find own executable")?;
+    let new = exe.with_file_name(".dpibreak.new");
+    std::fs::copy(tmp.0.join(format!("dpibreak-{ver}")).join("dpibreak"), &new)
+        .with_context(|| format!("cannot write {}", new.display()))?;
+    std::fs::set_permissions(&new, std::fs::Permissions::from_mode(0o755))?;
+    std::fs::rename(&new, &exe).with_context(|| format!("cannot replace {}", exe.display()))?;
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn install(_tag: &str, _keyring: &str) -> Result<()> {
+    Err(anyhow!("not supported on Windows; download the zip from \
+                 https://github.com/dilluti0n/dpibreak/releases/latest"))
+}
+
+/// `dpibreak self-update [--check-update] [--keyring PATH]`
+pub fn command(mut args: impl Iterator<Item = String>) -> Result<()> {
+    let mut check_only = false;
+    let mut keyring = DEFAULT_KEYRING.to_string();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--check-update" => check_only = true,
+            "--keyring" => keyring = args.next().context("self-update: --keyring: missing path")?,
+            _ => return Err(anyhow!("self-update: unknown argument: {arg}")),
+        }
+    }
+
+    let current = env!("CARGO_PKG_VERSION");
+    let tag = latest().context("self-update: cannot find the latest release")?;
+    let (Some(newest), Some(running)) = (version(&tag), version(current)) else {
+        return Err(anyhow!("self-update: cannot compare {tag} with v{current}"));
+    };
+    if newest <= running {
+        println!("DPIBreak v{current} is up to date");
+        return Ok(());
+    }
+
+    println!("DPIBreak {tag} is available (running v{current})");
+    if check_only {
+        return Ok(());
+    }
+
+    install(&tag, &keyring).context("self-update")?;
+    println!("Updated to {tag}; restart DPIBreak to run it");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_release() {
+        assert!(version("v0.10.0") > version("0.9.3"));
+        assert!(version("v0.6.2") == version("0.6.2"));
+        assert_eq!(version("v1.0-rc1"), None);
+
+        let json = r#"{"url": "x", "tag_name": "v0.7.0", "name": "DPIBreak 0.7.0"}"#;
+        assert_eq!(tag_name(json), Some("v0.7.0"));
+        assert_eq!(tag_name(r#"{"message": "Not Found"}"#), None);
+
+        #[cfg(target_os = "linux")]
+        {
+            let sums = "aa11  dpibreak-0.7.0-x86_64-unknown-linux-musl.tar.gz\n\
+                        bb22 *dpibreak-0.7.0-x86_64-pc-windows-msvc.zip\n";
+            assert_eq!(listed_digest(sums, "dpibreak-0.7.0-x86_64-unknown-linux-musl.tar.gz"), Some("aa11"));
+            assert_eq!(listed_digest(sums, "dpibreak-0.7.0-x86_64-pc-windows-msvc.zip"), Some("bb22"));
+            assert_eq!(listed_digest(sums, "dpibreak-0.7.0.tar.gz"), None);
+
+            assert_eq!(base64("aGVs bG8=").as_deref(), Some(&b"hello"[..]));
+            assert_eq!(base64("aGV*"), None);
+            let armor = "-----BEGIN PGP PUBLIC KEY BLOCK-----\nComment: x\n\naGVs\nbG8=\n=AbCd\n\
+                         -----END PGP PUBLIC KEY BLOCK-----\n";
+            assert_eq!(dearmor(armor).as_deref(), Some(&b"hello"[..]));
+            assert_eq!(dearmor("\u{99}\u{1}binary"), None);
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// SPDX-FileCopyrightText: 2026 Dilluti0n <hskimse1@gmail.com>
+// SPDX-License-Identifier: GPL-3.0-or-later
+
+//! `--webhook`: POST a JSON object to a URL on notable events
+//!
+//! Meant for alerts (ntfy, a Telegram relay, ...) when the bypass
+//! changes on its own or DPIBreak fails. Events are queued to a thread
+//! that POSTs them with `curl` one at a time, so the packet path never
+//! waits on the network; when the queue is full they are dropped. The
+//! event of a failed exit is POSTed right away, as nothing runs after
+//! it.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::OnceLock;
+use std::sync::mpsc::{self, SyncSender};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result, anyhow};
+
+use crate::{opt, stats};
+
+/// Events waiting to be POSTed; more are dropped.
+const QUEUE_LEN: usize = 64;
+
+/// Seconds curl may take for one event
+const POST_TIMEOUT_SECS: &str = "10";
+
+/// `s` as a JSON string
+fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if u32::from(c) < 0x20 => out.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// JSON object of `event` at `time` with string `fields`
+fn body(event: &str, time: u64, fields: &[(&str, &str)]) -> String {
+    let mut out = format!("{{\"event\":{},\"time\":{time},\"version\":{}",
+                          json_str(event), json_str(env!("CARGO_PKG_VERSION")));
+    for (k, v) in fields {
+        out += &format!(",{}:{}", json_str(k), json_str(v));
+    }
+    out.push('}');
+    out
+}
+
+fn post(url: &str, body: &str) -> Result<()> {
+    let mut child = Command::new("curl")
+        .args(["--silent", "--show-error", "--fail", "--max-time", POST_TIMEOUT_SECS])
+        .args(["-H", "content-type: application/json"])
+        .args(["--data-binary", "@-", url])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to spawn curl")?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(body.as_bytes()).context("failed to write event to curl")?;
+    }
+
+    let output = child.wait_with_output().context("failed to wait for curl")?;
+
+    if !output.status.success() {
+        return Err(anyhow!("curl: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    Ok(())
+}
+
+fn post_logged(url: &str, body: &str) {
+    match post(url, body) {
+        Ok(()) => stats::COUNTERS.webhook_sent.inc(),
+        Err(e) => {
+            crate::warn!("webhook: {e:#}");
+            stats::COUNTERS.webhook_failed.inc();
+        }
+    }
+}
+
+fn queue(url: &'static str) -> &'static SyncSender<String> {
+    static QUEUE: OnceLock<SyncSender<String>> = OnceLock::new();
+
+    QUEUE.get_or_init(|| {
+        let (tx, rx) = mpsc::sync_channel::<String>(QUEUE_LEN);
+        std::thread::spawn(move || {
+            for body in rx {
+                post_logged(url, &body);
+            }
+        });
+        tx
+    })
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Queue `event` with `fields` for `--webhook`, if given.
+pub fn notify(event: &str, fields: &[(&str, &str)]) {
+    let Some(url) = opt::webhook() else { return };
+
+    if queue(url).try_send(body(event, now(), fields)).is_err() {
+        crate::debug!("webhook: queue full; {event} dropped");
+        stats::COUNTERS.webhook_failed.inc();
+    }
+}
+
+/// POST `event` with `fields` to `--webhook`, if given, and wait for it.
+pub fn notify_now(event: &str, fields: &[(&str, &str)]) {
+    let Some(url) = opt::webhook() else { return };
+
+    post_logged(url, &body(event, now(), fields));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_body() {
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(body("exempted", 1000, &[("server", "192.0.2.1")]),
+                   format!("{{\"event\":\"exempted\",\"time\":1000,\"version\":\"{version}\",\"server\":\"192.0.2.1\"}}"));
+        assert_eq!(json_str("a\"b\\c\nd\u{1}é"), "\"a\\\"b\\\\c\\nd\\u0001é\"");
+    }
+}